@@ -1,3 +1,6 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
 fn main() {
     // Указываем компоновщику использовать наш скрипт
     println!("cargo:rerun-if-changed=linker.ld");
@@ -9,4 +12,96 @@ fn main() {
         "cargo:rustc-env=BOOTLOADER={}",
         bootloader_locator.display()
     );
+
+    // Упаковываем каталог initrd/ в архив USTAR, который ядро встраивает
+    // через include_bytes!
+    println!("cargo:rerun-if-changed=initrd");
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    let mut archive = Vec::new();
+    let root = Path::new("initrd");
+    if root.is_dir() {
+        pack_dir(root, root, &mut archive);
+    }
+    // Конец архива: два нулевых блока
+    archive.resize(archive.len() + 1024, 0);
+    fs::write(out_dir.join("initrd.tar"), archive).unwrap();
+}
+
+/// Добавляет в архив содержимое каталога `dir` в порядке имён
+fn pack_dir(root: &Path, dir: &Path, archive: &mut Vec<u8>) {
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+
+    for path in entries {
+        println!("cargo:rerun-if-changed={}", path.display());
+        let name = path
+            .strip_prefix(root)
+            .unwrap()
+            .to_str()
+            .expect("non UTF-8 path in initrd")
+            .replace('\\', "/");
+        if path.is_dir() {
+            push_header(archive, &format!("{}/", name), 0o755, 0, b'5');
+            pack_dir(root, &path, archive);
+        } else {
+            let data = fs::read(&path).unwrap();
+            push_header(archive, &name, file_mode(&path), data.len(), b'0');
+            archive.extend_from_slice(&data);
+            let padding = (512 - data.len() % 512) % 512;
+            archive.resize(archive.len() + padding, 0);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn file_mode(path: &Path) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).unwrap().permissions().mode() & 0o777
+}
+
+#[cfg(not(unix))]
+fn file_mode(_path: &Path) -> u32 {
+    0o644
+}
+
+/// Заголовок USTAR; длинные пути делятся на prefix и name
+fn push_header(archive: &mut Vec<u8>, path: &str, mode: u32, size: usize, kind: u8) {
+    let (prefix, name) = if path.len() <= 100 {
+        ("", path)
+    } else {
+        let split = path[..path.len().min(156)]
+            .rfind('/')
+            .filter(|&i| path.len() - i - 1 <= 100)
+            .unwrap_or_else(|| panic!("initrd path too long: {}", path));
+        (&path[..split], &path[split + 1..])
+    };
+
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], mode as u64);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size as u64);
+    write_octal(&mut header[136..148], 0);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // Контрольная сумма считается с полем, заполненным пробелами
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    write_octal(&mut header[148..155], checksum as u64);
+    archive.extend_from_slice(&header);
+}
+
+/// Восьмеричное число с ведущими нулями и завершающим нулём
+fn write_octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{:0width$o}", value, width = width);
+    field[..width].copy_from_slice(digits.as_bytes());
+    field[width] = 0;
 }
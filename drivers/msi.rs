@@ -0,0 +1,306 @@
+//! Message signalled interrupts for PCI devices
+//!
+//! With MSI or MSI-X a device raises an interrupt by writing a message to
+//! the local APIC instead of asserting a shared interrupt pin. Each
+//! message gets an IRQ line of its own from the range above the I/O APIC
+//! inputs (see `irq`), so drivers register their handlers on the lines in
+//! [`MsiVectors::irqs`] without sharing them with other devices.
+//!
+//! MSI gives a device a power of two of consecutive vectors programmed
+//! through its capability; MSI-X has a table of independent address/data
+//! pairs in one of the device's memory BARs.
+//!
+//! Drivers that need a single interrupt use [`PciDevice::request_irq`],
+//! which falls back to the interrupt pin for devices without MSI.
+
+use crate::address_space::AddressSpaceError;
+use crate::apic::{self, ApicError};
+use crate::drivers::pci::{CapabilityKind, PciDevice};
+use crate::irq::{self, IrqError, IrqHandle, IrqReturn};
+use crate::mmio::{self, CacheMode, Mmio};
+use alloc::vec::Vec;
+use core::fmt;
+
+// MSI capability registers, relative to the capability
+const MSI_CONTROL: u16 = 0x02;
+const MSI_ADDRESS: u16 = 0x04;
+const MSI_ADDRESS_HIGH: u16 = 0x08;
+const MSI_DATA_32: u16 = 0x08;
+const MSI_DATA_64: u16 = 0x0C;
+
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_64BIT: u16 = 1 << 7;
+
+// MSI-X capability registers, relative to the capability
+const MSIX_CONTROL: u16 = 0x02;
+const MSIX_TABLE: u16 = 0x04;
+
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+
+// MSI-X table entry layout
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_ENTRY_ADDRESS: usize = 0x0;
+const MSIX_ENTRY_ADDRESS_HIGH: usize = 0x4;
+const MSIX_ENTRY_DATA: usize = 0x8;
+const MSIX_ENTRY_CONTROL: usize = 0xC;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+/// Message signalled interrupts enabled on a device. Dropping it turns
+/// them off again, returns the IRQ lines and lets the device use its
+/// interrupt pin; unregister the handlers first.
+pub struct MsiVectors {
+    device: PciDevice,
+    /// Offset of the MSI or MSI-X capability
+    capability: u16,
+    irqs: Vec<u8>,
+    /// The MSI-X table, `None` for MSI
+    table: Option<Mmio>,
+}
+
+impl MsiVectors {
+    /// IRQ lines of the messages, in message order
+    pub fn irqs(&self) -> &[u8] {
+        &self.irqs
+    }
+
+    /// Whether the messages are MSI-X rather than MSI
+    pub fn is_msix(&self) -> bool {
+        self.table.is_some()
+    }
+}
+
+impl Drop for MsiVectors {
+    fn drop(&mut self) {
+        match &self.table {
+            Some(table) => {
+                for index in 0..self.irqs.len() {
+                    let control = index * MSIX_ENTRY_SIZE as usize + MSIX_ENTRY_CONTROL;
+                    table.update::<u32>(control, |control| control | MSIX_ENTRY_MASKED);
+                }
+                let offset = self.capability + MSIX_CONTROL;
+                let control = self.device.read_config_word(offset);
+                self.device
+                    .write_config_word(offset, control & !MSIX_ENABLE);
+            }
+            None => {
+                let offset = self.capability + MSI_CONTROL;
+                let control = self.device.read_config_word(offset);
+                self.device.write_config_word(offset, control & !MSI_ENABLE);
+            }
+        }
+        self.device.enable_intx();
+        for &irq in &self.irqs {
+            irq::free_msi_irq(irq);
+        }
+    }
+}
+
+/// Interrupt handler attached to a device with [`PciDevice::request_irq`].
+/// Dropping it removes the handler and turns MSI off again; the device
+/// must not raise interrupts any more by then.
+pub struct DeviceIrq {
+    irq: u8,
+    /// Taken when dropped
+    handle: Option<IrqHandle>,
+    msi: Option<MsiVectors>,
+}
+
+impl DeviceIrq {
+    /// IRQ line the handler is attached to
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+
+    /// Whether the device signals the interrupt by message
+    pub fn is_msi(&self) -> bool {
+        self.msi.is_some()
+    }
+}
+
+impl Drop for DeviceIrq {
+    fn drop(&mut self) {
+        // The handler goes first; the messages are turned off after it
+        if let Some(handle) = self.handle.take() {
+            irq::unregister_irq(handle);
+        }
+    }
+}
+
+impl PciDevice {
+    /// Attach `handler` to the device's interrupt: a single MSI or MSI-X
+    /// vector when the device supports it, the interrupt pin otherwise.
+    pub fn request_irq<F>(&self, name: &'static str, handler: F) -> Result<DeviceIrq, IrqError>
+    where
+        F: Fn() -> IrqReturn + Send + Sync + 'static,
+    {
+        let msi = self.enable_msi_vectors(1).ok();
+        let handle = match &msi {
+            Some(msi) => irq::register_irq(msi.irqs()[0], name, handler)?,
+            // Line 0xFF means the firmware didn't connect the pin
+            None if self.interrupt_pin == 0 || self.interrupt_line == 0xFF => {
+                return Err(IrqError::InvalidIrq(self.interrupt_line));
+            }
+            None => irq::register_pci_irq(self.interrupt_line, name, handler)?,
+        };
+        Ok(DeviceIrq {
+            irq: handle.irq(),
+            handle: Some(handle),
+            msi,
+        })
+    }
+
+    /// Enable up to `max` message signalled interrupts, preferring MSI-X
+    /// over MSI. The device may get fewer vectors than asked for.
+    pub fn enable_msi_vectors(&self, max: usize) -> Result<MsiVectors, MsiError> {
+        match self.enable_msix(max) {
+            Err(MsiError::NotSupported) => self.enable_msi(max),
+            result => result,
+        }
+    }
+
+    /// Enable MSI with the largest power of two of vectors that is at most
+    /// `max` and that the device supports.
+    pub fn enable_msi(&self, max: usize) -> Result<MsiVectors, MsiError> {
+        let capability = self
+            .find_capability(CapabilityKind::Msi)
+            .ok_or(MsiError::NotSupported)?
+            .offset;
+        if max == 0 {
+            return Err(MsiError::NoVectors);
+        }
+        let control = self.read_config_word(capability + MSI_CONTROL);
+        // Multiple Message Capable, log2 of the vectors the device has
+        let capable = 1 << ((control >> 1) & 0x7);
+        let count = 1 << max.min(capable).ilog2();
+
+        let first = irq::allocate_msi_irqs(count).map_err(MsiError::Irq)?;
+        let irqs: Vec<u8> = (first..first + count as u8).collect();
+        let (address, data) = match apic::msi_message(irq::vector(first)) {
+            Ok(message) => message,
+            Err(e) => {
+                irqs.iter().for_each(|&irq| irq::free_msi_irq(irq));
+                return Err(MsiError::Apic(e));
+            }
+        };
+
+        self.write_config_dword(capability + MSI_ADDRESS, address as u32);
+        if control & MSI_64BIT != 0 {
+            self.write_config_dword(capability + MSI_ADDRESS_HIGH, (address >> 32) as u32);
+            self.write_config_word(capability + MSI_DATA_64, data as u16);
+        } else {
+            self.write_config_word(capability + MSI_DATA_32, data as u16);
+        }
+        // Multiple Message Enable; the device puts the message number in the
+        // low bits of the data, hence the aligned vectors
+        let enable = (count.ilog2() as u16) << 4;
+        let control = (control & !(0x7 << 4)) | enable | MSI_ENABLE;
+        self.write_config_word(capability + MSI_CONTROL, control);
+        self.disable_intx();
+
+        Ok(MsiVectors {
+            device: *self,
+            capability,
+            irqs,
+            table: None,
+        })
+    }
+
+    /// Enable MSI-X with up to `max` vectors.
+    pub fn enable_msix(&self, max: usize) -> Result<MsiVectors, MsiError> {
+        let capability = self
+            .find_capability(CapabilityKind::MsiX)
+            .ok_or(MsiError::NotSupported)?
+            .offset;
+        if max == 0 {
+            return Err(MsiError::NoVectors);
+        }
+        let control = self.read_config_word(capability + MSIX_CONTROL);
+        let table_size = (control & 0x7FF) as usize + 1;
+        let count = max.min(table_size);
+
+        // BAR indicator in the low bits, offset into the BAR above them
+        let table = self.read_config_dword(capability + MSIX_TABLE);
+        let (bar, bar_size) = self
+            .memory_bar((table & 0x7) as usize)
+            .ok_or(MsiError::MissingTable)?;
+        let offset = (table & !0x7) as u64;
+        let size = count as u64 * MSIX_ENTRY_SIZE;
+        if offset + size > bar_size {
+            return Err(MsiError::MissingTable);
+        }
+        let table =
+            mmio::ioremap(bar + offset, size, CacheMode::Uncached).map_err(MsiError::Map)?;
+        // The table is only reachable with memory decoding on
+        self.enable_memory_space();
+
+        // Make do with fewer vectors if the lines run out
+        let mut irqs = Vec::with_capacity(count);
+        while irqs.len() < count {
+            match irq::allocate_msi_irqs(1) {
+                Ok(irq) => irqs.push(irq),
+                Err(_) if !irqs.is_empty() => break,
+                Err(e) => return Err(MsiError::Irq(e)),
+            }
+        }
+        let messages: Result<Vec<_>, _> = irqs
+            .iter()
+            .map(|&irq| apic::msi_message(irq::vector(irq)))
+            .collect();
+        let messages = match messages {
+            Ok(messages) => messages,
+            Err(e) => {
+                irqs.iter().for_each(|&irq| irq::free_msi_irq(irq));
+                return Err(MsiError::Apic(e));
+            }
+        };
+
+        // Entries are written with the whole function masked
+        let control = control | MSIX_FUNCTION_MASK;
+        self.write_config_word(capability + MSIX_CONTROL, control | MSIX_ENABLE);
+        for (index, (address, data)) in messages.iter().enumerate() {
+            let entry = index * MSIX_ENTRY_SIZE as usize;
+            table.update::<u32>(entry + MSIX_ENTRY_CONTROL, |c| c | MSIX_ENTRY_MASKED);
+            table.write::<u32>(entry + MSIX_ENTRY_ADDRESS, *address as u32);
+            table.write::<u32>(entry + MSIX_ENTRY_ADDRESS_HIGH, (*address >> 32) as u32);
+            table.write::<u32>(entry + MSIX_ENTRY_DATA, *data);
+            table.update::<u32>(entry + MSIX_ENTRY_CONTROL, |c| c & !MSIX_ENTRY_MASKED);
+        }
+        self.disable_intx();
+        let control = (control & !MSIX_FUNCTION_MASK) | MSIX_ENABLE;
+        self.write_config_word(capability + MSIX_CONTROL, control);
+
+        Ok(MsiVectors {
+            device: *self,
+            capability,
+            irqs,
+            table: Some(table),
+        })
+    }
+}
+
+/// Errors enabling message signalled interrupts
+#[derive(Debug, Clone, Copy)]
+pub enum MsiError {
+    /// The device has no MSI or MSI-X capability
+    NotSupported,
+    NoVectors,
+    /// The MSI-X table isn't inside a memory BAR
+    MissingTable,
+    Irq(IrqError),
+    Apic(ApicError),
+    Map(AddressSpaceError),
+}
+
+impl fmt::Display for MsiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MsiError::NotSupported => write!(f, "Device has no MSI capability"),
+            MsiError::NoVectors => write!(f, "No MSI vectors requested"),
+            MsiError::MissingTable => write!(f, "MSI-X table outside of the memory BARs"),
+            MsiError::Irq(e) => write!(f, "Failed to allocate MSI vector: {}", e),
+            MsiError::Apic(e) => write!(f, "Failed to compose MSI message: {}", e),
+            MsiError::Map(e) => write!(f, "Failed to map MSI-X table: {}", e),
+        }
+    }
+}
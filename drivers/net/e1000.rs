@@ -7,15 +7,27 @@
 extern crate alloc;
 
 use alloc::boxed::Box;
-use alloc::vec::Vec;
+use alloc::sync::Arc;
+use crate::dma::{DmaBuffer, DMA64_LIMIT};
+use crate::drivers::msi::DeviceIrq;
+use crate::drivers::pci::{DriverData, PciDevice, PciDriver, PciMatch, ProbeError};
+use crate::irq::IrqReturn;
+use crate::mmio::{self, CacheMode, Mmio};
+use crate::sync::IrqSpinLock;
+use crate::time;
 use core::fmt;
-use core::ptr::{read_volatile, write_volatile};
+use core::mem::size_of;
+use core::ptr;
+use core::time::Duration;
+use x86_64::PhysAddr;
 
 /// Number of descriptors in the transmit and receive rings
 const TX_RING_SIZE: usize = 16;
 const RX_RING_SIZE: usize = 16;
 /// Buffer size for each packet
 const BUFFER_SIZE: usize = 2048;
+/// Size of the register block behind BAR0
+pub const REGISTERS_SIZE: u64 = 0x20000;
 
 /// Transmit descriptor
 #[repr(C, packed)]
@@ -42,51 +54,56 @@ struct RxDesc {
 
 /// Intel E1000 network driver
 pub struct E1000Driver {
-    mmio_base: *mut u32,
-    tx_descs: Vec<TxDesc>,
-    rx_descs: Vec<RxDesc>,
-    tx_buffers: Vec<Box<[u8; BUFFER_SIZE]>>,
-    rx_buffers: Vec<Box<[u8; BUFFER_SIZE]>>,
+    regs: Mmio,
+    tx_descs: DmaBuffer,
+    rx_descs: DmaBuffer,
+    tx_buffers: DmaBuffer,
+    rx_buffers: DmaBuffer,
     tx_cur: usize,
     rx_cur: usize,
     initialized: bool,
 }
 
 impl E1000Driver {
-    /// Create a new driver instance
-    pub fn new(mmio_base: usize) -> Self {
-        let mut tx_descs = Vec::with_capacity(TX_RING_SIZE);
-        let mut rx_descs = Vec::with_capacity(RX_RING_SIZE);
-        let mut tx_buffers = Vec::with_capacity(TX_RING_SIZE);
-        let mut rx_buffers = Vec::with_capacity(RX_RING_SIZE);
-
-        for _ in 0..TX_RING_SIZE {
-            tx_descs.push(TxDesc {
-                addr: 0,
-                length: 0,
-                cso: 0,
-                cmd: 0,
-                status: 0,
-                css: 0,
-                special: 0,
-            });
-            tx_buffers.push(Box::new([0u8; BUFFER_SIZE]));
-        }
+    /// Create a new driver instance for the device whose registers are at
+    /// physical address `bar`
+    pub fn new(bar: PhysAddr) -> Result<Self, NetError> {
+        let regs = mmio::ioremap(bar, REGISTERS_SIZE, CacheMode::Uncached)
+            .map_err(|_| NetError::MapFailed)?;
+        Self::with_registers(regs)
+    }
 
-        for _ in 0..RX_RING_SIZE {
-            rx_descs.push(RxDesc {
-                addr: 0,
-                length: 0,
-                csum: 0,
-                status: 0,
-                errors: 0,
-                special: 0,
-            });
-            rx_buffers.push(Box::new([0u8; BUFFER_SIZE]));
-        }
+    /// Create a driver instance using already mapped registers
+    pub fn with_registers(regs: Mmio) -> Result<Self, NetError> {
+        // The device takes 64-bit addresses; rings need 128 byte alignment
+        let alloc = |size| {
+            DmaBuffer::new(size, 128, DMA64_LIMIT).map_err(|_| NetError::OutOfMemory)
+        };
+        Ok(Self::with_buffers(
+            regs,
+            alloc(TX_RING_SIZE * size_of::<TxDesc>())?,
+            alloc(RX_RING_SIZE * size_of::<RxDesc>())?,
+            alloc(TX_RING_SIZE * BUFFER_SIZE)?,
+            alloc(RX_RING_SIZE * BUFFER_SIZE)?,
+        ))
+    }
 
+    /// Create a driver instance using already mapped registers and already
+    /// allocated descriptor rings and packet buffers. The rings must be
+    /// 128 byte aligned.
+    pub fn with_buffers(
+        regs: Mmio,
+        tx_descs: DmaBuffer,
+        rx_descs: DmaBuffer,
+        tx_buffers: DmaBuffer,
+        rx_buffers: DmaBuffer,
+    ) -> Self {
+        assert!(tx_descs.len() >= TX_RING_SIZE * size_of::<TxDesc>());
+        assert!(rx_descs.len() >= RX_RING_SIZE * size_of::<RxDesc>());
+        assert!(tx_buffers.len() >= TX_RING_SIZE * BUFFER_SIZE);
+        assert!(rx_buffers.len() >= RX_RING_SIZE * BUFFER_SIZE);
         Self {
-            mmio_base: mmio_base as *mut u32,
+            regs,
             tx_descs,
             rx_descs,
             tx_buffers,
@@ -99,41 +116,60 @@ impl E1000Driver {
 
     /// Initialize the device and DMA rings
     pub fn init(&mut self) {
-        unsafe {
-            // Reset device
-            self.write_reg(0x0000, 0x04000000);
-            self.write_reg(0x0000, 0x00000000);
-
-            // Initialize transmit ring
-            for (i, buf) in self.tx_buffers.iter_mut().enumerate() {
-                self.tx_descs[i].addr = buf.as_ptr() as u64;
-                self.tx_descs[i].status = 0x1;
-            }
-            let tx_base = self.tx_descs.as_ptr() as u64;
-            self.write_reg(0x03800, (tx_base & 0xFFFF_FFFF) as u32);
-            self.write_reg(0x03804, (tx_base >> 32) as u32);
-            self.write_reg(0x03808, (TX_RING_SIZE * core::mem::size_of::<TxDesc>()) as u32);
-            self.write_reg(0x03810, 0);
-            self.write_reg(0x03818, 0);
-
-            // Initialize receive ring
-            for (i, buf) in self.rx_buffers.iter_mut().enumerate() {
-                self.rx_descs[i].addr = buf.as_ptr() as u64;
-            }
-            let rx_base = self.rx_descs.as_ptr() as u64;
-            self.write_reg(0x02800, (rx_base & 0xFFFF_FFFF) as u32);
-            self.write_reg(0x02804, (rx_base >> 32) as u32);
-            self.write_reg(0x02808, (RX_RING_SIZE * core::mem::size_of::<RxDesc>()) as u32);
-            self.write_reg(0x02810, 0);
-            self.write_reg(0x02818, (RX_RING_SIZE as u32) - 1);
-
-            // Enable transmitter and receiver
-            self.write_reg(0x00400, 0x0000000C);
-            self.write_reg(0x0100, 0x00000002);
+        // Reset device
+        self.write_reg(0x0000, 0x04000000);
+        self.write_reg(0x0000, 0x00000000);
+
+        // Initialize transmit ring
+        for i in 0..TX_RING_SIZE {
+            let buf = self.tx_buffers.phys_addr() + (i * BUFFER_SIZE) as u64;
+            let desc = self.tx_desc(i);
+            desc.addr = buf.as_u64();
+            desc.status = 0x1;
+        }
+        let tx_base = self.tx_descs.phys_addr().as_u64();
+        self.write_reg(0x03800, (tx_base & 0xFFFF_FFFF) as u32);
+        self.write_reg(0x03804, (tx_base >> 32) as u32);
+        self.write_reg(0x03808, (TX_RING_SIZE * size_of::<TxDesc>()) as u32);
+        self.write_reg(0x03810, 0);
+        self.write_reg(0x03818, 0);
+
+        // Initialize receive ring
+        for i in 0..RX_RING_SIZE {
+            let buf = self.rx_buffers.phys_addr() + (i * BUFFER_SIZE) as u64;
+            self.rx_desc(i).addr = buf.as_u64();
         }
+        let rx_base = self.rx_descs.phys_addr().as_u64();
+        self.write_reg(0x02800, (rx_base & 0xFFFF_FFFF) as u32);
+        self.write_reg(0x02804, (rx_base >> 32) as u32);
+        self.write_reg(0x02808, (RX_RING_SIZE * size_of::<RxDesc>()) as u32);
+        self.write_reg(0x02810, 0);
+        self.write_reg(0x02818, (RX_RING_SIZE as u32) - 1);
+
+        // Enable transmitter and receiver
+        self.write_reg(0x00400, 0x0000000C);
+        self.write_reg(0x0100, 0x00000002);
+
+        // Interrupt on transmit done, link change, received frames and a
+        // receive ring running low or overflowing
+        self.write_reg(0x00D0, 0x000000D5);
+
         self.initialized = true;
     }
 
+    /// Stop the rings and mask all interrupts, after which the device no
+    /// longer touches the DMA buffers
+    pub fn stop(&mut self) -> Result<(), NetError> {
+        self.write_reg(0x00D8, 0xFFFFFFFF);
+        self.write_reg(0x0100, 0);
+        self.write_reg(0x00400, 0);
+        // A reset drops whatever DMA is still in flight
+        self.write_reg(0x0000, 0x04000000);
+        self.initialized = false;
+        time::wait_for(Duration::from_millis(10), || self.read_reg(0x0000) & 0x04000000 == 0)
+            .map_err(|_| NetError::Timeout)
+    }
+
     /// Send an Ethernet frame
     pub fn send_packet(&mut self, data: &[u8]) -> Result<(), NetError> {
         if !self.initialized {
@@ -144,17 +180,16 @@ impl E1000Driver {
         }
 
         let idx = self.tx_cur % TX_RING_SIZE;
-        let buf = &mut self.tx_buffers[idx];
-        buf[..data.len()].copy_from_slice(data);
+        let buf = &mut self.tx_buffers[idx * BUFFER_SIZE..][..data.len()];
+        buf.copy_from_slice(data);
 
-        self.tx_descs[idx].length = data.len() as u16;
-        self.tx_descs[idx].cmd = 0b0000_1011; // EOP + IFCS + RS
-        self.tx_descs[idx].status = 0;
+        let desc = self.tx_desc(idx);
+        desc.length = data.len() as u16;
+        desc.cmd = 0b0000_1011; // EOP + IFCS + RS
+        desc.status = 0;
 
         self.tx_cur = (self.tx_cur + 1) % TX_RING_SIZE;
-        unsafe {
-            self.write_reg(0x03818, self.tx_cur as u32);
-        }
+        self.write_reg(0x03818, self.tx_cur as u32);
         Ok(())
     }
 
@@ -165,47 +200,111 @@ impl E1000Driver {
         }
 
         let idx = self.rx_cur % RX_RING_SIZE;
-        if self.rx_descs[idx].status & 0x01 == 0 {
+        // Written by the device behind our back
+        let status = unsafe { ptr::addr_of!(self.rx_desc(idx).status).read_volatile() };
+        if status & 0x01 == 0 {
             return Err(NetError::NoPacket);
         }
 
-        let length = self.rx_descs[idx].length as usize;
+        let length = self.rx_desc(idx).length as usize;
         if length > buffer.len() {
             return Err(NetError::BufferTooSmall);
         }
 
-        buffer[..length].copy_from_slice(&self.rx_buffers[idx][..length]);
-        self.rx_descs[idx].status = 0;
+        buffer[..length].copy_from_slice(&self.rx_buffers[idx * BUFFER_SIZE..][..length]);
+        self.rx_desc(idx).status = 0;
         self.rx_cur = (self.rx_cur + 1) % RX_RING_SIZE;
-        unsafe {
-            self.write_reg(0x02818, ((self.rx_cur + RX_RING_SIZE - 1) % RX_RING_SIZE) as u32);
-        }
+        self.write_reg(0x02818, ((self.rx_cur + RX_RING_SIZE - 1) % RX_RING_SIZE) as u32);
         Ok(length)
     }
 
     /// Handle an interrupt from the device
-    pub fn handle_interrupt(&mut self) {
+    pub fn handle_interrupt(&mut self) -> IrqReturn {
         if !self.initialized {
-            return;
+            return IrqReturn::None;
         }
 
-        unsafe {
-            let icr = self.read_reg(0x000C);
-            // Acknowledge interrupts by writing back the value
-            self.write_reg(0x000C, icr);
+        let icr = self.read_reg(0x00C0);
+        if icr == 0 {
+            return IrqReturn::None;
         }
+        // Acknowledge interrupts by writing back the value
+        self.write_reg(0x00C0, icr);
+        IrqReturn::Handled
+    }
+
+    fn tx_desc(&mut self, index: usize) -> &mut TxDesc {
+        assert!(index < TX_RING_SIZE);
+        unsafe { &mut *self.tx_descs.ptr::<TxDesc>().add(index) }
+    }
+
+    fn rx_desc(&mut self, index: usize) -> &mut RxDesc {
+        assert!(index < RX_RING_SIZE);
+        unsafe { &mut *self.rx_descs.ptr::<RxDesc>().add(index) }
     }
 
     #[inline]
-    unsafe fn read_reg(&self, offset: u32) -> u32 {
-        let ptr = self.mmio_base.add((offset / 4) as usize);
-        read_volatile(ptr)
+    fn read_reg(&self, offset: u32) -> u32 {
+        self.regs.read(offset as usize)
     }
 
     #[inline]
-    unsafe fn write_reg(&self, offset: u32, value: u32) {
-        let ptr = self.mmio_base.add((offset / 4) as usize);
-        write_volatile(ptr, value);
+    fn write_reg(&self, offset: u32, value: u32) {
+        self.regs.write(offset as usize, value);
+    }
+}
+
+/// Devices the driver handles
+const PCI_IDS: &[PciMatch] = &[
+    PciMatch::device(0x8086, 0x100E), // 82540EM, emulated by QEMU
+    PciMatch::device(0x8086, 0x100F), // 82545EM
+    PciMatch::device(0x8086, 0x1004), // 82543GC
+    PciMatch::device(0x8086, 0x107C), // 82541PI
+];
+
+/// Binds the driver to supported Intel controllers
+pub struct E1000PciDriver;
+
+/// State kept for a bound controller
+struct E1000Device {
+    driver: Arc<IrqSpinLock<E1000Driver>>,
+    irq: DeviceIrq,
+}
+
+impl PciDriver for E1000PciDriver {
+    fn name(&self) -> &'static str {
+        "e1000"
+    }
+
+    fn id_table(&self) -> &'static [PciMatch] {
+        PCI_IDS
+    }
+
+    fn probe(&self, device: &PciDevice) -> Result<DriverData, ProbeError> {
+        let (bar, _) = device
+            .memory_bar(0)
+            .ok_or(ProbeError::MissingResource("memory BAR"))?;
+        device.enable_memory_space();
+        device.enable_bus_master();
+        let driver = E1000Driver::new(bar).map_err(ProbeError::init)?;
+        let driver = Arc::new(IrqSpinLock::new(driver));
+        let handler = driver.clone();
+        let irq = device
+            .request_irq("e1000", move || handler.lock().handle_interrupt())
+            .map_err(ProbeError::init)?;
+        driver.lock().init();
+        Ok(Box::new(E1000Device { driver, irq }))
+    }
+
+    fn remove(&self, _device: &PciDevice, data: DriverData) {
+        if let Ok(device) = data.downcast::<E1000Device>() {
+            // The rings must be idle before the buffers go with the driver
+            let E1000Device { driver, irq } = *device;
+            if let Err(e) = driver.lock().stop() {
+                log::warn!("e1000: {}", e);
+            }
+            drop(irq);
+        }
     }
 }
 
@@ -215,6 +314,9 @@ pub enum NetError {
     NotInitialized,
     BufferTooSmall,
     NoPacket,
+    MapFailed,
+    OutOfMemory,
+    Timeout,
 }
 
 impl fmt::Display for NetError {
@@ -223,6 +325,9 @@ impl fmt::Display for NetError {
             NetError::NotInitialized => write!(f, "Driver not initialized"),
             NetError::BufferTooSmall => write!(f, "Buffer too small"),
             NetError::NoPacket => write!(f, "No packet available"),
+            NetError::MapFailed => write!(f, "Failed to map device registers"),
+            NetError::OutOfMemory => write!(f, "Out of memory for DMA buffers"),
+            NetError::Timeout => write!(f, "Reset timed out"),
         }
     }
 }
@@ -231,10 +336,50 @@ impl fmt::Display for NetError {
 mod tests {
     use super::*;
 
+    /// Heap memory standing in for DMA memory; the device never sees it
+    fn fake_dma(memory: &mut [u8]) -> DmaBuffer {
+        let virt = x86_64::VirtAddr::from_ptr(memory.as_mut_ptr());
+        unsafe { DmaBuffer::from_raw(virt, PhysAddr::zero(), memory.len()) }
+    }
+
     #[test]
     fn test_new_driver() {
-        let driver = E1000Driver::new(0xFEC00000);
-        assert_eq!(driver.mmio_base as usize, 0xFEC00000);
+        let mut regs = alloc::vec![0u32; REGISTERS_SIZE as usize / 4];
+        let mut tx_descs = alloc::vec![0u8; TX_RING_SIZE * size_of::<TxDesc>()];
+        let mut rx_descs = alloc::vec![0u8; RX_RING_SIZE * size_of::<RxDesc>()];
+        let mut tx_buffers = alloc::vec![0u8; TX_RING_SIZE * BUFFER_SIZE];
+        let mut rx_buffers = alloc::vec![0u8; RX_RING_SIZE * BUFFER_SIZE];
+        let mmio = unsafe { Mmio::from_raw(regs.as_mut_ptr().cast(), REGISTERS_SIZE as usize) };
+        let driver = E1000Driver::with_buffers(
+            mmio,
+            fake_dma(&mut tx_descs),
+            fake_dma(&mut rx_descs),
+            fake_dma(&mut tx_buffers),
+            fake_dma(&mut rx_buffers),
+        );
+        assert_eq!(driver.regs.as_mut_ptr(), regs.as_mut_ptr().cast());
         assert!(!driver.initialized);
     }
+
+    #[test_case]
+    fn test_init_rings() {
+        let mut regs = alloc::vec![0u32; REGISTERS_SIZE as usize / 4];
+        let mmio = unsafe { Mmio::from_raw(regs.as_mut_ptr().cast(), REGISTERS_SIZE as usize) };
+        let mut driver = E1000Driver::with_registers(mmio).unwrap();
+        driver.init();
+
+        // Ring base, low and high halves, and length for transmit and receive
+        let rings = [
+            (0x03800, driver.tx_descs.phys_addr(), TX_RING_SIZE * size_of::<TxDesc>()),
+            (0x02800, driver.rx_descs.phys_addr(), RX_RING_SIZE * size_of::<RxDesc>()),
+        ];
+        for (base, phys, len) in rings {
+            let ring = ((regs[base / 4 + 1] as u64) << 32) | regs[base / 4] as u64;
+            assert_eq!(ring, phys.as_u64());
+            assert_eq!(ring % 128, 0);
+            assert_eq!(regs[(base + 0x8) / 4] as usize, len);
+        }
+        let first = driver.tx_desc(0).addr;
+        assert_eq!(first, driver.tx_buffers.phys_addr().as_u64());
+    }
 }
@@ -7,13 +7,26 @@
 
 extern crate alloc;
 
-use alloc::boxed::Box;
 use core::fmt;
-use core::ptr;
+use core::time::Duration;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use crate::dma::{DmaBuffer, DMA32_LIMIT};
+use crate::drivers::msi::DeviceIrq;
+use crate::drivers::pci::{DriverData, PciDevice, PciDriver, PciMatch, ProbeError};
+use crate::irq::IrqReturn;
+use crate::sync::IrqSpinLock;
+use crate::time;
 use x86_64::instructions::port::Port;
 
-/// Size of the receive buffer
-const RX_BUFFER_SIZE: usize = 8192 + 16 + 1500;
+/// Size of the receive ring (RCR.RBLEN = 0)
+const RX_RING_SIZE: usize = 8192;
+/// Size of the receive buffer: the ring plus room for a packet that the
+/// card writes past its end, as it does with RCR.WRAP set
+const RX_BUFFER_SIZE: usize = RX_RING_SIZE + 16 + 1500;
+/// RCR: accept broadcast, multicast and physical match frames, and don't
+/// wrap packets around the end of the ring
+const RCR_CONFIG: u32 = 0x0000_008E;
 /// Size of each transmit buffer
 const TX_BUFFER_SIZE: usize = 1792;
 
@@ -21,8 +34,9 @@ const TX_BUFFER_SIZE: usize = 1792;
 pub struct RTL8139Driver {
     io_base: u16,
     irq: u8,
-    rx_buffer: Box<[u8; RX_BUFFER_SIZE]>,
-    tx_buffers: [Box<[u8; TX_BUFFER_SIZE]>; 4],
+    rx_buffer: DmaBuffer,
+    /// The four transmit buffers back to back
+    tx_buffers: DmaBuffer,
     cur_tx: usize,
     cur_rx: usize,
     initialized: bool,
@@ -30,21 +44,18 @@ pub struct RTL8139Driver {
 
 impl RTL8139Driver {
     /// Create new driver instance
-    pub fn new(io_base: u16, irq: u8) -> Self {
-        Self {
+    pub fn new(io_base: u16, irq: u8) -> Result<Self, NetError> {
+        // The card only takes 32-bit buffer addresses
+        let alloc = |size| DmaBuffer::new(size, 4, DMA32_LIMIT).map_err(|_| NetError::OutOfMemory);
+        Ok(Self {
             io_base,
             irq,
-            rx_buffer: Box::new([0u8; RX_BUFFER_SIZE]),
-            tx_buffers: [
-                Box::new([0u8; TX_BUFFER_SIZE]),
-                Box::new([0u8; TX_BUFFER_SIZE]),
-                Box::new([0u8; TX_BUFFER_SIZE]),
-                Box::new([0u8; TX_BUFFER_SIZE]),
-            ],
+            rx_buffer: alloc(RX_BUFFER_SIZE)?,
+            tx_buffers: alloc(4 * TX_BUFFER_SIZE)?,
             cur_tx: 0,
             cur_rx: 0,
             initialized: false,
-        }
+        })
     }
 
     /// Initialize the network card
@@ -54,17 +65,14 @@ impl RTL8139Driver {
             let mut cmd_port = Port::<u8>::new(self.io_base + 0x37);
             cmd_port.write(0x10);
             // Wait for reset completion
-            for _ in 0..1000 {
-                if cmd_port.read() & 0x10 == 0 {
-                    break;
-                }
-                x86_64::instructions::nop();
-            }
+            time::wait_for(Duration::from_millis(100), || cmd_port.read() & 0x10 == 0)
+                .map_err(|_| NetError::Timeout)?;
 
             // Set up receive buffer
-            let rx_buf_addr = self.rx_buffer.as_ptr() as u32;
+            let rx_buf_addr = self.rx_buffer.phys_addr().as_u64() as u32;
             let mut rbstart = Port::<u32>::new(self.io_base + 0x30);
             rbstart.write(rx_buf_addr);
+            Port::<u32>::new(self.io_base + 0x44).write(RCR_CONFIG);
 
             // Enable receiver and transmitter
             cmd_port.write(0x0C);
@@ -77,6 +85,16 @@ impl RTL8139Driver {
         Ok(())
     }
 
+    /// Mask interrupts and turn off the receiver and transmitter, after
+    /// which the card no longer touches the DMA buffers
+    pub fn stop(&mut self) {
+        unsafe {
+            Port::<u16>::new(self.io_base + 0x3C).write(0);
+            Port::<u8>::new(self.io_base + 0x37).write(0);
+        }
+        self.initialized = false;
+    }
+
     /// Send an Ethernet frame
     pub fn send_packet(&mut self, data: &[u8]) -> Result<(), NetError> {
         if !self.initialized {
@@ -87,13 +105,13 @@ impl RTL8139Driver {
         }
 
         let tx_index = self.cur_tx % 4;
-        let buf = &mut self.tx_buffers[tx_index];
-        buf[..data.len()].copy_from_slice(data);
+        let offset = tx_index * TX_BUFFER_SIZE;
+        self.tx_buffers[offset..offset + data.len()].copy_from_slice(data);
 
         unsafe {
-            let tx_addr_port = Port::<u32>::new(self.io_base + 0x20 + (tx_index as u16 * 4));
-            tx_addr_port.write(buf.as_ptr() as u32);
-            let tx_status_port = Port::<u32>::new(self.io_base + 0x10 + (tx_index as u16 * 4));
+            let mut tx_addr_port = Port::<u32>::new(self.io_base + 0x20 + (tx_index as u16 * 4));
+            tx_addr_port.write((self.tx_buffers.phys_addr() + offset as u64).as_u64() as u32);
+            let mut tx_status_port = Port::<u32>::new(self.io_base + 0x10 + (tx_index as u16 * 4));
             tx_status_port.write(data.len() as u32 & 0x1FFF);
         }
 
@@ -108,35 +126,87 @@ impl RTL8139Driver {
         }
 
         // Simplified: In real driver we would check the RX buffer head and tail
-        // pointers and handle ring wrapping. Here we just copy from the buffer.
-        let length_port = Port::<u16>::new(self.io_base + 0x1E);
-        let length: u16 = unsafe { length_port.read() };
-        if length as usize > buffer.len() {
+        // pointers. Packets never wrap (RCR.WRAP), but one starting near the
+        // end of the ring continues into the slack behind it.
+        let mut length_port = Port::<u16>::new(self.io_base + 0x1E);
+        let length = unsafe { length_port.read() } as usize;
+        if length > buffer.len() {
             return Err(NetError::BufferTooSmall);
         }
-        unsafe {
-            ptr::copy_nonoverlapping(
-                self.rx_buffer.as_ptr().add(self.cur_rx),
-                buffer.as_mut_ptr(),
-                length as usize,
-            );
-        }
-        self.cur_rx = (self.cur_rx + length as usize + 4) % RX_BUFFER_SIZE;
-        Ok(length as usize)
+        let packet = self
+            .rx_buffer
+            .get(self.cur_rx..self.cur_rx + length)
+            .ok_or(NetError::BadPacket)?;
+        buffer[..length].copy_from_slice(packet);
+        self.cur_rx = (self.cur_rx + length + 4) % RX_RING_SIZE;
+        Ok(length)
     }
 
     /// Handle an interrupt from the network card
-    pub fn handle_interrupt(&mut self) {
+    pub fn handle_interrupt(&mut self) -> IrqReturn {
         if !self.initialized {
-            return;
+            return IrqReturn::None;
         }
 
         unsafe {
             let mut isr = Port::<u16>::new(self.io_base + 0x3E);
             let status = isr.read();
+            if status == 0 {
+                return IrqReturn::None;
+            }
             // Acknowledge handled interrupts
             isr.write(status);
         }
+        IrqReturn::Handled
+    }
+}
+
+/// Devices the driver handles
+const PCI_IDS: &[PciMatch] = &[PciMatch::device(0x10EC, 0x8139)];
+
+/// Binds the driver to RTL8139 cards
+pub struct Rtl8139PciDriver;
+
+/// State kept for a bound card
+struct Rtl8139Device {
+    driver: Arc<IrqSpinLock<RTL8139Driver>>,
+    irq: DeviceIrq,
+}
+
+impl PciDriver for Rtl8139PciDriver {
+    fn name(&self) -> &'static str {
+        "rtl8139"
+    }
+
+    fn id_table(&self) -> &'static [PciMatch] {
+        PCI_IDS
+    }
+
+    fn probe(&self, device: &PciDevice) -> Result<DriverData, ProbeError> {
+        let io_base = device
+            .io_bar(0)
+            .ok_or(ProbeError::MissingResource("I/O BAR"))?;
+        device.enable_io_space();
+        device.enable_bus_master();
+        let driver =
+            RTL8139Driver::new(io_base, device.interrupt_line).map_err(ProbeError::init)?;
+        let driver = Arc::new(IrqSpinLock::new(driver));
+        let handler = driver.clone();
+        let irq = device
+            .request_irq("rtl8139", move || handler.lock().handle_interrupt())
+            .map_err(ProbeError::init)?;
+        driver.lock().irq = irq.irq();
+        driver.lock().init().map_err(ProbeError::init)?;
+        Ok(Box::new(Rtl8139Device { driver, irq }))
+    }
+
+    fn remove(&self, _device: &PciDevice, data: DriverData) {
+        if let Ok(device) = data.downcast::<Rtl8139Device>() {
+            // The card must be idle before the buffers go with the driver
+            let Rtl8139Device { driver, irq } = *device;
+            driver.lock().stop();
+            drop(irq);
+        }
     }
 }
 
@@ -145,6 +215,10 @@ impl RTL8139Driver {
 pub enum NetError {
     NotInitialized,
     BufferTooSmall,
+    Timeout,
+    OutOfMemory,
+    /// The card reported a packet that doesn't fit the receive buffer
+    BadPacket,
 }
 
 impl fmt::Display for NetError {
@@ -152,6 +226,9 @@ impl fmt::Display for NetError {
         match self {
             NetError::NotInitialized => write!(f, "Driver not initialized"),
             NetError::BufferTooSmall => write!(f, "Buffer too small"),
+            NetError::Timeout => write!(f, "Reset timed out"),
+            NetError::OutOfMemory => write!(f, "Out of memory for DMA buffers"),
+            NetError::BadPacket => write!(f, "Bad packet length"),
         }
     }
 }
@@ -162,7 +239,7 @@ mod tests {
 
     #[test]
     fn test_new_driver() {
-        let driver = RTL8139Driver::new(0xC000, 10);
+        let driver = RTL8139Driver::new(0xC000, 10).unwrap();
         assert_eq!(driver.io_base, 0xC000);
         assert_eq!(driver.irq, 10);
         assert!(!driver.initialized);
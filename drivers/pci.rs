@@ -1,13 +1,60 @@
 #![no_std]
 
 //! Simple PCI bus scanning utilities
+//!
+//! Configuration space is memory mapped (ECAM) where the ACPI MCFG table
+//! describes it, see [`init`], and otherwise reached through the
+//! 0xCF8/0xCFC ports. Devices found get their BARs decoded and their interrupt routing recorded;
+//! drivers turn on decoding and bus mastering through the command register
+//! methods of [`PciDevice`] before using them.
+//!
+//! The bus is enumerated once and the result cached: sizing the BARs
+//! briefly turns off the device's decoding, which must not happen under a
+//! driver using it.
+//!
+//! Also the PCI driver model: drivers implement [`PciDriver`] with a table
+//! of the devices they handle and are registered with [`register_driver`].
+//! Every device matching a driver's table gets offered to its `probe`; a
+//! successful probe binds the device to the driver until it is removed.
 
+use crate::acpi;
+use crate::mmio::{self, CacheMode, Mmio};
+use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
 
 const CONFIG_ADDRESS: u16 = 0xCF8;
 const CONFIG_DATA: u16 = 0xCFC;
 
+const COMMAND: u16 = 0x04;
+const STATUS: u16 = 0x06;
+const HEADER_TYPE: u16 = 0x0E;
+const BAR0: u16 = 0x10;
+const CARDBUS_CAPABILITIES: u16 = 0x14;
+const CAPABILITIES: u16 = 0x34;
+const INTERRUPT_LINE: u16 = 0x3C;
+const INTERRUPT_PIN: u16 = 0x3D;
+
+// Command register bits
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+/// Status register bit: the device has a capability list
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+/// Capabilities of a device followed before assuming the list loops
+const MAX_CAPABILITIES: usize = 48;
+/// Start of the extended capability list, past the legacy config space
+const EXTENDED_CAPABILITIES: u16 = 0x100;
+const MAX_EXTENDED_CAPABILITIES: usize = 960;
+
 /// PCI device identifier
 #[derive(Debug, Clone, Copy)]
 pub struct PciDeviceId {
@@ -24,29 +71,444 @@ pub struct PciDevice {
     pub id: PciDeviceId,
     pub class: u8,
     pub subclass: u8,
-    pub bar0: u32,
+    /// Programming interface, e.g. which USB host controller interface
+    pub prog_if: u8,
+    /// Base address registers; the upper half of a 64-bit BAR and
+    /// unimplemented BARs are `None`
+    pub bars: [Option<Bar>; 6],
+    /// Legacy IRQ the firmware routed the interrupt pin to; 0xFF if none
+    pub interrupt_line: u8,
+    /// Interrupt pin used, 1-4 for INTA#-INTD#; 0 if the device has none
+    pub interrupt_pin: u8,
+}
+
+/// Decoded base address register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// Range of I/O ports
+    Io { port: u16, size: u16 },
+    /// Range of physical memory
+    Memory {
+        addr: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64bit: bool,
+    },
+}
+
+impl PciDevice {
+    /// Address and size of BAR `index` if it is a memory BAR
+    pub fn memory_bar(&self, index: usize) -> Option<(PhysAddr, u64)> {
+        match self.bars.get(index)? {
+            Some(Bar::Memory { addr, size, .. }) => Some((PhysAddr::new(*addr), *size)),
+            _ => None,
+        }
+    }
+
+    /// First port of BAR `index` if it is an I/O BAR
+    pub fn io_bar(&self, index: usize) -> Option<u16> {
+        match self.bars.get(index)? {
+            Some(Bar::Io { port, .. }) => Some(*port),
+            _ => None,
+        }
+    }
+
+    /// Read the configuration register at `offset`
+    pub fn read_config_dword(&self, offset: u16) -> u32 {
+        read_config_dword(self.bus, self.device, self.function, offset)
+    }
+
+    pub fn read_config_word(&self, offset: u16) -> u16 {
+        read_config_word(self.bus, self.device, self.function, offset)
+    }
+
+    pub fn read_config_byte(&self, offset: u16) -> u8 {
+        read_config_byte(self.bus, self.device, self.function, offset)
+    }
+
+    /// Write the configuration dword at `offset`.
+    pub fn write_config_dword(&self, offset: u16, value: u32) {
+        write_config_dword(self.bus, self.device, self.function, offset, value)
+    }
+
+    pub fn write_config_word(&self, offset: u16, value: u16) {
+        write_config_word(self.bus, self.device, self.function, offset, value)
+    }
+
+    pub fn write_config_byte(&self, offset: u16, value: u8) {
+        write_config_byte(self.bus, self.device, self.function, offset, value)
+    }
+
+    /// Let the device start DMA transfers.
+    pub fn enable_bus_master(&self) {
+        self.update_command(|command| command | COMMAND_BUS_MASTER);
+    }
+
+    /// Stop the device from starting DMA transfers.
+    pub fn disable_bus_master(&self) {
+        self.update_command(|command| command & !COMMAND_BUS_MASTER);
+    }
+
+    /// Let the device respond to accesses to its memory BARs.
+    pub fn enable_memory_space(&self) {
+        self.update_command(|command| command | COMMAND_MEMORY_SPACE);
+    }
+
+    /// Let the device respond to accesses to its I/O BARs.
+    pub fn enable_io_space(&self) {
+        self.update_command(|command| command | COMMAND_IO_SPACE);
+    }
+
+    /// Let the device signal interrupts on its interrupt pin.
+    pub fn enable_intx(&self) {
+        self.update_command(|command| command & !COMMAND_INTX_DISABLE);
+    }
+
+    /// Keep the device from signalling interrupts on its interrupt pin,
+    /// e.g. once it uses MSI.
+    pub fn disable_intx(&self) {
+        self.update_command(|command| command | COMMAND_INTX_DISABLE);
+    }
+
+    /// The device's capability list
+    pub fn capabilities(&self) -> Capabilities {
+        let pointer = match self.read_config_byte(HEADER_TYPE) & 0x7F {
+            0x00 | 0x01 => CAPABILITIES,
+            0x02 => CARDBUS_CAPABILITIES,
+            _ => 0,
+        };
+        let next = if pointer != 0 && self.read_config_word(STATUS) & STATUS_CAPABILITIES != 0 {
+            self.read_config_byte(pointer) as u16
+        } else {
+            0
+        };
+        Capabilities {
+            device: *self,
+            next,
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+
+    /// The first capability of kind `kind`
+    pub fn find_capability(&self, kind: CapabilityKind) -> Option<Capability> {
+        self.capabilities()
+            .find(|capability| capability.kind == kind)
+    }
+
+    /// The device's PCI Express extended capability list. Empty without
+    /// ECAM, as port I/O can't reach it.
+    pub fn extended_capabilities(&self) -> ExtendedCapabilities {
+        ExtendedCapabilities {
+            device: *self,
+            next: EXTENDED_CAPABILITIES,
+            remaining: MAX_EXTENDED_CAPABILITIES,
+        }
+    }
+
+    /// The first extended capability of kind `kind`
+    pub fn find_extended_capability(
+        &self,
+        kind: ExtendedCapabilityKind,
+    ) -> Option<ExtendedCapability> {
+        self.extended_capabilities()
+            .find(|capability| capability.kind == kind)
+    }
+
+    fn update_command(&self, f: impl FnOnce(u16) -> u16) {
+        let command = self.read_config_word(COMMAND);
+        let updated = f(command);
+        if updated != command {
+            self.write_config_word(COMMAND, updated);
+        }
+    }
+
+    /// Whether this is the device at the same bus, slot and function
+    fn same_location(&self, other: &PciDevice) -> bool {
+        (self.bus, self.device, self.function) == (other.bus, other.device, other.function)
+    }
 }
 
-fn read_config_dword(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{} {:04x}:{:04x}",
+            self.bus, self.device, self.function, self.id.vendor_id, self.id.device_id
+        )
+    }
+}
+
+/// Kind of a capability, from its ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapabilityKind {
+    PowerManagement,
+    Msi,
+    VendorSpecific,
+    PciExpress,
+    MsiX,
+    Other(u8),
+}
+
+impl CapabilityKind {
+    pub fn from_id(id: u8) -> Self {
+        match id {
+            0x01 => CapabilityKind::PowerManagement,
+            0x05 => CapabilityKind::Msi,
+            0x09 => CapabilityKind::VendorSpecific,
+            0x10 => CapabilityKind::PciExpress,
+            0x11 => CapabilityKind::MsiX,
+            id => CapabilityKind::Other(id),
+        }
+    }
+}
+
+/// Entry of a device's capability list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub kind: CapabilityKind,
+    /// Configuration space offset of the capability's ID byte; its
+    /// registers follow
+    pub offset: u16,
+}
+
+/// Iterator over a device's capability list, see
+/// [`PciDevice::capabilities`]
+pub struct Capabilities {
+    device: PciDevice,
+    /// Offset of the next capability, 0 at the end
+    next: u16,
+    remaining: usize,
+}
+
+impl Iterator for Capabilities {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        // Capabilities live after the standard header and are dword aligned
+        let offset = self.next & 0xFC;
+        if offset < 0x40 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let header = self.device.read_config_word(offset);
+        self.next = header >> 8;
+        Some(Capability {
+            kind: CapabilityKind::from_id(header as u8),
+            offset,
+        })
+    }
+}
+
+/// Kind of an extended capability, from its ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtendedCapabilityKind {
+    AdvancedErrorReporting,
+    VirtualChannel,
+    DeviceSerialNumber,
+    VendorSpecific,
+    Other(u16),
+}
+
+impl ExtendedCapabilityKind {
+    pub fn from_id(id: u16) -> Self {
+        match id {
+            0x0001 => ExtendedCapabilityKind::AdvancedErrorReporting,
+            0x0002 => ExtendedCapabilityKind::VirtualChannel,
+            0x0003 => ExtendedCapabilityKind::DeviceSerialNumber,
+            0x000B => ExtendedCapabilityKind::VendorSpecific,
+            id => ExtendedCapabilityKind::Other(id),
+        }
+    }
+}
+
+/// Entry of a device's extended capability list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtendedCapability {
+    pub kind: ExtendedCapabilityKind,
+    pub version: u8,
+    /// Configuration space offset of the capability header
+    pub offset: u16,
+}
+
+/// Iterator over a device's extended capability list, see
+/// [`PciDevice::extended_capabilities`]
+pub struct ExtendedCapabilities {
+    device: PciDevice,
+    /// Offset of the next capability, 0 at the end
+    next: u16,
+    remaining: usize,
+}
+
+impl Iterator for ExtendedCapabilities {
+    type Item = ExtendedCapability;
+
+    fn next(&mut self) -> Option<ExtendedCapability> {
+        let offset = self.next & 0xFFC;
+        if offset < EXTENDED_CAPABILITIES || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        // ID in bits 0-15, version in 16-19 and the next offset above.
+        // Unreachable config space reads as all ones, a function without
+        // extended capabilities has a zero header.
+        let header = self.device.read_config_dword(offset);
+        if header == 0 || header == 0xFFFF_FFFF {
+            return None;
+        }
+        self.next = (header >> 20) as u16;
+        Some(ExtendedCapability {
+            kind: ExtendedCapabilityKind::from_id(header as u16),
+            version: ((header >> 16) & 0xF) as u8,
+            offset,
+        })
+    }
+}
+
+/// Memory-mapped configuration space of a range of buses in segment 0
+struct EcamRegion {
+    start_bus: u8,
+    end_bus: u8,
+    /// Configuration space of `start_bus` onwards, 1 MiB per bus
+    mmio: Mmio,
+}
+
+/// ECAM regions from the MCFG, set up by [`init`]
+static ECAM: Once<Vec<EcamRegion>> = Once::new();
+
+/// Map the enhanced configuration space the ACPI MCFG describes. Config
+/// space accesses then go through memory for the buses it covers and
+/// reach all 4 KiB of a function's configuration space; other buses, or
+/// all of them without an MCFG, keep using port I/O.
+pub fn init() {
+    ECAM.call_once(|| {
+        let mut regions = Vec::new();
+        // Port I/O only reaches segment 0, so that is all the bus scan covers
+        for entry in acpi::mcfg().into_iter().filter(|entry| entry.segment == 0) {
+            let buses = (entry.end_bus as u64).saturating_sub(entry.start_bus as u64) + 1;
+            let base = entry.base + ((entry.start_bus as u64) << 20);
+            match mmio::ioremap(base, buses << 20, CacheMode::Uncached) {
+                Ok(mmio) => {
+                    log::info!(
+                        "PCI: ECAM at {:#x} for buses {:02x}-{:02x}",
+                        base.as_u64(),
+                        entry.start_bus,
+                        entry.end_bus
+                    );
+                    regions.push(EcamRegion {
+                        start_bus: entry.start_bus,
+                        end_bus: entry.end_bus,
+                        mmio,
+                    });
+                }
+                Err(e) => {
+                    log::warn!("PCI: failed to map ECAM at {:#x}: {}", base.as_u64(), e);
+                }
+            }
+        }
+        regions
+    });
+}
+
+/// ECAM mapping and offset into it of the configuration register at
+/// `offset`, `None` if the bus isn't memory mapped. Offsets past the 4 KiB
+/// of a function would land in the next function's space and get `None`
+/// as well, so the caller treats them as out of reach.
+fn ecam(bus: u8, device: u8, function: u8, offset: u16) -> Option<(&'static Mmio, usize)> {
+    if offset >= 0x1000 {
+        return None;
+    }
+    let region = ECAM
+        .r#try()?
+        .iter()
+        .find(|region| (region.start_bus..=region.end_bus).contains(&bus))?;
+    let at = ((bus - region.start_bus) as usize) << 20
+        | (device as usize) << 15
+        | (function as usize) << 12
+        | offset as usize;
+    Some((&region.mmio, at))
+}
+
+/// The address register selects the dword `CONFIG_DATA` accesses, so an
+/// access is two port operations that must not interleave with another
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+/// Run `f` with the dword containing `offset` selected, `None` if port
+/// I/O can't reach the offset.
+fn with_config<R>(
+    bus: u8,
+    device: u8,
+    function: u8,
+    offset: u16,
+    f: impl FnOnce() -> R,
+) -> Option<R> {
+    if offset >= 0x100 {
+        return None;
+    }
     let address = ((bus as u32) << 16)
         | ((device as u32) << 11)
         | ((function as u32) << 8)
         | ((offset as u32) & 0xFC)
         | 0x8000_0000;
-    unsafe {
-        let mut addr = Port::<u32>::new(CONFIG_ADDRESS);
-        let mut data = Port::<u32>::new(CONFIG_DATA);
-        addr.write(address);
-        data.read()
+    Some(without_interrupts(|| {
+        let _guard = CONFIG_LOCK.lock();
+        unsafe { Port::<u32>::new(CONFIG_ADDRESS).write(address) };
+        f()
+    }))
+}
+
+/// Read the configuration dword at `offset`. Registers out of reach, past
+/// the first 256 bytes without ECAM or past 4 KiB with it, read as all ones.
+fn read_config_dword(bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+    let offset = offset & !3;
+    if let Some((mmio, at)) = ecam(bus, device, function, offset) {
+        return mmio.read::<u32>(at);
     }
+    with_config(bus, device, function, offset, || unsafe {
+        Port::<u32>::new(CONFIG_DATA).read()
+    })
+    .unwrap_or(0xFFFF_FFFF)
 }
 
-fn read_config_word(bus: u8, device: u8, function: u8, offset: u8) -> u16 {
+/// Write the configuration dword at `offset`. Writes to registers out of
+/// reach are dropped.
+pub fn write_config_dword(bus: u8, device: u8, function: u8, offset: u16, value: u32) {
+    let offset = offset & !3;
+    if let Some((mmio, at)) = ecam(bus, device, function, offset) {
+        return mmio.write::<u32>(at, value);
+    }
+    with_config(bus, device, function, offset, || unsafe {
+        Port::<u32>::new(CONFIG_DATA).write(value)
+    });
+}
+
+/// Write the configuration word at `offset`. Only those two bytes are
+/// written, so write-one-to-clear bits next to them are left alone.
+pub fn write_config_word(bus: u8, device: u8, function: u8, offset: u16, value: u16) {
+    let offset = offset & !1;
+    if let Some((mmio, at)) = ecam(bus, device, function, offset) {
+        return mmio.write::<u16>(at, value);
+    }
+    with_config(bus, device, function, offset, || unsafe {
+        Port::<u16>::new(CONFIG_DATA + (offset & 2)).write(value)
+    });
+}
+
+/// Write the configuration byte at `offset`.
+pub fn write_config_byte(bus: u8, device: u8, function: u8, offset: u16, value: u8) {
+    if let Some((mmio, at)) = ecam(bus, device, function, offset) {
+        return mmio.write::<u8>(at, value);
+    }
+    with_config(bus, device, function, offset, || unsafe {
+        Port::<u8>::new(CONFIG_DATA + (offset & 3)).write(value)
+    });
+}
+
+fn read_config_word(bus: u8, device: u8, function: u8, offset: u16) -> u16 {
     let dword = read_config_dword(bus, device, function, offset);
     ((dword >> ((offset & 2) * 8)) & 0xFFFF) as u16
 }
 
-fn read_config_byte(bus: u8, device: u8, function: u8, offset: u8) -> u8 {
+fn read_config_byte(bus: u8, device: u8, function: u8, offset: u16) -> u8 {
     (read_config_word(bus, device, function, offset & 0xFE) >> ((offset & 1) * 8)) as u8
 }
 
@@ -60,20 +522,131 @@ fn read_device(bus: u8, device: u8, function: u8) -> PciDevice {
     let class_info = read_config_dword(bus, device, function, 0x08);
     let class = (class_info >> 24) as u8;
     let subclass = (class_info >> 16) as u8;
-    let bar0 = read_config_dword(bus, device, function, 0x10);
+    let prog_if = (class_info >> 8) as u8;
+    // PCI-to-PCI bridges have two BARs; other headers are laid out differently
+    let bar_count = match read_config_byte(bus, device, function, HEADER_TYPE) & 0x7F {
+        0x00 => 6,
+        0x01 => 2,
+        _ => 0,
+    };
+    let bars = read_bars(bus, device, function, bar_count);
+    // Type 0 and 1 headers keep the interrupt registers at the same place
+    let (interrupt_line, interrupt_pin) = if bar_count > 0 {
+        (
+            read_config_byte(bus, device, function, INTERRUPT_LINE),
+            read_config_byte(bus, device, function, INTERRUPT_PIN),
+        )
+    } else {
+        (0xFF, 0)
+    };
     PciDevice {
         bus,
         device,
         function,
-        id: PciDeviceId { vendor_id: vendor, device_id },
+        id: PciDeviceId {
+            vendor_id: vendor,
+            device_id,
+        },
         class,
         subclass,
-        bar0,
+        prog_if,
+        bars,
+        interrupt_line,
+        interrupt_pin,
+    }
+}
+
+/// Read and size the first `count` BARs.
+fn read_bars(bus: u8, device: u8, function: u8, count: usize) -> [Option<Bar>; 6] {
+    let mut sized = [(0, 0); 6];
+    // Decoding off while the BARs hold the sizing pattern
+    let command = read_config_word(bus, device, function, COMMAND);
+    let decoding = COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE;
+    write_config_word(bus, device, function, COMMAND, command & !decoding);
+    for (index, bar) in sized.iter_mut().enumerate().take(count) {
+        *bar = size_bar(bus, device, function, BAR0 + index as u16 * 4);
+    }
+    write_config_word(bus, device, function, COMMAND, command);
+    decode_bars(&sized[..count])
+}
+
+/// Decode the BARs of a header from their values and sizing masks, see
+/// [`size_bar`]. A 64-bit BAR takes the slot after it for its upper half;
+/// one in the last slot has no upper half and is left out as malformed.
+fn decode_bars(sized: &[(u32, u32)]) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+    let mut index = 0;
+    while index < sized.len() {
+        let (raw, mask) = sized[index];
+        if raw & 0x7 == 0x4 {
+            if let Some(&(raw_high, mask_high)) = sized.get(index + 1) {
+                bars[index] = decode_bar(
+                    (raw_high as u64) << 32 | raw as u64,
+                    (mask_high as u64) << 32 | mask as u64,
+                );
+            }
+            index += 2;
+        } else {
+            bars[index] = decode_bar(raw as u64, mask as u64);
+            index += 1;
+        }
     }
+    bars
+}
+
+/// Value of the BAR at `offset` and what it reads back after writing all
+/// ones, which has the bits below its size clear. The value is restored.
+fn size_bar(bus: u8, device: u8, function: u8, offset: u16) -> (u32, u32) {
+    let raw = read_config_dword(bus, device, function, offset);
+    write_config_dword(bus, device, function, offset, 0xFFFF_FFFF);
+    let mask = read_config_dword(bus, device, function, offset);
+    write_config_dword(bus, device, function, offset, raw);
+    (raw, mask)
 }
 
-/// Scan the entire PCI bus
+/// Decode a BAR from its value and sizing mask, both including the upper
+/// half for a 64-bit BAR. `None` if the BAR is unimplemented.
+fn decode_bar(raw: u64, mask: u64) -> Option<Bar> {
+    if raw & 1 != 0 {
+        let mut mask = mask as u32 & !0x3;
+        if mask == 0 {
+            return None;
+        }
+        // Devices decoding only 16 address bits leave the upper half clear
+        if mask & 0xFFFF_0000 == 0 {
+            mask |= 0xFFFF_0000;
+        }
+        return Some(Bar::Io {
+            port: (raw as u32 & !0x3) as u16,
+            size: (!mask).wrapping_add(1) as u16,
+        });
+    }
+
+    let is_64bit = raw & 0x6 == 0x4;
+    let mut mask = mask & !0xF;
+    if mask == 0 {
+        return None;
+    }
+    if !is_64bit {
+        mask |= 0xFFFF_FFFF_0000_0000;
+    }
+    Some(Bar::Memory {
+        addr: raw & !0xF,
+        size: (!mask).wrapping_add(1),
+        prefetchable: raw & 0x8 != 0,
+        is_64bit,
+    })
+}
+
+static DEVICES: Mutex<Option<Vec<PciDevice>>> = Mutex::new(None);
+
+/// Scan the entire PCI bus. Only the first call enumerates the bus, later
+/// ones return the same devices.
 pub fn scan_bus() -> Vec<PciDevice> {
+    without_interrupts(|| DEVICES.lock().get_or_insert_with(enumerate).clone())
+}
+
+fn enumerate() -> Vec<PciDevice> {
     let mut devices = Vec::new();
     for bus in 0u8..=255 {
         for dev in 0u8..32 {
@@ -104,6 +677,236 @@ pub fn find_audio_devices() -> Vec<PciDevice> {
     scan_bus().into_iter().filter(|d| d.class == 0x04).collect()
 }
 
+/// Entry of a driver's device table. Fields left `None` match anything.
+#[derive(Debug, Clone, Copy)]
+pub struct PciMatch {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>,
+}
+
+impl PciMatch {
+    const ANY: PciMatch = PciMatch {
+        vendor_id: None,
+        device_id: None,
+        class: None,
+        subclass: None,
+        prog_if: None,
+    };
+
+    /// Match one vendor and device ID
+    pub const fn device(vendor_id: u16, device_id: u16) -> Self {
+        Self {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            ..Self::ANY
+        }
+    }
+
+    /// Match every device of a class and subclass
+    pub const fn class(class: u8, subclass: u8) -> Self {
+        Self {
+            class: Some(class),
+            subclass: Some(subclass),
+            ..Self::ANY
+        }
+    }
+
+    /// Match every device of a class and subclass with programming
+    /// interface `prog_if`
+    pub const fn class_interface(class: u8, subclass: u8, prog_if: u8) -> Self {
+        Self {
+            prog_if: Some(prog_if),
+            ..Self::class(class, subclass)
+        }
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        fn field<T: PartialEq>(wanted: Option<T>, actual: T) -> bool {
+            wanted.map_or(true, |wanted| wanted == actual)
+        }
+        field(self.vendor_id, device.id.vendor_id)
+            && field(self.device_id, device.id.device_id)
+            && field(self.class, device.class)
+            && field(self.subclass, device.subclass)
+            && field(self.prog_if, device.prog_if)
+    }
+}
+
+/// State a driver keeps for a device bound to it
+pub type DriverData = Box<dyn Any + Send>;
+
+/// Driver for PCI devices
+pub trait PciDriver: Sync {
+    /// Name listed for bound devices
+    fn name(&self) -> &'static str;
+
+    /// Devices the driver may handle
+    fn id_table(&self) -> &'static [PciMatch];
+
+    /// Set up `device` and return the driver's state for it. On error the
+    /// device is offered to the next matching driver.
+    fn probe(&self, device: &PciDevice) -> Result<DriverData, ProbeError>;
+
+    /// Stop using `device`, which is being unbound. Bus mastering is
+    /// already off, but the driver must still stop the device's DMA and
+    /// interrupts before freeing the memory they use. By default the state
+    /// is simply dropped.
+    fn remove(&self, device: &PciDevice, data: DriverData) {
+        let _ = device;
+        drop(data);
+    }
+}
+
+struct Binding {
+    device: PciDevice,
+    driver: &'static dyn PciDriver,
+    data: DriverData,
+}
+
+/// Device bound to a driver
+#[derive(Debug, Clone, Copy)]
+pub struct BoundDevice {
+    pub device: PciDevice,
+    pub driver: &'static str,
+}
+
+static DRIVERS: Mutex<Vec<&'static dyn PciDriver>> = Mutex::new(Vec::new());
+static BINDINGS: Mutex<Vec<Binding>> = Mutex::new(Vec::new());
+
+fn is_bound(device: &PciDevice) -> bool {
+    without_interrupts(|| {
+        BINDINGS
+            .lock()
+            .iter()
+            .any(|binding| binding.device.same_location(device))
+    })
+}
+
+/// Offer every unbound device in `devices` to `driver`.
+fn bind_driver(driver: &'static dyn PciDriver, devices: &[PciDevice]) {
+    for device in devices {
+        if is_bound(device) || !driver.id_table().iter().any(|m| m.matches(device)) {
+            continue;
+        }
+        // Probing may take a while, so without the bindings locked
+        match driver.probe(device) {
+            Ok(data) => without_interrupts(|| {
+                BINDINGS.lock().push(Binding {
+                    device: *device,
+                    driver,
+                    data,
+                })
+            }),
+            Err(e) => {
+                device.disable_bus_master();
+                log::warn!("PCI {}: {} probe failed: {}", device, driver.name(), e);
+            }
+        }
+    }
+}
+
+/// Add `driver` and bind it to the unbound devices it matches. Drivers
+/// registered earlier get the first chance at a device.
+pub fn register_driver(driver: &'static dyn PciDriver) {
+    without_interrupts(|| DRIVERS.lock().push(driver));
+    bind_driver(driver, &scan_bus());
+}
+
+/// Offer devices that are still unbound, e.g. after a failed probe, to
+/// the registered drivers again.
+pub fn rescan() {
+    let devices = scan_bus();
+    let drivers = without_interrupts(|| DRIVERS.lock().clone());
+    for driver in drivers {
+        bind_driver(driver, &devices);
+    }
+}
+
+/// Unbind `device` from its driver, calling the driver's `remove`.
+/// Returns whether the device was bound.
+pub fn unbind(device: &PciDevice) -> bool {
+    let binding = without_interrupts(|| {
+        let mut bindings = BINDINGS.lock();
+        let index = bindings
+            .iter()
+            .position(|binding| binding.device.same_location(device))?;
+        Some(bindings.remove(index))
+    });
+    match binding {
+        Some(binding) => {
+            release(binding);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Remove `driver` and unbind every device bound to it.
+pub fn unregister_driver(driver: &'static dyn PciDriver) {
+    let is_driver = |other: &&'static dyn PciDriver| other.name() == driver.name();
+    let bindings: Vec<Binding> = without_interrupts(|| {
+        DRIVERS.lock().retain(|other| !is_driver(other));
+        let mut bindings = BINDINGS.lock();
+        let (removed, kept) = core::mem::take(&mut *bindings)
+            .into_iter()
+            .partition(|binding| is_driver(&binding.driver));
+        *bindings = kept;
+        removed
+    });
+    for binding in bindings {
+        release(binding);
+    }
+}
+
+/// Let the driver of `binding` stop using the device. DMA is cut off
+/// first so the device can't write to memory the driver frees.
+fn release(binding: Binding) {
+    binding.device.disable_bus_master();
+    binding.driver.remove(&binding.device, binding.data);
+}
+
+/// Devices bound to a driver, in binding order
+pub fn bound_devices() -> Vec<BoundDevice> {
+    without_interrupts(|| {
+        BINDINGS
+            .lock()
+            .iter()
+            .map(|binding| BoundDevice {
+                device: binding.device,
+                driver: binding.driver.name(),
+            })
+            .collect()
+    })
+}
+
+/// Why a driver didn't take a device
+#[derive(Debug, Clone)]
+pub enum ProbeError {
+    /// The device lacks a resource the driver needs, e.g. a BAR
+    MissingResource(&'static str),
+    /// Setting up the device failed
+    Init(String),
+}
+
+impl ProbeError {
+    /// Failed setup, described by the driver's own error
+    pub fn init(error: impl fmt::Display) -> Self {
+        ProbeError::Init(alloc::format!("{}", error))
+    }
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeError::MissingResource(what) => write!(f, "Missing {}", what),
+            ProbeError::Init(error) => write!(f, "{}", error),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -117,4 +920,111 @@ mod tests {
         assert_eq!(id.vendor_id, 0x8086);
         assert_eq!(id.device_id, 0x1234);
     }
+
+    #[test]
+    fn test_match() {
+        let device = PciDevice {
+            bus: 0,
+            device: 3,
+            function: 0,
+            id: PciDeviceId {
+                vendor_id: 0x8086,
+                device_id: 0x24CD,
+            },
+            class: 0x0C,
+            subclass: 0x03,
+            prog_if: 0x20,
+            bars: [None; 6],
+            interrupt_line: 11,
+            interrupt_pin: 1,
+        };
+        assert!(PciMatch::device(0x8086, 0x24CD).matches(&device));
+        assert!(!PciMatch::device(0x8086, 0x100E).matches(&device));
+        assert!(PciMatch::class(0x0C, 0x03).matches(&device));
+        assert!(PciMatch::class_interface(0x0C, 0x03, 0x20).matches(&device));
+        assert!(!PciMatch::class_interface(0x0C, 0x03, 0x30).matches(&device));
+    }
+
+    #[test]
+    fn test_capability_kind() {
+        assert_eq!(CapabilityKind::from_id(0x05), CapabilityKind::Msi);
+        assert_eq!(CapabilityKind::from_id(0x11), CapabilityKind::MsiX);
+        assert_eq!(CapabilityKind::from_id(0x10), CapabilityKind::PciExpress);
+        assert_eq!(CapabilityKind::from_id(0x12), CapabilityKind::Other(0x12));
+        assert_eq!(
+            ExtendedCapabilityKind::from_id(0x0001),
+            ExtendedCapabilityKind::AdvancedErrorReporting
+        );
+        assert_eq!(
+            ExtendedCapabilityKind::from_id(0x0019),
+            ExtendedCapabilityKind::Other(0x0019)
+        );
+    }
+
+    #[test]
+    fn test_decode_bar() {
+        assert_eq!(
+            decode_bar(0xFEBC_0000, 0xFFFE_0000),
+            Some(Bar::Memory {
+                addr: 0xFEBC_0000,
+                size: 0x20000,
+                prefetchable: false,
+                is_64bit: false
+            })
+        );
+        assert_eq!(
+            decode_bar(0x0000_0008_0000_000C, 0xFFFF_FFFF_FFFF_C00C),
+            Some(Bar::Memory {
+                addr: 0x8_0000_0000,
+                size: 0x4000,
+                prefetchable: true,
+                is_64bit: true
+            })
+        );
+        assert_eq!(
+            decode_bar(0xC001, 0xFFFF_FFE1),
+            Some(Bar::Io {
+                port: 0xC000,
+                size: 0x20
+            })
+        );
+        assert_eq!(
+            decode_bar(0xC001, 0x0000_FFE1),
+            Some(Bar::Io {
+                port: 0xC000,
+                size: 0x20
+            })
+        );
+        assert_eq!(decode_bar(0, 0), None);
+    }
+
+    #[test]
+    fn test_decode_bars() {
+        let io = (0xC001, 0xFFFF_FFE1);
+        let memory_64 = (0x0000_000C, 0xFFFF_C00C);
+        let bars = decode_bars(&[io, memory_64, (0x8, 0xFFFF_FFFF)]);
+        assert_eq!(
+            bars[0],
+            Some(Bar::Io {
+                port: 0xC000,
+                size: 0x20
+            })
+        );
+        assert_eq!(
+            bars[1],
+            Some(Bar::Memory {
+                addr: 0x8_0000_0000,
+                size: 0x4000,
+                prefetchable: true,
+                is_64bit: true
+            })
+        );
+        assert_eq!(bars[2], None);
+        // A type 1 header has two BARs, a 64-bit one can't start in BAR1
+        assert_eq!(
+            decode_bars(&[io, memory_64]),
+            [bars[0], None, None, None, None, None]
+        );
+        assert_eq!(decode_bars(&[io, io, io, io, io, memory_64])[5], None);
+    }
 }
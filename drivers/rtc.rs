@@ -0,0 +1,288 @@
+//! CMOS Real-Time Clock driver for Orbita OS
+//!
+//! Reads calendar time from the MC146818 compatible RTC, handling BCD and
+//! binary encodings, 12 and 24 hour modes and the optional century register.
+//!
+//! The CMOS is reached through an index port and a data port, so every
+//! access, here or elsewhere, goes through [`with_cmos`] to keep the pair
+//! from being interleaved.
+
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+/// Status A: update in progress
+const STATUS_A_UIP: u8 = 0x80;
+/// Status B: values are binary rather than BCD
+const STATUS_B_BINARY: u8 = 0x04;
+/// Status B: hours are in 24 hour format
+const STATUS_B_24H: u8 = 0x02;
+/// PM flag in the hours register in 12 hour mode
+const HOURS_PM: u8 = 0x80;
+
+/// Number of attempts to get two identical consecutive readings
+const READ_ATTEMPTS: usize = 16;
+/// Status A polls before giving up on an update finishing; an update takes
+/// about 2 ms and each poll at least a microsecond
+const UIP_POLLS: usize = 100_000;
+
+/// The CMOS index and data ports, borrowed with [`with_cmos`]
+pub struct Cmos {
+    _private: (),
+}
+
+static CMOS: Mutex<Cmos> = Mutex::new(Cmos { _private: () });
+
+impl Cmos {
+    /// Read CMOS register `reg`
+    pub fn read(&mut self, reg: u8) -> u8 {
+        unsafe {
+            Port::<u8>::new(CMOS_ADDRESS).write(reg);
+            Port::<u8>::new(CMOS_DATA).read()
+        }
+    }
+
+    /// Write `value` to CMOS register `reg`
+    pub fn write(&mut self, reg: u8, value: u8) {
+        unsafe {
+            Port::<u8>::new(CMOS_ADDRESS).write(reg);
+            Port::<u8>::new(CMOS_DATA).write(value);
+        }
+    }
+}
+
+/// Run `f` with the CMOS to itself and interrupts disabled.
+pub fn with_cmos<R>(f: impl FnOnce(&mut Cmos) -> R) -> R {
+    without_interrupts(|| f(&mut CMOS.lock()))
+}
+
+/// Calendar date and time (UTC)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00
+    pub fn to_unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        days as u64 * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    /// Date and time `timestamp` seconds after the Unix epoch
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        let days = (timestamp / 86400) as i64;
+        let secs = timestamp % 86400;
+        let (year, month, day) = civil_from_days(days);
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && (1..=31).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// Howard Hinnant's civil calendar algorithms
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+/// Raw register snapshot
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+/// CMOS real-time clock
+pub struct Rtc {
+    /// CMOS index of the century register (from the ACPI FADT), if any
+    century_register: Option<u8>,
+}
+
+impl Rtc {
+    /// Create a driver instance
+    pub const fn new(century_register: Option<u8>) -> Self {
+        Self { century_register }
+    }
+
+    /// Wait for a running update to finish and snapshot the time registers
+    fn read_raw(&self, cmos: &mut Cmos) -> Result<RawTime, RtcError> {
+        (0..UIP_POLLS)
+            .find(|_| cmos.read(REG_STATUS_A) & STATUS_A_UIP == 0)
+            .ok_or(RtcError::UpdateTimeout)?;
+        Ok(RawTime {
+            second: cmos.read(REG_SECONDS),
+            minute: cmos.read(REG_MINUTES),
+            hour: cmos.read(REG_HOURS),
+            day: cmos.read(REG_DAY),
+            month: cmos.read(REG_MONTH),
+            year: cmos.read(REG_YEAR),
+            century: self.century_register.map_or(0, |reg| cmos.read(reg)),
+        })
+    }
+
+    /// Read the current date and time.
+    ///
+    /// The registers are read until two consecutive snapshots match so an
+    /// update in the middle of the read can't produce a torn value.
+    pub fn read(&self) -> Result<DateTime, RtcError> {
+        let (raw, status_b) = with_cmos(|cmos| {
+            let mut last = self.read_raw(cmos)?;
+            let mut stable = None;
+            for _ in 0..READ_ATTEMPTS {
+                let current = self.read_raw(cmos)?;
+                if current == last {
+                    stable = Some(current);
+                    break;
+                }
+                last = current;
+            }
+            let raw = stable.ok_or(RtcError::Unstable)?;
+            Ok((raw, cmos.read(REG_STATUS_B)))
+        })?;
+        let datetime = Self::decode(raw, status_b, self.century_register.is_some());
+        if !datetime.is_valid() {
+            return Err(RtcError::InvalidTime);
+        }
+        Ok(datetime)
+    }
+
+    fn decode(raw: RawTime, status_b: u8, has_century: bool) -> DateTime {
+        let binary = status_b & STATUS_B_BINARY != 0;
+        let convert = |value: u8| if binary { value } else { bcd_to_binary(value) };
+
+        let pm = raw.hour & HOURS_PM != 0;
+        let mut hour = convert(raw.hour & !HOURS_PM);
+        if status_b & STATUS_B_24H == 0 {
+            // 12 AM is midnight, 12 PM is noon
+            hour %= 12;
+            if pm {
+                hour += 12;
+            }
+        }
+
+        let year = convert(raw.year) as u16;
+        let year = if has_century {
+            convert(raw.century) as u16 * 100 + year
+        } else {
+            2000 + year
+        };
+
+        DateTime {
+            year,
+            month: convert(raw.month),
+            day: convert(raw.day),
+            hour,
+            minute: convert(raw.minute),
+            second: convert(raw.second),
+        }
+    }
+}
+
+/// RTC errors
+#[derive(Debug, Clone, Copy)]
+pub enum RtcError {
+    Unstable,
+    InvalidTime,
+    /// An update never finished
+    UpdateTimeout,
+}
+
+impl fmt::Display for RtcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RtcError::Unstable => write!(f, "RTC value kept changing"),
+            RtcError::InvalidTime => write!(f, "RTC returned an invalid time"),
+            RtcError::UpdateTimeout => write!(f, "RTC update did not finish"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_bcd_12h() {
+        let raw = RawTime {
+            second: 0x45,
+            minute: 0x30,
+            hour: HOURS_PM | 0x12,
+            day: 0x17,
+            month: 0x10,
+            year: 0x26,
+            century: 0x20,
+        };
+        let datetime = Rtc::decode(raw, 0, true);
+        assert_eq!(datetime.to_unix_timestamp(), 1792240245);
+        assert_eq!(DateTime::from_unix_timestamp(1792240245), datetime);
+    }
+}
@@ -5,8 +5,17 @@
 //! Implements basic AC97 audio codec support
 
 use core::fmt;
+use core::mem::size_of;
+use core::time::Duration;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use crate::dma::{DmaBuffer, DMA32_LIMIT};
+use crate::drivers::msi::DeviceIrq;
+use crate::drivers::pci::{DriverData, PciDevice, PciDriver, PciMatch, ProbeError};
+use crate::irq::IrqReturn;
+use crate::sync::IrqSpinLock;
+use crate::time;
 use x86_64::instructions::port::Port;
-use alloc::vec::Vec;
 
 const AC97_RESET: u16 = 0x00;
 const AC97_MASTER_VOLUME: u16 = 0x02;
@@ -22,8 +31,18 @@ const NABM_BASE: u16 = 0x10; // Native Audio Bus Master
 // Bus master registers
 const BD_BAR: u16 = 0x00; // Buffer Descriptor List Base Address
 const BD_LAST_VALID: u16 = 0x05; // Last Valid Index
+const BD_STATUS: u16 = 0x16; // Status Register
 const BD_CONTROL: u16 = 0x1B; // Control Register
 
+// Control and status bits for the last valid buffer, completion and FIFO
+// error interrupts
+const BD_INTERRUPTS: u8 = 0x1C;
+
+/// Entries in a buffer descriptor list
+const BD_COUNT: usize = 32;
+/// Bytes of samples per buffer descriptor
+const BD_CHUNK_SIZE: usize = 4096;
+
 /// AC97 Buffer Descriptor
 #[repr(C, packed)]
 struct BufferDescriptor {
@@ -37,7 +56,10 @@ pub struct AC97Driver {
     nam_base: u16,
     nabm_base: u16,
     initialized: bool,
-    buffer_descriptors: Vec<BufferDescriptor>,
+    /// Buffer descriptor list and the samples it points to, kept while
+    /// the device may read them
+    buffer_descriptors: Option<DmaBuffer>,
+    samples: Option<DmaBuffer>,
 }
 
 impl AC97Driver {
@@ -47,7 +69,8 @@ impl AC97Driver {
             nam_base,
             nabm_base,
             initialized: false,
-            buffer_descriptors: Vec::new(),
+            buffer_descriptors: None,
+            samples: None,
         }
     }
 
@@ -81,15 +104,9 @@ impl AC97Driver {
             reset_port.write(0);
             
             // Wait for codec ready
-            for _ in 0..1000 {
-                let status = reset_port.read();
-                if status != 0xFFFF {
-                    return Ok(());
-                }
-                x86_64::instructions::nop();
-            }
+            time::wait_for(Duration::from_millis(100), || reset_port.read() != 0xFFFF)
+                .map_err(|_| SoundError::CodecTimeout)
         }
-        Err(SoundError::CodecTimeout)
     }
 
     /// Read a codec register
@@ -139,39 +156,46 @@ impl AC97Driver {
 
         unsafe {
             let mut ctrl = Port::<u8>::new(self.nabm_base + BD_CONTROL);
-            ctrl.write(0x01); // Run
+            ctrl.write(0x01 | BD_INTERRUPTS); // Run
         }
 
         Ok(())
     }
 
-    /// Setup DMA descriptors for the provided buffer
+    /// Copy the provided buffer into DMA memory and set up descriptors for it
     fn setup_dma(&mut self, data: &[u8]) -> Result<(), SoundError> {
-        if data.is_empty() {
+        if data.is_empty() || data.len() > BD_COUNT * BD_CHUNK_SIZE {
             return Err(SoundError::BufferOverflow);
         }
 
-        self.buffer_descriptors.clear();
-        for chunk in data.chunks(4096) {
+        // The previous buffers are freed below, the device must be done with them
+        self.stop()?;
+
+        // The bus master only takes 32-bit addresses
+        let alloc = |size| DmaBuffer::new(size, 8, DMA32_LIMIT).map_err(|_| SoundError::DMAError);
+        let mut samples = alloc(data.len())?;
+        samples.copy_from_slice(data);
+
+        let chunks = data.len().div_ceil(BD_CHUNK_SIZE);
+        let descriptors = alloc(chunks * size_of::<BufferDescriptor>())?;
+        for (i, chunk) in data.chunks(BD_CHUNK_SIZE).enumerate() {
             let desc = BufferDescriptor {
-                addr: chunk.as_ptr() as u32,
+                addr: (samples.phys_addr().as_u64() + (i * BD_CHUNK_SIZE) as u64) as u32,
                 samples: (chunk.len() / 2) as u16,
                 flags: 0x8000,
             };
-            self.buffer_descriptors.push(desc);
-        }
-
-        if self.buffer_descriptors.is_empty() {
-            return Err(SoundError::BufferOverflow);
+            unsafe { descriptors.ptr::<BufferDescriptor>().add(i).write(desc) };
         }
 
         unsafe {
             let mut bdbar = Port::<u32>::new(self.nabm_base + BD_BAR);
-            bdbar.write(self.buffer_descriptors.as_ptr() as u32);
+            bdbar.write(descriptors.phys_addr().as_u64() as u32);
             let mut lvi = Port::<u8>::new(self.nabm_base + BD_LAST_VALID);
-            lvi.write((self.buffer_descriptors.len() - 1) as u8);
+            lvi.write((chunks - 1) as u8);
         }
 
+        self.buffer_descriptors = Some(descriptors);
+        self.samples = Some(samples);
         Ok(())
     }
 
@@ -183,6 +207,75 @@ impl AC97Driver {
         }
         Ok(())
     }
+
+    /// Handle an interrupt from the bus master
+    pub fn handle_interrupt(&mut self) -> IrqReturn {
+        unsafe {
+            let mut status_port = Port::<u16>::new(self.nabm_base + BD_STATUS);
+            let status = status_port.read() & BD_INTERRUPTS as u16;
+            if status == 0 {
+                return IrqReturn::None;
+            }
+            // The bits are cleared by writing them back
+            status_port.write(status);
+        }
+        IrqReturn::Handled
+    }
+}
+
+/// Devices the driver handles
+const PCI_IDS: &[PciMatch] = &[
+    PciMatch::device(0x8086, 0x2415), // ICH, emulated by QEMU
+    PciMatch::device(0x8086, 0x2425), // ICH0
+    PciMatch::device(0x8086, 0x2445), // ICH2
+    PciMatch::device(0x8086, 0x2485), // ICH3
+    PciMatch::device(0x8086, 0x24C5), // ICH4
+    PciMatch::device(0x8086, 0x24D5), // ICH5
+    PciMatch::device(0x8086, 0x266E), // ICH6
+];
+
+/// Binds the driver to Intel ICH AC97 controllers
+pub struct Ac97PciDriver;
+
+/// State kept for a bound controller
+struct Ac97Device {
+    driver: Arc<IrqSpinLock<AC97Driver>>,
+    irq: DeviceIrq,
+}
+
+impl PciDriver for Ac97PciDriver {
+    fn name(&self) -> &'static str {
+        "ac97"
+    }
+
+    fn id_table(&self) -> &'static [PciMatch] {
+        PCI_IDS
+    }
+
+    fn probe(&self, device: &PciDevice) -> Result<DriverData, ProbeError> {
+        // Mixer registers behind BAR0, bus master registers behind BAR1
+        let (Some(nam_base), Some(nabm_base)) = (device.io_bar(0), device.io_bar(1)) else {
+            return Err(ProbeError::MissingResource("I/O BARs"));
+        };
+        device.enable_io_space();
+        device.enable_bus_master();
+        let driver = Arc::new(IrqSpinLock::new(AC97Driver::new(nam_base, nabm_base)));
+        let handler = driver.clone();
+        let irq = device
+            .request_irq("ac97", move || handler.lock().handle_interrupt())
+            .map_err(ProbeError::init)?;
+        driver.lock().init().map_err(ProbeError::init)?;
+        Ok(Box::new(Ac97Device { driver, irq }))
+    }
+
+    fn remove(&self, _device: &PciDevice, data: DriverData) {
+        if let Ok(device) = data.downcast::<Ac97Device>() {
+            // The DMA buffers go with the driver
+            let Ac97Device { driver, irq } = *device;
+            let _ = driver.lock().stop();
+            drop(irq);
+        }
+    }
 }
 
 /// Sound driver errors
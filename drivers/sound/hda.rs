@@ -3,19 +3,54 @@
 //! Intel High Definition Audio driver skeleton
 
 use core::fmt;
-use x86_64::instructions::port::Port;
+use core::time::Duration;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use crate::drivers::msi::DeviceIrq;
+use crate::drivers::pci::{DriverData, PciDevice, PciDriver, PciMatch, ProbeError};
+use crate::irq::IrqReturn;
+use crate::mmio::{self, CacheMode, Mmio};
+use crate::sync::IrqSpinLock;
+use crate::time;
+use x86_64::PhysAddr;
+
+/// Size of the controller register block
+pub const REGISTERS_SIZE: u64 = 0x4000;
+
+const GCAP: usize = 0x00; // Global Capabilities
+const GCTL: usize = 0x08; // Global Control
+const STATESTS: usize = 0x0E; // State Change Status
+const INTCTL: usize = 0x20; // Interrupt Control
+const INTSTS: usize = 0x24; // Interrupt Status
+const CORBCTL: usize = 0x4C; // CORB Control
+const RIRBCTL: usize = 0x5C; // RIRB Control
+const RIRBSTS: usize = 0x5D; // RIRB Status
+const DPLBASE: usize = 0x70; // DMA Position Lower Base Address
+
+/// Stream descriptors, 0x20 bytes each
+const STREAMS: usize = 0x80;
 
 /// HDA driver structure
 pub struct HdaDriver {
-    pub base: u32,
+    pub base: PhysAddr,
+    regs: Mmio,
     codecs: [bool; 15],
 }
 
 impl HdaDriver {
-    /// Create a new HDA driver
-    pub fn new(base: u32) -> Self {
+    /// Create a new HDA driver for the controller whose registers are at
+    /// physical address `base`
+    pub fn new(base: PhysAddr) -> Result<Self, HdaError> {
+        let regs = mmio::ioremap(base, REGISTERS_SIZE, CacheMode::Uncached)
+            .map_err(|_| HdaError::MapFailed)?;
+        Ok(Self::with_registers(base, regs))
+    }
+
+    /// Create a driver using already mapped registers
+    pub fn with_registers(base: PhysAddr, regs: Mmio) -> Self {
         Self {
             base,
+            regs,
             codecs: [false; 15],
         }
     }
@@ -24,36 +59,68 @@ impl HdaDriver {
     pub fn init(&mut self) -> Result<(), HdaError> {
         self.reset_controller()?;
         self.discover_codecs();
+        // Global and controller interrupts; streams add their own bits
+        self.regs.write::<u32>(INTCTL, 0xC000_0000);
         Ok(())
     }
 
+    /// Stop the streams and the command rings and hold the controller in
+    /// reset, after which it no longer does DMA
+    pub fn stop(&mut self) -> Result<(), HdaError> {
+        self.regs.write::<u32>(INTCTL, 0);
+        for stream in 0..self.stream_count() {
+            self.regs.update::<u8>(STREAMS + stream * 0x20, |ctl| ctl & !0x2);
+        }
+        self.regs.update::<u8>(CORBCTL, |ctl| ctl & !0x2);
+        self.regs.update::<u8>(RIRBCTL, |ctl| ctl & !0x2);
+        self.regs.update::<u32>(DPLBASE, |base| base & !0x1);
+        self.regs.write::<u32>(GCTL, 0);
+        time::wait_for(Duration::from_millis(100), || self.regs.read::<u32>(GCTL) & 0x1 == 0)
+            .map_err(|_| HdaError::Timeout)
+    }
+
+    /// Handle an interrupt from the controller
+    pub fn handle_interrupt(&mut self) -> IrqReturn {
+        let status = self.regs.read::<u32>(INTSTS);
+        if status == 0 || status == 0xFFFF_FFFF {
+            return IrqReturn::None;
+        }
+        // The summary bits clear once the sources are acknowledged
+        for stream in (0..self.stream_count()).filter(|s| status & (1 << s) != 0) {
+            self.regs.write::<u8>(STREAMS + stream * 0x20 + 0x3, 0x1C);
+        }
+        if status & (1 << 30) != 0 {
+            let rirb = self.regs.read::<u8>(RIRBSTS);
+            self.regs.write::<u8>(RIRBSTS, rirb);
+            let states = self.regs.read::<u16>(STATESTS);
+            self.regs.write::<u16>(STATESTS, states);
+        }
+        IrqReturn::Handled
+    }
+
+    /// Number of input, output and bidirectional stream descriptors
+    fn stream_count(&self) -> usize {
+        let gcap = self.regs.read::<u16>(GCAP);
+        (((gcap >> 8) & 0xF) + ((gcap >> 12) & 0xF) + ((gcap >> 3) & 0x1F)) as usize
+    }
+
     /// Perform controller reset
     fn reset_controller(&mut self) -> Result<(), HdaError> {
-        unsafe {
-            let mut gctl = Port::<u32>::new((self.base + 0x08) as u16); // Global Control
-            gctl.write(0);
-            for _ in 0..1000 {
-                if gctl.read() & 0x1 == 0 {
-                    break;
-                }
-                x86_64::instructions::nop();
-            }
-            gctl.write(1);
-            for _ in 0..1000 {
-                if gctl.read() & 0x1 == 1 {
-                    return Ok(());
-                }
-                x86_64::instructions::nop();
-            }
-        }
-        Err(HdaError::Timeout)
+        self.regs.write::<u32>(GCTL, 0);
+        time::wait_for(Duration::from_millis(100), || self.regs.read::<u32>(GCTL) & 0x1 == 0)
+            .map_err(|_| HdaError::Timeout)?;
+        self.regs.write::<u32>(GCTL, 1);
+        time::wait_for(Duration::from_millis(100), || self.regs.read::<u32>(GCTL) & 0x1 == 1)
+            .map_err(|_| HdaError::Timeout)?;
+        // Codecs need 521 us after leaving reset before they answer
+        time::busy_wait_us(521);
+        Ok(())
     }
 
     /// Detect available codecs
     fn discover_codecs(&mut self) {
         for i in 0..15 {
-            let offset = 0x60 + i as u32 * 4;
-            let presence = unsafe { Port::<u32>::new((self.base + offset) as u16).read() } & 0x1;
+            let presence = self.regs.read::<u32>(0x60 + i * 4) & 0x1;
             self.codecs[i] = presence != 0;
         }
     }
@@ -68,20 +135,67 @@ impl HdaDriver {
         if stream >= 15 {
             return Err(HdaError::InvalidStream);
         }
-        let offset = 0x80 + stream as u32 * 0x20;
-        unsafe {
-            Port::<u32>::new((self.base + offset + 0x18) as u16).write(buffer_addr);
-            Port::<u32>::new((self.base + offset + 0x1C) as u16).write(length);
-        }
+        let offset = 0x80 + stream * 0x20;
+        self.regs.write::<u32>(offset + 0x18, buffer_addr);
+        self.regs.write::<u32>(offset + 0x1C, length);
         Ok(())
     }
 }
 
+/// Devices the driver handles
+const PCI_IDS: &[PciMatch] = &[PciMatch::class(0x04, 0x03)];
+
+/// Binds the driver to every HD Audio controller
+pub struct HdaPciDriver;
+
+/// State kept for a bound controller
+struct HdaDevice {
+    driver: Arc<IrqSpinLock<HdaDriver>>,
+    irq: DeviceIrq,
+}
+
+impl PciDriver for HdaPciDriver {
+    fn name(&self) -> &'static str {
+        "hda"
+    }
+
+    fn id_table(&self) -> &'static [PciMatch] {
+        PCI_IDS
+    }
+
+    fn probe(&self, device: &PciDevice) -> Result<DriverData, ProbeError> {
+        let (base, _) = device
+            .memory_bar(0)
+            .ok_or(ProbeError::MissingResource("memory BAR"))?;
+        device.enable_memory_space();
+        device.enable_bus_master();
+        let driver = HdaDriver::new(base).map_err(ProbeError::init)?;
+        let driver = Arc::new(IrqSpinLock::new(driver));
+        let handler = driver.clone();
+        let irq = device
+            .request_irq("hda", move || handler.lock().handle_interrupt())
+            .map_err(ProbeError::init)?;
+        driver.lock().init().map_err(ProbeError::init)?;
+        Ok(Box::new(HdaDevice { driver, irq }))
+    }
+
+    fn remove(&self, _device: &PciDevice, data: DriverData) {
+        if let Ok(device) = data.downcast::<HdaDevice>() {
+            let HdaDevice { driver, irq } = *device;
+            if let Err(e) = driver.lock().stop() {
+                log::warn!("hda: {}", e);
+            }
+            drop(irq);
+        }
+    }
+}
+
 /// HDA driver errors
 #[derive(Debug, Clone, Copy)]
 pub enum HdaError {
     Timeout,
     InvalidStream,
+    MapFailed,
 }
 
 impl fmt::Display for HdaError {
@@ -89,6 +203,7 @@ impl fmt::Display for HdaError {
         match self {
             HdaError::Timeout => write!(f, "HDA reset timeout"),
             HdaError::InvalidStream => write!(f, "Invalid stream index"),
+            HdaError::MapFailed => write!(f, "Failed to map HDA registers"),
         }
     }
 }
@@ -99,8 +214,10 @@ mod tests {
 
     #[test]
     fn test_new() {
-        let hda = HdaDriver::new(0xF0000000);
-        assert_eq!(hda.base, 0xF0000000);
+        let mut regs = [0u32; REGISTERS_SIZE as usize / 4];
+        let mmio = unsafe { Mmio::from_raw(regs.as_mut_ptr().cast(), REGISTERS_SIZE as usize) };
+        let hda = HdaDriver::with_registers(PhysAddr::new(0xF0000000), mmio);
+        assert_eq!(hda.base.as_u64(), 0xF0000000);
         assert!(!hda.codecs.iter().any(|c| *c));
     }
 }
@@ -5,7 +5,26 @@
 //! Provides controller initialization, port discovery and NCQ command skeletons.
 
 use core::fmt;
+use core::mem::offset_of;
 use bit_field::BitField;
+use alloc::boxed::Box;
+use crate::drivers::pci::{DriverData, PciDevice, PciDriver, PciMatch, ProbeError};
+use crate::mmio::{self, CacheMode, Mmio};
+use crate::time;
+use core::time::Duration;
+use x86_64::PhysAddr;
+
+/// Size of the register block behind ABAR with all 32 ports
+pub const REGISTERS_SIZE: u64 = 0x1100;
+/// Offset and size of each port's registers
+const PORTS: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+
+// Port command bits: start, FIS receive enable and the matching running bits
+const CMD_ST: usize = 0;
+const CMD_FRE: usize = 4;
+const CMD_FR: usize = 14;
+const CMD_CR: usize = 15;
 
 /// Host Bus Adapter memory structure (simplified).
 #[repr(C)]
@@ -41,29 +60,63 @@ pub struct HbaPort {
 
 /// AHCI controller abstraction.
 pub struct AhciController {
-    hba: &'static mut HbaMem,
+    hba: Mmio,
 }
 
 impl AhciController {
-    /// Create controller from a memory mapped address.
-    ///
-    /// # Safety
-    /// Caller must ensure the address contains valid HBA registers.
-    pub unsafe fn new(hba_address: usize) -> Self {
-        Self { hba: &mut *(hba_address as *mut HbaMem) }
+    /// Create controller for the HBA whose registers are at physical address `abar`.
+    pub fn new(abar: PhysAddr) -> Result<Self, AhciError> {
+        let hba = mmio::ioremap(abar, REGISTERS_SIZE, CacheMode::Uncached)
+            .map_err(|_| AhciError::MapFailed)?;
+        Ok(Self::with_registers(hba))
+    }
+
+    /// Create controller from already mapped HBA registers.
+    pub fn with_registers(hba: Mmio) -> Self {
+        Self { hba }
     }
 
     /// Initialize AHCI mode.
     pub fn init(&mut self) {
-        unsafe {
-            // Set AHCI enable bit
-            self.hba.global_host_control.set_bit(31, true);
+        // Set AHCI enable bit
+        self.hba.update::<u32>(offset_of!(HbaMem, global_host_control), |mut ghc| {
+            ghc.set_bit(31, true);
+            ghc
+        });
+    }
+
+    /// Stop the command engines and FIS receive of every port and mask the
+    /// controller's interrupts, after which the HBA no longer does DMA.
+    pub fn stop(&mut self) -> Result<(), AhciError> {
+        self.hba.update::<u32>(offset_of!(HbaMem, global_host_control), |mut ghc| {
+            ghc.set_bit(1, false);
+            ghc
+        });
+        let ports = self.discover_ports();
+        for port in (0..32).filter(|port| ports.get_bit(*port)) {
+            let cmd = PORTS + port * PORT_SIZE + offset_of!(HbaPort, command_and_status);
+            // The spec gives each engine 500 ms to stop
+            self.hba.update::<u32>(cmd, |mut cmd| {
+                cmd.set_bit(CMD_ST, false);
+                cmd
+            });
+            let running = || self.hba.read::<u32>(cmd).get_bit(CMD_CR);
+            time::wait_for(Duration::from_millis(500), || !running())
+                .map_err(|_| AhciError::Timeout)?;
+            self.hba.update::<u32>(cmd, |mut cmd| {
+                cmd.set_bit(CMD_FRE, false);
+                cmd
+            });
+            let running = || self.hba.read::<u32>(cmd).get_bit(CMD_FR);
+            time::wait_for(Duration::from_millis(500), || !running())
+                .map_err(|_| AhciError::Timeout)?;
         }
+        Ok(())
     }
 
     /// Return a bitmap of implemented ports.
     pub fn discover_ports(&self) -> u32 {
-        self.hba.ports_implemented
+        self.hba.read(offset_of!(HbaMem, ports_implemented))
     }
 
     /// Read sectors using a normal command (skeleton).
@@ -85,10 +138,48 @@ impl AhciController {
     }
 }
 
+/// Devices the driver handles
+const PCI_IDS: &[PciMatch] = &[PciMatch::class_interface(0x01, 0x06, 0x01)];
+
+/// Binds the driver to SATA controllers in AHCI mode
+pub struct AhciPciDriver;
+
+impl PciDriver for AhciPciDriver {
+    fn name(&self) -> &'static str {
+        "ahci"
+    }
+
+    fn id_table(&self) -> &'static [PciMatch] {
+        PCI_IDS
+    }
+
+    fn probe(&self, device: &PciDevice) -> Result<DriverData, ProbeError> {
+        // The HBA registers are behind BAR5
+        let (abar, _) = device
+            .memory_bar(5)
+            .ok_or(ProbeError::MissingResource("ABAR"))?;
+        device.enable_memory_space();
+        device.enable_bus_master();
+        let mut controller = AhciController::new(abar).map_err(ProbeError::init)?;
+        controller.init();
+        Ok(Box::new(controller))
+    }
+
+    fn remove(&self, _device: &PciDevice, data: DriverData) {
+        if let Ok(mut controller) = data.downcast::<AhciController>() {
+            if let Err(e) = controller.stop() {
+                log::warn!("ahci: {}", e);
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum AhciError {
     NoPort,
     CommandFailed,
+    MapFailed,
+    Timeout,
 }
 
 impl fmt::Display for AhciError {
@@ -96,6 +187,8 @@ impl fmt::Display for AhciError {
         match self {
             AhciError::NoPort => write!(f, "Port not available"),
             AhciError::CommandFailed => write!(f, "Command failed"),
+            AhciError::MapFailed => write!(f, "Failed to map HBA registers"),
+            AhciError::Timeout => write!(f, "Port command engine did not stop"),
         }
     }
 }
@@ -114,7 +207,8 @@ mod tests {
             _reserved: [0; 11],
             ports: unsafe { core::mem::zeroed() },
         };
-        let controller = AhciController { hba: unsafe { &mut *( &mem as *const _ as *mut HbaMem ) } };
+        let hba = unsafe { Mmio::from_raw(&mem as *const _ as *mut u8, core::mem::size_of::<HbaMem>()) };
+        let controller = AhciController::with_registers(hba);
         assert_eq!(controller.discover_ports(), 0x5);
     }
 }
@@ -4,9 +4,22 @@
 //!
 //! Provides disk detection, sector read/write and DMA skeleton.
 
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::fmt;
+use core::time::Duration;
+use crate::block::{BlockDevice, BlockError};
+use crate::drivers::pci::{DriverData, PciDevice, PciDriver, PciMatch, ProbeError};
+use crate::time;
 use x86_64::instructions::port::Port;
 
+/// How long to wait for the drive to become ready
+const ATA_TIMEOUT_MS: u64 = 1000;
+
+/// Command and control ports of the primary and secondary channel in
+/// compatibility mode
+pub const LEGACY_CHANNELS: [(u16, u16); 2] = [(0x1F0, 0x3F6), (0x170, 0x376)];
+
 /// Represents an ATA controller on a legacy IDE bus.
 pub struct AtaController {
     pub io_base: u16,
@@ -27,17 +40,29 @@ impl AtaController {
             drive_head.write(0xA0); // Master drive
 
             let mut status = Port::<u8>::new(self.io_base + 7);
+            // A floating bus reads all ones: nothing attached
+            if status.read() == 0xFF {
+                return Ok(false);
+            }
             status.write(0xEC); // IDENTIFY
 
             // Wait for BSY to clear
-            for _ in 0..100000 {
-                let s = status.read();
-                if s & 0x80 == 0 {
-                    return Ok(s != 0);
+            let mut s = 0;
+            time::wait_for(Duration::from_millis(ATA_TIMEOUT_MS), || {
+                s = status.read();
+                s & 0x80 == 0
+            })
+            .map_err(|_| AtaError::Timeout)?;
+
+            // Discard the identify data so the next command starts clean
+            if s & 0x08 != 0 {
+                let mut data = Port::<u16>::new(self.io_base);
+                for _ in 0..256 {
+                    data.read();
                 }
             }
+            Ok(s != 0)
         }
-        Err(AtaError::Timeout)
     }
 
     /// Read a single 512-byte sector using PIO.
@@ -61,12 +86,8 @@ impl AtaController {
             command.write(0x20); // READ SECTOR
 
             let mut status = Port::<u8>::new(self.io_base + 7);
-            for _ in 0..100000 {
-                let s = status.read();
-                if s & 0x08 != 0 {
-                    break;
-                }
-            }
+            time::wait_for(Duration::from_millis(ATA_TIMEOUT_MS), || status.read() & 0x08 != 0)
+                .map_err(|_| AtaError::Timeout)?;
 
             let mut data = Port::<u16>::new(self.io_base);
             for i in 0..256 {
@@ -99,12 +120,20 @@ impl AtaController {
             drive_head.write(0xE0 | (((lba >> 24) & 0x0F) as u8));
             command.write(0x30); // WRITE SECTOR
 
+            let mut status = Port::<u8>::new(self.io_base + 7);
+            time::wait_for(Duration::from_millis(ATA_TIMEOUT_MS), || status.read() & 0x08 != 0)
+                .map_err(|_| AtaError::Timeout)?;
+
             let mut data = Port::<u16>::new(self.io_base);
             for i in 0..256 {
                 let lo = buffer[i * 2] as u16;
                 let hi = buffer[i * 2 + 1] as u16;
                 data.write((hi << 8) | lo);
             }
+
+            // The sector is only on the disk once BSY clears
+            time::wait_for(Duration::from_millis(ATA_TIMEOUT_MS), || status.read() & 0x80 == 0)
+                .map_err(|_| AtaError::Timeout)?;
         }
         Ok(())
     }
@@ -127,6 +156,92 @@ impl AtaController {
     }
 }
 
+impl BlockDevice for AtaController {
+    fn block_size(&self) -> usize {
+        512
+    }
+
+    fn read_block(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let lba = lba28(block)?;
+        self.read_sector(lba, buffer).map_err(BlockError::from)
+    }
+
+    fn write_block(&mut self, block: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        let lba = lba28(block)?;
+        self.write_sector(lba, buffer).map_err(BlockError::from)
+    }
+}
+
+/// LBA of `block` for the PIO commands, which take 28 bits
+fn lba28(block: u64) -> Result<u32, BlockError> {
+    if block < 1 << 28 {
+        Ok(block as u32)
+    } else {
+        Err(BlockError::OutOfRange)
+    }
+}
+
+impl From<AtaError> for BlockError {
+    fn from(e: AtaError) -> Self {
+        match e {
+            AtaError::InvalidLba => BlockError::OutOfRange,
+            AtaError::BufferTooSmall => BlockError::BufferTooSmall,
+            AtaError::DeviceNotFound | AtaError::Timeout => BlockError::Io,
+        }
+    }
+}
+
+/// Devices the driver handles
+const PCI_IDS: &[PciMatch] = &[PciMatch::class(0x01, 0x01)];
+
+/// Binds the driver to IDE controllers
+pub struct AtaPciDriver;
+
+impl PciDriver for AtaPciDriver {
+    fn name(&self) -> &'static str {
+        "ata"
+    }
+
+    fn id_table(&self) -> &'static [PciMatch] {
+        PCI_IDS
+    }
+
+    /// The driver state is a controller for each channel. Drives are left
+    /// alone here: the swap code may already be using them.
+    fn probe(&self, device: &PciDevice) -> Result<DriverData, ProbeError> {
+        let bus_master = device.io_bar(4);
+        let mut channels = Vec::new();
+        for (channel, &(io_base, control_base)) in LEGACY_CHANNELS.iter().enumerate() {
+            // Bits 0 and 2 of the interface select native mode, with the
+            // ports in BAR0-1 and BAR2-3
+            let native = device.prog_if & (1 << (channel * 2)) != 0;
+            let (io_base, control_base) = if native {
+                let command = device.io_bar(channel * 2);
+                let control = device.io_bar(channel * 2 + 1);
+                let (Some(command), Some(control)) = (command, control) else {
+                    return Err(ProbeError::MissingResource("I/O BARs"));
+                };
+                (command, control + 2)
+            } else {
+                (io_base, control_base)
+            };
+
+            let mut controller = AtaController::new(io_base, control_base);
+            if let Some(bus_master) = bus_master {
+                controller.setup_dma(bus_master + channel as u16 * 8);
+            }
+            channels.push(controller);
+        }
+        // Legacy ports decode whatever the command register says, native
+        // ones and the bus master registers need I/O space on
+        device.enable_io_space();
+        if bus_master.is_some() {
+            device.enable_bus_master();
+        }
+        Ok(Box::new(channels))
+    }
+}
+
 /// Errors returned by the ATA driver.
 #[derive(Debug, Clone, Copy)]
 pub enum AtaError {
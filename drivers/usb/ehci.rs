@@ -2,7 +2,9 @@
 
 //! EHCI (Enhanced Host Controller Interface) driver skeleton
 
+use alloc::boxed::Box;
 use core::fmt;
+use crate::drivers::pci::{DriverData, PciDevice, PciDriver, PciMatch, ProbeError};
 use crate::drivers::usb::UsbError;
 
 /// EHCI controller structure
@@ -25,6 +27,35 @@ impl EHCIDriver {
     }
 }
 
+/// Devices the driver handles
+const PCI_IDS: &[PciMatch] = &[PciMatch::class_interface(0x0C, 0x03, 0x20)];
+
+/// Binds the driver to EHCI host controllers
+pub struct EhciPciDriver;
+
+impl PciDriver for EhciPciDriver {
+    fn name(&self) -> &'static str {
+        "ehci"
+    }
+
+    fn id_table(&self) -> &'static [PciMatch] {
+        PCI_IDS
+    }
+
+    fn probe(&self, device: &PciDevice) -> Result<DriverData, ProbeError> {
+        // The driver takes 32-bit register addresses
+        let mem_base = device
+            .memory_bar(0)
+            .and_then(|(addr, _)| u32::try_from(addr.as_u64()).ok())
+            .ok_or(ProbeError::MissingResource("32-bit memory BAR"))?;
+        device.enable_memory_space();
+        device.enable_bus_master();
+        let mut driver = EHCIDriver::new(mem_base);
+        driver.init().map_err(ProbeError::init)?;
+        Ok(Box::new(driver))
+    }
+}
+
 impl fmt::Debug for EHCIDriver {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("EHCIDriver")
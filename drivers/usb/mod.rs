@@ -5,6 +5,8 @@ pub mod xhci;
 pub mod mass_storage;
 pub mod hid;
 
+use core::fmt;
+
 /// Common USB error type
 #[derive(Debug, Clone, Copy)]
 pub enum UsbError {
@@ -12,3 +14,13 @@ pub enum UsbError {
     InitializationFailed,
     TransferError,
 }
+
+impl fmt::Display for UsbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsbError::ControllerNotFound => write!(f, "USB controller not found"),
+            UsbError::InitializationFailed => write!(f, "USB controller initialization failed"),
+            UsbError::TransferError => write!(f, "USB transfer failed"),
+        }
+    }
+}
@@ -2,7 +2,9 @@
 
 //! OHCI (Open Host Controller Interface) driver skeleton
 
+use alloc::boxed::Box;
 use core::fmt;
+use crate::drivers::pci::{DriverData, PciDevice, PciDriver, PciMatch, ProbeError};
 use crate::drivers::usb::UsbError;
 
 /// OHCI controller structure
@@ -25,6 +27,35 @@ impl OHCIDriver {
     }
 }
 
+/// Devices the driver handles
+const PCI_IDS: &[PciMatch] = &[PciMatch::class_interface(0x0C, 0x03, 0x10)];
+
+/// Binds the driver to OHCI host controllers
+pub struct OhciPciDriver;
+
+impl PciDriver for OhciPciDriver {
+    fn name(&self) -> &'static str {
+        "ohci"
+    }
+
+    fn id_table(&self) -> &'static [PciMatch] {
+        PCI_IDS
+    }
+
+    fn probe(&self, device: &PciDevice) -> Result<DriverData, ProbeError> {
+        // The driver takes 32-bit register addresses
+        let mem_base = device
+            .memory_bar(0)
+            .and_then(|(addr, _)| u32::try_from(addr.as_u64()).ok())
+            .ok_or(ProbeError::MissingResource("32-bit memory BAR"))?;
+        device.enable_memory_space();
+        device.enable_bus_master();
+        let mut driver = OHCIDriver::new(mem_base);
+        driver.init().map_err(ProbeError::init)?;
+        Ok(Box::new(driver))
+    }
+}
+
 impl fmt::Debug for OHCIDriver {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("OHCIDriver")
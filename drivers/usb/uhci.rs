@@ -2,7 +2,9 @@
 
 //! UHCI (Universal Host Controller Interface) driver skeleton
 
+use alloc::boxed::Box;
 use core::fmt;
+use crate::drivers::pci::{DriverData, PciDevice, PciDriver, PciMatch, ProbeError};
 use crate::drivers::usb::UsbError;
 
 /// UHCI controller structure
@@ -25,6 +27,34 @@ impl UHCIDriver {
     }
 }
 
+/// Devices the driver handles
+const PCI_IDS: &[PciMatch] = &[PciMatch::class_interface(0x0C, 0x03, 0x00)];
+
+/// Binds the driver to UHCI host controllers
+pub struct UhciPciDriver;
+
+impl PciDriver for UhciPciDriver {
+    fn name(&self) -> &'static str {
+        "uhci"
+    }
+
+    fn id_table(&self) -> &'static [PciMatch] {
+        PCI_IDS
+    }
+
+    fn probe(&self, device: &PciDevice) -> Result<DriverData, ProbeError> {
+        // UHCI has its registers in I/O space behind BAR4
+        let io_base = device
+            .io_bar(4)
+            .ok_or(ProbeError::MissingResource("I/O BAR"))?;
+        device.enable_io_space();
+        device.enable_bus_master();
+        let mut driver = UHCIDriver::new(io_base);
+        driver.init().map_err(ProbeError::init)?;
+        Ok(Box::new(driver))
+    }
+}
+
 impl fmt::Debug for UHCIDriver {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("UHCIDriver")
@@ -2,7 +2,9 @@
 
 //! XHCI (eXtensible Host Controller Interface) driver skeleton
 
+use alloc::boxed::Box;
 use core::fmt;
+use crate::drivers::pci::{DriverData, PciDevice, PciDriver, PciMatch, ProbeError};
 use crate::drivers::usb::UsbError;
 
 /// XHCI controller structure
@@ -25,6 +27,33 @@ impl XHCIDriver {
     }
 }
 
+/// Devices the driver handles
+const PCI_IDS: &[PciMatch] = &[PciMatch::class_interface(0x0C, 0x03, 0x30)];
+
+/// Binds the driver to xHCI host controllers
+pub struct XhciPciDriver;
+
+impl PciDriver for XhciPciDriver {
+    fn name(&self) -> &'static str {
+        "xhci"
+    }
+
+    fn id_table(&self) -> &'static [PciMatch] {
+        PCI_IDS
+    }
+
+    fn probe(&self, device: &PciDevice) -> Result<DriverData, ProbeError> {
+        let (mem_base, _) = device
+            .memory_bar(0)
+            .ok_or(ProbeError::MissingResource("memory BAR"))?;
+        device.enable_memory_space();
+        device.enable_bus_master();
+        let mut driver = XHCIDriver::new(mem_base.as_u64());
+        driver.init().map_err(ProbeError::init)?;
+        Ok(Box::new(driver))
+    }
+}
+
 impl fmt::Debug for XHCIDriver {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("XHCIDriver")
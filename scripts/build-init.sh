@@ -0,0 +1,27 @@
+#!/bin/bash
+# Сборка /bin/init для initrd из user/init/init.S.
+# Нужны GNU as и ld (binutils); готовый бинарник лежит в репозитории,
+# поэтому запускать скрипт нужно только после изменения исходника.
+
+set -e
+
+cd "$(dirname "$0")/.."
+
+build_dir=$(mktemp -d)
+trap 'rm -rf "$build_dir"' EXIT
+
+# Адрес загрузки берётся из ядра, чтобы не расходиться с USER_START
+user_start=$(sed -n 's/^pub const USER_START: u64 = \(0x[0-9a-fA-F_]*\);$/\1/p' src/usermode.rs | tr -d _)
+if [ -z "$user_start" ]; then
+    echo "USER_START not found in src/usermode.rs" >&2
+    exit 1
+fi
+
+as --64 -o "$build_dir/init.o" user/init/init.S
+ld -static -nostdlib --build-id=none -z noexecstack -z max-page-size=0x1000 \
+    --defsym=USER_START="$user_start" -T user/init/init.ld -o "$build_dir/init" "$build_dir/init.o"
+strip "$build_dir/init"
+
+mkdir -p initrd/bin
+install -m 755 "$build_dir/init" initrd/bin/init
+echo "Built initrd/bin/init"
@@ -11,6 +11,7 @@ fi
 # Параметры QEMU
 QEMU_ARGS="-drive format=raw,file=target/x86_64-unknown-none/release/bootimage-orbita.bin"
 QEMU_ARGS="$QEMU_ARGS -m 512M"
+QEMU_ARGS="$QEMU_ARGS -smp ${CPUS:-4}"
 QEMU_ARGS="$QEMU_ARGS -cpu qemu64,+sse,+sse2"
 QEMU_ARGS="$QEMU_ARGS -serial stdio"
 QEMU_ARGS="$QEMU_ARGS -vga std"
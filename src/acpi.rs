@@ -0,0 +1,284 @@
+//! Minimal ACPI table discovery
+//!
+//! Locates the RSDP in the BIOS areas, walks the RSDT/XSDT and parses the
+//! tables the kernel needs (the MADT and MCFG).
+
+use crate::memory;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+use spin::Once;
+use x86_64::PhysAddr;
+
+/// Common header of every system description table
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0+
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Physical addresses of all tables listed in the RSDT/XSDT
+static TABLES: Once<Vec<PhysAddr>> = Once::new();
+
+/// Locate the RSDP and record the system description tables.
+pub fn init() -> Result<(), AcpiError> {
+    let rsdp_addr = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+    let rsdp: Rsdp = unsafe { read_unaligned(memory::phys_to_virt(rsdp_addr).as_ptr()) };
+
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (PhysAddr::new(rsdp.xsdt_address), 8)
+    } else {
+        (PhysAddr::new(rsdp.rsdt_address as u64), 4)
+    };
+    let header = table_header(root);
+    if !checksum_ok(root, header.length as usize) {
+        return Err(AcpiError::InvalidChecksum);
+    }
+
+    let entries_start = memory::phys_to_virt(root) + size_of::<SdtHeader>();
+    let count = (header.length as usize - size_of::<SdtHeader>()) / entry_size;
+    let tables = (0..count)
+        .map(|i| {
+            let ptr = entries_start + (i * entry_size) as u64;
+            let addr = unsafe {
+                if entry_size == 8 {
+                    read_unaligned(ptr.as_ptr::<u64>())
+                } else {
+                    read_unaligned(ptr.as_ptr::<u32>()) as u64
+                }
+            };
+            PhysAddr::new(addr)
+        })
+        .filter(|&addr| checksum_ok(addr, table_header(addr).length as usize))
+        .collect();
+    TABLES.call_once(|| tables);
+    Ok(())
+}
+
+/// Physical address of the first table with the given signature
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    TABLES
+        .r#try()?
+        .iter()
+        .copied()
+        .find(|&addr| &table_header(addr).signature == signature)
+}
+
+/// Header of the table at `addr`
+pub fn table_header(addr: PhysAddr) -> SdtHeader {
+    unsafe { read_unaligned(memory::phys_to_virt(addr).as_ptr()) }
+}
+
+/// Table contents following the header
+pub fn table_body(addr: PhysAddr) -> &'static [u8] {
+    let header = table_header(addr);
+    let start = memory::phys_to_virt(addr) + size_of::<SdtHeader>();
+    unsafe {
+        core::slice::from_raw_parts(
+            start.as_ptr(),
+            header.length as usize - size_of::<SdtHeader>(),
+        )
+    }
+}
+
+fn checksum_ok(addr: PhysAddr, length: usize) -> bool {
+    let bytes =
+        unsafe { core::slice::from_raw_parts(memory::phys_to_virt(addr).as_ptr::<u8>(), length) };
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Search the EBDA and the BIOS ROM area for the RSDP signature.
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda_segment =
+        unsafe { read_unaligned(memory::phys_to_virt(PhysAddr::new(0x40E)).as_ptr::<u16>()) };
+    let ebda = (ebda_segment as u64) << 4;
+    let areas = [(ebda, ebda + 1024), (0xE0000, 0x100000)];
+
+    for (start, end) in areas {
+        if start == 0 {
+            continue;
+        }
+        for addr in (start..end).step_by(16) {
+            let addr = PhysAddr::new(addr);
+            let signature =
+                unsafe { read_unaligned(memory::phys_to_virt(addr).as_ptr::<[u8; 8]>()) };
+            // The ACPI 1.0 part of the structure is 20 bytes long
+            if &signature == b"RSD PTR " && checksum_ok(addr, 20) {
+                return Some(addr);
+            }
+        }
+    }
+    None
+}
+
+/// Processor entry from the MADT
+#[derive(Debug, Clone, Copy)]
+pub struct MadtProcessor {
+    pub acpi_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+/// I/O APIC entry from the MADT
+#[derive(Debug, Clone, Copy)]
+pub struct MadtIoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+/// Interrupt source override: ISA IRQ `source` is wired to `gsi`
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub source: u8,
+    pub gsi: u32,
+    /// MPS INTI flags (polarity in bits 0-1, trigger mode in bits 2-3)
+    pub flags: u16,
+}
+
+/// Parsed Multiple APIC Description Table
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// Dual 8259 PICs are present and must be masked when using the APIC
+    pub pcat_compat: bool,
+    pub processors: Vec<MadtProcessor>,
+    pub io_apics: Vec<MadtIoApic>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+/// Parse the MADT ("APIC" table)
+pub fn madt() -> Option<Madt> {
+    let body = table_body(find_table(b"APIC")?);
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(read_u32(body, 0) as u64),
+        pcat_compat: read_u32(body, 4) & 1 != 0,
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    let mut offset = 8;
+    while offset + 2 <= body.len() {
+        let entry_type = body[offset];
+        let length = body[offset + 1] as usize;
+        if length < 2 || offset + length > body.len() {
+            break;
+        }
+        let entry = &body[offset..offset + length];
+        match entry_type {
+            0 => madt.processors.push(MadtProcessor {
+                acpi_id: entry[2],
+                apic_id: entry[3],
+                enabled: read_u32(entry, 4) & 1 != 0,
+            }),
+            1 => madt.io_apics.push(MadtIoApic {
+                id: entry[2],
+                address: PhysAddr::new(read_u32(entry, 4) as u64),
+                gsi_base: read_u32(entry, 8),
+            }),
+            2 => madt.overrides.push(InterruptOverride {
+                source: entry[3],
+                gsi: read_u32(entry, 4),
+                flags: u16::from_le_bytes([entry[8], entry[9]]),
+            }),
+            5 => {
+                madt.local_apic_address =
+                    PhysAddr::new(u64::from_le_bytes(entry[4..12].try_into().unwrap()))
+            }
+            _ => {}
+        }
+        offset += length;
+    }
+    Some(madt)
+}
+
+/// Enhanced configuration space of a range of PCI buses, from the MCFG
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    /// Address of the configuration space of bus 0 in the segment, even
+    /// if `start_bus` is higher
+    pub base: PhysAddr,
+    /// PCI segment group
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// Parse the MCFG, empty if the firmware provides none.
+///
+/// Entries with a base address that isn't a valid physical address or an
+/// empty bus range are skipped.
+pub fn mcfg() -> Vec<McfgEntry> {
+    let Some(table) = find_table(b"MCFG") else {
+        return Vec::new();
+    };
+    // 8 reserved bytes, then 16-byte entries
+    table_body(table)
+        .get(8..)
+        .unwrap_or_default()
+        .chunks_exact(16)
+        .filter_map(|entry| {
+            let base = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+            Some(McfgEntry {
+                base: PhysAddr::try_new(base).ok()?,
+                segment: u16::from_le_bytes([entry[8], entry[9]]),
+                start_bus: entry[10],
+                end_bus: entry[11],
+            })
+        })
+        .filter(|entry| entry.start_bus <= entry.end_bus)
+        .collect()
+}
+
+/// CMOS index of the RTC century register from the FADT, if the firmware provides one
+pub fn century_register() -> Option<u8> {
+    let body = table_body(find_table(b"FACP")?);
+    // Offset 108 in the full table
+    let century = *body.get(108 - size_of::<SdtHeader>())?;
+    (century != 0).then_some(century)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// ACPI errors
+#[derive(Debug, Clone, Copy)]
+pub enum AcpiError {
+    RsdpNotFound,
+    InvalidChecksum,
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AcpiError::RsdpNotFound => write!(f, "RSDP not found"),
+            AcpiError::InvalidChecksum => write!(f, "Invalid table checksum"),
+        }
+    }
+}
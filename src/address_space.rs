@@ -0,0 +1,1040 @@
+//! User address spaces
+//!
+//! Every user program gets its own level 4 table. Entries outside the user
+//! range point at the kernel's lower level tables, so kernel mappings are
+//! shared and stay in sync; the user range is private to the address space
+//! and freed with it.
+//!
+//! The user range is described by a [`VmaTree`]. Mapping only records an
+//! area; its pages are filled in by [`handle_page_fault`] on first access,
+//! or up front by [`AddressSpace::populate`]. User pages are only ever
+//! touched through the physical memory mapping here, so an address space can
+//! be filled before it is activated.
+//!
+//! `fork` shares frames instead of copying them: private pages become
+//! read-only in both address spaces and are marked [`COW`], and the
+//! first write to one gets a private copy in [`handle_page_fault`]. Frames
+//! mapped more than once are reference counted in `memory`. Pages of shared
+//! memory regions are marked [`SHARED`] and stay shared across `fork`.
+//!
+//! Under memory pressure private anonymous pages are swapped out (see
+//! `swap`). Their entries stay behind non-present and marked [`SWAPPED`],
+//! with the swap slot in place of the frame address; the next access reads
+//! the page back in.
+
+use crate::memory::{self, GlobalFrameAllocator};
+use crate::shm::SharedMemory;
+use crate::swap;
+use crate::usermode::{is_user_range, USER_END, USER_START};
+use crate::vma::{Backing, Protection, Vma, VmaTree};
+use alloc::sync::Arc;
+use bootloader::BootInfo;
+use core::convert::Infallible;
+use core::fmt;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::{MapToError, MappedFrame, MapperFlush, TranslateResult};
+use x86_64::structures::paging::page_table::{PageTableEntry, PageTableLevel};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Level 4 slots covering the user range
+const USER_SLOTS: core::ops::Range<usize> = (USER_START >> 39) as usize..(USER_END >> 39) as usize;
+
+/// Kernel areas that grow at run time. Their level 4 entries must exist
+/// before an address space copies the kernel half, or later mappings there
+/// would be missing from it.
+const KERNEL_AREAS: [u64; 3] = [
+    crate::allocator::HEAP_START as u64,
+    memory::MMIO_START,
+    crate::thread::KERNEL_STACK_AREA,
+];
+
+/// Software page flag: read-only share of a writable page, copied on the
+/// first write
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+/// Software page flag: page of a shared memory region
+pub const SHARED: PageTableFlags = PageTableFlags::BIT_10;
+/// Software page flag of a non-present entry: the page is swapped out and
+/// the entry's address is its swap slot times the page size
+pub const SWAPPED: PageTableFlags = PageTableFlags::BIT_11;
+
+/// Flags of the page tables leading to user pages; the leaf entries decide
+/// the actual permissions
+const TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+/// Where a new mapping goes
+#[derive(Debug, Clone, Copy)]
+pub enum Placement {
+    /// Lowest free range at or above the hint
+    Anywhere(VirtAddr),
+    /// Exactly here, replacing whatever is mapped there
+    Fixed(VirtAddr),
+    /// Exactly here, failing if anything is mapped there
+    FixedNoReplace(VirtAddr),
+}
+
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    mapper: Mutex<OffsetPageTable<'static>>,
+    /// Areas of the user range; locked before the mapper
+    vmas: Mutex<VmaTree>,
+    /// CPUs the address space is loaded on
+    loaded: AtomicUsize,
+}
+
+impl AddressSpace {
+    /// Create an address space with an empty user range.
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        let frame = GlobalFrameAllocator
+            .allocate_frame()
+            .ok_or(AddressSpaceError::OutOfMemory)?;
+        let table = unsafe { &mut *table_ptr(frame) };
+        table.zero();
+
+        memory::with_mapper(|kernel| {
+            let kernel_table = kernel.level_4_table();
+            for addr in KERNEL_AREAS {
+                let entry = &mut kernel_table[VirtAddr::new(addr).p4_index()];
+                if entry.is_unused() {
+                    let frame = GlobalFrameAllocator
+                        .allocate_frame()
+                        .ok_or(AddressSpaceError::OutOfMemory)?;
+                    unsafe { (*table_ptr(frame)).zero() };
+                    entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+                }
+            }
+            for (i, entry) in kernel_table.iter().enumerate() {
+                if !USER_SLOTS.contains(&i) {
+                    table[i] = entry.clone();
+                }
+            }
+            Ok(())
+        })
+        .inspect_err(|_| unsafe { GlobalFrameAllocator.deallocate_frame(frame) })?;
+
+        let offset = memory::phys_to_virt(PhysAddr::new(0));
+        Ok(AddressSpace {
+            level_4_frame: frame,
+            mapper: Mutex::new(unsafe { OffsetPageTable::new(table, offset) }),
+            vmas: Mutex::new(VmaTree::new(USER_START..USER_END)),
+            loaded: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Whether this address space is loaded on the executing CPU
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Whether another CPU has this address space loaded and may cache its
+    /// translations
+    fn loaded_elsewhere(&self) -> bool {
+        self.loaded.load(Ordering::SeqCst) > self.is_active() as usize
+    }
+
+    /// Put the address space behind an [`Arc`] for its threads and make its
+    /// pages candidates for swapping.
+    pub fn into_shared(self) -> Arc<Self> {
+        let space = Arc::new(self);
+        swap::register(&space);
+        space
+    }
+
+    /// Run `f` with the mapper of this address space locked and interrupts
+    /// disabled.
+    pub fn with_mapper<R>(&self, f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+        without_interrupts(|| f(&mut self.mapper.lock()))
+    }
+
+    /// Run `f` with the areas of this address space locked and interrupts
+    /// disabled.
+    fn with_vmas<R>(&self, f: impl FnOnce(&mut VmaTree) -> R) -> R {
+        without_interrupts(|| f(&mut self.vmas.lock()))
+    }
+
+    /// Add an area of `size` bytes, rounded up to whole pages, and return
+    /// its start. Its pages are filled in on first access.
+    pub fn map(
+        &self,
+        placement: Placement,
+        size: u64,
+        protection: Protection,
+        backing: Backing,
+    ) -> Result<VirtAddr, AddressSpaceError> {
+        let size = page_align(size)?;
+        self.with_vmas(|vmas| {
+            let start = match placement {
+                Placement::Anywhere(hint) => vmas
+                    .find_free(size, hint)
+                    .ok_or(AddressSpaceError::NoSpace)?,
+                Placement::Fixed(addr) | Placement::FixedNoReplace(addr) => {
+                    check_range(addr, size)?;
+                    if let Placement::Fixed(_) = placement {
+                        self.unmap_areas(vmas, addr, addr + size);
+                    } else if !vmas.is_free(addr, addr + size) {
+                        return Err(AddressSpaceError::AlreadyMapped);
+                    }
+                    addr
+                }
+            };
+            vmas.insert(Vma {
+                start,
+                end: start + size,
+                protection,
+                backing,
+            })?;
+            vmas.merge(start, start + size);
+            Ok(start)
+        })
+    }
+
+    /// Map `count` zeroed pages at `start` right away.
+    ///
+    /// Only `WRITABLE` and `NO_EXECUTE` of `flags` matter; the pages are
+    /// always readable.
+    pub fn map_zeroed(
+        &self,
+        start: Page,
+        count: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        let mut protection = Protection::READ;
+        if flags.contains(PageTableFlags::WRITABLE) {
+            protection = protection | Protection::WRITE;
+        }
+        if !flags.contains(PageTableFlags::NO_EXECUTE) {
+            protection = protection | Protection::EXEC;
+        }
+        let size = count * memory::FRAME_SIZE;
+        let start = self.map(
+            Placement::FixedNoReplace(start.start_address()),
+            size,
+            protection,
+            Backing::Anonymous,
+        )?;
+        self.populate(start, size)
+    }
+
+    /// Remove everything mapped in `size` bytes at `addr`; holes are fine.
+    pub fn unmap(&self, addr: VirtAddr, size: u64) -> Result<(), AddressSpaceError> {
+        let size = page_align(size)?;
+        check_range(addr, size)?;
+        self.with_vmas(|vmas| self.unmap_areas(vmas, addr, addr + size));
+        Ok(())
+    }
+
+    /// Change the protection of `size` bytes at `addr`, which must be mapped
+    /// without holes.
+    pub fn protect(
+        &self,
+        addr: VirtAddr,
+        size: u64,
+        protection: Protection,
+    ) -> Result<(), AddressSpaceError> {
+        let size = page_align(size)?;
+        check_range(addr, size)?;
+        let end = addr + size;
+        self.with_vmas(|vmas| {
+            if !vmas.covers(addr, end) {
+                return Err(AddressSpaceError::NotMapped);
+            }
+            for vma in vmas.overlapping(addr, end) {
+                match vma.backing {
+                    Backing::Guard => return Err(AddressSpaceError::PermissionDenied),
+                    Backing::File { shared: true, .. }
+                        if protection.contains(Protection::WRITE) =>
+                    {
+                        return Err(AddressSpaceError::PermissionDenied)
+                    }
+                    _ => {}
+                }
+            }
+            for vma in vmas.isolate(addr, end) {
+                vma.protection = protection;
+            }
+            vmas.merge(addr, end);
+
+            let base = protection.user_page_flags();
+            let active = self.is_active();
+            self.with_mapper(|_| {
+                for_each_page(self.level_4_frame, addr, end, |page, entry| {
+                    let old = entry.flags();
+                    // Swapped in with the area's protection
+                    if !old.contains(PageTableFlags::PRESENT) {
+                        return Ok(());
+                    }
+                    let mut flags = base | (old & (COW | SHARED));
+                    // A private page still shared with another address
+                    // space: the next write must copy first
+                    let frame = PhysFrame::containing_address(entry.addr());
+                    if !old.contains(SHARED) && memory::is_frame_shared(frame) {
+                        flags.insert(COW);
+                    }
+                    if flags.contains(COW) {
+                        flags.remove(PageTableFlags::WRITABLE);
+                    }
+                    entry.set_flags(flags);
+                    if active {
+                        tlb::flush(page.start_address());
+                    }
+                    Ok::<(), Infallible>(())
+                })
+            })
+            .ok();
+            Ok(())
+        })
+    }
+
+    /// Protection of the area containing `addr`
+    pub fn protection(&self, addr: VirtAddr) -> Option<Protection> {
+        self.with_vmas(|vmas| vmas.find(addr).map(|vma| vma.protection))
+    }
+
+    /// Fill in or swap in every page of `size` bytes at `addr` that isn't
+    /// present. Guard pages and pages without any access are skipped.
+    pub fn populate(&self, addr: VirtAddr, size: u64) -> Result<(), AddressSpaceError> {
+        if size == 0 {
+            return Ok(());
+        }
+        if !is_user_range(addr.as_u64(), size) {
+            return Err(AddressSpaceError::InvalidAddress);
+        }
+        let first = Page::<Size4KiB>::containing_address(addr);
+        let last = Page::<Size4KiB>::containing_address(addr + (size - 1));
+        self.with_vmas(|vmas| {
+            for page in Page::range_inclusive(first, last) {
+                let vma = vmas
+                    .find(page.start_address())
+                    .ok_or(AddressSpaceError::NotMapped)?;
+                if vma.protection != Protection::NONE && !matches!(vma.backing, Backing::Guard) {
+                    self.fault_in(vma, page)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Whether user code may access `len` bytes at `addr`, writing if
+    /// `write` is set. Pages not filled in yet count as accessible.
+    pub fn check_access(&self, addr: VirtAddr, len: u64, write: bool) -> bool {
+        if len == 0 {
+            return true;
+        }
+        if !is_user_range(addr.as_u64(), len) {
+            return false;
+        }
+        let end = addr + len;
+        self.with_vmas(|vmas| {
+            vmas.covers(addr, end)
+                && vmas
+                    .overlapping(addr, end)
+                    .all(|vma| vma.protection.allows(write, false))
+        })
+    }
+
+    /// Frame and flags behind the page containing `addr`
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        self.with_mapper(|mapper| match mapper.translate(addr) {
+            TranslateResult::Mapped {
+                frame,
+                offset,
+                flags,
+            } if flags.contains(PageTableFlags::PRESENT) => {
+                Some((frame.start_address() + offset, flags))
+            }
+            _ => None,
+        })
+    }
+
+    /// Copy `bytes` to user address `addr`, ignoring page permissions.
+    ///
+    /// Every page in the range must be present.
+    pub fn write(&self, addr: VirtAddr, bytes: &[u8]) -> Result<(), AddressSpaceError> {
+        if !is_user_range(addr.as_u64(), bytes.len() as u64) {
+            return Err(AddressSpaceError::InvalidAddress);
+        }
+        let mut addr = addr;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let (phys, _) = self.translate(addr).ok_or(AddressSpaceError::NotMapped)?;
+            let chunk = bytes
+                .len()
+                .min((memory::FRAME_SIZE - addr.as_u64() % memory::FRAME_SIZE) as usize);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    bytes.as_ptr(),
+                    memory::phys_to_virt(phys).as_mut_ptr::<u8>(),
+                    chunk,
+                );
+            }
+            addr += chunk as u64;
+            bytes = &bytes[chunk..];
+        }
+        Ok(())
+    }
+
+    /// Copy of this address space for `fork`, sharing every frame.
+    ///
+    /// Private pages turn copy-on-write in both address spaces, read-only
+    /// ones too, so a later `mprotect` can't make the shared frame writable.
+    pub fn duplicate(&self) -> Result<AddressSpace, AddressSpaceError> {
+        let child = AddressSpace::new()?;
+        // Locked so the parent can't change under the walk
+        self.with_vmas(|vmas| {
+            *child.vmas.lock() = vmas.clone();
+            self.with_mapper(|_| {
+                child.with_mapper(|mapper| {
+                    let (start, end) = (VirtAddr::new(USER_START), VirtAddr::new(USER_END));
+                    for_each_page(self.level_4_frame, start, end, |page, entry| {
+                        let frame = PhysFrame::containing_address(entry.addr());
+                        // Each side reads its own copy back from the slot
+                        if let Some(slot) = swapped_slot(entry) {
+                            match unsafe { map_user(mapper, page, frame, SWAPPED) } {
+                                Ok(flush) => flush.ignore(),
+                                Err(_) => return Err(AddressSpaceError::OutOfMemory),
+                            }
+                            swap::share_slot(slot);
+                            return Ok(());
+                        }
+                        let mut flags = entry.flags();
+                        if !flags.contains(SHARED) {
+                            flags = (flags - PageTableFlags::WRITABLE) | COW;
+                            entry.set_flags(flags);
+                        }
+                        match unsafe { map_user(mapper, page, frame, flags) } {
+                            Ok(flush) => flush.ignore(),
+                            Err(_) => return Err(AddressSpaceError::OutOfMemory),
+                        }
+                        memory::share_frame(frame);
+                        Ok(())
+                    })
+                })
+            })
+        })?;
+        // Drop the writable translations of the pages just made read-only
+        if self.is_active() {
+            tlb::flush_all();
+        }
+        Ok(child)
+    }
+
+    /// Map shared memory `memory` per `placement`, readable and writable.
+    pub(crate) fn attach(
+        &self,
+        placement: Placement,
+        memory: Arc<SharedMemory>,
+    ) -> Result<VirtAddr, AddressSpaceError> {
+        let size = memory.size();
+        self.map(
+            placement,
+            size,
+            Protection::READ | Protection::WRITE,
+            Backing::Shared { memory, first: 0 },
+        )
+    }
+
+    /// Unmap the shared memory region attached at `addr`, including parts
+    /// whose protection was changed since.
+    pub(crate) fn detach(&self, addr: VirtAddr) -> Result<(), AddressSpaceError> {
+        self.with_vmas(|vmas| {
+            let memory = match vmas.find(addr) {
+                Some(Vma {
+                    start,
+                    backing: Backing::Shared { memory, first: 0 },
+                    ..
+                }) if *start == addr => memory.clone(),
+                _ => return Err(AddressSpaceError::NotMapped),
+            };
+            let mut end = addr;
+            for vma in vmas.overlapping(addr, VirtAddr::new(USER_END)) {
+                match &vma.backing {
+                    Backing::Shared { memory: other, .. }
+                        if vma.start == end && Arc::ptr_eq(other, &memory) =>
+                    {
+                        end = vma.end
+                    }
+                    _ => break,
+                }
+            }
+            self.unmap_areas(vmas, addr, end);
+            Ok(())
+        })
+    }
+
+    /// Drop the areas in `start..end` and their pages.
+    fn unmap_areas(&self, vmas: &mut VmaTree, start: VirtAddr, end: VirtAddr) {
+        // Shared memory the areas hold goes only after its pages
+        let removed = vmas.remove(start, end);
+        let active = self.is_active();
+        self.with_mapper(|_| {
+            for_each_page(self.level_4_frame, start, end, |page, entry| {
+                let frame = PhysFrame::containing_address(entry.addr());
+                let slot = swapped_slot(entry);
+                entry.set_unused();
+                if let Some(slot) = slot {
+                    swap::release_slot(slot);
+                    return Ok(());
+                }
+                if active {
+                    tlb::flush(page.start_address());
+                }
+                unsafe { memory::release_frame(frame) };
+                Ok::<(), Infallible>(())
+            })
+        })
+        .ok();
+        drop(removed);
+    }
+
+    /// Make `page` of `vma` present, reading it back from swap or filling
+    /// it in. Pages already present are left alone.
+    fn fault_in(&self, vma: &Vma, page: Page) -> Result<(), AddressSpaceError> {
+        if let Some(entry) = leaf_entry(self.level_4_frame, page.start_address()) {
+            if entry.flags().contains(PageTableFlags::PRESENT) {
+                return Ok(());
+            }
+            if let Some(slot) = swapped_slot(entry) {
+                return self.swap_in(vma, entry, slot);
+            }
+        }
+        self.fill(vma, page)
+    }
+
+    /// Read the page swapped out to `slot` back in and point `entry` at it.
+    fn swap_in(
+        &self,
+        vma: &Vma,
+        entry: &mut PageTableEntry,
+        slot: u64,
+    ) -> Result<(), AddressSpaceError> {
+        let frame = GlobalFrameAllocator
+            .allocate_frame()
+            .ok_or(AddressSpaceError::OutOfMemory)?;
+        if swap::read_page(slot, frame).is_err() {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            return Err(AddressSpaceError::Swap);
+        }
+        // Non-present entries aren't cached, so no flush
+        self.with_mapper(|_| entry.set_frame(frame, vma.protection.user_page_flags()));
+        swap::release_slot(slot);
+        Ok(())
+    }
+
+    /// Back `page` of `vma` with the frame its contents belong in.
+    fn fill(&self, vma: &Vma, page: Page) -> Result<(), AddressSpaceError> {
+        let offset = page.start_address() - vma.start;
+        let mut flags = vma.protection.user_page_flags();
+        let frame = match &vma.backing {
+            Backing::Shared { memory, first } => {
+                let frame = memory.frames()[first + (offset / memory::FRAME_SIZE) as usize];
+                memory::share_frame(frame);
+                flags |= SHARED;
+                frame
+            }
+            Backing::Anonymous | Backing::File { .. } => {
+                let frame = GlobalFrameAllocator
+                    .allocate_frame()
+                    .ok_or(AddressSpaceError::OutOfMemory)?;
+                let contents = unsafe {
+                    core::slice::from_raw_parts_mut(
+                        memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                        memory::FRAME_SIZE as usize,
+                    )
+                };
+                contents.fill(0);
+                if let Backing::File {
+                    data,
+                    offset: file_offset,
+                    ..
+                } = vma.backing
+                {
+                    let start = file_offset.saturating_add(offset).min(data.len() as u64) as usize;
+                    let bytes = &data[start..data.len().min(start + contents.len())];
+                    contents[..bytes.len()].copy_from_slice(bytes);
+                }
+                frame
+            }
+            Backing::Guard | Backing::Physical { .. } => return Err(AddressSpaceError::NotMapped),
+        };
+
+        let active = self.is_active();
+        self.with_mapper(
+            |mapper| match unsafe { map_user(mapper, page, frame, flags) } {
+                Ok(flush) => {
+                    finish(flush, active);
+                    Ok(())
+                }
+                Err(e) => {
+                    unsafe { memory::release_frame(frame) };
+                    Err(match e {
+                        MapToError::PageAlreadyMapped(_) => AddressSpaceError::AlreadyMapped,
+                        _ => AddressSpaceError::OutOfMemory,
+                    })
+                }
+            },
+        )
+    }
+
+    /// Resolve a page fault at user address `addr`, see
+    /// [`handle_page_fault`].
+    fn handle_fault(
+        &self,
+        addr: VirtAddr,
+        error_code: PageFaultErrorCode,
+    ) -> Result<(), AddressSpaceError> {
+        let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+        let exec = error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH);
+        let page = Page::containing_address(addr);
+        self.with_vmas(|vmas| {
+            let vma = vmas.find(addr).ok_or(AddressSpaceError::NotMapped)?;
+            if !vma.protection.allows(write, exec) {
+                return Err(AddressSpaceError::PermissionDenied);
+            }
+            if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+                if !write {
+                    return Err(AddressSpaceError::PermissionDenied);
+                }
+                self.break_cow(page)
+            } else {
+                // Another thread may have faulted the page in meanwhile
+                self.fault_in(vma, page)
+            }
+        })
+    }
+
+    /// Give the page at `page` a private copy of its copy-on-write frame.
+    fn break_cow(&self, page: Page) -> Result<(), AddressSpaceError> {
+        let active = self.is_active();
+        self.with_mapper(|mapper| {
+            let (frame, flags) = match mapper.translate(page.start_address()) {
+                TranslateResult::Mapped {
+                    frame: MappedFrame::Size4KiB(frame),
+                    flags,
+                    ..
+                } if flags.contains(COW) => (frame, flags),
+                _ => return Err(AddressSpaceError::PermissionDenied),
+            };
+            let flags = (flags - COW) | PageTableFlags::WRITABLE;
+
+            // The last sharer keeps the frame
+            if !memory::is_frame_shared(frame) {
+                return match unsafe { mapper.update_flags(page, flags) } {
+                    Ok(flush) => {
+                        finish(flush, active);
+                        Ok(())
+                    }
+                    Err(_) => Err(AddressSpaceError::NotMapped),
+                };
+            }
+
+            let copy = GlobalFrameAllocator
+                .allocate_frame()
+                .ok_or(AddressSpaceError::OutOfMemory)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    memory::phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                    memory::phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                    memory::FRAME_SIZE as usize,
+                );
+            }
+            let remapped = mapper.unmap(page).is_ok_and(|(_, flush)| {
+                flush.ignore();
+                match unsafe { map_user(mapper, page, copy, flags) } {
+                    Ok(flush) => {
+                        finish(flush, active);
+                        true
+                    }
+                    Err(_) => false,
+                }
+            });
+            if remapped {
+                unsafe { memory::release_frame(frame) };
+                Ok(())
+            } else {
+                unsafe { GlobalFrameAllocator.deallocate_frame(copy) };
+                Err(AddressSpaceError::NotMapped)
+            }
+        })
+    }
+
+    /// Swap out up to `want` pages of anonymous areas at or above `from`,
+    /// looking at no more than `budget` pages. Returns the number of pages
+    /// freed and where to go on next time, `None` past the last area.
+    ///
+    /// Skipped while another CPU has the address space loaded.
+    pub(crate) fn reclaim(
+        &self,
+        from: VirtAddr,
+        want: usize,
+        budget: &mut usize,
+    ) -> (usize, Option<VirtAddr>) {
+        if self.loaded_elsewhere() {
+            return (0, None);
+        }
+        self.with_vmas(|vmas| {
+            let mut freed = 0;
+            for vma in vmas.overlapping(from, VirtAddr::new(USER_END)) {
+                if !matches!(vma.backing, Backing::Anonymous) {
+                    continue;
+                }
+                let start = vma.start.max(from);
+                let stopped = self.with_mapper(|_| {
+                    for_each_page(self.level_4_frame, start, vma.end, |page, entry| {
+                        if freed == want || *budget == 0 {
+                            return Err(page.start_address());
+                        }
+                        *budget -= 1;
+                        match self.evict(page, entry) {
+                            Some(true) => freed += 1,
+                            Some(false) => {}
+                            // Try the page again next time
+                            None => return Err(page.start_address()),
+                        }
+                        Ok(())
+                    })
+                });
+                if let Err(next) = stopped {
+                    return (freed, Some(next));
+                }
+            }
+            (freed, None)
+        })
+    }
+
+    /// Swap out `page` unless it was used since the last look, which only
+    /// clears its accessed bit. Returns whether its frame was freed, `None`
+    /// if pages can't be swapped out right now.
+    fn evict(&self, page: Page, entry: &mut PageTableEntry) -> Option<bool> {
+        let flags = entry.flags();
+        let frame = PhysFrame::containing_address(entry.addr());
+        if !flags.contains(PageTableFlags::PRESENT)
+            || flags.contains(SHARED)
+            || memory::is_frame_shared(frame)
+        {
+            return Some(false);
+        }
+        if flags.contains(PageTableFlags::ACCESSED) {
+            // Not flushed: a cached translation only keeps the page in
+            // memory for another round
+            entry.set_flags(flags - PageTableFlags::ACCESSED);
+            return Some(false);
+        }
+
+        let slot = swap::allocate_slot()?;
+        entry.set_addr(swap_addr(slot), SWAPPED);
+        // Pairs with `activate`: a CPU loading the address space from now
+        // on only sees the swap entry
+        fence(Ordering::SeqCst);
+        if self.loaded_elsewhere() {
+            entry.set_addr(frame.start_address(), flags);
+            swap::release_slot(slot);
+            return None;
+        }
+        if self.is_active() {
+            tlb::flush(page.start_address());
+        }
+        if swap::write_page(slot, frame).is_err() {
+            entry.set_addr(frame.start_address(), flags);
+            swap::release_slot(slot);
+            return None;
+        }
+        unsafe { memory::release_frame(frame) };
+        Some(true)
+    }
+
+    /// Load this address space on the executing CPU.
+    ///
+    /// # Safety
+    /// The address space must stay alive while it is loaded.
+    pub(crate) unsafe fn activate(&self) {
+        let this = self as *const _ as *mut AddressSpace;
+        let previous = crate::percpu::current()
+            .address_space
+            .swap(this, Ordering::Relaxed);
+        if previous != this {
+            self.loaded.fetch_add(1, Ordering::SeqCst);
+        }
+        if !self.is_active() {
+            let (_, flags) = Cr3::read();
+            Cr3::write(self.level_4_frame, flags);
+        }
+        if previous != this {
+            unload(previous);
+        }
+    }
+}
+
+/// Run `f` on the address space loaded on the executing CPU, unless that is
+/// the kernel page table.
+pub fn with_current<R>(f: impl FnOnce(&AddressSpace) -> R) -> Option<R> {
+    let space = crate::percpu::current()
+        .address_space
+        .load(Ordering::Relaxed);
+    // Valid while loaded, see `activate`
+    unsafe { space.as_ref() }.map(f)
+}
+
+/// Try to resolve a page fault at user address `addr` in the loaded address
+/// space. Returns `true` if the faulting access can be retried.
+///
+/// Fills in pages of mapped areas on first access, reads swapped out pages
+/// back in and copies copy-on-write pages on the first write. When memory
+/// runs out, pages are swapped out to make room. Runs in the page fault
+/// handler; the area and mapper locks are only ever held with interrupts
+/// disabled and never across a user access, so they can be taken normally.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if !is_user_range(addr.as_u64(), 1) {
+        return false;
+    }
+    with_current(|space| loop {
+        match space.handle_fault(addr, error_code) {
+            Ok(()) => return true,
+            Err(AddressSpaceError::OutOfMemory) if swap::reclaim(swap::RECLAIM_BATCH) > 0 => {}
+            Err(_) => return false,
+        }
+    })
+    .unwrap_or(false)
+}
+
+/// Load the kernel page table on the executing CPU.
+///
+/// # Safety
+/// The caller must not be running on user mappings.
+pub(crate) unsafe fn activate_kernel() {
+    let kernel = memory::kernel_level_4_frame();
+    let (active, flags) = Cr3::read();
+    if active != kernel {
+        Cr3::write(kernel, flags);
+    }
+    let previous = crate::percpu::current()
+        .address_space
+        .swap(core::ptr::null_mut(), Ordering::Relaxed);
+    unload(previous);
+}
+
+/// Count `space`, just replaced on the executing CPU, as no longer loaded
+/// there.
+///
+/// # Safety
+/// `space` must be null or the address space that was loaded.
+unsafe fn unload(space: *mut AddressSpace) {
+    // Still alive, see `activate`
+    if let Some(space) = space.as_ref() {
+        space.loaded.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        debug_assert!(!self.is_active(), "dropping the active address space");
+        let table = unsafe { &mut *table_ptr(self.level_4_frame) };
+        for i in USER_SLOTS {
+            free_table(&mut table[i], PageTableLevel::Four);
+        }
+        unsafe { GlobalFrameAllocator.deallocate_frame(self.level_4_frame) };
+    }
+}
+
+/// Check that the bootloader kept its mappings out of the user range. Address
+/// spaces only share the kernel's level 4 entries outside [`USER_SLOTS`], so
+/// anything mapped there disappears as soon as a user table is loaded.
+pub fn check_boot_layout(boot_info: &BootInfo) {
+    let stack = 0u8;
+    let areas = [
+        ("physical memory", VirtAddr::new(boot_info.physical_memory_offset)),
+        ("boot info", VirtAddr::from_ptr(boot_info)),
+        ("boot stack", VirtAddr::from_ptr(&stack)),
+    ];
+    for (name, addr) in areas {
+        let slot = usize::from(addr.p4_index());
+        assert!(
+            !USER_SLOTS.contains(&slot),
+            "{} mapped in user level 4 slot {}",
+            name,
+            slot
+        );
+    }
+}
+
+/// `size` rounded up to whole pages; zero is an error
+fn page_align(size: u64) -> Result<u64, AddressSpaceError> {
+    match size.checked_next_multiple_of(memory::FRAME_SIZE) {
+        Some(size) if size > 0 => Ok(size),
+        _ => Err(AddressSpaceError::InvalidAddress),
+    }
+}
+
+/// Check that `size` bytes at page-aligned `addr` lie in the user range.
+fn check_range(addr: VirtAddr, size: u64) -> Result<(), AddressSpaceError> {
+    if addr.is_aligned(memory::FRAME_SIZE) && is_user_range(addr.as_u64(), size) {
+        Ok(())
+    } else {
+        Err(AddressSpaceError::InvalidAddress)
+    }
+}
+
+/// Free the table `entry` points to, everything mapped through it and the
+/// frames behind its pages. `level` is the level of the table holding `entry`.
+fn free_table(entry: &mut PageTableEntry, level: PageTableLevel) {
+    if let Some(slot) = swapped_slot(entry) {
+        swap::release_slot(slot);
+        entry.set_unused();
+        return;
+    }
+    if !entry.flags().contains(PageTableFlags::PRESENT) {
+        return;
+    }
+    let frame = PhysFrame::containing_address(entry.addr());
+    if let Some(next) = level.next_lower_level() {
+        // User mappings are 4 KiB only, anything huge is not ours to free
+        if !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let table = unsafe { &mut *table_ptr(frame) };
+            for child in table.iter_mut() {
+                free_table(child, next);
+            }
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+    } else {
+        unsafe { memory::release_frame(frame) };
+    }
+    entry.set_unused();
+}
+
+/// Call `f` with the entry of every 4 KiB page present or swapped out in
+/// `start..end` of the level 4 table in `level_4_frame`, stopping at the
+/// first error. Missing tables are skipped whole.
+fn for_each_page<E>(
+    level_4_frame: PhysFrame,
+    start: VirtAddr,
+    end: VirtAddr,
+    mut f: impl FnMut(Page, &mut PageTableEntry) -> Result<(), E>,
+) -> Result<(), E> {
+    let level_4 = unsafe { &*table_ptr(level_4_frame) };
+    let mut addr = start.align_down(memory::FRAME_SIZE).as_u64();
+    while addr < end.as_u64() {
+        let virt = VirtAddr::new(addr);
+        let Some(level_3) = next_table(&level_4[virt.p4_index()]) else {
+            addr = next_boundary(addr, 39);
+            continue;
+        };
+        let Some(level_2) = next_table(&level_3[virt.p3_index()]) else {
+            addr = next_boundary(addr, 30);
+            continue;
+        };
+        let Some(level_1) = next_table(&level_2[virt.p2_index()]) else {
+            addr = next_boundary(addr, 21);
+            continue;
+        };
+        let entry = &mut level_1[virt.p1_index()];
+        if !entry.is_unused() {
+            f(Page::containing_address(virt), entry)?;
+        }
+        addr += memory::FRAME_SIZE;
+    }
+    Ok(())
+}
+
+/// Entry of the 4 KiB page containing `addr` in the level 4 table in
+/// `level_4_frame`, if its page table exists
+fn leaf_entry(level_4_frame: PhysFrame, addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+    let level_4 = unsafe { &*table_ptr(level_4_frame) };
+    let level_3 = next_table(&level_4[addr.p4_index()])?;
+    let level_2 = next_table(&level_3[addr.p3_index()])?;
+    let level_1 = next_table(&level_2[addr.p2_index()])?;
+    Some(&mut level_1[addr.p1_index()])
+}
+
+/// Swap slot of a swapped out page's entry
+fn swapped_slot(entry: &PageTableEntry) -> Option<u64> {
+    let flags = entry.flags();
+    (flags.contains(SWAPPED) && !flags.contains(PageTableFlags::PRESENT))
+        .then(|| entry.addr().as_u64() / memory::FRAME_SIZE)
+}
+
+/// Address stored in the entry of a page swapped out to `slot`
+fn swap_addr(slot: u64) -> PhysAddr {
+    PhysAddr::new(slot * memory::FRAME_SIZE)
+}
+
+/// First address past `addr` aligned to `1 << shift`
+fn next_boundary(addr: u64, shift: u32) -> u64 {
+    ((addr >> shift) + 1) << shift
+}
+
+/// Table an entry points to, unless it is empty or maps a huge page
+fn next_table(entry: &PageTableEntry) -> Option<&'static mut PageTable> {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+        return None;
+    }
+    Some(unsafe { &mut *table_ptr(PhysFrame::containing_address(entry.addr())) })
+}
+
+/// Map a user page, creating the tables above it writable and user
+/// accessible so the leaf flags alone decide the access.
+unsafe fn map_user(
+    mapper: &mut OffsetPageTable<'static>,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<MapperFlush<Size4KiB>, MapToError<Size4KiB>> {
+    mapper.map_to_with_table_flags(page, frame, flags, TABLE_FLAGS, &mut GlobalFrameAllocator)
+}
+
+/// Flush a changed translation if the address space is loaded; otherwise
+/// no TLB can hold it.
+fn finish(flush: MapperFlush<Size4KiB>, active: bool) {
+    if active {
+        flush.flush();
+    } else {
+        flush.ignore();
+    }
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// Address space errors
+#[derive(Debug, Clone, Copy)]
+pub enum AddressSpaceError {
+    /// Outside the managed range, misaligned or empty
+    InvalidAddress,
+    AlreadyMapped,
+    NotMapped,
+    OutOfMemory,
+    /// No free range large enough
+    NoSpace,
+    /// Protection the area can't have
+    PermissionDenied,
+    /// Reading or writing the swap area failed
+    Swap,
+}
+
+impl fmt::Display for AddressSpaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressSpaceError::InvalidAddress => write!(f, "Invalid address range"),
+            AddressSpaceError::AlreadyMapped => write!(f, "Page already mapped"),
+            AddressSpaceError::NotMapped => write!(f, "Page not mapped"),
+            AddressSpaceError::OutOfMemory => write!(f, "Out of memory for page tables"),
+            AddressSpaceError::NoSpace => write!(f, "No free address range"),
+            AddressSpaceError::PermissionDenied => write!(f, "Protection not allowed"),
+            AddressSpaceError::Swap => write!(f, "Swap I/O error"),
+        }
+    }
+}
@@ -1,46 +1,265 @@
+use crate::address_space::AddressSpaceError;
+use crate::memory;
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr::null_mut;
-use linked_list_allocator::LockedHeap;
-use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
-    VirtAddr,
-};
+use core::ptr::{null_mut, NonNull};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// Size handed to the allocator at boot
+pub const HEAP_INITIAL_SIZE: usize = 1024 * 1024; // 1 MiB
+/// Upper bound the heap may grow to
+pub const HEAP_MAX_SIZE: usize = 256 * 1024 * 1024; // 256 MiB
+/// Minimum amount the heap is extended by each time it grows
+const HEAP_GROW_STEP: usize = 64 * 1024;
+
+/// Block sizes served by the slab caches
+pub const SLAB_SIZES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+/// Memory carved into blocks when a slab cache runs empty
+const SLAB_CHUNK_SIZE: usize = 4096;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
-
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
-    }
+static ALLOCATOR: Locked<KernelHeap> = Locked::new(KernelHeap::new());
+
+/// Register the whole heap range as a demand region and hand the initial
+/// part to the allocator. Heap pages are mapped when first touched.
+pub fn init_heap() -> Result<(), AddressSpaceError> {
+    memory::register_demand_region(
+        VirtAddr::new(HEAP_START as u64),
+        HEAP_MAX_SIZE as u64,
+        PageTableFlags::WRITABLE,
+    )?;
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR
+            .lock()
+            .fallback
+            .init(HEAP_START, HEAP_INITIAL_SIZE);
     }
 
     Ok(())
 }
 
+/// Heap usage counters
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes the heap currently spans, mapped or not
+    pub heap_size: usize,
+    /// Bytes handed out by the linked-list allocator (includes slab chunks)
+    pub fallback_used: usize,
+    /// Blocks in use per slab cache, indexed like [`SLAB_SIZES`]
+    pub slab_used: [usize; SLAB_SIZES.len()],
+    /// Free blocks kept per slab cache
+    pub slab_free: [usize; SLAB_SIZES.len()],
+    pub allocations: u64,
+    pub deallocations: u64,
+    /// Number of times the heap was extended
+    pub grow_count: u64,
+}
+
+/// Current heap statistics
+pub fn stats() -> HeapStats {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let heap = ALLOCATOR.lock();
+        let mut stats = heap.stats;
+        stats.heap_size = heap.fallback.size();
+        stats.fallback_used = heap.fallback.used();
+        for (i, slab) in heap.slabs.iter().enumerate() {
+            stats.slab_used[i] = slab.total_blocks - slab.free_blocks;
+            stats.slab_free[i] = slab.free_blocks;
+        }
+        stats
+    })
+}
+
+/// Wrapper providing interior mutability for `GlobalAlloc` implementations
+pub struct Locked<A> {
+    inner: Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<A> {
+        self.inner.lock()
+    }
+}
+
+struct FreeBlock {
+    next: Option<NonNull<FreeBlock>>,
+}
+
+/// Free list of equally sized blocks
+struct SlabCache {
+    block_size: usize,
+    free_list: Option<NonNull<FreeBlock>>,
+    total_blocks: usize,
+    free_blocks: usize,
+}
+
+impl SlabCache {
+    const fn new(block_size: usize) -> Self {
+        SlabCache {
+            block_size,
+            free_list: None,
+            total_blocks: 0,
+            free_blocks: 0,
+        }
+    }
+
+    fn pop(&mut self) -> Option<NonNull<u8>> {
+        let block = self.free_list?;
+        self.free_list = unsafe { block.as_ref().next };
+        self.free_blocks -= 1;
+        Some(block.cast())
+    }
+
+    /// # Safety
+    /// `ptr` must point to an unused block of `block_size` bytes.
+    unsafe fn push(&mut self, ptr: NonNull<u8>) {
+        let block = ptr.cast::<FreeBlock>();
+        block.as_ptr().write(FreeBlock {
+            next: self.free_list,
+        });
+        self.free_list = Some(block);
+        self.free_blocks += 1;
+    }
+
+    /// Split a freshly allocated chunk into blocks.
+    ///
+    /// # Safety
+    /// `chunk` must be an unused, `SLAB_CHUNK_SIZE`-aligned region of `SLAB_CHUNK_SIZE` bytes.
+    unsafe fn refill(&mut self, chunk: NonNull<u8>) {
+        let count = SLAB_CHUNK_SIZE / self.block_size;
+        for i in (0..count).rev() {
+            self.push(NonNull::new_unchecked(
+                chunk.as_ptr().add(i * self.block_size),
+            ));
+        }
+        self.total_blocks += count;
+    }
+}
+
+/// Kernel heap: slab caches for small objects in front of a growable
+/// linked-list heap.
+pub struct KernelHeap {
+    slabs: [SlabCache; SLAB_SIZES.len()],
+    fallback: Heap,
+    stats: HeapStats,
+}
+
+// Free list pointers only ever point into the heap, which is owned by the allocator.
+unsafe impl Send for KernelHeap {}
+
+impl KernelHeap {
+    pub const fn new() -> Self {
+        KernelHeap {
+            slabs: [
+                SlabCache::new(SLAB_SIZES[0]),
+                SlabCache::new(SLAB_SIZES[1]),
+                SlabCache::new(SLAB_SIZES[2]),
+                SlabCache::new(SLAB_SIZES[3]),
+                SlabCache::new(SLAB_SIZES[4]),
+                SlabCache::new(SLAB_SIZES[5]),
+                SlabCache::new(SLAB_SIZES[6]),
+                SlabCache::new(SLAB_SIZES[7]),
+                SlabCache::new(SLAB_SIZES[8]),
+            ],
+            fallback: Heap::empty(),
+            stats: HeapStats {
+                heap_size: 0,
+                fallback_used: 0,
+                slab_used: [0; SLAB_SIZES.len()],
+                slab_free: [0; SLAB_SIZES.len()],
+                allocations: 0,
+                deallocations: 0,
+                grow_count: 0,
+            },
+        }
+    }
+
+    /// Slab cache serving `layout`, if any. Blocks are naturally aligned to
+    /// their size, so the alignment only has to fit as well.
+    fn slab_index(layout: &Layout) -> Option<usize> {
+        let required = layout.size().max(layout.align());
+        SLAB_SIZES.iter().position(|&size| size >= required)
+    }
+
+    fn alloc_slab(&mut self, index: usize) -> Option<NonNull<u8>> {
+        if let Some(block) = self.slabs[index].pop() {
+            return Some(block);
+        }
+        let chunk_layout = Layout::from_size_align(SLAB_CHUNK_SIZE, SLAB_CHUNK_SIZE).ok()?;
+        let chunk = self.alloc_fallback(chunk_layout)?;
+        unsafe { self.slabs[index].refill(chunk) };
+        self.slabs[index].pop()
+    }
+
+    fn alloc_fallback(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        if let Ok(ptr) = self.fallback.allocate_first_fit(layout) {
+            return Some(ptr);
+        }
+        self.grow(layout.size() + layout.align()).ok()?;
+        self.fallback.allocate_first_fit(layout).ok()
+    }
+
+    /// Extend the heap by at least `min_bytes`. The new pages lie in the
+    /// heap's demand region and get mapped as they are touched.
+    fn grow(&mut self, min_bytes: usize) -> Result<(), AddressSpaceError> {
+        let bytes = (min_bytes.max(HEAP_GROW_STEP) + 4095) & !4095;
+        let size = self.fallback.size();
+        if size + bytes > HEAP_MAX_SIZE {
+            return Err(AddressSpaceError::NoSpace);
+        }
+        // A fault on a heap page that can't get a frame is fatal, so running
+        // out of memory has to fail the allocation here instead
+        let free = memory::frame_stats().map_or(0, |stats| stats.free_bytes());
+        if free < bytes as u64 {
+            return Err(AddressSpaceError::OutOfMemory);
+        }
+
+        unsafe { self.fallback.extend(bytes) };
+        self.stats.grow_count += 1;
+        Ok(())
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<KernelHeap> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut heap = self.lock();
+            let ptr = match KernelHeap::slab_index(&layout) {
+                Some(index) => heap.alloc_slab(index),
+                None => heap.alloc_fallback(layout),
+            };
+            match ptr {
+                Some(ptr) => {
+                    heap.stats.allocations += 1;
+                    ptr.as_ptr()
+                }
+                None => null_mut(),
+            }
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut heap = self.lock();
+            let ptr = NonNull::new_unchecked(ptr);
+            match KernelHeap::slab_index(&layout) {
+                Some(index) => heap.slabs[index].push(ptr),
+                None => heap.fallback.deallocate(ptr, layout),
+            }
+            heap.stats.deallocations += 1;
+        })
+    }
+}
+
 struct Dummy;
 
 unsafe impl GlobalAlloc for Dummy {
@@ -0,0 +1,370 @@
+//! Local APIC and I/O APIC driver
+//!
+//! Replaces the legacy 8259 PIC once the MADT has been found. ISA IRQs are
+//! routed through the I/O APIC honoring the MADT interrupt source overrides,
+//! PCI interrupts are routed level triggered, active low.
+
+use crate::acpi::{self, InterruptOverride};
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::registers::model_specific::Msr;
+use x86_64::VirtAddr;
+
+// Local APIC registers
+const LAPIC_ID: u32 = 0x020;
+const LAPIC_TPR: u32 = 0x080;
+const LAPIC_EOI: u32 = 0x0B0;
+const LAPIC_SVR: u32 = 0x0F0;
+const LAPIC_ICR_LOW: u32 = 0x300;
+const LAPIC_ICR_HIGH: u32 = 0x310;
+const LAPIC_LVT_LINT0: u32 = 0x350;
+const LAPIC_LVT_LINT1: u32 = 0x360;
+const LAPIC_LVT_ERROR: u32 = 0x370;
+
+// Interrupt command register fields
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_SEND_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+/// Message address of interrupts for the local APIC, destination ID in
+/// bits 12-19
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// Vector the local APIC delivers spurious interrupts on
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+/// Vector for local APIC internal errors
+pub const ERROR_VECTOR: u8 = 0xFE;
+
+// I/O APIC registers
+const IOAPIC_REGSEL: u64 = 0x00;
+const IOAPIC_WIN: u64 = 0x10;
+const IOAPIC_VER: u32 = 0x01;
+const IOAPIC_REDTBL: u32 = 0x10;
+
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+
+/// Virtual address of the local APIC registers, 0 while the PIC is in use
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Interrupt pin polarity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Interrupt trigger mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// A single I/O APIC
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, reg: u32) -> u32 {
+        write_volatile((self.base + IOAPIC_REGSEL).as_mut_ptr::<u32>(), reg);
+        read_volatile((self.base + IOAPIC_WIN).as_ptr::<u32>())
+    }
+
+    unsafe fn write(&self, reg: u32, value: u32) {
+        write_volatile((self.base + IOAPIC_REGSEL).as_mut_ptr::<u32>(), reg);
+        write_volatile((self.base + IOAPIC_WIN).as_mut_ptr::<u32>(), value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+
+    fn write_entry(&self, gsi: u32, entry: u64) {
+        let reg = IOAPIC_REDTBL + (gsi - self.gsi_base) * 2;
+        unsafe {
+            // Write the high half first so the entry never fires half-programmed
+            self.write(reg + 1, (entry >> 32) as u32);
+            self.write(reg, entry as u32);
+        }
+    }
+
+    fn read_entry(&self, gsi: u32) -> u64 {
+        let reg = IOAPIC_REDTBL + (gsi - self.gsi_base) * 2;
+        unsafe { (self.read(reg) as u64) | ((self.read(reg + 1) as u64) << 32) }
+    }
+}
+
+struct IoApicState {
+    io_apics: Vec<IoApic>,
+    overrides: Vec<InterruptOverride>,
+}
+
+lazy_static! {
+    static ref IO_APICS: Mutex<IoApicState> = Mutex::new(IoApicState {
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    });
+}
+
+/// Whether interrupts are delivered through the APIC
+pub fn is_enabled() -> bool {
+    LAPIC_BASE.load(Ordering::Relaxed) != 0
+}
+
+unsafe fn lapic_read(reg: u32) -> u32 {
+    read_volatile((LAPIC_BASE.load(Ordering::Relaxed) + reg as u64) as *const u32)
+}
+
+unsafe fn lapic_write(reg: u32, value: u32) {
+    write_volatile(
+        (LAPIC_BASE.load(Ordering::Relaxed) + reg as u64) as *mut u32,
+        value,
+    );
+}
+
+/// APIC ID of the current processor
+pub fn lapic_id() -> u8 {
+    (unsafe { lapic_read(LAPIC_ID) } >> 24) as u8
+}
+
+/// Signal end of interrupt to the local APIC
+pub fn eoi() {
+    unsafe { lapic_write(LAPIC_EOI, 0) };
+}
+
+/// Discover the APICs from the MADT, mask the 8259 PIC and enable the local APIC.
+///
+/// All I/O APIC inputs start out masked; use [`route_isa_irq`] and
+/// [`route_pci_irq`] to connect them to vectors.
+pub fn init() -> Result<(), ApicError> {
+    let has_apic = raw_cpuid::CpuId::new()
+        .get_feature_info()
+        .map_or(false, |info| info.has_apic());
+    if !has_apic {
+        return Err(ApicError::NotSupported);
+    }
+    let madt = acpi::madt().ok_or(ApicError::NoMadt)?;
+    if madt.io_apics.is_empty() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    let mut io_apics = Vec::new();
+    for info in &madt.io_apics {
+        let base = crate::memory::map_mmio(info.address, 0x20).map_err(|_| ApicError::MapFailed)?;
+        let mut io_apic = IoApic {
+            base,
+            gsi_base: info.gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = ((unsafe { io_apic.read(IOAPIC_VER) } >> 16) & 0xFF) + 1;
+        for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+            io_apic.write_entry(gsi, REDIRECTION_MASKED);
+        }
+        io_apics.push(io_apic);
+    }
+
+    let lapic = crate::memory::map_mmio(madt.local_apic_address, 0x400)
+        .map_err(|_| ApicError::MapFailed)?;
+
+    // Mask every line on the legacy PICs; they stay remapped above the
+    // exception vectors so a stray interrupt can't look like a fault.
+    unsafe { crate::interrupts::PICS.lock().disable() };
+
+    LAPIC_BASE.store(lapic.as_u64(), Ordering::Relaxed);
+    enable_local();
+
+    let mut state = IO_APICS.lock();
+    state.io_apics = io_apics;
+    state.overrides = madt.overrides;
+    Ok(())
+}
+
+/// Software-enable the local APIC of the executing processor.
+fn enable_local() {
+    unsafe {
+        let mut base_msr = Msr::new(IA32_APIC_BASE);
+        let value = base_msr.read();
+        base_msr.write(value | APIC_BASE_ENABLE);
+
+        lapic_write(LAPIC_TPR, 0);
+        lapic_write(LAPIC_LVT_LINT0, 1 << 16);
+        lapic_write(LAPIC_LVT_LINT1, 1 << 16);
+        lapic_write(LAPIC_LVT_ERROR, ERROR_VECTOR as u32);
+        lapic_write(LAPIC_SVR, 0x100 | SPURIOUS_VECTOR as u32);
+    }
+}
+
+/// Enable the local APIC of an application processor.
+///
+/// Every local APIC sits at the same address, so the mapping made by
+/// [`init`] on the bootstrap processor is reused.
+pub fn init_ap() -> Result<(), ApicError> {
+    if !is_enabled() {
+        return Err(ApicError::NotInitialized);
+    }
+    enable_local();
+    Ok(())
+}
+
+/// Write the interrupt command register and wait until the local APIC has
+/// accepted the IPI.
+fn send_command(apic_id: u8, command: u32) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        while lapic_read(LAPIC_ICR_LOW) & ICR_SEND_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+        lapic_write(LAPIC_ICR_HIGH, (apic_id as u32) << 24);
+        // Writing the low half sends the IPI
+        lapic_write(LAPIC_ICR_LOW, command);
+        while lapic_read(LAPIC_ICR_LOW) & ICR_SEND_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// Send a fixed interrupt on `vector` to the processor with `apic_id`.
+pub fn send_ipi(apic_id: u8, vector: u8) {
+    send_command(apic_id, vector as u32);
+}
+
+/// Send a fixed interrupt on `vector` to every processor except this one.
+pub fn broadcast_ipi(vector: u8) {
+    send_command(0, ICR_ALL_EXCLUDING_SELF | vector as u32);
+}
+
+/// Reset the processor with `apic_id` into the wait-for-SIPI state.
+pub fn send_init(apic_id: u8) {
+    send_command(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Start the processor with `apic_id` in real mode at physical address
+/// `page << 12`.
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_command(apic_id, ICR_DELIVERY_STARTUP | page as u32);
+}
+
+/// Address and data of a message signalled interrupt on `vector` for the
+/// current CPU, edge triggered with fixed delivery.
+pub fn msi_message(vector: u8) -> Result<(u64, u32), ApicError> {
+    if !is_enabled() {
+        return Err(ApicError::NotInitialized);
+    }
+    Ok((MSI_ADDRESS_BASE | (lapic_id() as u64) << 12, vector as u32))
+}
+
+/// Route ISA IRQ `irq` to `vector` on the current CPU.
+///
+/// Applies the MADT interrupt source override for the IRQ, if any.
+/// ISA interrupts default to edge triggered, active high.
+pub fn route_isa_irq(irq: u8, vector: u8) -> Result<(), ApicError> {
+    let (gsi, polarity, trigger) = isa_irq_gsi(irq);
+    route_gsi(gsi, vector, polarity, trigger)
+}
+
+/// Route a PCI interrupt arriving on `gsi` to `vector` (level triggered, active low).
+pub fn route_pci_irq(gsi: u32, vector: u8) -> Result<(), ApicError> {
+    route_gsi(gsi, vector, Polarity::ActiveLow, TriggerMode::Level)
+}
+
+/// Global system interrupt, polarity and trigger mode for an ISA IRQ
+pub fn isa_irq_gsi(irq: u8) -> (u32, Polarity, TriggerMode) {
+    let state = IO_APICS.lock();
+    match state.overrides.iter().find(|o| o.source == irq) {
+        Some(o) => {
+            let polarity = match o.flags & 0b11 {
+                0b11 => Polarity::ActiveLow,
+                _ => Polarity::ActiveHigh,
+            };
+            let trigger = match (o.flags >> 2) & 0b11 {
+                0b11 => TriggerMode::Level,
+                _ => TriggerMode::Edge,
+            };
+            (o.gsi, polarity, trigger)
+        }
+        None => (irq as u32, Polarity::ActiveHigh, TriggerMode::Edge),
+    }
+}
+
+/// Program the redirection entry for `gsi` and unmask it.
+pub fn route_gsi(
+    gsi: u32,
+    vector: u8,
+    polarity: Polarity,
+    trigger: TriggerMode,
+) -> Result<(), ApicError> {
+    if !is_enabled() {
+        return Err(ApicError::NotInitialized);
+    }
+    let mut entry = vector as u64 | ((lapic_id() as u64) << 56);
+    if polarity == Polarity::ActiveLow {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if trigger == TriggerMode::Level {
+        entry |= REDIRECTION_LEVEL;
+    }
+
+    let state = IO_APICS.lock();
+    let io_apic = state
+        .io_apics
+        .iter()
+        .find(|a| a.handles(gsi))
+        .ok_or(ApicError::InvalidGsi)?;
+    io_apic.write_entry(gsi, entry);
+    Ok(())
+}
+
+/// Mask or unmask `gsi` without touching the rest of its redirection entry.
+pub fn set_gsi_masked(gsi: u32, masked: bool) -> Result<(), ApicError> {
+    let state = IO_APICS.lock();
+    let io_apic = state
+        .io_apics
+        .iter()
+        .find(|a| a.handles(gsi))
+        .ok_or(ApicError::InvalidGsi)?;
+    let entry = io_apic.read_entry(gsi);
+    let entry = if masked {
+        entry | REDIRECTION_MASKED
+    } else {
+        entry & !REDIRECTION_MASKED
+    };
+    io_apic.write_entry(gsi, entry);
+    Ok(())
+}
+
+/// APIC errors
+#[derive(Debug, Clone, Copy)]
+pub enum ApicError {
+    NotSupported,
+    NoMadt,
+    NoIoApic,
+    MapFailed,
+    NotInitialized,
+    InvalidGsi,
+}
+
+impl fmt::Display for ApicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApicError::NotSupported => write!(f, "CPU has no local APIC"),
+            ApicError::NoMadt => write!(f, "MADT not found"),
+            ApicError::NoIoApic => write!(f, "No I/O APIC described"),
+            ApicError::MapFailed => write!(f, "Failed to map APIC registers"),
+            ApicError::NotInitialized => write!(f, "APIC not initialized"),
+            ApicError::InvalidGsi => write!(f, "No I/O APIC handles this GSI"),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Block devices
+//!
+//! Storage drivers implement [`BlockDevice`] so that the rest of the kernel
+//! can read and write fixed-size blocks without knowing the controller.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Partition type of Linux swap partitions in the MBR
+pub const MBR_LINUX_SWAP: u8 = 0x82;
+
+/// Device storing data in fixed-size blocks
+pub trait BlockDevice: Send {
+    /// Size of a block in bytes
+    fn block_size(&self) -> usize;
+
+    /// Read block `block` into `buffer`, which holds at least one block.
+    fn read_block(&mut self, block: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write the first block of `buffer` to block `block`.
+    fn write_block(&mut self, block: u64, buffer: &[u8]) -> Result<(), BlockError>;
+}
+
+/// Primary partition from a master boot record
+#[derive(Debug, Clone, Copy)]
+pub struct Partition {
+    pub kind: u8,
+    /// First block
+    pub start: u64,
+    /// Length in blocks
+    pub blocks: u64,
+}
+
+/// Primary partitions listed in the MBR of `device`, empty if it has none.
+pub fn mbr_partitions(device: &mut dyn BlockDevice) -> Result<Vec<Partition>, BlockError> {
+    let mut sector = vec![0; device.block_size().max(512)];
+    device.read_block(0, &mut sector)?;
+    if sector[510..512] != [0x55, 0xAA] {
+        return Ok(Vec::new());
+    }
+    let field = |entry: &[u8], offset: usize| {
+        u32::from_le_bytes(entry[offset..offset + 4].try_into().unwrap()) as u64
+    };
+    Ok(sector[446..510]
+        .chunks_exact(16)
+        .filter(|entry| entry[4] != 0)
+        .map(|entry| Partition {
+            kind: entry[4],
+            start: field(entry, 8),
+            blocks: field(entry, 12),
+        })
+        .collect())
+}
+
+/// Block device errors
+#[derive(Debug, Clone, Copy)]
+pub enum BlockError {
+    /// Block past the end of the device
+    OutOfRange,
+    BufferTooSmall,
+    /// The device reported an error or didn't answer
+    Io,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::OutOfRange => write!(f, "Block out of range"),
+            BlockError::BufferTooSmall => write!(f, "Buffer smaller than a block"),
+            BlockError::Io => write!(f, "Block device I/O error"),
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! DMA buffers
+//!
+//! Devices address memory physically, so a buffer they access has to be
+//! physically contiguous and, for devices with 32-bit address registers,
+//! lie below 4 GiB. A [`DmaBuffer`] is such a run of zeroed frames together
+//! with its physical address. x86 keeps DMA coherent with the caches, so the
+//! CPU uses the ordinary cached physical memory mapping.
+
+use crate::memory::{self, GlobalFrameAllocator, FRAME_SIZE};
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use x86_64::structures::paging::frame::PhysFrameRange;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Limit for devices that take 32-bit addresses
+pub const DMA32_LIMIT: PhysAddr = PhysAddr::new(1 << 32);
+/// Limit for devices that take full 64-bit addresses
+pub const DMA64_LIMIT: PhysAddr = PhysAddr::new(0x000F_FFFF_FFFF_F000);
+
+/// Physically contiguous, zeroed memory for a device
+pub struct DmaBuffer {
+    phys: PhysAddr,
+    virt: VirtAddr,
+    size: usize,
+    /// Frames freed with the buffer, `None` for memory it doesn't own
+    frames: Option<PhysFrameRange>,
+}
+
+impl DmaBuffer {
+    /// Allocate `size` bytes ending below `limit`, aligned to `align` bytes
+    /// (a power of two). Buffers are always at least page aligned.
+    pub fn new(size: usize, align: usize, limit: PhysAddr) -> Result<Self, DmaError> {
+        if size == 0 {
+            return Err(DmaError::InvalidSize);
+        }
+        if !align.is_power_of_two() {
+            return Err(DmaError::InvalidAlignment);
+        }
+        let count = (size as u64).div_ceil(FRAME_SIZE) as usize;
+        let align_frames = (align as u64).div_ceil(FRAME_SIZE) as usize;
+        let frames = GlobalFrameAllocator
+            .allocate_contiguous_below(count, align_frames, limit)
+            .ok_or(DmaError::OutOfMemory)?;
+
+        let phys = frames.start.start_address();
+        let buffer = DmaBuffer {
+            phys,
+            virt: memory::phys_to_virt(phys),
+            size,
+            frames: Some(frames),
+        };
+        unsafe {
+            buffer
+                .ptr::<u8>()
+                .write_bytes(0, count * FRAME_SIZE as usize)
+        };
+        Ok(buffer)
+    }
+
+    /// Wrap `size` bytes at `virt` that the caller set aside for a device,
+    /// which finds them at `phys`. The memory isn't freed with the buffer.
+    ///
+    /// # Safety
+    /// The memory must stay valid, and untouched by anything but the
+    /// buffer's user and the device, for the life of the buffer.
+    pub unsafe fn from_raw(virt: VirtAddr, phys: PhysAddr, size: usize) -> Self {
+        DmaBuffer {
+            phys,
+            virt,
+            size,
+            frames: None,
+        }
+    }
+
+    /// Physical address of the first byte, to hand to the device
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Start of the buffer as a pointer to `T`, e.g. for descriptor rings
+    pub fn ptr<T>(&self) -> *mut T {
+        self.virt.as_mut_ptr()
+    }
+}
+
+impl Deref for DmaBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr(), self.size) }
+    }
+}
+
+impl DerefMut for DmaBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr(), self.size) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        // The driver has stopped the device from using the buffer
+        if let Some(frames) = self.frames {
+            unsafe { GlobalFrameAllocator.deallocate_contiguous(frames) };
+        }
+    }
+}
+
+/// DMA allocation errors
+#[derive(Debug, Clone, Copy)]
+pub enum DmaError {
+    InvalidSize,
+    InvalidAlignment,
+    OutOfMemory,
+}
+
+impl fmt::Display for DmaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DmaError::InvalidSize => write!(f, "Invalid DMA buffer size"),
+            DmaError::InvalidAlignment => write!(f, "Invalid DMA buffer alignment"),
+            DmaError::OutOfMemory => write!(f, "Out of memory for DMA buffer"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_dma_buffer() {
+        let size = 3 * FRAME_SIZE as usize + 1;
+        let mut buffer = DmaBuffer::new(size, 0x4000, DMA32_LIMIT).unwrap();
+        let phys = buffer.phys_addr().as_u64();
+        assert_eq!(phys % 0x4000, 0);
+        assert!(phys + size as u64 <= DMA32_LIMIT.as_u64());
+        assert_eq!(buffer.len(), size);
+        assert!(buffer.iter().all(|&byte| byte == 0));
+
+        buffer[size - 1] = 0xAB;
+        let last = unsafe { buffer.ptr::<u8>().add(size - 1).read() };
+        assert_eq!(last, 0xAB);
+    }
+
+    #[test_case]
+    fn test_dma_buffer_freed() {
+        let free = memory::frame_stats().unwrap().free_bytes();
+        let buffer = DmaBuffer::new(8 * FRAME_SIZE as usize, 1, DMA32_LIMIT).unwrap();
+        assert!(memory::frame_stats().unwrap().free_bytes() < free);
+        drop(buffer);
+        assert_eq!(memory::frame_stats().unwrap().free_bytes(), free);
+    }
+}
@@ -1,6 +1,20 @@
 #[path = "../../drivers/pci.rs"]
 pub mod pci;
 
+#[path = "../../drivers/msi.rs"]
+pub mod msi;
+
+#[path = "../../drivers/rtc.rs"]
+pub mod rtc;
+
+pub mod net {
+    #[path = "../../../drivers/net/e1000.rs"]
+    pub mod e1000;
+
+    #[path = "../../../drivers/net/rtl8139.rs"]
+    pub mod rtl8139;
+}
+
 pub mod sound {
     #[path = "../../../drivers/sound/ac97.rs"]
     pub mod ac97;
@@ -8,3 +22,39 @@ pub mod sound {
     #[path = "../../../drivers/sound/hda.rs"]
     pub mod hda;
 }
+
+pub mod storage {
+    #[path = "../../../drivers/storage/ahci.rs"]
+    pub mod ahci;
+
+    #[path = "../../../drivers/storage/ata.rs"]
+    pub mod ata;
+}
+
+#[path = "../../drivers/usb/mod.rs"]
+pub mod usb;
+
+use pci::PciDriver;
+
+/// Built-in PCI drivers, in the order they get to probe devices
+static PCI_DRIVERS: [&dyn PciDriver; 10] = [
+    &net::e1000::E1000PciDriver,
+    &net::rtl8139::Rtl8139PciDriver,
+    &sound::ac97::Ac97PciDriver,
+    &sound::hda::HdaPciDriver,
+    &storage::ahci::AhciPciDriver,
+    &storage::ata::AtaPciDriver,
+    &usb::uhci::UhciPciDriver,
+    &usb::ohci::OhciPciDriver,
+    &usb::ehci::EhciPciDriver,
+    &usb::xhci::XhciPciDriver,
+];
+
+/// Set up PCI configuration access and register the built-in PCI drivers,
+/// binding them to the devices present.
+pub fn init() {
+    pci::init();
+    for driver in PCI_DRIVERS {
+        pci::register_driver(driver);
+    }
+}
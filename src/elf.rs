@@ -0,0 +1,478 @@
+//! ELF64 program loader
+//!
+//! Loads statically linked x86_64 executables (`ET_EXEC`) into a fresh
+//! [`AddressSpace`] and builds the initial user stack the System V ABI
+//! describes: `argc`, the `argv` and `envp` pointer arrays and the auxiliary
+//! vector, with the strings they point to above them.
+
+use crate::address_space::{AddressSpace, AddressSpaceError, Placement};
+use crate::memory::FRAME_SIZE;
+use crate::usermode::{self, USER_END};
+use crate::vma::{Backing, Protection};
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::VirtAddr;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// Auxiliary vector keys
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+/// Top of the user stack; the page above it stays unmapped
+pub const USER_STACK_TOP: u64 = USER_END - FRAME_SIZE;
+/// Size of the user stack area
+pub const USER_STACK_SIZE: u64 = 256 * 1024;
+/// Size of the guard area below the user stack
+pub const STACK_GUARD_SIZE: u64 = 64 * 1024;
+/// Room `argv` and `envp` may take on the stack, strings included
+const MAX_ARG_SIZE: usize = 64 * 1024;
+
+/// Loadable segment
+#[derive(Debug, Clone, Copy)]
+pub struct Segment {
+    pub vaddr: u64,
+    pub memsz: u64,
+    pub offset: u64,
+    pub filesz: u64,
+    pub flags: u32,
+}
+
+impl Segment {
+    /// Protection matching the segment permissions; loaded segments are
+    /// always readable
+    fn protection(&self) -> Protection {
+        let mut protection = Protection::READ;
+        if self.flags & PF_W != 0 {
+            protection = protection | Protection::WRITE;
+        }
+        if self.flags & PF_X != 0 {
+            protection = protection | Protection::EXEC;
+        }
+        protection
+    }
+}
+
+/// Validated ELF executable
+pub struct ElfImage<'a> {
+    data: &'a [u8],
+    entry: u64,
+    phoff: u64,
+    phnum: u16,
+}
+
+impl<'a> ElfImage<'a> {
+    /// Check the ELF header and every program header of `data`.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_SIZE || data[0..4] != ELF_MAGIC {
+            return Err(ElfError::NotElf);
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || data[6] != EV_CURRENT {
+            return Err(ElfError::UnsupportedFormat);
+        }
+        match read_u16(data, 16) {
+            ET_EXEC => {}
+            // Position independent executables need relocating
+            ET_DYN => return Err(ElfError::Unsupported),
+            _ => return Err(ElfError::NotExecutable),
+        }
+        if read_u16(data, 18) != EM_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+
+        let image = ElfImage {
+            data,
+            entry: read_u64(data, 24),
+            phoff: read_u64(data, 32),
+            phnum: read_u16(data, 56),
+        };
+        if read_u16(data, 54) as usize != PROGRAM_HEADER_SIZE || image.phnum == 0 {
+            return Err(ElfError::Malformed);
+        }
+        let table_end = image
+            .phoff
+            .checked_add(image.phnum as u64 * PROGRAM_HEADER_SIZE as u64)
+            .ok_or(ElfError::Malformed)?;
+        if table_end > data.len() as u64 {
+            return Err(ElfError::Malformed);
+        }
+
+        let mut entry_executable = false;
+        let mut previous_end = 0;
+        for i in 0..image.phnum as usize {
+            if image.program_type(i) == PT_INTERP {
+                return Err(ElfError::Unsupported);
+            }
+        }
+        for segment in image.segments() {
+            if segment.filesz > segment.memsz {
+                return Err(ElfError::Malformed);
+            }
+            match segment.offset.checked_add(segment.filesz) {
+                Some(end) if end <= data.len() as u64 => {}
+                _ => return Err(ElfError::Malformed),
+            }
+            if segment.memsz == 0 {
+                continue;
+            }
+            if !usermode::is_user_range(segment.vaddr, segment.memsz)
+                || segment.vaddr + segment.memsz
+                    > USER_STACK_TOP - USER_STACK_SIZE - STACK_GUARD_SIZE
+            {
+                return Err(ElfError::BadAddress);
+            }
+            // `load` expects the segments sorted by address, at most sharing a page
+            if segment.vaddr < previous_end {
+                return Err(ElfError::Malformed);
+            }
+            previous_end = segment.vaddr + segment.memsz;
+            if segment.flags & PF_X != 0
+                && (segment.vaddr..segment.vaddr + segment.memsz).contains(&image.entry)
+            {
+                entry_executable = true;
+            }
+        }
+        if !entry_executable {
+            return Err(ElfError::BadAddress);
+        }
+        Ok(image)
+    }
+
+    /// Address execution starts at
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new(self.entry)
+    }
+
+    fn program_header(&self, index: usize) -> &'a [u8] {
+        let start = self.phoff as usize + index * PROGRAM_HEADER_SIZE;
+        &self.data[start..start + PROGRAM_HEADER_SIZE]
+    }
+
+    fn program_type(&self, index: usize) -> u32 {
+        read_u32(self.program_header(index), 0)
+    }
+
+    /// `PT_LOAD` segments in file order
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + '_ {
+        (0..self.phnum as usize)
+            .filter(|&i| self.program_type(i) == PT_LOAD)
+            .map(|i| {
+                let header = self.program_header(i);
+                Segment {
+                    flags: read_u32(header, 4),
+                    offset: read_u64(header, 8),
+                    vaddr: read_u64(header, 16),
+                    filesz: read_u64(header, 32),
+                    memsz: read_u64(header, 40),
+                }
+            })
+    }
+
+    /// User address of the program header table, for `AT_PHDR`
+    fn program_headers_addr(&self) -> Option<u64> {
+        let phdr = (0..self.phnum as usize).find(|&i| self.program_type(i) == PT_PHDR);
+        if let Some(i) = phdr {
+            return Some(read_u64(self.program_header(i), 16));
+        }
+        // Otherwise the table is loaded if a segment covers its file offset
+        self.segments()
+            .find(|s| self.phoff >= s.offset && self.phoff < s.offset + s.filesz)
+            .map(|s| s.vaddr + (self.phoff - s.offset))
+    }
+
+    /// Map and fill every segment in `space`.
+    pub fn load(&self, space: &AddressSpace) -> Result<(), ElfError> {
+        for segment in self.segments().filter(|s| s.memsz > 0) {
+            let protection = segment.protection();
+            let vaddr = VirtAddr::new(segment.vaddr);
+            let mut start = vaddr.align_down(FRAME_SIZE);
+            let end = (vaddr + segment.memsz).align_up(FRAME_SIZE);
+            // Page shared with the previous segment: grant both permissions
+            if let Some(existing) = space.protection(start) {
+                space.protect(start, FRAME_SIZE, existing | protection)?;
+                start += FRAME_SIZE;
+            }
+            if start < end {
+                space.map(
+                    Placement::FixedNoReplace(start),
+                    end - start,
+                    protection,
+                    Backing::Anonymous,
+                )?;
+            }
+            space.populate(vaddr, segment.memsz)?;
+            let offset = segment.offset as usize;
+            let bytes = &self.data[offset..offset + segment.filesz as usize];
+            space.write(vaddr, bytes)?;
+        }
+        Ok(())
+    }
+}
+
+/// Map the user stack in `space` and lay out `argv`, `envp` and the
+/// auxiliary vector on it. Returns the initial stack pointer.
+pub fn setup_stack(
+    space: &AddressSpace,
+    image: &ElfImage,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<VirtAddr, ElfError> {
+    let strings_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    let pointers_size = (argv.len() + envp.len() + 2) * 8;
+    if strings_size + pointers_size > MAX_ARG_SIZE {
+        return Err(ElfError::ArgumentsTooLarge);
+    }
+
+    let stack_bottom = VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE);
+    space.map(
+        Placement::FixedNoReplace(stack_bottom - STACK_GUARD_SIZE),
+        STACK_GUARD_SIZE,
+        Protection::NONE,
+        Backing::Guard,
+    )?;
+    space.map(
+        Placement::FixedNoReplace(stack_bottom),
+        USER_STACK_SIZE,
+        Protection::READ | Protection::WRITE,
+        Backing::Anonymous,
+    )?;
+    // The rest of the stack is filled in as it grows
+    let used = MAX_ARG_SIZE as u64 + FRAME_SIZE;
+    space.populate(VirtAddr::new(USER_STACK_TOP - used), used)?;
+
+    // Strings and the AT_RANDOM bytes go at the very top
+    let mut sp = USER_STACK_TOP;
+    let mut push_bytes = |bytes: &[u8]| -> Result<u64, ElfError> {
+        sp -= bytes.len() as u64;
+        space.write(VirtAddr::new(sp), bytes)?;
+        Ok(sp)
+    };
+    let random = push_bytes(&random_bytes())?;
+    let mut push_strings = |strings: &[&str]| -> Result<Vec<u64>, ElfError> {
+        let mut addrs = Vec::with_capacity(strings.len() + 1);
+        for s in strings {
+            push_bytes(&[0])?;
+            addrs.push(push_bytes(s.as_bytes())?);
+        }
+        addrs.push(0);
+        Ok(addrs)
+    };
+    let envp_addrs = push_strings(envp)?;
+    let argv_addrs = push_strings(argv)?;
+
+    let auxv = [
+        (AT_PHDR, image.program_headers_addr().unwrap_or(0)),
+        (AT_PHENT, PROGRAM_HEADER_SIZE as u64),
+        (AT_PHNUM, image.phnum as u64),
+        (AT_PAGESZ, FRAME_SIZE),
+        (AT_ENTRY, image.entry),
+        (AT_RANDOM, random),
+        (AT_NULL, 0),
+    ];
+    let mut words = Vec::with_capacity(1 + argv_addrs.len() + envp_addrs.len() + 2 * auxv.len());
+    words.push(argv.len() as u64);
+    words.extend_from_slice(&argv_addrs);
+    words.extend_from_slice(&envp_addrs);
+    for (key, value) in auxv {
+        words.push(key);
+        words.push(value);
+    }
+
+    // The ABI wants rsp 16-byte aligned at argc
+    let mut sp = (sp & !0xF) - words.len() as u64 * 8;
+    sp &= !0xF;
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    space.write(VirtAddr::new(sp), &bytes)?;
+    Ok(VirtAddr::new(sp))
+}
+
+/// Seed for `AT_RANDOM`. Not cryptographic: the ABI only asks for bytes
+/// that differ between runs
+fn random_bytes() -> [u8; 16] {
+    let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+    let now = crate::time::now_ns();
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&(tsc ^ now.rotate_left(32)).to_le_bytes());
+    bytes[8..].copy_from_slice(&(now.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ tsc).to_le_bytes());
+    bytes
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Errors loading a program
+#[derive(Debug, Clone, Copy)]
+pub enum ElfError {
+    NotElf,
+    /// Not a 64-bit little-endian ELF of the current version
+    UnsupportedFormat,
+    NotExecutable,
+    WrongMachine,
+    /// Dynamically linked or position independent
+    Unsupported,
+    Malformed,
+    /// Segment or entry point outside the user range
+    BadAddress,
+    ArgumentsTooLarge,
+    AddressSpace(AddressSpaceError),
+}
+
+impl From<AddressSpaceError> for ElfError {
+    fn from(e: AddressSpaceError) -> Self {
+        ElfError::AddressSpace(e)
+    }
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::NotElf => write!(f, "Not an ELF file"),
+            ElfError::UnsupportedFormat => write!(f, "Not a 64-bit little-endian ELF"),
+            ElfError::NotExecutable => write!(f, "Not an executable"),
+            ElfError::WrongMachine => write!(f, "Not an x86_64 program"),
+            ElfError::Unsupported => write!(f, "Dynamically linked programs are not supported"),
+            ElfError::Malformed => write!(f, "Malformed program headers"),
+            ElfError::BadAddress => write!(f, "Program outside the user address range"),
+            ElfError::ArgumentsTooLarge => write!(f, "Argument list too long"),
+            ElfError::AddressSpace(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usermode::USER_START;
+
+    const TEXT: u64 = USER_START + 0x40_0000;
+
+    /// ELF header followed by a program header table with room for `phnum`
+    /// entries and 0x100 bytes of segment data
+    fn image(phnum: u16) -> Vec<u8> {
+        let mut data = alloc::vec![0u8; HEADER_SIZE + phnum as usize * PROGRAM_HEADER_SIZE + 0x100];
+        data[0..4].copy_from_slice(&ELF_MAGIC);
+        data[4] = ELFCLASS64;
+        data[5] = ELFDATA2LSB;
+        data[6] = EV_CURRENT;
+        data[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+        data[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+        data[24..32].copy_from_slice(&TEXT.to_le_bytes());
+        data[32..40].copy_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+        data[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        data[56..58].copy_from_slice(&phnum.to_le_bytes());
+        data
+    }
+
+    fn set_segment(data: &mut [u8], index: usize, vaddr: u64, memsz: u64, flags: u32) {
+        let at = HEADER_SIZE + index * PROGRAM_HEADER_SIZE;
+        let offset = (data.len() - 0x100) as u64;
+        let header = &mut data[at..at + PROGRAM_HEADER_SIZE];
+        header[0..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        header[4..8].copy_from_slice(&flags.to_le_bytes());
+        header[8..16].copy_from_slice(&offset.to_le_bytes());
+        header[16..24].copy_from_slice(&vaddr.to_le_bytes());
+        header[32..40].copy_from_slice(&0x100u64.to_le_bytes());
+        header[40..48].copy_from_slice(&memsz.to_le_bytes());
+    }
+
+    fn two_segments(data_vaddr: u64) -> Vec<u8> {
+        let mut data = image(2);
+        set_segment(&mut data, 0, TEXT, 0x1800, PF_X);
+        set_segment(&mut data, 1, data_vaddr, 0x1000, PF_W);
+        data
+    }
+
+    #[test_case]
+    fn test_elf_parse() {
+        let data = two_segments(TEXT + 0x2000);
+        let image = ElfImage::parse(&data).unwrap();
+        assert_eq!(image.entry().as_u64(), TEXT);
+        assert_eq!(image.segments().count(), 2);
+    }
+
+    #[test_case]
+    fn test_elf_truncated() {
+        let data = two_segments(TEXT + 0x2000);
+        assert!(matches!(
+            ElfImage::parse(&data[..HEADER_SIZE - 1]),
+            Err(ElfError::NotElf)
+        ));
+        // Header complete, program header table cut off
+        assert!(matches!(
+            ElfImage::parse(&data[..HEADER_SIZE + PROGRAM_HEADER_SIZE]),
+            Err(ElfError::Malformed)
+        ));
+        // Segment data cut off
+        assert!(matches!(
+            ElfImage::parse(&data[..data.len() - 1]),
+            Err(ElfError::Malformed)
+        ));
+    }
+
+    #[test_case]
+    fn test_elf_program_headers_out_of_range() {
+        let mut data = two_segments(TEXT + 0x2000);
+        let len = data.len() as u64;
+        data[32..40].copy_from_slice(&len.to_le_bytes());
+        assert!(matches!(ElfImage::parse(&data), Err(ElfError::Malformed)));
+        data[32..40].copy_from_slice(&(u64::MAX - 8).to_le_bytes());
+        assert!(matches!(ElfImage::parse(&data), Err(ElfError::Malformed)));
+
+        // Segment file range past the end of the file
+        let mut data = two_segments(TEXT + 0x2000);
+        let at = HEADER_SIZE + 8;
+        data[at..at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(ElfImage::parse(&data), Err(ElfError::Malformed)));
+
+        // More file bytes than memory
+        let mut data = two_segments(TEXT + 0x2000);
+        set_segment(&mut data, 1, TEXT + 0x2000, 0x80, PF_W);
+        assert!(matches!(ElfImage::parse(&data), Err(ElfError::Malformed)));
+    }
+
+    #[test_case]
+    fn test_elf_overlapping_segments() {
+        let data = two_segments(TEXT + 0x1000);
+        assert!(matches!(ElfImage::parse(&data), Err(ElfError::Malformed)));
+        // Out of order
+        let data = two_segments(TEXT - 0x1000);
+        assert!(matches!(ElfImage::parse(&data), Err(ElfError::Malformed)));
+        // Sharing the page at the end of the first segment is fine
+        let data = two_segments(TEXT + 0x1800);
+        assert!(ElfImage::parse(&data).is_ok());
+    }
+}
@@ -0,0 +1,541 @@
+//! CPU exception handling
+//!
+//! Every architectural exception enters through a small assembly stub that
+//! saves all general purpose registers, so handlers get a complete
+//! [`ExceptionFrame`] instead of just the hardware-pushed stack frame.
+
+use crate::serial_println;
+use core::arch::global_asm;
+use core::fmt;
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+/// Register state saved by the exception entry stubs
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Error code pushed by the CPU, or 0 for exceptions without one
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl ExceptionFrame {
+    /// Whether the exception was raised while running in ring 3
+    pub fn from_user_mode(&self) -> bool {
+        self.cs & 0b11 == 3
+    }
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}",
+            self.rax, self.rbx, self.rcx, self.rdx
+        )?;
+        writeln!(
+            f,
+            "RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}",
+            self.rsi, self.rdi, self.rbp, self.rsp
+        )?;
+        writeln!(
+            f,
+            "R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}",
+            self.r8, self.r9, self.r10, self.r11
+        )?;
+        writeln!(
+            f,
+            "R12={:016x} R13={:016x} R14={:016x} R15={:016x}",
+            self.r12, self.r13, self.r14, self.r15
+        )?;
+        write!(
+            f,
+            "RIP={:016x} CS={:04x} SS={:04x} RFLAGS={:016x}",
+            self.rip, self.cs, self.ss, self.rflags
+        )
+    }
+}
+
+/// Human readable exception name
+pub fn exception_name(vector: u64) -> &'static str {
+    match vector {
+        0 => "DIVIDE ERROR",
+        1 => "DEBUG",
+        2 => "NON-MASKABLE INTERRUPT",
+        3 => "BREAKPOINT",
+        4 => "OVERFLOW",
+        5 => "BOUND RANGE EXCEEDED",
+        6 => "INVALID OPCODE",
+        7 => "DEVICE NOT AVAILABLE",
+        8 => "DOUBLE FAULT",
+        10 => "INVALID TSS",
+        11 => "SEGMENT NOT PRESENT",
+        12 => "STACK SEGMENT FAULT",
+        13 => "GENERAL PROTECTION FAULT",
+        14 => "PAGE FAULT",
+        16 => "X87 FLOATING POINT",
+        17 => "ALIGNMENT CHECK",
+        18 => "MACHINE CHECK",
+        19 => "SIMD FLOATING POINT",
+        20 => "VIRTUALIZATION",
+        21 => "CONTROL PROTECTION",
+        28 => "HYPERVISOR INJECTION",
+        29 => "VMM COMMUNICATION",
+        30 => "SECURITY",
+        _ => "RESERVED",
+    }
+}
+
+/// Decoded selector error code (#TS, #NP, #SS, #GP)
+#[derive(Debug, Clone, Copy)]
+pub struct SelectorErrorCode(pub u64);
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "no selector");
+        }
+        let table = match (self.0 >> 1) & 0b11 {
+            0b00 => "GDT",
+            0b10 => "LDT",
+            _ => "IDT",
+        };
+        write!(f, "{} index {:#x}", table, (self.0 >> 3) & 0x1FFF)?;
+        if self.0 & 1 != 0 {
+            write!(f, " (external event)")?;
+        }
+        Ok(())
+    }
+}
+
+macro_rules! exception_stub {
+    ($name:ident, $vector:literal) => {
+        global_asm!(concat!(
+            ".global ",
+            stringify!($name),
+            "\n",
+            stringify!($name),
+            ":\n",
+            "push 0\n",
+            "push ",
+            stringify!($vector),
+            "\n",
+            "jmp exception_common\n"
+        ));
+        extern "C" {
+            fn $name();
+        }
+    };
+    ($name:ident, $vector:literal, error_code) => {
+        global_asm!(concat!(
+            ".global ",
+            stringify!($name),
+            "\n",
+            stringify!($name),
+            ":\n",
+            "push ",
+            stringify!($vector),
+            "\n",
+            "jmp exception_common\n"
+        ));
+        extern "C" {
+            fn $name();
+        }
+    };
+}
+
+exception_stub!(exception_stub_0, 0);
+exception_stub!(exception_stub_1, 1);
+exception_stub!(exception_stub_2, 2);
+exception_stub!(exception_stub_3, 3);
+exception_stub!(exception_stub_4, 4);
+exception_stub!(exception_stub_5, 5);
+exception_stub!(exception_stub_6, 6);
+exception_stub!(exception_stub_7, 7);
+exception_stub!(exception_stub_8, 8, error_code);
+exception_stub!(exception_stub_10, 10, error_code);
+exception_stub!(exception_stub_11, 11, error_code);
+exception_stub!(exception_stub_12, 12, error_code);
+exception_stub!(exception_stub_13, 13, error_code);
+exception_stub!(exception_stub_14, 14, error_code);
+exception_stub!(exception_stub_16, 16);
+exception_stub!(exception_stub_17, 17, error_code);
+exception_stub!(exception_stub_18, 18);
+exception_stub!(exception_stub_19, 19);
+exception_stub!(exception_stub_20, 20);
+exception_stub!(exception_stub_21, 21, error_code);
+exception_stub!(exception_stub_28, 28);
+exception_stub!(exception_stub_29, 29, error_code);
+exception_stub!(exception_stub_30, 30, error_code);
+
+// Common entry: switch to the kernel GS base if the exception came from
+// ring 3, save registers in `ExceptionFrame` order, call the Rust
+// dispatcher with a pointer to the frame, restore and return. The stack is
+// 16-byte aligned at the call since the frame is 176 bytes. Page faults
+// arrive on an interrupt stack and first move their frame to the stack
+// `page_fault_stack` picks.
+global_asm!(
+    "exception_common:",
+    "test qword ptr [rsp + 24], 3",
+    "jz 2f",
+    "swapgs",
+    "2:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "cmp qword ptr [rsp + 120], 14",
+    "jne 4f",
+    "call {page_fault_stack}",
+    "test rax, rax",
+    "jz 4f",
+    "sub rax, 176",
+    "mov rsi, rsp",
+    "mov rdi, rax",
+    "mov ecx, 22",
+    "rep movsq",
+    "mov rsp, rax",
+    "4:",
+    "mov rdi, rsp",
+    "call {dispatch}",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "add rsp, 16",
+    "test qword ptr [rsp + 8], 3",
+    "jz 3f",
+    "swapgs",
+    "3:",
+    "iretq",
+    dispatch = sym exception_dispatch,
+    page_fault_stack = sym page_fault_stack,
+);
+
+/// Point every architectural exception vector at its entry stub.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    let addr = |stub: unsafe extern "C" fn()| VirtAddr::new(stub as usize as u64);
+    unsafe {
+        idt.divide_error.set_handler_addr(addr(exception_stub_0));
+        idt.debug.set_handler_addr(addr(exception_stub_1));
+        idt.non_maskable_interrupt
+            .set_handler_addr(addr(exception_stub_2))
+            .set_stack_index(crate::gdt::NMI_IST_INDEX);
+        idt.breakpoint.set_handler_addr(addr(exception_stub_3));
+        idt.overflow.set_handler_addr(addr(exception_stub_4));
+        idt.bound_range_exceeded
+            .set_handler_addr(addr(exception_stub_5));
+        idt.invalid_opcode.set_handler_addr(addr(exception_stub_6));
+        idt.device_not_available
+            .set_handler_addr(addr(exception_stub_7));
+        idt.double_fault
+            .set_handler_addr(addr(exception_stub_8))
+            .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(addr(exception_stub_10));
+        idt.segment_not_present
+            .set_handler_addr(addr(exception_stub_11));
+        idt.stack_segment_fault
+            .set_handler_addr(addr(exception_stub_12));
+        idt.general_protection_fault
+            .set_handler_addr(addr(exception_stub_13));
+        idt.page_fault
+            .set_handler_addr(addr(exception_stub_14))
+            .set_stack_index(crate::gdt::PAGE_FAULT_IST_INDEX);
+        idt.x87_floating_point
+            .set_handler_addr(addr(exception_stub_16));
+        idt.alignment_check
+            .set_handler_addr(addr(exception_stub_17));
+        idt.machine_check
+            .set_handler_addr(addr(exception_stub_18))
+            .set_stack_index(crate::gdt::MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point
+            .set_handler_addr(addr(exception_stub_19));
+        idt.virtualization.set_handler_addr(addr(exception_stub_20));
+        idt.cp_protection_exception
+            .set_handler_addr(addr(exception_stub_21));
+        idt.hv_injection_exception
+            .set_handler_addr(addr(exception_stub_28));
+        idt.vmm_communication_exception
+            .set_handler_addr(addr(exception_stub_29));
+        idt.security_exception
+            .set_handler_addr(addr(exception_stub_30));
+    }
+}
+
+extern "C" fn exception_dispatch(frame: &mut ExceptionFrame) {
+    match frame.vector {
+        1 | 2 | 3 => report_trap(frame),
+        14 => page_fault(frame),
+        // Machine checks and double faults are never the program's fault
+        8 | 18 => fatal_exception(frame),
+        _ if frame.from_user_mode() => user_fault(frame),
+        _ => fatal_exception(frame),
+    }
+}
+
+/// Report a trap that returns to the interrupted code. It may have hit while
+/// the serial port was held, possibly on this very CPU, so the message is
+/// dropped rather than waited for.
+fn report_trap(frame: &ExceptionFrame) {
+    use core::fmt::Write;
+
+    if let Some(mut port) = crate::serial::SERIAL1.try_lock() {
+        let _ = writeln!(port, "EXCEPTION: {}\n{}", exception_name(frame.vector), frame);
+    }
+}
+
+/// Stack to handle a page fault on, or 0 to stay on the interrupt stack.
+///
+/// Only a kernel stack growing into its demand region is resolved on the
+/// interrupt stack, which needs nothing but the frame allocator and the
+/// kernel page table. Everything else may fault again, on the heap or on a
+/// user page, and would then overwrite its own frame, so it moves to the
+/// stack the CPU would have used: the interrupted one, or the thread's
+/// kernel stack for faults in ring 3. The pages the frame is copied to are
+/// mapped here first.
+extern "C" fn page_fault_stack(frame: &ExceptionFrame) -> u64 {
+    use x86_64::registers::control::Cr2;
+    use x86_64::structures::paging::{Page, Size4KiB};
+
+    let top = if frame.from_user_mode() {
+        crate::percpu::current().kernel_stack()
+    } else if crate::thread::is_stack_area(Cr2::read()) {
+        return 0;
+    } else {
+        frame.rsp
+    } & !0xF;
+    let size = core::mem::size_of::<ExceptionFrame>() as u64;
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(top - size));
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(top - 1));
+    for page in Page::range_inclusive(first, last) {
+        let addr = page.start_address();
+        // Every other stack is mapped in full
+        if !crate::thread::is_stack_area(addr) || crate::thread::is_resident_stack_page(addr) {
+            continue;
+        }
+        if crate::thread::is_stack_guard_page(addr) || !crate::memory::ensure_mapped(addr) {
+            return 0;
+        }
+    }
+    top
+}
+
+fn page_fault(frame: &mut ExceptionFrame) {
+    use x86_64::registers::control::Cr2;
+
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    let addr = Cr2::read();
+    // Kernel writes to user buffers can hit copy-on-write pages as well.
+    // Stack guard pages lie in the stack area's demand region but must
+    // stay unmapped.
+    if !crate::address_space::handle_page_fault(addr, error_code)
+        && (crate::thread::is_stack_guard_page(addr)
+            || !crate::memory::handle_page_fault(addr, error_code))
+    {
+        if frame.from_user_mode() {
+            user_fault(frame);
+        }
+        fatal_exception(frame);
+    }
+}
+
+/// A user program faulted: report it and return to the kernel code that
+/// started the program.
+fn user_fault(frame: &ExceptionFrame) -> ! {
+    serial_println!(
+        "User mode {} at {:#x} (error code {:#x})",
+        exception_name(frame.vector),
+        frame.rip,
+        frame.error_code
+    );
+    if frame.vector == 14 {
+        describe_page_fault(PageFaultErrorCode::from_bits_truncate(frame.error_code));
+    }
+    crate::usermode::leave(crate::usermode::UserExit::Fault(frame.vector as u8))
+}
+
+fn describe_page_fault(error_code: PageFaultErrorCode) {
+    use x86_64::registers::control::Cr2;
+
+    let address = Cr2::read();
+    serial_println!("Accessed address (CR2): {:#x}", address.as_u64());
+    serial_println!(
+        "  {} {} in {} mode{}{}",
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "protection violation on"
+        } else {
+            "non-present page on"
+        },
+        if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch"
+        } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write"
+        } else {
+            "read"
+        },
+        if error_code.contains(PageFaultErrorCode::USER_MODE) {
+            "user"
+        } else {
+            "kernel"
+        },
+        if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            ", reserved bit set in page table"
+        } else {
+            ""
+        },
+        if error_code.contains(PageFaultErrorCode::PROTECTION_KEY) {
+            ", protection key"
+        } else {
+            ""
+        },
+    );
+    if let Some(region) = crate::memory::try_demand_region(address) {
+        serial_println!("  address lies in demand region {:?}", region);
+    }
+    if crate::thread::is_stack_guard_page(address) {
+        serial_println!("  address lies in a kernel stack guard page (stack overflow)");
+    }
+}
+
+/// Common path for unrecoverable exceptions: dump everything we know and panic.
+pub fn fatal_exception(frame: &ExceptionFrame) -> ! {
+    use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+
+    unsafe { force_unlock_serial() };
+    let name = exception_name(frame.vector);
+    serial_println!("EXCEPTION: {} (vector {})", name, frame.vector);
+    match frame.vector {
+        10 | 11 | 12 | 13 => {
+            serial_println!(
+                "Error code: {:#x} ({})",
+                frame.error_code,
+                SelectorErrorCode(frame.error_code)
+            );
+        }
+        14 => {
+            serial_println!("Error code: {:#x}", frame.error_code);
+            describe_page_fault(PageFaultErrorCode::from_bits_truncate(frame.error_code));
+        }
+        8 => {
+            serial_println!("Error code: {:#x}", frame.error_code);
+            // A page fault on an overflowed stack can't push its frame and escalates
+            if crate::thread::is_stack_guard_page(Cr2::read()) {
+                serial_println!("Kernel stack overflow (CR2 in guard page)");
+            }
+        }
+        17 | 21 | 29 | 30 => {
+            serial_println!("Error code: {:#x}", frame.error_code);
+        }
+        18 => {
+            use x86_64::registers::model_specific::Msr;
+            let mcg_status = unsafe { Msr::new(0x17A).read() };
+            serial_println!("MCG_STATUS: {:#x}", mcg_status);
+        }
+        _ => {}
+    }
+    serial_println!("{}", frame);
+    serial_println!(
+        "CR0={:#x} CR2={:#x} CR3={:#x} CR4={:#x}",
+        Cr0::read_raw(),
+        Cr2::read().as_u64(),
+        Cr3::read().0.start_address().as_u64(),
+        Cr4::read_raw()
+    );
+    print_instruction_bytes(VirtAddr::new(frame.rip));
+    print_stack(VirtAddr::new(frame.rsp));
+    panic!("EXCEPTION: {}", name);
+}
+
+/// The exception may have hit while the serial port was locked; printing
+/// anything would then deadlock.
+unsafe fn force_unlock_serial() {
+    crate::serial::SERIAL1.force_unlock();
+}
+
+/// Whether `len` bytes at `addr` are mapped in the kernel page table.
+/// Only tries the mapper lock since the fault may have hit while it was held.
+fn is_mapped(addr: VirtAddr, len: u64) -> bool {
+    use x86_64::structures::paging::Translate;
+
+    let mapper = match crate::memory::MAPPER.try_lock() {
+        Some(mapper) => mapper,
+        None => return false,
+    };
+    match mapper.as_ref() {
+        Some(mapper) => {
+            mapper.translate_addr(addr).is_some()
+                && mapper.translate_addr(addr + (len - 1)).is_some()
+        }
+        None => false,
+    }
+}
+
+/// Dump the bytes at the faulting instruction if its page is mapped.
+fn print_instruction_bytes(rip: VirtAddr) {
+    const LEN: u64 = 16;
+    if !is_mapped(rip, LEN) {
+        serial_println!("Instruction at {:#x}: <not mapped>", rip.as_u64());
+        return;
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(rip.as_ptr::<u8>(), LEN as usize) };
+    serial_println!("Instruction at {:#x}: {:02x?}", rip.as_u64(), bytes);
+}
+
+/// Dump the top of the interrupted stack.
+fn print_stack(rsp: VirtAddr) {
+    const WORDS: u64 = 8;
+    if rsp.as_u64() % 8 != 0 || !is_mapped(rsp, WORDS * 8) {
+        serial_println!("Stack at {:#x}: <not mapped>", rsp.as_u64());
+        return;
+    }
+    serial_println!("Stack at {:#x}:", rsp.as_u64());
+    for i in 0..WORDS {
+        let addr = rsp + i * 8;
+        let value = unsafe { *addr.as_ptr::<u64>() };
+        serial_println!("  {:#x}: {:016x}", addr.as_u64(), value);
+    }
+}
@@ -0,0 +1,128 @@
+//! Open files and per-process file descriptor tables
+//!
+//! Descriptors refer to shared open file descriptions: a `fork` child gets
+//! copies of its parent's descriptors that share the file offset, as POSIX
+//! expects.
+
+use crate::sync::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Highest number of descriptors a process may have open
+pub const MAX_FDS: usize = 64;
+
+/// Open file description
+pub enum OpenFile {
+    /// Serial console; writes go to the serial port, reads see end of file
+    Console,
+    /// Read-only file from the initial ramdisk
+    Initrd { data: &'static [u8], offset: usize },
+}
+
+impl OpenFile {
+    /// Read into `buf` at the current offset, returning the byte count.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FileError> {
+        match self {
+            OpenFile::Console => Ok(0),
+            OpenFile::Initrd { data, offset } => {
+                let remaining = &data[(*offset).min(data.len())..];
+                let count = remaining.len().min(buf.len());
+                buf[..count].copy_from_slice(&remaining[..count]);
+                *offset += count;
+                Ok(count)
+            }
+        }
+    }
+
+    /// Contents to map for `mmap`, `None` if the file can't be mapped
+    pub fn mapping(&self) -> Option<&'static [u8]> {
+        match self {
+            OpenFile::Console => None,
+            OpenFile::Initrd { data, .. } => Some(data),
+        }
+    }
+
+    /// Write `buf`, returning the byte count.
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, FileError> {
+        match self {
+            OpenFile::Console => {
+                crate::serial::write_bytes(buf);
+                Ok(buf.len())
+            }
+            OpenFile::Initrd { .. } => Err(FileError::NotWritable),
+        }
+    }
+}
+
+pub type FileRef = Arc<Mutex<OpenFile>>;
+
+/// Descriptor table of a process
+#[derive(Clone, Default)]
+pub struct FdTable {
+    files: Vec<Option<FileRef>>,
+}
+
+impl FdTable {
+    /// Table with stdin, stdout and stderr on the console
+    pub fn with_console() -> Self {
+        let console = Arc::new(Mutex::new(OpenFile::Console));
+        FdTable {
+            files: alloc::vec![Some(console.clone()), Some(console.clone()), Some(console)],
+        }
+    }
+
+    pub fn get(&self, fd: usize) -> Result<FileRef, FileError> {
+        self.files
+            .get(fd)
+            .and_then(|file| file.clone())
+            .ok_or(FileError::BadFd)
+    }
+
+    /// Install `file` at the lowest free descriptor.
+    pub fn insert(&mut self, file: OpenFile) -> Result<usize, FileError> {
+        let file = Some(Arc::new(Mutex::new(file)));
+        if let Some(fd) = self.files.iter().position(|slot| slot.is_none()) {
+            self.files[fd] = file;
+            return Ok(fd);
+        }
+        if self.files.len() >= MAX_FDS {
+            return Err(FileError::TooManyOpen);
+        }
+        self.files.push(file);
+        Ok(self.files.len() - 1)
+    }
+
+    pub fn close(&mut self, fd: usize) -> Result<(), FileError> {
+        match self.files.get_mut(fd) {
+            Some(slot @ Some(_)) => {
+                *slot = None;
+                Ok(())
+            }
+            _ => Err(FileError::BadFd),
+        }
+    }
+
+    /// Close every descriptor.
+    pub fn clear(&mut self) {
+        self.files.clear();
+    }
+}
+
+/// File errors
+#[derive(Debug, Clone, Copy)]
+pub enum FileError {
+    BadFd,
+    TooManyOpen,
+    NotWritable,
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileError::BadFd => write!(f, "Bad file descriptor"),
+            FileError::TooManyOpen => write!(f, "Too many open files"),
+            FileError::NotWritable => write!(f, "File not open for writing"),
+        }
+    }
+}
@@ -1,52 +1,114 @@
+use alloc::boxed::Box;
+use core::ptr::{addr_of, addr_of_mut};
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// NMIs and machine checks can hit anywhere, including in the middle of a
+/// stack switch, so they get known good stacks as well
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+/// Page faults on a kernel stack's demand region can't push their frame on
+/// that stack
+pub const PAGE_FAULT_IST_INDEX: u16 = 3;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
-}
+/// Number of interrupt stacks set up in every CPU's TSS
+pub const IST_STACKS: usize = 4;
+
+/// TSS of the bootstrap processor; RSP0 is rewritten on thread switches
+static mut BSP_TSS: TaskStateSegment = TaskStateSegment::new();
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
+        const STACK_SIZE: usize = 4096 * 5;
+        static mut STACKS: [[u8; STACK_SIZE]; IST_STACKS] = [[0; STACK_SIZE]; IST_STACKS];
+
+        let tss = unsafe { &mut *addr_of_mut!(BSP_TSS) };
+        let stacks_start = VirtAddr::from_ptr(addr_of!(STACKS));
+        set_ist_stacks(
+            tss,
+            core::array::from_fn(|i| stacks_start + (i + 1) * STACK_SIZE),
+        );
+        build_gdt(tss)
     };
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// Stacks grow down, so each entry is the top of its stack.
+fn set_ist_stacks(tss: &mut TaskStateSegment, stacks: [VirtAddr; IST_STACKS]) {
+    for (index, stack) in stacks.into_iter().enumerate() {
+        tss.interrupt_stack_table[index] = stack;
+    }
 }
 
-pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+/// Segment selectors; the layout is the same in every CPU's GDT.
+///
+/// `syscall`/`sysret` derive the selectors they load from STAR, which
+/// requires kernel code, kernel data, user data and user code in this order.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+fn build_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code = gdt.add_entry(Descriptor::user_code_segment());
+    let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            kernel_code,
+            kernel_data,
+            user_data,
+            user_code,
+            tss,
+        },
+    )
+}
+
+fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors, tss: *mut TaskStateSegment) {
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    gdt.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(selectors.kernel_code);
+        SS::set_reg(selectors.kernel_data);
+        load_tss(selectors.tss);
     }
+    crate::percpu::current().set_tss(tss);
+}
+
+/// Load the bootstrap processor's GDT and TSS.
+///
+/// The per-CPU block must already be installed.
+pub fn init() {
+    load(&GDT.0, &GDT.1, addr_of_mut!(BSP_TSS));
+}
+
+/// Load a GDT and TSS of its own on an application processor.
+///
+/// A TSS can only be active on one CPU, so each processor gets a fresh
+/// pair; both live for the rest of the kernel's lifetime.
+pub fn init_ap(ist_stacks: [VirtAddr; IST_STACKS]) {
+    let mut tss = TaskStateSegment::new();
+    set_ist_stacks(&mut tss, ist_stacks);
+    let tss = Box::into_raw(Box::new(tss));
+
+    let (gdt, selectors) = build_gdt(unsafe { &*tss });
+    let gdt: &'static GlobalDescriptorTable = Box::leak(Box::new(gdt));
+    load(gdt, &selectors, tss);
+}
+
+/// Selectors of the loaded GDTs
+pub fn selectors() -> Selectors {
+    GDT.1
 }
@@ -1,10 +1,13 @@
+use crate::memory;
+use crate::mmio::{self, CacheMode};
+use crate::sync::Mutex;
+use alloc::vec::Vec;
 use bootloader::framebuffer::{Framebuffer as BootFramebuffer, PixelFormat as BootPixelFormat};
 use bootloader::BootInfo;
 use core::fmt::Write;
-use alloc::vec::Vec;
 use font8x8::{UnicodeFonts, BASIC_FONTS};
 use lazy_static::lazy_static;
-use spin::Mutex;
+use x86_64::VirtAddr;
 
 // Actual framebuffer type provided by the bootloader
 type FrameBuffer = BootFramebuffer;
@@ -44,7 +47,7 @@ impl Framebuffer {
             },
         };
 
-        let buffer = framebuffer.buffer_mut();
+        let buffer = write_combining(framebuffer.buffer_mut());
         let back_buffer = vec![0; buffer.len()];
 
         Self {
@@ -141,6 +144,21 @@ lazy_static! {
     pub static ref FRAMEBUFFER: Mutex<Option<Framebuffer>> = Mutex::new(None);
 }
 
+/// Map the framebuffer again write-combining. The bootloader's mapping
+/// uses the default memory type, which the MTRRs usually make uncached,
+/// and the full-frame copies of `swap_buffers` crawl through it. Keeps
+/// `buffer` if remapping fails.
+fn write_combining(buffer: &'static mut [u8]) -> &'static mut [u8] {
+    let virt = VirtAddr::from_ptr(buffer.as_ptr());
+    let Some(phys) = memory::virt_to_phys(virt) else {
+        return buffer;
+    };
+    match mmio::ioremap(phys, buffer.len() as u64, CacheMode::WriteCombining) {
+        Ok(mapping) => mapping.leak(),
+        Err(_) => buffer,
+    }
+}
+
 pub fn init(boot_info: &'static BootInfo) {
     if let Some(fb) = boot_info.framebuffer.as_mut() {
         let framebuffer = Framebuffer::new(fb);
@@ -0,0 +1,205 @@
+//! Initial ramdisk
+//!
+//! `build.rs` packs the `initrd/` directory into a USTAR archive that is
+//! linked into the kernel image; files are served straight out of it.
+//! `bin/init`, the first program started, is built from `user/init`.
+
+use alloc::string::String;
+use core::str;
+
+static ARCHIVE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd.tar"));
+
+const BLOCK_SIZE: usize = 512;
+
+/// Regular file in the ramdisk
+#[derive(Debug, Clone)]
+pub struct File {
+    /// Path relative to the ramdisk root, without a leading `/`
+    pub path: String,
+    pub data: &'static [u8],
+}
+
+/// Iterator over the regular files of the ramdisk
+pub struct Files {
+    archive: &'static [u8],
+    offset: usize,
+}
+
+impl Iterator for Files {
+    type Item = File;
+
+    fn next(&mut self) -> Option<File> {
+        loop {
+            let header = self.archive.get(self.offset..self.offset + BLOCK_SIZE)?;
+            if header.iter().all(|&b| b == 0) || !checksum_ok(header) {
+                return None;
+            }
+            let size = parse_octal(&header[124..136])? as usize;
+            let data_start = self.offset + BLOCK_SIZE;
+            let data = self
+                .archive
+                .get(data_start..data_start.checked_add(size)?)?;
+            self.offset = data_start + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+
+            // Only regular files, directories and the rest are skipped
+            if header[156] != b'0' && header[156] != 0 {
+                continue;
+            }
+            let name = field_str(&header[0..100])?;
+            let prefix = match &header[257..262] {
+                b"ustar" => field_str(&header[345..500])?,
+                _ => "",
+            };
+            let mut path = String::from(prefix);
+            if !path.is_empty() {
+                path.push('/');
+            }
+            path.push_str(name);
+            return Some(File {
+                path: String::from(normalize(&path)),
+                data,
+            });
+        }
+    }
+}
+
+/// All regular files in the ramdisk
+pub fn files() -> Files {
+    Files {
+        archive: ARCHIVE,
+        offset: 0,
+    }
+}
+
+/// Contents of the file at `path`; leading `/` and `./` are ignored
+pub fn read(path: &str) -> Option<&'static [u8]> {
+    let path = normalize(path);
+    files().find(|file| file.path == path).map(|file| file.data)
+}
+
+/// Log the ramdisk contents.
+pub fn init() {
+    let (count, bytes) = files().fold((0, 0), |(count, bytes), file| {
+        (count + 1, bytes + file.data.len())
+    });
+    log::info!("Initrd: {} file(s), {} KiB", count, bytes.div_ceil(1024));
+}
+
+fn normalize(path: &str) -> &str {
+    let mut path = path;
+    loop {
+        if let Some(rest) = path.strip_prefix('/') {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix("./") {
+            path = rest;
+        } else {
+            return path;
+        }
+    }
+}
+
+/// NUL-terminated (or full-width) string field
+fn field_str(field: &[u8]) -> Option<&str> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..len]).ok()
+}
+
+/// Octal number padded with spaces or NULs
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let digits = field_str(field)?.trim_matches(' ');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+fn checksum_ok(header: &[u8]) -> bool {
+    let expected = match parse_octal(&header[148..156]) {
+        Some(value) => value,
+        None => return false,
+    };
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if (148..156).contains(&i) {
+                b' ' as u64
+            } else {
+                b as u64
+            }
+        })
+        .sum();
+    sum == expected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// USTAR header for a regular file, with `size` as the raw size field
+    fn header(name: &str, size: &[u8]) -> [u8; BLOCK_SIZE] {
+        let mut header = [0u8; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..124 + size.len()].copy_from_slice(size);
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[148..156].fill(b' ');
+        let sum: u32 = header.iter().map(|&b| b as u32).sum();
+        header[148..155].copy_from_slice(alloc::format!("{:06o}\0", sum).as_bytes());
+        header
+    }
+
+    /// Archive of `header` and `data`, padded and terminated like tar does
+    fn archive(header: &[u8], data: &[u8]) -> Files {
+        let mut archive = Vec::from(header);
+        archive.extend_from_slice(data);
+        archive.resize(
+            archive.len().next_multiple_of(BLOCK_SIZE) + 2 * BLOCK_SIZE,
+            0,
+        );
+        Files {
+            archive: archive.leak(),
+            offset: 0,
+        }
+    }
+
+    #[test_case]
+    fn test_tar_file() {
+        let files: Vec<File> =
+            archive(&header("./bin/hello", b"00000000005\0"), b"hello").collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "bin/hello");
+        assert_eq!(files[0].data, b"hello");
+        // Space padded size
+        let mut files = archive(&header("a", b"     5 "), b"hello");
+        assert_eq!(files.next().unwrap().data, b"hello");
+    }
+
+    #[test_case]
+    fn test_tar_bad_size() {
+        // Not octal
+        assert!(archive(&header("a", b"0000000009\0"), b"hello")
+            .next()
+            .is_none());
+        assert!(archive(&header("a", b"12x\0"), b"hello").next().is_none());
+        // Base-256 encoding isn't supported
+        assert!(archive(
+            &header("a", &[0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5]),
+            b"hello"
+        )
+        .next()
+        .is_none());
+        // Larger than the archive
+        assert!(archive(&header("a", b"77777777777\0"), b"hello")
+            .next()
+            .is_none());
+    }
+
+    #[test_case]
+    fn test_tar_bad_checksum() {
+        let mut header = header("a", b"00000000005\0");
+        header[0] = b'b';
+        assert!(archive(&header, b"hello").next().is_none());
+    }
+}
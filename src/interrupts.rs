@@ -1,14 +1,14 @@
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
-use crate::serial_println;
+use x86_64::structures::idt::InterruptDescriptorTable;
+use crate::irq::{self, interrupt_entry, IrqReturn};
+use crate::sync::IrqSpinLock;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: IrqSpinLock<ChainedPics> =
+    IrqSpinLock::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -22,22 +22,20 @@ impl InterruptIndex {
         self as u8
     }
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+    /// IRQ line of the interrupt
+    pub fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
     }
 }
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        crate::exceptions::install(&mut idt);
+        crate::irq::install(&mut idt);
+        crate::smp::install(&mut idt);
+        irq::set_entry(&mut idt, crate::apic::ERROR_VECTOR, apic_error_entry);
+        irq::set_entry(&mut idt, crate::apic::SPURIOUS_VECTOR, spurious_interrupt_entry);
         idt
     };
 }
@@ -46,54 +44,54 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
+/// Bring up the interrupt controllers and attach the keyboard handler.
+///
+/// The 8259 PIC is initialized first so interrupts work even without an APIC,
+/// then delivery is switched to the local/I/O APIC when the MADT describes one.
+pub fn init_controllers() {
+    unsafe {
+        let mut pics = PICS.lock();
+        pics.initialize();
+        // Lines are unmasked as handlers get registered
+        pics.write_masks(0xFF, 0xFF);
+    }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
+    match crate::apic::init() {
+        Ok(()) => {
+            log::info!("Interrupts routed through the APIC");
+        }
+        Err(e) => {
+            log::warn!("APIC unavailable ({}), using 8259 PIC", e);
+        }
+    }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+    if let Err(e) = crate::irq::register_irq(
+        InterruptIndex::Keyboard.irq(),
+        "keyboard",
+        keyboard_interrupt,
+    ) {
+        log::error!("Failed to register keyboard interrupt: {}", e);
     }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
+/// Hand the scancode to the keyboard task; decoding happens outside interrupt context
+fn keyboard_interrupt() -> IrqReturn {
     use x86_64::instructions::port::Port;
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
-            Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
-        );
-    }
-
-    let mut keyboard = KEYBOARD.lock();
     let mut port = Port::new(0x60);
-
     let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => {
-                    crate::vga_buffer::WRITER.lock().write_byte(character as u8);
-                }
-                DecodedKey::RawKey(key) => {
-                    serial_println!("Special key pressed: {:?}", key);
-                }
-            }
-        }
-    }
+    crate::task::keyboard::add_scancode(scancode);
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    IrqReturn::Handled
 }
+
+interrupt_entry!(apic_error_entry, apic_error_handler);
+interrupt_entry!(spurious_interrupt_entry, spurious_interrupt_handler);
+
+extern "C" fn apic_error_handler() {
+    log::warn!("Local APIC error");
+    crate::apic::eoi();
+}
+
+/// Spurious interrupts must not be acknowledged
+extern "C" fn spurious_interrupt_handler() {}
@@ -0,0 +1,434 @@
+//! Hardware interrupt dispatch
+//!
+//! Every IRQ line has its own IDT stub that runs the chain of handlers
+//! registered for it and then acknowledges the interrupt. IRQ `n` is
+//! delivered on vector `IRQ_BASE + n`: lines 0-23 are ISA/GSI interrupts,
+//! lines 24 and up are reserved for message signalled interrupts.
+//!
+//! All hardware interrupts and IPIs enter through `interrupt_common`, which
+//! does the `swapgs` needed when the interrupt arrives from ring 3 (see
+//! `percpu`). Vectors other than the IRQ lines get their entry point from
+//! [`interrupt_entry`].
+
+use crate::apic::{self, ApicError};
+use crate::interrupts::{PICS, PIC_1_OFFSET};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::VirtAddr;
+
+/// Vector of IRQ 0
+pub const IRQ_BASE: u8 = PIC_1_OFFSET;
+/// Number of IRQ lines with a dispatch stub
+pub const IRQ_COUNT: usize = 48;
+/// First IRQ line not wired to the I/O APIC
+pub const MSI_IRQ_BASE: u8 = 24;
+
+/// Result of an interrupt handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    /// The device raised the interrupt and it was serviced
+    Handled,
+    /// The interrupt came from another device sharing the line
+    None,
+}
+
+type Handler = Box<dyn Fn() -> IrqReturn + Send + Sync>;
+
+struct Registration {
+    id: u64,
+    name: &'static str,
+    handler: Handler,
+}
+
+/// Token returned by [`register_irq`], used to remove the handler again
+#[derive(Debug, PartialEq, Eq)]
+pub struct IrqHandle {
+    irq: u8,
+    id: u64,
+}
+
+impl IrqHandle {
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+}
+
+/// Per-line interrupt counters
+#[derive(Debug, Clone, Copy)]
+pub struct IrqStats {
+    pub irq: u8,
+    /// Interrupts delivered on the line
+    pub count: u64,
+    /// Interrupts no handler claimed
+    pub unhandled: u64,
+    pub handlers: usize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLERS: Mutex<Vec<Registration>> = Mutex::new(Vec::new());
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+static HANDLERS: [Mutex<Vec<Registration>>; IRQ_COUNT] = [NO_HANDLERS; IRQ_COUNT];
+static COUNTS: [AtomicU64; IRQ_COUNT] = [ZERO; IRQ_COUNT];
+static UNHANDLED: [AtomicU64; IRQ_COUNT] = [ZERO; IRQ_COUNT];
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+/// Message signalled lines handed out, bit `n` for IRQ `MSI_IRQ_BASE + n`
+static MSI_ALLOCATED: Mutex<u32> = Mutex::new(0);
+/// Lines below 16 registered with [`register_pci_irq`], bit `n` for IRQ `n`
+static PCI_LINES: AtomicU64 = AtomicU64::new(0);
+
+// Common interrupt entry. The entry stub has pushed a handler and its
+// argument on top of the CPU's frame; switch to the kernel GS base when
+// coming from ring 3, save the caller-saved registers, call
+// `handler(argument)` and undo it all. The CPU aligns the stack to 16 bytes
+// before its 40-byte frame, so with the 16 pushed by the stub and the 72
+// here the call is aligned.
+global_asm!(
+    ".global interrupt_common",
+    "interrupt_common:",
+    "test qword ptr [rsp + 24], 3",
+    "jz 2f",
+    "swapgs",
+    "2:",
+    "push rax",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "mov rdi, [rsp + 72]",
+    "mov rax, [rsp + 80]",
+    "cld",
+    "call rax",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rax",
+    "add rsp, 16",
+    "test qword ptr [rsp + 8], 3",
+    "jz 3f",
+    "swapgs",
+    "3:",
+    "iretq",
+);
+
+/// Define `$name`, an IDT entry point running the `extern "C" fn()`
+/// `$handler` through `interrupt_common`. Install it with [`set_entry`].
+macro_rules! interrupt_entry {
+    ($name:ident, $handler:path) => {
+        core::arch::global_asm!(
+            concat!(".global ", stringify!($name), "\n", stringify!($name), ":"),
+            "push rax",
+            "lea rax, [rip + {handler}]",
+            "xchg [rsp], rax",
+            "push 0",
+            "jmp interrupt_common",
+            handler = sym $handler,
+        );
+        extern "C" {
+            fn $name();
+        }
+    };
+}
+pub(crate) use interrupt_entry;
+
+/// Spacing of the IRQ entry stubs in `irq_entries`
+const IRQ_ENTRY_SIZE: u64 = 32;
+
+// One entry stub per IRQ line, each calling `irq_entry` with its line
+global_asm!(
+    ".balign 32",
+    ".global irq_entries",
+    "irq_entries:",
+    concat!(
+        ".irp irq, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19,",
+        " 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39,",
+        " 40, 41, 42, 43, 44, 45, 46, 47"
+    ),
+    "push rax",
+    "lea rax, [rip + {irq_entry}]",
+    "xchg [rsp], rax",
+    "push \\irq",
+    "jmp interrupt_common",
+    ".balign 32",
+    ".endr",
+    irq_entry = sym irq_entry,
+);
+
+extern "C" {
+    static irq_entries: u8;
+}
+
+/// Point vector `vector` of `idt` at an entry point made by
+/// [`interrupt_entry`].
+pub(crate) fn set_entry(
+    idt: &mut InterruptDescriptorTable,
+    vector: u8,
+    entry: unsafe extern "C" fn(),
+) {
+    unsafe { idt[vector as usize].set_handler_addr(VirtAddr::new(entry as usize as u64)) };
+}
+
+/// Point the IRQ vectors of `idt` at the dispatch stubs.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    let entries = VirtAddr::from_ptr(core::ptr::addr_of!(irq_entries));
+    for irq in 0..IRQ_COUNT {
+        let entry = entries + irq as u64 * IRQ_ENTRY_SIZE;
+        unsafe { idt[IRQ_BASE as usize + irq].set_handler_addr(entry) };
+    }
+}
+
+/// IDT vector IRQ `irq` is delivered on
+pub fn vector(irq: u8) -> u8 {
+    IRQ_BASE + irq
+}
+
+extern "C" fn irq_entry(irq: u64) {
+    dispatch(irq as u8);
+}
+
+fn dispatch(irq: u8) {
+    let index = irq as usize;
+    COUNTS[index].fetch_add(1, Ordering::Relaxed);
+
+    let mut handled = false;
+    for registration in HANDLERS[index].lock().iter() {
+        if (registration.handler)() == IrqReturn::Handled {
+            handled = true;
+        }
+    }
+    if !handled {
+        UNHANDLED[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    end_of_interrupt(irq);
+
+    // Preempt on the way out once the handlers are done and acknowledged
+    if crate::scheduler::need_resched() {
+        crate::scheduler::schedule();
+    }
+}
+
+fn end_of_interrupt(irq: u8) {
+    if apic::is_enabled() {
+        apic::eoi();
+    } else if irq < 16 {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector(irq)) };
+    }
+}
+
+/// Attach `handler` to IRQ line `irq` and unmask the line.
+///
+/// Lines can be shared: every handler on the line runs for each interrupt
+/// and reports whether its device was the source. Handlers run with
+/// interrupts disabled and must not register or unregister IRQs themselves.
+///
+/// Lines 0-15 are routed as ISA interrupts (edge triggered, active high
+/// unless the MADT overrides it); use [`register_pci_irq`] for the
+/// interrupt pin of a PCI device.
+pub fn register_irq<F>(irq: u8, name: &'static str, handler: F) -> Result<IrqHandle, IrqError>
+where
+    F: Fn() -> IrqReturn + Send + Sync + 'static,
+{
+    register(irq, name, false, Box::new(handler))
+}
+
+/// Attach `handler` to IRQ line `irq` carrying the interrupt pin of a PCI
+/// device, and unmask the line.
+///
+/// Unlike [`register_irq`] the line is routed level triggered, active low
+/// even below 16, where the firmware may have steered a PCI link onto an
+/// ISA line. Such a line can't be shared with ISA devices.
+pub fn register_pci_irq<F>(irq: u8, name: &'static str, handler: F) -> Result<IrqHandle, IrqError>
+where
+    F: Fn() -> IrqReturn + Send + Sync + 'static,
+{
+    register(irq, name, true, Box::new(handler))
+}
+
+fn register(
+    irq: u8,
+    name: &'static str,
+    pci: bool,
+    handler: Handler,
+) -> Result<IrqHandle, IrqError> {
+    if irq as usize >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq(irq));
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let first = without_interrupts(|| {
+        let mut chain = HANDLERS[irq as usize].lock();
+        if irq < 16 {
+            let bit = 1 << irq;
+            if chain.is_empty() {
+                if pci {
+                    PCI_LINES.fetch_or(bit, Ordering::Relaxed);
+                } else {
+                    PCI_LINES.fetch_and(!bit, Ordering::Relaxed);
+                }
+            } else if is_pci_line(irq) != pci {
+                return Err(IrqError::TriggerMismatch(irq));
+            }
+        }
+        chain.push(Registration { id, name, handler });
+        Ok(chain.len() == 1)
+    })?;
+
+    if first {
+        if let Err(e) = set_line_enabled(irq, true) {
+            without_interrupts(|| HANDLERS[irq as usize].lock().retain(|r| r.id != id));
+            return Err(e);
+        }
+    }
+    Ok(IrqHandle { irq, id })
+}
+
+/// Remove a handler added with [`register_irq`], masking the line when it was the last one.
+pub fn unregister_irq(handle: IrqHandle) {
+    let empty = without_interrupts(|| {
+        let mut chain = HANDLERS[handle.irq as usize].lock();
+        chain.retain(|r| r.id != handle.id);
+        chain.is_empty()
+    });
+    if empty {
+        let _ = set_line_enabled(handle.irq, false);
+    }
+}
+
+fn set_line_enabled(irq: u8, enabled: bool) -> Result<(), IrqError> {
+    if irq >= MSI_IRQ_BASE {
+        // Message signalled interrupts are enabled in the device itself
+        return Ok(());
+    }
+
+    if apic::is_enabled() {
+        let result = match (is_pci_line(irq), enabled) {
+            (false, true) => apic::route_isa_irq(irq, vector(irq)),
+            (true, true) => apic::route_pci_irq(irq as u32, vector(irq)),
+            (false, false) => apic::set_gsi_masked(apic::isa_irq_gsi(irq).0, true),
+            (true, false) => apic::set_gsi_masked(irq as u32, true),
+        };
+        return result.map_err(IrqError::Routing);
+    }
+
+    if irq >= 16 {
+        return Err(IrqError::InvalidIrq(irq));
+    }
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let [mut master, mut slave] = unsafe { pics.read_masks() };
+        if irq < 8 {
+            master = set_bit(master, irq, !enabled);
+        } else {
+            slave = set_bit(slave, irq - 8, !enabled);
+            if enabled {
+                // Cascade line from the slave PIC
+                master &= !(1 << 2);
+            }
+        }
+        unsafe { pics.write_masks(master, slave) };
+    });
+    Ok(())
+}
+
+/// Whether line `irq` is routed as a PCI interrupt
+fn is_pci_line(irq: u8) -> bool {
+    irq >= 16 || PCI_LINES.load(Ordering::Relaxed) & (1 << irq) != 0
+}
+
+fn set_bit(mask: u8, bit: u8, set: bool) -> u8 {
+    if set {
+        mask | (1 << bit)
+    } else {
+        mask & !(1 << bit)
+    }
+}
+
+/// Reserve `count` consecutive lines for message signalled interrupts and
+/// return the first. `count` must be a power of two; the vector of the
+/// first line is a multiple of it, as multiple message MSI requires.
+pub fn allocate_msi_irqs(count: usize) -> Result<u8, IrqError> {
+    let lines = IRQ_COUNT - MSI_IRQ_BASE as usize;
+    if !count.is_power_of_two() || count > lines {
+        return Err(IrqError::NoFreeIrq);
+    }
+    let mask = ((1u64 << count) - 1) as u32;
+    without_interrupts(|| {
+        let mut allocated = MSI_ALLOCATED.lock();
+        let first = (0..=lines - count)
+            .filter(|&n| vector(MSI_IRQ_BASE + n as u8) as usize % count == 0)
+            .find(|&n| *allocated & (mask << n) == 0)
+            .ok_or(IrqError::NoFreeIrq)?;
+        *allocated |= mask << first;
+        Ok(MSI_IRQ_BASE + first as u8)
+    })
+}
+
+/// Return a line reserved with [`allocate_msi_irqs`]. Its handlers must
+/// have been unregistered.
+pub fn free_msi_irq(irq: u8) {
+    if (MSI_IRQ_BASE..IRQ_COUNT as u8).contains(&irq) {
+        without_interrupts(|| *MSI_ALLOCATED.lock() &= !(1 << (irq - MSI_IRQ_BASE)));
+    }
+}
+
+/// Counters for IRQ line `irq`
+pub fn stats(irq: u8) -> IrqStats {
+    let index = irq as usize;
+    IrqStats {
+        irq,
+        count: COUNTS[index].load(Ordering::Relaxed),
+        unhandled: UNHANDLED[index].load(Ordering::Relaxed),
+        handlers: without_interrupts(|| HANDLERS[index].lock().len()),
+    }
+}
+
+/// Names of the handlers attached to `irq`
+pub fn handler_names(irq: u8) -> Vec<&'static str> {
+    without_interrupts(|| {
+        HANDLERS[irq as usize]
+            .lock()
+            .iter()
+            .map(|r| r.name)
+            .collect()
+    })
+}
+
+/// IRQ registration errors
+#[derive(Debug, Clone, Copy)]
+pub enum IrqError {
+    InvalidIrq(u8),
+    Routing(ApicError),
+    /// No message signalled lines left
+    NoFreeIrq,
+    /// The line already has handlers expecting the other trigger mode
+    TriggerMismatch(u8),
+}
+
+impl fmt::Display for IrqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IrqError::InvalidIrq(irq) => write!(f, "Invalid IRQ {}", irq),
+            IrqError::Routing(e) => write!(f, "Failed to route IRQ: {}", e),
+            IrqError::NoFreeIrq => write!(f, "No free message signalled IRQ"),
+            IrqError::TriggerMismatch(irq) => {
+                write!(f, "IRQ {} is shared between ISA and PCI devices", irq)
+            }
+        }
+    }
+}
@@ -3,6 +3,7 @@ use crate::mouse::MouseCursor;
 use crate::window_manager::{Window, WindowManager};
 use core::fmt::Write;
 use crate::serial_println;
+use crate::task::{executor::Executor, keyboard, Task};
 
 pub fn start() {
     serial_println!("Orbita OS Starting...");
@@ -28,15 +29,16 @@ pub fn start() {
 
     serial_println!("Graphics initialized");
 
-    // Detect audio devices via PCI
-    let audio = crate::drivers::pci::find_audio_devices();
-    serial_println!("Found {} audio device(s)", audio.len());
+    // Bind PCI drivers to the devices present
+    crate::drivers::init();
+    for bound in crate::drivers::pci::bound_devices() {
+        log::info!("PCI {}: {}", bound.device, bound.driver);
+    }
     serial_println!("System ready");
 
-    // Основной цикл ядра
-    loop {
-        // Здесь будет обработка системных событий
-        x86_64::instructions::hlt();
-    }
+    // Основной цикл ядра: задачи выполняются по мере пробуждения
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.run();
 }
 
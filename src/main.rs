@@ -12,39 +12,99 @@ use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use x86_64::VirtAddr;
 
+mod acpi;
+mod address_space;
 mod allocator;
+mod apic;
+mod block;
+mod dma;
+mod elf;
+mod exceptions;
+mod fd;
 mod gdt;
 mod graphics;
 mod graphics_accel;
+mod initrd;
 mod interrupts;
+mod irq;
 mod kernel;
 mod mouse;
 mod memory;
+mod mmio;
+mod percpu;
+mod process;
 mod window_manager;
+mod scheduler;
 mod serial;
+mod shm;
+mod smp;
+mod swap;
+mod sync;
+mod syscall;
+mod task;
+mod thread;
+mod time;
+mod usermode;
 mod vga_buffer;
+mod vma;
 mod drivers;
 
 entry_point!(kernel_main);
 
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // Инициализация базовых компонентов
+    percpu::init_bsp();
     gdt::init();
     interrupts::init_idt();
-    unsafe { interrupts::PICS.lock().initialize() };
-    x86_64::instructions::interrupts::enable();
+    syscall::init();
 
     // Инициализация последовательного порта
     serial::init();
 
     // Инициализация памяти
+    address_space::check_boot_layout(boot_info);
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator =
-        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::init_frame_allocator(&boot_info.memory_map, phys_mem_offset) };
+    mmio::init_pat();
 
     // Инициализация heap
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    allocator::init_heap().expect("heap initialization failed");
+    thread::init().expect("kernel stack area registration failed");
+    #[cfg(test)]
+    test_main();
+    if let Some(stats) = memory::frame_stats() {
+        log::info!(
+            "Physical memory: {} KiB free of {} KiB",
+            stats.free_bytes() / 1024,
+            stats.total_bytes() / 1024
+        );
+    }
+
+    // Контроллеры прерываний: APIC из таблиц ACPI, иначе 8259 PIC
+    if let Err(e) = acpi::init() {
+        log::warn!("ACPI: {}", e);
+    }
+    interrupts::init_controllers();
+    time::init();
+    time::init_wall_clock();
+
+    // Запуск остальных процессоров
+    if let Err(e) = smp::init() {
+        log::warn!("SMP: {}", e);
+    }
+    log::info!("{} CPU(s) online", smp::online_cpus());
+    scheduler::init();
+    x86_64::instructions::interrupts::enable();
+
+    // Swap-раздел на ATA-дисках, если он есть
+    swap::init();
+
+    // Первая пользовательская программа из initrd
+    initrd::init();
+    if let Err(e) = process::spawn("/bin/init", &["/bin/init"], &[]) {
+        log::error!("Failed to start /bin/init: {}", e);
+    }
 
     // Переход в графический режим
     graphics::init(boot_info);
@@ -57,6 +117,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    smp::halt_others();
     serial_println!("Kernel panic: {}", info);
     hlt_loop();
 }
@@ -1,12 +1,133 @@
+use crate::address_space::AddressSpaceError;
+use crate::vma::{Backing, Protection, Vma, VmaTree};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
 use x86_64::{
-    structures::paging::{FrameAllocator, Mapper, Page, PageTable, PhysFrame, Size4KiB},
+    registers::control::{Cr0, Cr0Flags},
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        frame::PhysFrameRange, mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page,
+        PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
     PhysAddr, VirtAddr,
 };
 
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+/// Size of a physical frame in bytes
+pub const FRAME_SIZE: u64 = 4096;
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// Physical address of the kernel's level 4 table
+static KERNEL_LEVEL_4: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// Mapper for the active kernel page table
+    pub static ref MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+}
+
+/// Initialize the global kernel page table mapper.
+///
+/// # Safety
+/// All physical memory must be mapped at `physical_memory_offset`.
+/// Must only be called once.
+pub unsafe fn init(physical_memory_offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let (level_4_frame, _) = x86_64::registers::control::Cr3::read();
+    KERNEL_LEVEL_4.store(level_4_frame.start_address().as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
+    *MAPPER.lock() = Some(OffsetPageTable::new(level_4_table, physical_memory_offset));
+
+    // Make kernel writes honour read-only user pages too, copy-on-write
+    // depends on it. Application processors copy CR0 from here.
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+}
+
+/// Virtual address of a physical address in the bootloader's physical memory mapping
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+/// Physical address behind `addr` in the kernel page table, `None` if it
+/// isn't mapped
+pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
+    with_mapper(|mapper| mapper.translate_addr(addr))
+}
+
+/// Level 4 table of the kernel address space, the one active at boot
+pub fn kernel_level_4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_LEVEL_4.load(Ordering::Relaxed)))
+}
+
+/// Run `f` with the kernel mapper locked and interrupts disabled.
+///
+/// Panics if called before [`init`].
+pub fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        f(MAPPER.lock().as_mut().expect("memory::init was not called"))
+    })
+}
+
+/// Back `count` pages starting at `start` with freshly allocated frames.
+///
+/// On failure the pages mapped so far are unmapped and their frames freed
+/// again, so nothing is left behind.
+pub fn map_pages(
+    start: Page,
+    count: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    with_mapper(|mapper| {
+        for (mapped, page) in Page::range(start, start + count).enumerate() {
+            let result = match GlobalFrameAllocator.allocate_frame() {
+                Some(frame) => unsafe {
+                    mapper
+                        .map_to(page, frame, flags, &mut GlobalFrameAllocator)
+                        .inspect_err(|_| GlobalFrameAllocator.deallocate_frame(frame))
+                },
+                None => Err(MapToError::FrameAllocationFailed),
+            };
+            match result {
+                Ok(flush) => flush.flush(),
+                Err(e) => {
+                    // The caller never got to use the pages, so only this
+                    // CPU can have them in its TLB, hence no unmap_pages
+                    for page in Page::range(start, start + mapped as u64) {
+                        if let Ok((frame, flush)) = mapper.unmap(page) {
+                            flush.flush();
+                            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Unmap `count` pages starting at `start` and free the frames behind them.
+///
+/// Pages that aren't mapped are skipped.
+pub fn unmap_pages(start: Page, count: u64) {
+    // Allocated up front: the heap must not be entered under the mapper lock
+    let mut frames = Vec::with_capacity(count as usize);
+    with_mapper(|mapper| {
+        for page in Page::range(start, start + count) {
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.ignore();
+                frames.push(frame);
+            }
+        }
+    });
+    // Other CPUs may still cache the old translations, so the frames can
+    // only be reused once every TLB has been flushed
+    crate::smp::tlb_shootdown(start.start_address(), count);
+    for frame in frames {
+        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    }
 }
 
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
@@ -21,36 +142,585 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr
 }
 
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+/// Physical memory usage counters
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    /// Frames reported as usable by the bootloader
+    pub total_frames: usize,
+    /// Frames currently handed out (including the bitmap itself)
+    pub used_frames: usize,
+}
+
+impl FrameStats {
+    /// Number of frames still available for allocation
+    pub fn free_frames(&self) -> usize {
+        self.total_frames - self.used_frames
+    }
+
+    /// Free physical memory in bytes
+    pub fn free_bytes(&self) -> u64 {
+        self.free_frames() as u64 * FRAME_SIZE
+    }
+
+    /// Usable physical memory in bytes
+    pub fn total_bytes(&self) -> u64 {
+        self.total_frames as u64 * FRAME_SIZE
+    }
+}
+
+/// Bitmap based physical frame allocator
+///
+/// One bit per 4 KiB frame, set while the frame is in use. The bitmap
+/// itself lives in the first usable region large enough to hold it and is
+/// accessed through the bootloader's physical memory mapping.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    frame_count: usize,
+    stats: FrameStats,
+    next_free: usize,
 }
 
-impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
+impl BitmapFrameAllocator {
+    /// Build the allocator from the bootloader memory map.
+    ///
+    /// # Safety
+    /// The memory map must be valid and all physical memory must be mapped
+    /// at `physical_memory_offset`. Must only be called once.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        let max_addr = usable().map(|r| r.range.end_addr()).max().unwrap_or(0);
+        let frame_count = (max_addr / FRAME_SIZE) as usize;
+        let words = (frame_count + 63) / 64;
+        let bitmap_bytes = (words * 8) as u64;
+        let bitmap_frames = ((bitmap_bytes + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+
+        let bitmap_region = usable()
+            .find(|r| {
+                r.range.end_addr() - r.range.start_addr() >= bitmap_frames as u64 * FRAME_SIZE
+            })
+            .expect("no usable region large enough for the frame bitmap");
+        let bitmap_start = bitmap_region.range.start_addr();
+
+        let ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = core::slice::from_raw_parts_mut(ptr, words);
+        bitmap.fill(u64::MAX);
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            frame_count,
+            stats: FrameStats {
+                total_frames: 0,
+                used_frames: 0,
+            },
+            next_free: 0,
+        };
+
+        for region in usable() {
+            let start = (region.range.start_addr() / FRAME_SIZE) as usize;
+            let end = (region.range.end_addr() / FRAME_SIZE) as usize;
+            for index in start..end {
+                allocator.clear_bit(index);
+            }
+            allocator.stats.total_frames += end - start;
         }
+
+        // The bitmap occupies its own frames, and frame 0 is never handed out
+        // so that a zero physical address can't be mistaken for a valid one.
+        let bitmap_first = (bitmap_start / FRAME_SIZE) as usize;
+        for index in bitmap_first..bitmap_first + bitmap_frames {
+            allocator.mark_used(index);
+        }
+        allocator.mark_used(0);
+
+        allocator
+    }
+
+    /// Current usage statistics
+    pub fn stats(&self) -> FrameStats {
+        self.stats
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    /// Allocate `count` physically contiguous frames.
+    ///
+    /// The first frame is aligned to `align` frames (must be a power of two).
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrameRange> {
+        self.allocate_contiguous_below(count, align, Self::frame(self.frame_count).start_address())
+    }
+
+    /// Allocate `count` physically contiguous frames that lie entirely
+    /// below `limit`, aligned like [`Self::allocate_contiguous`].
+    pub fn allocate_contiguous_below(
+        &mut self,
+        count: usize,
+        align: usize,
+        limit: PhysAddr,
+    ) -> Option<PhysFrameRange> {
+        if count == 0 || !align.is_power_of_two() {
+            return None;
+        }
+
+        let end = ((limit.as_u64() / FRAME_SIZE) as usize).min(self.frame_count);
+        let mut start = 0;
+        while start + count <= end {
+            match (start..start + count).find(|&i| self.is_used(i)) {
+                Some(used) => start = (used + 1 + align - 1) & !(align - 1),
+                None => {
+                    for index in start..start + count {
+                        self.mark_used(index);
+                    }
+                    let first = Self::frame(start);
+                    return Some(PhysFrame::range(first, first + count as u64));
+                }
+            }
+        }
+        None
+    }
+
+    /// Allocate a single frame that lies entirely below `limit`.
+    pub fn allocate_below(&mut self, limit: PhysAddr) -> Option<PhysFrame> {
+        let end = ((limit.as_u64() / FRAME_SIZE) as usize).min(self.frame_count);
+        let index = (0..end).find(|&i| !self.is_used(i))?;
+        self.mark_used(index);
+        Some(Self::frame(index))
+    }
+
+    /// Release a range previously returned by `allocate_contiguous`.
+    ///
+    /// # Safety
+    /// The frames must no longer be referenced by any mapping.
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            self.deallocate_frame(frame);
+        }
+    }
+
+    fn frame(index: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+    }
+
+    fn index(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / FRAME_SIZE) as usize
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn clear_bit(&mut self, index: usize) {
+        self.bitmap[index / 64] &= !(1 << (index % 64));
+    }
+
+    fn mark_used(&mut self, index: usize) {
+        if !self.is_used(index) {
+            self.bitmap[index / 64] |= 1 << (index % 64);
+            self.stats.used_frames += 1;
+        }
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        let words = self.bitmap.len();
+        for step in 0..words {
+            let word = (self.next_free + step) % words;
+            if self.bitmap[word] == u64::MAX {
+                continue;
+            }
+            let index = word * 64 + self.bitmap[word].trailing_ones() as usize;
+            if index >= self.frame_count {
+                continue;
+            }
+            self.mark_used(index);
+            self.next_free = word;
+            return Some(Self::frame(index));
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = Self::index(frame);
+        assert!(
+            index < self.frame_count && self.is_used(index),
+            "double free of physical frame {:?}",
+            frame
+        );
+        self.clear_bit(index);
+        self.stats.used_frames -= 1;
+        if index / 64 < self.next_free {
+            self.next_free = index / 64;
+        }
     }
 }
 
+lazy_static! {
+    pub static ref FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+}
+
+/// Set up the global physical frame allocator.
+///
+/// # Safety
+/// See [`BitmapFrameAllocator::init`].
+pub unsafe fn init_frame_allocator(
+    memory_map: &'static MemoryMap,
+    physical_memory_offset: VirtAddr,
+) {
+    let allocator = BitmapFrameAllocator::init(memory_map, physical_memory_offset);
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+}
+
+/// Physical memory statistics, if the frame allocator is initialized
+pub fn frame_stats() -> Option<FrameStats> {
+    with_frame_allocator(|allocator| allocator.stats())
+}
+
+/// Run `f` with the global frame allocator locked and interrupts disabled.
+fn with_frame_allocator<R>(f: impl FnOnce(&mut BitmapFrameAllocator) -> R) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().as_mut().map(f))
+}
+
+/// Handle to the global frame allocator usable wherever the `x86_64` paging
+/// traits expect an allocator.
+pub struct GlobalFrameAllocator;
+
+impl GlobalFrameAllocator {
+    /// Allocate physically contiguous frames, see [`BitmapFrameAllocator::allocate_contiguous`]
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrameRange> {
+        with_frame_allocator(|allocator| allocator.allocate_contiguous(count, align)).flatten()
+    }
+
+    /// Allocate physically contiguous frames below `limit`, see
+    /// [`BitmapFrameAllocator::allocate_contiguous_below`]
+    pub fn allocate_contiguous_below(
+        &mut self,
+        count: usize,
+        align: usize,
+        limit: PhysAddr,
+    ) -> Option<PhysFrameRange> {
+        with_frame_allocator(|allocator| allocator.allocate_contiguous_below(count, align, limit))
+            .flatten()
+    }
+
+    /// Allocate a frame below `limit`, see [`BitmapFrameAllocator::allocate_below`]
+    pub fn allocate_below(&mut self, limit: PhysAddr) -> Option<PhysFrame> {
+        with_frame_allocator(|allocator| allocator.allocate_below(limit)).flatten()
+    }
+
+    /// Release frames obtained from `allocate_contiguous`.
+    ///
+    /// # Safety
+    /// The frames must no longer be referenced by any mapping.
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        with_frame_allocator(|allocator| allocator.deallocate_contiguous(range));
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        with_frame_allocator(|allocator| allocator.allocate_frame()).flatten()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        with_frame_allocator(|allocator| allocator.deallocate_frame(frame));
+    }
+}
+
+lazy_static! {
+    /// Reference counts of frames mapped more than once; frames not listed
+    /// have a single owner
+    static ref FRAME_REFS: Mutex<BTreeMap<PhysFrame, usize>> = Mutex::new(BTreeMap::new());
+}
+
+/// Take another reference to `frame`, which must already be owned.
+pub fn share_frame(frame: PhysFrame) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *FRAME_REFS.lock().entry(frame).or_insert(1) += 1;
+    });
+}
+
+/// Whether `frame` has more than one owner
+pub fn is_frame_shared(frame: PhysFrame) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| FRAME_REFS.lock().contains_key(&frame))
+}
+
+/// Drop a reference to `frame`, freeing it with the last one.
+///
+/// # Safety
+/// The caller must own a reference and no longer map the frame through it.
+pub unsafe fn release_frame(frame: PhysFrame) {
+    let last = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut refs = FRAME_REFS.lock();
+        match refs.get_mut(&frame) {
+            Some(count) if *count > 2 => {
+                *count -= 1;
+                false
+            }
+            Some(_) => {
+                refs.remove(&frame);
+                false
+            }
+            None => true,
+        }
+    });
+    if last {
+        GlobalFrameAllocator.deallocate_frame(frame);
+    }
+}
+
+/// Start of the virtual window used for device memory mappings
+pub(crate) const MMIO_START: u64 = 0x_5555_0000_0000;
+/// End of the window: the end of its level 4 slot, the only one every
+/// address space shares
+const MMIO_END: u64 = 0x_5580_0000_0000;
+
+lazy_static! {
+    /// Areas of the device window
+    static ref KERNEL_WINDOW: Mutex<VmaTree> = Mutex::new(VmaTree::new(MMIO_START..MMIO_END));
+}
+
+/// Map `size` bytes of physical memory at `phys` into the kernel's device
+/// window with `flags` added to `PRESENT | WRITABLE | NO_EXECUTE`, e.g. the
+/// caching bits for device memory, or none for DMA buffers.
+///
+/// Returns the virtual address corresponding to `phys`.
+pub fn map_physical(
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<VirtAddr, AddressSpaceError> {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::<Size4KiB>::containing_address(phys + (size.max(1) - 1));
+    let pages = last - first + 1;
+    let page_flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | flags;
+
+    // The window is only locked to reserve and release the area: unmapping
+    // waits for the other CPUs to flush their TLBs, which must not happen
+    // with the lock held
+    let start = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut window = KERNEL_WINDOW.lock();
+        let start = window
+            .find_free(pages * FRAME_SIZE, VirtAddr::new(MMIO_START))
+            .ok_or(AddressSpaceError::NoSpace)?;
+        window.insert(Vma {
+            start,
+            end: start + pages * FRAME_SIZE,
+            protection: Protection::READ | Protection::WRITE,
+            backing: Backing::Physical {
+                start: first.start_address(),
+                flags,
+            },
+        })?;
+        Ok(start)
+    })?;
+
+    let mapped = with_mapper(|mapper| {
+        for (i, frame) in PhysFrame::range_inclusive(first, last).enumerate() {
+            let page = Page::<Size4KiB>::containing_address(start + i as u64 * FRAME_SIZE);
+            match unsafe { mapper.map_to(page, frame, page_flags, &mut GlobalFrameAllocator) } {
+                Ok(flush) => flush.flush(),
+                Err(_) => return Err(i as u64),
+            }
+        }
+        Ok(())
+    });
+    if let Err(count) = mapped {
+        unmap_window(start, count);
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            KERNEL_WINDOW
+                .lock()
+                .remove(start, start + pages * FRAME_SIZE)
+        });
+        return Err(AddressSpaceError::OutOfMemory);
+    }
+    Ok(start + (phys.as_u64() - first.start_address().as_u64()))
+}
+
+/// Unmap the device window area containing `virt`, as returned by
+/// [`map_physical`] or [`map_mmio`].
+pub fn unmap_physical(virt: VirtAddr) -> Result<(), AddressSpaceError> {
+    let (start, end) = x86_64::instructions::interrupts::without_interrupts(|| {
+        KERNEL_WINDOW
+            .lock()
+            .find(virt)
+            .map(|vma| (vma.start, vma.end))
+            .ok_or(AddressSpaceError::NotMapped)
+    })?;
+    // The area stays reserved until its pages are gone from every TLB
+    unmap_window(start, (end - start) / FRAME_SIZE);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        KERNEL_WINDOW.lock().remove(start, end)
+    });
+    Ok(())
+}
+
+/// Unmap `count` pages at `start` of the device window. The frames belong
+/// to devices or their drivers and are left alone.
+fn unmap_window(start: VirtAddr, count: u64) {
+    let first = Page::<Size4KiB>::containing_address(start);
+    with_mapper(|mapper| {
+        for page in Page::range(first, first + count) {
+            if let Ok((_, flush)) = mapper.unmap(page) {
+                flush.ignore();
+            }
+        }
+    });
+    crate::smp::tlb_shootdown(start, count);
+}
+
+/// Map `size` bytes of device memory at `phys` uncached into the device
+/// window.
+///
+/// Returns the virtual address corresponding to `phys`.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, AddressSpaceError> {
+    map_physical(phys, size, crate::mmio::CacheMode::Uncached.page_flags())
+}
+
+/// Virtual range whose pages are backed with zeroed frames on first access
+#[derive(Debug, Clone, Copy)]
+pub struct DemandRegion {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+}
+
+impl DemandRegion {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end
+    }
+}
+
+/// Maximum number of demand regions
+const MAX_DEMAND_REGIONS: usize = 16;
+
+/// Kept out of the heap, which is a demand region itself
+static DEMAND_REGIONS: Mutex<heapless::Vec<DemandRegion, MAX_DEMAND_REGIONS>> =
+    Mutex::new(heapless::Vec::new());
+
+/// Register `size` bytes at `start` to be mapped lazily with `flags`.
+///
+/// Pages already mapped in the range are left untouched. Fails with
+/// `NoSpace` once [`MAX_DEMAND_REGIONS`] are registered.
+pub fn register_demand_region(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), AddressSpaceError> {
+    let region = DemandRegion {
+        start: start.align_down(FRAME_SIZE),
+        end: (start + size).align_up(FRAME_SIZE),
+        flags: flags | PageTableFlags::PRESENT,
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        DEMAND_REGIONS
+            .lock()
+            .push(region)
+            .map_err(|_| AddressSpaceError::NoSpace)
+    })
+}
+
+/// Remove the demand region starting at `start`. Mapped pages stay mapped.
+pub fn unregister_demand_region(start: VirtAddr) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        DEMAND_REGIONS
+            .lock()
+            .retain(|region| region.start != start.align_down(FRAME_SIZE));
+    });
+}
+
+/// Demand region containing `addr`, if any, without waiting for the
+/// region lock. For fault diagnostics, which may run with it held.
+pub fn try_demand_region(addr: VirtAddr) -> Option<DemandRegion> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        DEMAND_REGIONS
+            .try_lock()?
+            .iter()
+            .find(|region| region.contains(addr))
+            .copied()
+    })
+}
+
+/// Demand region containing `addr`, if any
+pub fn demand_region(addr: VirtAddr) -> Option<DemandRegion> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        DEMAND_REGIONS
+            .lock()
+            .iter()
+            .find(|region| region.contains(addr))
+            .copied()
+    })
+}
+
+/// Try to resolve a page fault at `addr`.
+///
+/// Returns `true` if the page was backed with a fresh frame and the faulting
+/// instruction can be restarted. Called from the page fault handler; the
+/// region, mapper and frame allocator locks are only ever held with
+/// interrupts disabled and never across an access to a demand region (the
+/// heap, or a kernel stack below its resident top), so they can be taken
+/// normally. Another CPU holding them just makes this one wait. Nothing
+/// here may touch the heap.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    let region = match demand_region(addr) {
+        Some(region) => region,
+        None => return false,
+    };
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !region.flags.contains(PageTableFlags::WRITABLE)
+    {
+        return false;
+    }
+
+    let frame = match GlobalFrameAllocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    unsafe {
+        core::ptr::write_bytes(
+            phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+            0,
+            FRAME_SIZE as usize,
+        );
+    }
+
+    let page = Page::containing_address(addr);
+    let mapped = with_mapper(|mapper| unsafe {
+        mapper.map_to(page, frame, region.flags, &mut GlobalFrameAllocator)
+    });
+    match mapped {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        // Another CPU faulted on the same page and mapped it first
+        Err(MapToError::PageAlreadyMapped(_)) => {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            true
+        }
+        Err(_) => {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            false
+        }
+    }
+}
+
+/// Make sure the page containing `addr` is mapped, backing it from its
+/// demand region if it isn't yet.
+pub fn ensure_mapped(addr: VirtAddr) -> bool {
+    virt_to_phys(addr).is_some() || handle_page_fault(addr, PageFaultErrorCode::CAUSED_BY_WRITE)
+}
+
 use x86_64::structures::paging::OffsetPageTable;
 
 pub fn create_example_mapping(
@@ -66,3 +736,39 @@ pub fn create_example_mapping(
     let map_to_result = unsafe { mapper.map_to(page, frame, flags, frame_allocator) };
     map_to_result.expect("map_to failed").flush();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_demand_region_fault() {
+        // Level 4 slot no kernel area uses
+        let start = VirtAddr::new(0x_7777_0000_0000);
+        register_demand_region(start, 2 * FRAME_SIZE, PageTableFlags::WRITABLE).unwrap();
+        let page = start + FRAME_SIZE;
+        assert!(virt_to_phys(page).is_none());
+
+        let ptr = page.as_mut_ptr::<u64>();
+        unsafe {
+            assert_eq!(ptr.read_volatile(), 0);
+            ptr.write_volatile(0x1234);
+            assert_eq!(ptr.read_volatile(), 0x1234);
+        }
+        assert!(virt_to_phys(page).is_some());
+        assert!(virt_to_phys(start).is_none());
+
+        unregister_demand_region(start);
+        unmap_pages(Page::containing_address(page), 1);
+    }
+
+    #[test_case]
+    fn test_heap_demand_region() {
+        use crate::allocator::{HEAP_MAX_SIZE, HEAP_START};
+
+        let end = VirtAddr::new((HEAP_START + HEAP_MAX_SIZE) as u64);
+        assert!(demand_region(VirtAddr::new(HEAP_START as u64)).is_some());
+        assert!(demand_region(end - 1u64).is_some());
+        assert!(demand_region(end).is_none());
+    }
+}
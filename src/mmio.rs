@@ -0,0 +1,190 @@
+//! Device memory mappings
+//!
+//! [`ioremap`] maps a physical range, typically a PCI memory BAR, into the
+//! kernel's device window with a caching mode suited to device memory and
+//! returns an [`Mmio`] handle. Registers are accessed through the handle
+//! with volatile, bounds checked reads and writes; the range is unmapped
+//! when the handle is dropped.
+//!
+//! Write-combining relies on the page attribute table, which [`init_pat`]
+//! reprograms on every CPU.
+
+use crate::address_space::AddressSpaceError;
+use crate::memory;
+use core::mem::{align_of, size_of};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+const IA32_PAT: u32 = 0x277;
+
+// Memory types of PAT entries
+const PAT_UC: u64 = 0x00;
+const PAT_WC: u64 = 0x01;
+const PAT_WT: u64 = 0x04;
+const PAT_WB: u64 = 0x06;
+const PAT_UC_MINUS: u64 = 0x07;
+
+/// Power-on layout with entry 2 (PCD alone) turned from UC- into WC.
+/// Entries 4-7 need the PAT bit, which 4 KiB mappings don't use here.
+const PAT_VALUE: u64 = PAT_WB
+    | PAT_WT << 8
+    | PAT_WC << 16
+    | PAT_UC << 24
+    | PAT_WB << 32
+    | PAT_WT << 40
+    | PAT_UC_MINUS << 48
+    | PAT_UC << 56;
+
+/// Program the page attribute table of the calling CPU. Must run on every
+/// CPU before it touches a write-combining mapping, as the entries have to
+/// agree everywhere.
+pub fn init_pat() {
+    let has_pat = raw_cpuid::CpuId::new()
+        .get_feature_info()
+        .map_or(false, |info| info.has_pat());
+    if !has_pat {
+        // PCD alone then selects UC-, which is still correct, only slower
+        return;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        core::arch::asm!("wbinvd", options(nostack));
+        Msr::new(IA32_PAT).write(PAT_VALUE);
+        core::arch::asm!("wbinvd", options(nostack));
+        x86_64::instructions::tlb::flush_all();
+    });
+}
+
+/// Caching of a device mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Strongly uncached, for registers
+    Uncached,
+    /// Reads cached, writes go straight through
+    WriteThrough,
+    /// Uncached, writes buffered and combined, for framebuffers
+    WriteCombining,
+}
+
+impl CacheMode {
+    /// Page table bits selecting the mode's PAT entry
+    pub fn page_flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::WriteCombining => PageTableFlags::NO_CACHE,
+        }
+    }
+}
+
+/// Map `size` bytes of device memory at `phys` with caching `mode`.
+pub fn ioremap(phys: PhysAddr, size: u64, mode: CacheMode) -> Result<Mmio, AddressSpaceError> {
+    if size == 0 {
+        return Err(AddressSpaceError::InvalidAddress);
+    }
+    let base = memory::map_physical(phys, size, mode.page_flags())?;
+    Ok(Mmio {
+        base,
+        size: size as usize,
+        mapped: true,
+    })
+}
+
+/// Width of a device register
+pub trait Register: Copy + private::Sealed {}
+
+impl Register for u8 {}
+impl Register for u16 {}
+impl Register for u32 {}
+impl Register for u64 {}
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for u8 {}
+    impl Sealed for u16 {}
+    impl Sealed for u32 {}
+    impl Sealed for u64 {}
+}
+
+/// Mapped device memory
+pub struct Mmio {
+    base: VirtAddr,
+    size: usize,
+    /// Whether the range is ours to unmap
+    mapped: bool,
+}
+
+impl Mmio {
+    /// Handle for `size` bytes at `base` that are already accessible, e.g.
+    /// registers emulated in ordinary memory. The range is not unmapped on
+    /// drop.
+    ///
+    /// # Safety
+    /// The range must stay valid for reads and writes while the handle
+    /// lives.
+    pub unsafe fn from_raw(base: *mut u8, size: usize) -> Self {
+        Mmio {
+            base: VirtAddr::from_ptr(base),
+            size,
+            mapped: false,
+        }
+    }
+
+    /// Size of the range in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.base.as_mut_ptr()
+    }
+
+    /// Read the register at byte `offset`.
+    ///
+    /// Panics if the register is misaligned or not inside the range.
+    pub fn read<T: Register>(&self, offset: usize) -> T {
+        unsafe { self.register::<T>(offset).read_volatile() }
+    }
+
+    /// Write `value` to the register at byte `offset`.
+    ///
+    /// Panics if the register is misaligned or not inside the range.
+    pub fn write<T: Register>(&self, offset: usize, value: T) {
+        unsafe { self.register::<T>(offset).write_volatile(value) }
+    }
+
+    /// Replace the register at byte `offset` with `f` of its value.
+    pub fn update<T: Register>(&self, offset: usize, f: impl FnOnce(T) -> T) {
+        self.write(offset, f(self.read(offset)));
+    }
+
+    /// Keep the mapping for good and return it as plain memory, for
+    /// memory-like devices such as framebuffers.
+    pub fn leak(self) -> &'static mut [u8] {
+        let memory = unsafe { core::slice::from_raw_parts_mut(self.as_mut_ptr(), self.size) };
+        core::mem::forget(self);
+        memory
+    }
+
+    fn register<T: Register>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset % align_of::<T>() == 0
+                && offset
+                    .checked_add(size_of::<T>())
+                    .is_some_and(|end| end <= self.size),
+            "register {:#x} outside of MMIO range of {:#x} bytes",
+            offset,
+            self.size
+        );
+        (self.base + offset as u64).as_mut_ptr()
+    }
+}
+
+impl Drop for Mmio {
+    fn drop(&mut self) {
+        if self.mapped {
+            let _ = memory::unmap_physical(self.base);
+        }
+    }
+}
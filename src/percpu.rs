@@ -0,0 +1,144 @@
+//! Per-CPU data
+//!
+//! Every processor owns a [`PerCpu`] block whose address is loaded into its
+//! GS base, so the running CPU finds its own data with a single `gs:` load
+//! and no lock.
+//!
+//! That holds while the CPU runs kernel code. User programs get a GS base
+//! of their own, which they can change, and the block's address waits in
+//! IA32_KERNEL_GS_BASE meanwhile: every entry from ring 3 (system calls,
+//! exceptions, interrupts) starts with `swapgs` and every return to ring 3
+//! ends with one.
+
+use crate::address_space::AddressSpace;
+use core::arch::asm;
+use core::mem::offset_of;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use x86_64::registers::model_specific::GsBase;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+/// Offsets of the fields the assembly entry stubs access through `gs:`
+pub(crate) const USER_RSP_OFFSET: usize = 8;
+pub(crate) const KERNEL_RSP_OFFSET: usize = 16;
+pub(crate) const TSS_RSP0_OFFSET: usize = 24;
+
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of the block itself; must stay the first field, `current`
+    /// reads it through `gs:[0]`
+    self_ptr: AtomicU64,
+    /// User stack pointer stashed by the syscall entry stub
+    user_rsp: AtomicU64,
+    /// Kernel stack pointer for traps from ring 3 on this CPU
+    kernel_rsp: AtomicU64,
+    /// Address of the RSP0 slot in this CPU's TSS
+    tss_rsp0: AtomicU64,
+    /// Logical CPU number, 0 is the bootstrap processor
+    pub id: usize,
+    pub apic_id: u8,
+    pub(crate) online: AtomicBool,
+    /// Set by the initiator of a TLB shootdown until this CPU has flushed
+    pub(crate) tlb_flush_pending: AtomicBool,
+    /// User address space loaded on this CPU, null for the kernel tables
+    pub(crate) address_space: AtomicPtr<AddressSpace>,
+}
+
+const _: () = {
+    assert!(offset_of!(PerCpu, user_rsp) == USER_RSP_OFFSET);
+    assert!(offset_of!(PerCpu, kernel_rsp) == KERNEL_RSP_OFFSET);
+    assert!(offset_of!(PerCpu, tss_rsp0) == TSS_RSP0_OFFSET);
+};
+
+impl PerCpu {
+    pub(crate) fn new(id: usize, apic_id: u8) -> Self {
+        PerCpu {
+            self_ptr: AtomicU64::new(0),
+            user_rsp: AtomicU64::new(0),
+            kernel_rsp: AtomicU64::new(0),
+            tss_rsp0: AtomicU64::new(0),
+            id,
+            apic_id,
+            online: AtomicBool::new(false),
+            tlb_flush_pending: AtomicBool::new(false),
+            address_space: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    /// Record the TSS loaded on this CPU.
+    pub(crate) fn set_tss(&self, tss: *mut TaskStateSegment) {
+        let rsp0 = unsafe { addr_of_mut!((*tss).privilege_stack_table[0]) };
+        self.tss_rsp0.store(rsp0 as u64, Ordering::Relaxed);
+    }
+
+    /// Stack pointer traps from ring 3 currently switch to
+    pub(crate) fn kernel_stack(&self) -> u64 {
+        self.kernel_rsp.load(Ordering::Relaxed)
+    }
+
+    /// Switch the stack used for syscalls and interrupts from ring 3.
+    pub(crate) fn set_kernel_stack(&self, rsp: u64) {
+        self.kernel_rsp.store(rsp, Ordering::Relaxed);
+        let rsp0 = self.tss_rsp0.load(Ordering::Relaxed) as *mut u64;
+        if !rsp0.is_null() {
+            // The TSS is packed, so the slot is only 4-byte aligned
+            unsafe { rsp0.write_unaligned(rsp) };
+        }
+    }
+}
+
+lazy_static! {
+    static ref BSP: PerCpu = PerCpu::new(0, initial_apic_id());
+}
+
+/// APIC ID of the executing processor as reported by CPUID
+pub fn initial_apic_id() -> u8 {
+    raw_cpuid::CpuId::new()
+        .get_feature_info()
+        .map_or(0, |info| info.initial_local_apic_id())
+}
+
+/// Point the GS base of the executing processor at `cpu`.
+pub(crate) fn install(cpu: &'static PerCpu) {
+    let addr = VirtAddr::from_ptr(cpu);
+    cpu.self_ptr.store(addr.as_u64(), Ordering::Relaxed);
+    GsBase::write(addr);
+}
+
+/// Install the bootstrap processor's block.
+///
+/// Must run before anything calls [`current`].
+pub fn init_bsp() {
+    install(&BSP);
+    BSP.online.store(true, Ordering::Release);
+}
+
+/// Block of the bootstrap processor
+pub fn bsp() -> &'static PerCpu {
+    &BSP
+}
+
+/// Whether the executing processor is the bootstrap processor
+pub fn is_bsp() -> bool {
+    core::ptr::eq(current(), &*BSP)
+}
+
+/// Block of the executing processor
+pub fn current() -> &'static PerCpu {
+    let ptr: u64;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, readonly, preserves_flags));
+        &*(ptr as *const PerCpu)
+    }
+}
+
+/// Logical number of the executing processor
+pub fn cpu_id() -> usize {
+    current().id
+}
@@ -0,0 +1,353 @@
+//! User processes
+//!
+//! A process is a user program run by one thread, with its own address space
+//! and file descriptor table. Processes form a tree: an exiting process
+//! frees its memory and descriptors right away and stays a zombie holding
+//! the exit status until its parent collects it with [`wait`]. Children of
+//! an exiting process are handed to init.
+
+use crate::address_space::AddressSpace;
+use crate::elf::{self, ElfError, ElfImage};
+use crate::fd::FdTable;
+use crate::initrd;
+use crate::scheduler;
+use crate::sync::{Condvar, Mutex};
+use crate::syscall::SyscallFrame;
+use crate::thread::{self, ThreadError};
+use crate::usermode::{self, UserExit};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    /// The first process started, which inherits orphans
+    pub const INIT: Pid = Pid(1);
+
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn from_u64(pid: u64) -> Self {
+        Pid(pid)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// How a process ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// Called `exit` with this code
+    Exited(i32),
+    /// Killed by the exception with this vector
+    Faulted(u8),
+}
+
+impl ExitStatus {
+    /// Status word as `wait` reports it, encoded like Linux: the exit code
+    /// in bits 8-15, or the number of the signal a Unix kernel would have
+    /// sent for the exception in bits 0-6
+    pub fn wait_status(&self) -> u32 {
+        const SIGILL: u32 = 4;
+        const SIGTRAP: u32 = 5;
+        const SIGBUS: u32 = 7;
+        const SIGFPE: u32 = 8;
+        const SIGSEGV: u32 = 11;
+        match *self {
+            ExitStatus::Exited(code) => (code as u32 & 0xFF) << 8,
+            ExitStatus::Faulted(vector) => match vector {
+                0 | 16 | 19 => SIGFPE,
+                1 | 3 => SIGTRAP,
+                6 => SIGILL,
+                17 => SIGBUS,
+                _ => SIGSEGV,
+            },
+        }
+    }
+}
+
+impl From<UserExit> for ExitStatus {
+    fn from(exit: UserExit) -> Self {
+        match exit {
+            UserExit::Exited(code) => ExitStatus::Exited(code as i32),
+            UserExit::Fault(vector) => ExitStatus::Faulted(vector),
+        }
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitStatus::Exited(code) => write!(f, "exited with status {}", code),
+            ExitStatus::Faulted(vector) => write!(f, "killed by exception {}", vector),
+        }
+    }
+}
+
+struct Process {
+    name: String,
+    parent: Option<Pid>,
+    fds: FdTable,
+    /// Set once the process has exited
+    exit: Option<ExitStatus>,
+}
+
+static PROCESSES: Mutex<BTreeMap<Pid, Process>> = Mutex::new(BTreeMap::new());
+/// Notified whenever a process exits
+static CHILD_EXITED: Condvar = Condvar::new();
+
+/// Start the program at `path` in the initrd as a new process.
+///
+/// The new process is a child of the calling process, if any.
+pub fn spawn(path: &str, argv: &[&str], envp: &[&str]) -> Result<Pid, ProcessError> {
+    let data = initrd::read(path).ok_or(ProcessError::NotFound)?;
+    let (space, context) = load(data, argv, envp)?;
+    let pid = Pid::new();
+    let name = String::from(program_name(path));
+    PROCESSES.lock().insert(
+        pid,
+        Process {
+            name: name.clone(),
+            parent: thread::current_process(),
+            fds: FdTable::with_console(),
+            exit: None,
+        },
+    );
+    start(pid, &name, space, context)?;
+    Ok(pid)
+}
+
+/// Duplicate the calling process. The child resumes from the same system
+/// call with the registers in `frame` and a return value of 0.
+pub fn fork(frame: &SyscallFrame) -> Result<Pid, ProcessError> {
+    let parent = current()?;
+    let space = thread::current_address_space()
+        .ok_or(ProcessError::NotAProcess)?
+        .duplicate()
+        .map_err(|_| ProcessError::OutOfMemory)?;
+
+    let pid = Pid::new();
+    let name = {
+        let mut processes = PROCESSES.lock();
+        let process = processes.get(&parent).ok_or(ProcessError::NotAProcess)?;
+        let child = Process {
+            name: process.name.clone(),
+            parent: Some(parent),
+            fds: process.fds.clone(),
+            exit: None,
+        };
+        let name = child.name.clone();
+        processes.insert(pid, child);
+        name
+    };
+
+    let mut context = *frame;
+    context.rax = 0;
+    start(pid, &name, space, context)?;
+    Ok(pid)
+}
+
+/// Replace the program of the calling process with the one at `path`.
+///
+/// On success `frame` holds the initial context of the new program, so the
+/// system call "returns" into it; on failure the caller is left untouched.
+pub fn exec(
+    frame: &mut SyscallFrame,
+    path: &str,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<(), ProcessError> {
+    let pid = current()?;
+    let data = initrd::read(path).ok_or(ProcessError::NotFound)?;
+    let (space, context) = load(data, argv, envp)?;
+
+    // Nothing can fail from here on. The old address space is freed as soon
+    // as it is no longer loaded.
+    drop(scheduler::replace_address_space(Some(space.into_shared())));
+    *frame = context;
+    if let Some(process) = PROCESSES.lock().get_mut(&pid) {
+        process.name = String::from(program_name(path));
+    }
+    Ok(())
+}
+
+/// Wait for a child to exit and reap it.
+///
+/// Waits for `pid` only, or for any child if `None`. Returns `None` without
+/// blocking if `block` is false and no child has exited yet.
+pub fn wait(pid: Option<Pid>, block: bool) -> Result<Option<(Pid, ExitStatus)>, ProcessError> {
+    let me = current()?;
+    let mut processes = PROCESSES.lock();
+    loop {
+        let mut found = false;
+        let mut exited = None;
+        for (&child, process) in processes.iter() {
+            if process.parent != Some(me) || pid.is_some_and(|pid| pid != child) {
+                continue;
+            }
+            found = true;
+            if let Some(status) = process.exit {
+                exited = Some((child, status));
+                break;
+            }
+        }
+        if !found {
+            return Err(ProcessError::NoChild);
+        }
+        if let Some((child, status)) = exited {
+            processes.remove(&child);
+            return Ok(Some((child, status)));
+        }
+        if !block {
+            return Ok(None);
+        }
+        processes = CHILD_EXITED.wait(processes);
+    }
+}
+
+/// Process of the calling thread
+pub fn current() -> Result<Pid, ProcessError> {
+    thread::current_process().ok_or(ProcessError::NotAProcess)
+}
+
+/// Parent of `pid`, `None` for orphans and init
+pub fn parent(pid: Pid) -> Option<Pid> {
+    PROCESSES
+        .lock()
+        .get(&pid)
+        .and_then(|process| process.parent)
+}
+
+/// Run `f` on the descriptor table of the calling process.
+pub fn with_fds<R>(f: impl FnOnce(&mut FdTable) -> R) -> Result<R, ProcessError> {
+    let pid = current()?;
+    let mut processes = PROCESSES.lock();
+    let process = processes.get_mut(&pid).ok_or(ProcessError::NotAProcess)?;
+    Ok(f(&mut process.fds))
+}
+
+/// Build the address space and initial context of the program in `data`.
+fn load(
+    data: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<(AddressSpace, SyscallFrame), ProcessError> {
+    let image = ElfImage::parse(data)?;
+    let space = AddressSpace::new().map_err(ElfError::from)?;
+    image.load(&space)?;
+    let stack = elf::setup_stack(&space, &image, argv, envp)?;
+    let context = SyscallFrame::new(image.entry().as_u64(), stack.as_u64(), 0);
+    Ok((space, context))
+}
+
+/// Start the thread of process `pid`, which is already in the table.
+fn start(
+    pid: Pid,
+    name: &str,
+    space: AddressSpace,
+    context: SyscallFrame,
+) -> Result<(), ProcessError> {
+    let result = thread::spawn_user(name, pid, space.into_shared(), move || {
+        let status = match usermode::run_context(&context) {
+            Ok(exit) => ExitStatus::from(exit),
+            Err(e) => {
+                log::warn!("Process {}: {}", pid, e);
+                ExitStatus::Exited(-1)
+            }
+        };
+        exit_current(status);
+    });
+    if let Err(e) = result {
+        PROCESSES.lock().remove(&pid);
+        return Err(ProcessError::Thread(e));
+    }
+    Ok(())
+}
+
+/// Turn the calling process into a zombie holding `status`. Called by the
+/// process thread once its program has ended.
+fn exit_current(status: ExitStatus) {
+    let pid = current().expect("exit outside a process");
+    // Only the status outlives the process; its memory goes right away
+    drop(scheduler::replace_address_space(None));
+
+    {
+        let mut processes = PROCESSES.lock();
+        let heir = (pid != Pid::INIT && processes.contains_key(&Pid::INIT)).then_some(Pid::INIT);
+        let mut orphaned_zombies = alloc::vec::Vec::new();
+        for (&child, process) in processes.iter_mut() {
+            if process.parent == Some(pid) {
+                process.parent = heir;
+                if heir.is_none() && process.exit.is_some() {
+                    orphaned_zombies.push(child);
+                }
+            }
+        }
+        for child in orphaned_zombies {
+            processes.remove(&child);
+        }
+
+        let process = processes.get_mut(&pid).expect("current process missing");
+        process.fds.clear();
+        process.exit = Some(status);
+        let parent_alive = process
+            .parent
+            .is_some_and(|parent| processes.contains_key(&parent));
+        if !parent_alive {
+            // Nobody is left to collect the status
+            let process = processes.remove(&pid).unwrap();
+            log::info!("Process {} ({}) {}", pid, process.name, status);
+        }
+    }
+    CHILD_EXITED.notify_all();
+}
+
+fn program_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Process errors
+#[derive(Debug, Clone, Copy)]
+pub enum ProcessError {
+    /// No such program in the initrd
+    NotFound,
+    Exec(ElfError),
+    OutOfMemory,
+    /// No child matches the request
+    NoChild,
+    /// Called from a kernel thread
+    NotAProcess,
+    Thread(ThreadError),
+}
+
+impl From<ElfError> for ProcessError {
+    fn from(e: ElfError) -> Self {
+        ProcessError::Exec(e)
+    }
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessError::NotFound => write!(f, "Program not found"),
+            ProcessError::Exec(e) => write!(f, "{}", e),
+            ProcessError::OutOfMemory => write!(f, "Out of memory for the process"),
+            ProcessError::NoChild => write!(f, "No child process"),
+            ProcessError::NotAProcess => write!(f, "Not called from a process"),
+            ProcessError::Thread(e) => write!(f, "{}", e),
+        }
+    }
+}
@@ -0,0 +1,462 @@
+//! Preemptive priority round-robin scheduler
+//!
+//! The highest priority level with a ready thread always runs; threads of
+//! the same level share the CPU in time slices of [`TIME_SLICE_TICKS`].
+//! Switches happen either voluntarily (`yield`, `sleep`, blocking) or on the
+//! way out of an interrupt handler once [`need_resched`] is set.
+//!
+//! Threads only run on the bootstrap processor: there is one `current`
+//! thread and one idle thread, and application processors stay in the idle
+//! loop of `smp::ap_entry`. [`schedule`] returns right away on an AP, so an
+//! interrupt handled there can't switch the BSP's thread out from under it.
+
+use crate::address_space::AddressSpace;
+use crate::process::Pid;
+use crate::thread::{Priority, Thread, ThreadId, ThreadState, PRIORITY_LEVELS};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use x86_64::VirtAddr;
+
+/// Timer ticks a thread may run before others of its priority get a turn
+pub const TIME_SLICE_TICKS: u32 = 10;
+
+// Save the callee-saved registers on the old stack, store its stack pointer
+// to `*old_rsp` and resume the thread whose stack pointer is `new_rsp`.
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+);
+
+extern "C" {
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+}
+
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    ready: [VecDeque<ThreadId>; PRIORITY_LEVELS],
+    /// Thread running on the BSP
+    current: ThreadId,
+    /// Runs on the BSP when no other thread is ready
+    idle: ThreadId,
+    slice_left: u32,
+}
+
+lazy_static! {
+    static ref SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+}
+
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+/// Adopt the running code as the boot thread and create the idle thread.
+pub fn init() {
+    let idle = Thread::new("idle", Priority::Idle, Box::new(idle_loop))
+        .expect("failed to create idle thread");
+    let idle_id = idle.id;
+
+    let mut threads = BTreeMap::new();
+    threads.insert(ThreadId::BOOT, Box::new(Thread::boot()));
+    threads.insert(idle_id, Box::new(idle));
+
+    without_interrupts(|| {
+        *SCHEDULER.lock() = Some(Scheduler {
+            threads,
+            ready: Default::default(),
+            current: ThreadId::BOOT,
+            idle: idle_id,
+            slice_left: TIME_SLICE_TICKS,
+        });
+    });
+}
+
+fn idle_loop() {
+    loop {
+        interrupts::enable_and_hlt();
+    }
+}
+
+pub fn is_running() -> bool {
+    without_interrupts(|| SCHEDULER.lock().is_some())
+}
+
+/// Whether the interrupted thread should be switched out before returning
+pub fn need_resched() -> bool {
+    NEED_RESCHED.load(Ordering::Relaxed)
+}
+
+/// Build the initial stack of a new thread so `switch_context` "returns"
+/// into [`thread_start`]. Returns the stack pointer to resume from.
+pub(crate) fn prepare_stack(top: VirtAddr) -> u64 {
+    let mut sp = top.as_u64() as *mut u64;
+    unsafe {
+        // Fake return address of thread_start; keeps the ABI stack alignment
+        sp = sp.sub(1);
+        sp.write(0);
+        sp = sp.sub(1);
+        sp.write(thread_start as usize as u64);
+        // rbx, rbp, r12-r15
+        for _ in 0..6 {
+            sp = sp.sub(1);
+            sp.write(0);
+        }
+    }
+    sp as u64
+}
+
+extern "C" fn thread_start() -> ! {
+    let entry = without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let scheduler = guard.as_mut().expect("scheduler not running");
+        let current = scheduler.current;
+        scheduler
+            .threads
+            .get_mut(&current)
+            .and_then(|t| t.entry.take())
+    });
+    // Threads are entered from `schedule` with interrupts disabled
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit_current()
+}
+
+impl Scheduler {
+    fn current_mut(&mut self) -> &mut Thread {
+        self.threads
+            .get_mut(&self.current)
+            .expect("current thread missing")
+    }
+
+    fn enqueue(&mut self, id: ThreadId) {
+        if id == self.idle {
+            return;
+        }
+        let current_priority = self.threads[&self.current].priority;
+        if let Some(thread) = self.threads.get(&id) {
+            self.ready[thread.priority as usize].push_back(id);
+            if thread.priority > current_priority || self.current == self.idle {
+                NEED_RESCHED.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn wake(&mut self, id: ThreadId) {
+        if let Some(thread) = self.threads.get_mut(&id) {
+            if matches!(thread.state, ThreadState::Blocked | ThreadState::Sleeping) {
+                thread.state = ThreadState::Ready;
+                self.enqueue(id);
+            }
+        }
+    }
+
+    /// Threads that have exited and are no longer running on their stack
+    fn reap(&mut self) -> Vec<Box<Thread>> {
+        let current = self.current;
+        let dead: Vec<ThreadId> = self
+            .threads
+            .values()
+            .filter(|t| t.state == ThreadState::Exited && t.id != current)
+            .map(|t| t.id)
+            .collect();
+        dead.iter()
+            .filter_map(|id| self.threads.remove(id))
+            .collect()
+    }
+
+    fn next_ready(&mut self) -> ThreadId {
+        for level in (0..PRIORITY_LEVELS).rev() {
+            while let Some(id) = self.ready[level].pop_front() {
+                if self.threads.get(&id).map(|t| t.state) == Some(ThreadState::Ready) {
+                    return id;
+                }
+            }
+        }
+        self.idle
+    }
+
+    /// Pick the thread to run next. Returns the stack pointer slots to
+    /// switch between, or `None` if the current thread keeps running.
+    fn pick_next(&mut self) -> Option<(*mut u64, u64)> {
+        NEED_RESCHED.store(false, Ordering::Relaxed);
+        let current = self.current;
+        if self.current_mut().state == ThreadState::Running {
+            self.current_mut().state = ThreadState::Ready;
+            self.enqueue(current);
+            NEED_RESCHED.store(false, Ordering::Relaxed);
+        }
+
+        let next = self.next_ready();
+        self.slice_left = TIME_SLICE_TICKS;
+        self.threads.get_mut(&next).unwrap().state = ThreadState::Running;
+        if next == current {
+            return None;
+        }
+
+        // The trap stack of a thread in user mode is part of its context
+        let cpu = crate::percpu::current();
+        self.threads.get_mut(&current).unwrap().kernel_rsp = cpu.kernel_stack();
+        cpu.set_kernel_stack(self.threads[&next].kernel_rsp);
+        match &self.threads[&next].address_space {
+            Some(space) => unsafe { space.activate() },
+            None => unsafe { crate::address_space::activate_kernel() },
+        }
+
+        self.current = next;
+        let old_rsp = &mut self.threads.get_mut(&current).unwrap().rsp as *mut u64;
+        let new_rsp = self.threads[&next].rsp;
+        Some((old_rsp, new_rsp))
+    }
+}
+
+/// Switch to the next ready thread, if any.
+///
+/// The current thread is requeued unless it blocked, went to sleep or exited.
+/// Does nothing on an application processor, which never runs threads.
+pub fn schedule() {
+    if !crate::percpu::is_bsp() {
+        return;
+    }
+    without_interrupts(|| {
+        let (switch, dead) = {
+            let mut guard = SCHEDULER.lock();
+            let scheduler = match guard.as_mut() {
+                Some(scheduler) => scheduler,
+                None => return,
+            };
+            let dead = scheduler.reap();
+            (scheduler.pick_next(), dead)
+        };
+        // Stacks are unmapped outside the scheduler lock
+        drop(dead);
+
+        if let Some((old_rsp, new_rsp)) = switch {
+            // Thread objects are boxed, so the slot stays valid after the
+            // lock is released; nothing else touches it until we're switched out
+            unsafe { switch_context(old_rsp, new_rsp) };
+        }
+    });
+}
+
+/// Account a timer tick: wake sleepers and expire the time slice.
+///
+/// Called from the timer interrupt.
+pub(crate) fn tick() {
+    let mut guard = match SCHEDULER.try_lock() {
+        Some(guard) => guard,
+        None => return,
+    };
+    let scheduler = match guard.as_mut() {
+        Some(scheduler) => scheduler,
+        None => return,
+    };
+
+    let now = crate::time::now_ns();
+    let expired: Vec<ThreadId> = scheduler
+        .threads
+        .values()
+        .filter(|t| t.state == ThreadState::Sleeping && t.wake_at <= now)
+        .map(|t| t.id)
+        .collect();
+    for id in expired {
+        scheduler.wake(id);
+    }
+
+    scheduler.slice_left = scheduler.slice_left.saturating_sub(1);
+    if scheduler.slice_left == 0 {
+        NEED_RESCHED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Add a newly created thread to the ready queues.
+pub(crate) fn add_thread(thread: Thread) {
+    without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let scheduler = guard.as_mut().expect("scheduler not running");
+        let id = thread.id;
+        scheduler.threads.insert(id, Box::new(thread));
+        scheduler.enqueue(id);
+    });
+}
+
+/// ID of the running thread
+pub fn current_id() -> ThreadId {
+    without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_ref()
+            .map_or(ThreadId::BOOT, |scheduler| scheduler.current)
+    })
+}
+
+/// Address space of the running thread
+pub fn current_address_space() -> Option<Arc<AddressSpace>> {
+    without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_ref()
+            .and_then(|scheduler| scheduler.threads[&scheduler.current].address_space.clone())
+    })
+}
+
+/// Process of the running thread
+pub fn current_process() -> Option<Pid> {
+    without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_ref()
+            .and_then(|scheduler| scheduler.threads[&scheduler.current].process)
+    })
+}
+
+/// Give the running thread `space` (the kernel page table for `None`), load
+/// it and return the one it had.
+pub fn replace_address_space(space: Option<Arc<AddressSpace>>) -> Option<Arc<AddressSpace>> {
+    without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let scheduler = guard.as_mut().expect("scheduler not running");
+        let thread = scheduler.current_mut();
+        match &space {
+            Some(space) => unsafe { space.activate() },
+            None => unsafe { crate::address_space::activate_kernel() },
+        }
+        core::mem::replace(&mut thread.address_space, space)
+    })
+}
+
+/// Block the current thread until `deadline_ns` on the monotonic clock.
+///
+/// Panics on an application processor, which never runs threads.
+pub fn sleep_until(deadline_ns: u64) {
+    assert!(crate::percpu::is_bsp(), "sleep_until called on an AP");
+    without_interrupts(|| {
+        {
+            let mut guard = SCHEDULER.lock();
+            let scheduler = match guard.as_mut() {
+                Some(scheduler) => scheduler,
+                None => return,
+            };
+            let current = scheduler.current_mut();
+            current.state = ThreadState::Sleeping;
+            current.wake_at = deadline_ns;
+        }
+        schedule();
+    });
+}
+
+/// Mark the current thread blocked after running `register` with its ID
+/// under the scheduler lock, then switch away.
+///
+/// `register` must make sure someone calls [`unblock`] later. Returns once
+/// the thread has been woken. Panics on an application processor, which
+/// never runs threads.
+pub fn block_current(register: impl FnOnce(ThreadId)) {
+    assert!(crate::percpu::is_bsp(), "block_current called on an AP");
+    without_interrupts(|| {
+        {
+            let mut guard = SCHEDULER.lock();
+            let scheduler = guard.as_mut().expect("scheduler not running");
+            let current = scheduler.current;
+            register(current);
+            scheduler.current_mut().state = ThreadState::Blocked;
+        }
+        schedule();
+    });
+}
+
+/// Make a blocked or sleeping thread ready again.
+pub fn unblock(id: ThreadId) {
+    without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            scheduler.wake(id);
+        }
+    });
+}
+
+/// Block until thread `id` has exited.
+pub fn wait_for_exit(id: ThreadId) {
+    without_interrupts(|| loop {
+        let exited = {
+            let mut guard = SCHEDULER.lock();
+            let scheduler = guard.as_mut().expect("scheduler not running");
+            let current = scheduler.current;
+            match scheduler.threads.get_mut(&id) {
+                Some(thread) if thread.state != ThreadState::Exited => {
+                    thread.joiners.push(current);
+                    scheduler.current_mut().state = ThreadState::Blocked;
+                    false
+                }
+                _ => true,
+            }
+        };
+        if exited {
+            break;
+        }
+        schedule();
+    });
+}
+
+/// Terminate the current thread and wake everyone joining it.
+pub fn exit_current() -> ! {
+    interrupts::disable();
+    {
+        let mut guard = SCHEDULER.lock();
+        let scheduler = guard.as_mut().expect("scheduler not running");
+        let current = scheduler.current_mut();
+        current.state = ThreadState::Exited;
+        let joiners = core::mem::take(&mut current.joiners);
+        for id in joiners {
+            scheduler.wake(id);
+        }
+    }
+    schedule();
+    unreachable!("exited thread was scheduled again");
+}
+
+/// Snapshot of a thread for diagnostics
+#[derive(Debug, Clone)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: alloc::string::String,
+    pub state: ThreadState,
+    pub priority: Priority,
+}
+
+/// All threads known to the scheduler
+pub fn threads() -> Vec<ThreadInfo> {
+    without_interrupts(|| {
+        SCHEDULER.lock().as_ref().map_or(Vec::new(), |scheduler| {
+            scheduler
+                .threads
+                .values()
+                .map(|t| ThreadInfo {
+                    id: t.id,
+                    name: t.name.clone(),
+                    state: t.state,
+                    priority: t.priority,
+                })
+                .collect()
+        })
+    })
+}
@@ -1,30 +1,69 @@
 use lazy_static::lazy_static;
-use spin::Mutex;
+use crate::sync::IrqSpinLock;
 use uart_16550::SerialPort;
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    pub static ref SERIAL1: IrqSpinLock<SerialPort> = {
         let mut serial_port = unsafe { SerialPort::new(0x3F8) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqSpinLock::new(serial_port)
     };
 }
 
 pub fn init() {
     let _ = &*SERIAL1;
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(log::LevelFilter::Info);
+    }
+}
+
+/// `log` backend writing timestamped lines to the serial port
+struct SerialLogger;
+
+static LOGGER: SerialLogger = SerialLogger;
+
+impl log::Log for SerialLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let millis = crate::time::unix_time_ns() / 1_000_000 % 1000;
+        crate::serial_println!(
+            "[{}.{:03}] {:5} {}: {}",
+            crate::time::wall_clock(),
+            millis,
+            record.level(),
+            record.target(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+/// Send raw bytes, e.g. output of user programs that may not be UTF-8.
+pub fn write_bytes(bytes: &[u8]) {
+    // Keep the time spent with interrupts disabled short
+    for chunk in bytes.chunks(64) {
+        let mut port = SERIAL1.lock();
+        for &byte in chunk {
+            port.send(byte);
+        }
+    }
 }
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-
-    interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    });
+
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("Printing to serial failed");
 }
 
 #[macro_export]
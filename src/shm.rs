@@ -0,0 +1,179 @@
+//! Shared memory regions
+//!
+//! A region is a set of zeroed frames that any number of processes can map
+//! into their address spaces, System V style: [`get`] looks a region up by
+//! key or creates it, [`attach`] maps it into the calling process and
+//! [`detach`] unmaps it again. Regions stay mapped across `fork`. A region
+//! removed with [`remove`] disappears from the registry at once and its
+//! memory is freed after the last process detaches it.
+
+use crate::address_space::{AddressSpaceError, Placement};
+use crate::memory::{self, GlobalFrameAllocator};
+use crate::sync::Mutex;
+use crate::thread;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{FrameAllocator, PhysFrame};
+use x86_64::VirtAddr;
+
+/// Key for a region that [`get`] never finds again
+pub const PRIVATE: u64 = 0;
+/// Largest region size
+pub const MAX_SIZE: u64 = 64 * 1024 * 1024;
+/// Where regions attached without an address are placed
+pub const ATTACH_START: u64 = 0x_2000_0000_0000;
+
+/// Memory of a shared region
+pub struct SharedMemory {
+    frames: Vec<PhysFrame>,
+}
+
+impl SharedMemory {
+    /// Zeroed region of `size` bytes, rounded up to whole frames.
+    fn new(size: u64) -> Result<Self, ShmError> {
+        let count = size.div_ceil(memory::FRAME_SIZE) as usize;
+        let mut region = SharedMemory {
+            frames: Vec::with_capacity(count),
+        };
+        for _ in 0..count {
+            let frame = GlobalFrameAllocator
+                .allocate_frame()
+                .ok_or(ShmError::OutOfMemory)?;
+            unsafe {
+                memory::phys_to_virt(frame.start_address())
+                    .as_mut_ptr::<u8>()
+                    .write_bytes(0, memory::FRAME_SIZE as usize);
+            }
+            region.frames.push(frame);
+        }
+        Ok(region)
+    }
+
+    pub fn frames(&self) -> &[PhysFrame] {
+        &self.frames
+    }
+
+    /// Size in bytes
+    pub fn size(&self) -> u64 {
+        self.frames.len() as u64 * memory::FRAME_SIZE
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        for &frame in &self.frames {
+            // Mappings still holding the frame keep it alive
+            unsafe { memory::release_frame(frame) };
+        }
+    }
+}
+
+struct Region {
+    key: u64,
+    memory: Arc<SharedMemory>,
+}
+
+static REGIONS: Mutex<BTreeMap<u64, Region>> = Mutex::new(BTreeMap::new());
+/// Ids are never reused, so a stale id can't reach a newer region
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Id of the region with `key`, creating a `size` byte region if there is
+/// none and `create` is set. [`PRIVATE`] always creates a new region.
+pub fn get(key: u64, size: u64, create: bool) -> Result<u64, ShmError> {
+    let mut regions = REGIONS.lock();
+    if key != PRIVATE {
+        if let Some((&id, region)) = regions.iter().find(|(_, region)| region.key == key) {
+            if size > region.memory.size() {
+                return Err(ShmError::TooLarge);
+            }
+            return Ok(id);
+        }
+        if !create {
+            return Err(ShmError::NotFound);
+        }
+    }
+    if size == 0 {
+        return Err(ShmError::InvalidSize);
+    }
+    if size > MAX_SIZE {
+        return Err(ShmError::TooLarge);
+    }
+
+    let memory = Arc::new(SharedMemory::new(size)?);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    regions.insert(id, Region { key, memory });
+    Ok(id)
+}
+
+/// Unnamed region of `size` bytes, for shared anonymous mappings
+pub fn anonymous(size: u64) -> Result<Arc<SharedMemory>, ShmError> {
+    if size == 0 || size > MAX_SIZE {
+        return Err(ShmError::InvalidSize);
+    }
+    Ok(Arc::new(SharedMemory::new(size)?))
+}
+
+/// Map region `id` into the calling process at `addr`, or wherever there is
+/// room if `None`. Returns the address it was mapped at.
+pub fn attach(id: u64, addr: Option<VirtAddr>) -> Result<VirtAddr, ShmError> {
+    let memory = REGIONS
+        .lock()
+        .get(&id)
+        .map(|region| region.memory.clone())
+        .ok_or(ShmError::NotFound)?;
+    let space = thread::current_address_space().ok_or(ShmError::NotAProcess)?;
+    let placement = addr.map_or(
+        Placement::Anywhere(VirtAddr::new(ATTACH_START)),
+        Placement::FixedNoReplace,
+    );
+    Ok(space.attach(placement, memory)?)
+}
+
+/// Unmap the region the calling process attached at `addr`.
+pub fn detach(addr: VirtAddr) -> Result<(), ShmError> {
+    let space = thread::current_address_space().ok_or(ShmError::NotAProcess)?;
+    Ok(space.detach(addr)?)
+}
+
+/// Remove region `id` from the registry.
+pub fn remove(id: u64) -> Result<(), ShmError> {
+    REGIONS
+        .lock()
+        .remove(&id)
+        .map(drop)
+        .ok_or(ShmError::NotFound)
+}
+
+/// Shared memory errors
+#[derive(Debug, Clone, Copy)]
+pub enum ShmError {
+    NotFound,
+    InvalidSize,
+    TooLarge,
+    OutOfMemory,
+    /// Called from a kernel thread
+    NotAProcess,
+    AddressSpace(AddressSpaceError),
+}
+
+impl From<AddressSpaceError> for ShmError {
+    fn from(e: AddressSpaceError) -> Self {
+        ShmError::AddressSpace(e)
+    }
+}
+
+impl fmt::Display for ShmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShmError::NotFound => write!(f, "No such shared memory region"),
+            ShmError::InvalidSize => write!(f, "Invalid shared memory size"),
+            ShmError::TooLarge => write!(f, "Shared memory region too large"),
+            ShmError::OutOfMemory => write!(f, "Out of memory for shared memory"),
+            ShmError::NotAProcess => write!(f, "Not called from a process"),
+            ShmError::AddressSpace(e) => write!(f, "{}", e),
+        }
+    }
+}
@@ -0,0 +1,542 @@
+//! Symmetric multiprocessing
+//!
+//! Application processors (APs) listed in the MADT are started one at a time
+//! with the INIT-SIPI-SIPI sequence. They enter the kernel through a real mode
+//! trampoline copied below 1 MiB, load a GDT, TSS and stack of their own and
+//! then idle with interrupts enabled, answering IPIs such as TLB shootdowns.
+//!
+//! APs are idle-only: they never run threads. The scheduler keeps a single
+//! running thread and idle thread, both on the bootstrap processor (BSP),
+//! and [`scheduler::schedule`](crate::scheduler::schedule) does nothing on
+//! an AP. Device interrupts are routed to the CPU that registers them,
+//! which is the BSP for every driver, so APs only see IPIs.
+
+use crate::irq::{self, interrupt_entry};
+use crate::memory::{self, GlobalFrameAllocator};
+use crate::percpu::{self, PerCpu};
+use crate::thread::KernelStack;
+use crate::{acpi, apic, gdt, time};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::fmt;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use lazy_static::lazy_static;
+use raw_cpuid::TopologyType;
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use x86_64::instructions::tlb;
+use x86_64::registers::control::{Cr0, Cr3, Cr4};
+use x86_64::registers::model_specific::Efer;
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// IPI asking the other processors to flush TLB entries
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xFD;
+/// IPI stopping a processor for good
+pub const HALT_VECTOR: u8 = 0xFC;
+
+/// Above this many pages a shootdown flushes the whole TLB instead
+const FLUSH_ALL_THRESHOLD: u64 = 32;
+/// How long an AP gets to report in after its startup IPIs
+const AP_STARTUP_TIMEOUT: Duration = Duration::from_millis(100);
+/// EFER bits the APs copy from the BSP: SCE, LME and NXE
+const EFER_MASK: u64 = (1 << 0) | (1 << 8) | (1 << 11);
+
+// Startup code for the APs. It is copied to a page below 1 MiB and entered in
+// real mode with CS pointing at that page, so everything is addressed
+// relative to the trampoline base, which is kept in ebx. The far jump
+// targets and the GDT pointer are patched with absolute addresses on copy.
+global_asm!(
+    ".global smp_trampoline_start",
+    ".global smp_trampoline_end",
+    ".global smp_trampoline_protected",
+    ".global smp_trampoline_long",
+    ".global smp_trampoline_gdt",
+    ".global smp_trampoline_gdt_ptr",
+    ".global smp_trampoline_jump32",
+    ".global smp_trampoline_jump64",
+    ".global smp_trampoline_args",
+    ".code16",
+    "smp_trampoline_start:",
+    "cli",
+    "cld",
+    "mov %cs, %ax",
+    "mov %ax, %ds",
+    "xor %ebx, %ebx",
+    "mov %ax, %bx",
+    "shl $4, %ebx",
+    "lgdtl (smp_trampoline_gdt_ptr - smp_trampoline_start)",
+    "mov %cr0, %eax",
+    "or $1, %eax",
+    "mov %eax, %cr0",
+    "ljmpl *(smp_trampoline_jump32 - smp_trampoline_start)",
+    ".code32",
+    "smp_trampoline_protected:",
+    "mov $0x10, %ax",
+    "mov %ax, %ds",
+    "mov %ax, %es",
+    "mov %ax, %ss",
+    // Same paging setup as the BSP: CR4, CR3, EFER, then CR0 enables paging
+    "mov (smp_trampoline_args - smp_trampoline_start + 8)(%ebx), %eax",
+    "mov %eax, %cr4",
+    "mov (smp_trampoline_args - smp_trampoline_start)(%ebx), %eax",
+    "mov %eax, %cr3",
+    "mov $0xC0000080, %ecx",
+    "rdmsr",
+    "or (smp_trampoline_args - smp_trampoline_start + 16)(%ebx), %eax",
+    "wrmsr",
+    "mov (smp_trampoline_args - smp_trampoline_start + 24)(%ebx), %eax",
+    "mov %eax, %cr0",
+    "ljmp *(smp_trampoline_jump64 - smp_trampoline_start)(%ebx)",
+    ".code64",
+    "smp_trampoline_long:",
+    // The upper halves of the registers are undefined after the mode switch
+    "mov %ebx, %ebx",
+    "xor %eax, %eax",
+    "mov %ax, %ds",
+    "mov %ax, %es",
+    "mov %ax, %ss",
+    "mov (smp_trampoline_args - smp_trampoline_start + 32)(%rbx), %rsp",
+    "mov (smp_trampoline_args - smp_trampoline_start + 48)(%rbx), %rdi",
+    "xor %ebp, %ebp",
+    "call *(smp_trampoline_args - smp_trampoline_start + 40)(%rbx)",
+    "2:",
+    "cli",
+    "hlt",
+    "jmp 2b",
+    ".balign 8",
+    "smp_trampoline_gdt:",
+    ".quad 0",
+    ".quad 0x00CF9A000000FFFF", // 0x08: 32-bit code
+    ".quad 0x00CF92000000FFFF", // 0x10: data
+    ".quad 0x00AF9A000000FFFF", // 0x18: 64-bit code
+    "smp_trampoline_gdt_ptr:",
+    ".word smp_trampoline_gdt_ptr - smp_trampoline_gdt - 1",
+    ".long 0",
+    "smp_trampoline_jump32:",
+    ".long 0",
+    ".word 0x08",
+    "smp_trampoline_jump64:",
+    ".long 0",
+    ".word 0x18",
+    ".balign 8",
+    "smp_trampoline_args:",
+    ".fill 7, 8, 0",
+    "smp_trampoline_end:",
+    options(att_syntax)
+);
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_end: u8;
+    static smp_trampoline_protected: u8;
+    static smp_trampoline_long: u8;
+    static smp_trampoline_gdt: u8;
+    static smp_trampoline_gdt_ptr: u8;
+    static smp_trampoline_jump32: u8;
+    static smp_trampoline_jump64: u8;
+    static smp_trampoline_args: u8;
+}
+
+/// Values the trampoline loads; the layout matches the offsets used above
+#[repr(C)]
+struct TrampolineArgs {
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    cr0: u64,
+    stack_top: u64,
+    entry: u64,
+    arg: u64,
+}
+
+/// What an AP needs before it can run Rust code on its own data
+struct ApStartup {
+    percpu: &'static PerCpu,
+    ist_stacks: [VirtAddr; gdt::IST_STACKS],
+}
+
+lazy_static! {
+    /// Per-CPU blocks of every processor that came online, BSP first
+    static ref CPUS: Mutex<Vec<&'static PerCpu>> = Mutex::new(Vec::new());
+}
+
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// Serializes TLB shootdowns
+static SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+static SHOOTDOWN_START: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_COUNT: AtomicU64 = AtomicU64::new(0);
+/// Processors that have yet to acknowledge the current shootdown
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+fn trampoline_offset(symbol: *const u8) -> u64 {
+    symbol as u64 - addr_of!(smp_trampoline_start) as u64
+}
+
+/// Identity mapped copy of the startup code below 1 MiB
+struct Trampoline {
+    frame: PhysFrame,
+}
+
+impl Trampoline {
+    fn install() -> Result<Self, SmpError> {
+        // The SIPI vector holds the page number, so the code must sit below 1 MiB
+        let frame = GlobalFrameAllocator
+            .allocate_below(PhysAddr::new(0x10_0000))
+            .ok_or(SmpError::NoLowMemory)?;
+        let base = frame.start_address().as_u64();
+
+        // The code runs at its physical address for a few instructions after
+        // paging is switched on
+        let page = Page::containing_address(VirtAddr::new(base));
+        let mapped = memory::with_mapper(|mapper| unsafe {
+            mapper
+                .map_to(
+                    page,
+                    frame,
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                    &mut GlobalFrameAllocator,
+                )
+                .map(|flush| flush.flush())
+        });
+        if mapped.is_err() {
+            use x86_64::structures::paging::FrameDeallocator;
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            return Err(SmpError::MapFailed);
+        }
+
+        unsafe {
+            let start = addr_of!(smp_trampoline_start);
+            let len = trampoline_offset(addr_of!(smp_trampoline_end)) as usize;
+            let dst = VirtAddr::new(base).as_mut_ptr::<u8>();
+            core::ptr::copy_nonoverlapping(start, dst, len);
+
+            let patch = |symbol: *const u8, offset: u64, value: u64| {
+                let at = dst.add((trampoline_offset(symbol) + offset) as usize) as *mut u32;
+                at.write_unaligned(value as u32);
+            };
+            patch(
+                addr_of!(smp_trampoline_gdt_ptr),
+                2,
+                base + trampoline_offset(addr_of!(smp_trampoline_gdt)),
+            );
+            patch(
+                addr_of!(smp_trampoline_jump32),
+                0,
+                base + trampoline_offset(addr_of!(smp_trampoline_protected)),
+            );
+            patch(
+                addr_of!(smp_trampoline_jump64),
+                0,
+                base + trampoline_offset(addr_of!(smp_trampoline_long)),
+            );
+        }
+        Ok(Trampoline { frame })
+    }
+
+    fn page(&self) -> Page {
+        Page::containing_address(VirtAddr::new(self.frame.start_address().as_u64()))
+    }
+
+    fn vector(&self) -> u8 {
+        (self.frame.start_address().as_u64() >> 12) as u8
+    }
+
+    fn args(&self) -> *mut TrampolineArgs {
+        let offset = trampoline_offset(addr_of!(smp_trampoline_args));
+        (self.page().start_address() + offset).as_mut_ptr()
+    }
+}
+
+impl Drop for Trampoline {
+    fn drop(&mut self) {
+        // Also returns the frame to the allocator
+        memory::unmap_pages(self.page(), 1);
+    }
+}
+
+/// Start every enabled application processor described by the MADT.
+///
+/// Processors that fail to start are reported and skipped. Must run on the
+/// BSP after the APIC, the heap and the monotonic clock are up.
+pub fn init() -> Result<(), SmpError> {
+    without_interrupts(|| CPUS.lock().push(percpu::bsp()));
+    log_cpu(percpu::bsp());
+
+    if !apic::is_enabled() {
+        return Err(SmpError::NoApic);
+    }
+    let madt = acpi::madt().ok_or(SmpError::NoMadt)?;
+    let bsp_apic_id = apic::lapic_id();
+    let aps: Vec<u8> = madt
+        .processors
+        .iter()
+        .filter(|p| p.enabled && p.apic_id != bsp_apic_id)
+        .map(|p| p.apic_id)
+        .collect();
+    if aps.is_empty() {
+        return Ok(());
+    }
+
+    // The trampoline only loads 32 bits of CR3
+    if Cr3::read().0.start_address().as_u64() > u32::MAX as u64 {
+        return Err(SmpError::PageTableAbove4GiB);
+    }
+
+    let trampoline = Trampoline::install()?;
+    let mut stuck = false;
+    for apic_id in aps {
+        let id = without_interrupts(|| CPUS.lock().len());
+        match start_ap(&trampoline, id, apic_id) {
+            Ok(percpu) => {
+                without_interrupts(|| CPUS.lock().push(percpu));
+                log_cpu(percpu);
+            }
+            Err(e) => {
+                log::warn!("CPU with APIC ID {} failed to start: {}", apic_id, e);
+                stuck |= matches!(e, SmpError::Timeout);
+            }
+        }
+    }
+    if stuck {
+        // A late AP could still be executing the trampoline
+        core::mem::forget(trampoline);
+    }
+    Ok(())
+}
+
+fn start_ap(trampoline: &Trampoline, id: usize, apic_id: u8) -> Result<&'static PerCpu, SmpError> {
+    let stack = KernelStack::new().map_err(|_| SmpError::OutOfMemory)?;
+    let ist_stacks = (0..gdt::IST_STACKS)
+        .map(|_| KernelStack::new_resident())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| SmpError::OutOfMemory)?;
+    let percpu: &'static PerCpu = Box::leak(Box::new(PerCpu::new(id, apic_id)));
+    let startup = Box::new(ApStartup {
+        percpu,
+        ist_stacks: core::array::from_fn(|i| ist_stacks[i].top()),
+    });
+
+    unsafe {
+        trampoline.args().write(TrampolineArgs {
+            cr3: Cr3::read().0.start_address().as_u64(),
+            cr4: Cr4::read_raw(),
+            efer: Efer::read_raw() & EFER_MASK,
+            cr0: Cr0::read_raw(),
+            stack_top: stack.top().as_u64(),
+            entry: ap_entry as usize as u64,
+            arg: &*startup as *const ApStartup as u64,
+        });
+    }
+
+    // INIT, wait 10 ms, then up to two SIPIs as the MP specification asks
+    apic::send_init(apic_id);
+    time::busy_wait_us(10_000);
+    apic::send_startup(apic_id, trampoline.vector());
+    let started = time::wait_for(Duration::from_millis(1), || percpu.is_online()).or_else(|_| {
+        apic::send_startup(apic_id, trampoline.vector());
+        time::wait_for(AP_STARTUP_TIMEOUT, || percpu.is_online())
+    });
+
+    // The AP never gives its stacks back, and one that missed the deadline
+    // may still wake up and use them
+    core::mem::forget(stack);
+    core::mem::forget(ist_stacks);
+    match started {
+        Ok(()) => Ok(percpu),
+        Err(_) => {
+            Box::leak(startup);
+            Err(SmpError::Timeout)
+        }
+    }
+}
+
+/// First Rust code run by an AP, on its own kernel stack. Never returns:
+/// the AP sits in `hlt` for good and only wakes up for interrupts.
+extern "C" fn ap_entry(startup: *const ApStartup) -> ! {
+    let (percpu, ist_stacks) = {
+        let startup = unsafe { &*startup };
+        (startup.percpu, startup.ist_stacks)
+    };
+    percpu::install(percpu);
+    gdt::init_ap(ist_stacks);
+    crate::interrupts::init_idt();
+    crate::syscall::init();
+    crate::mmio::init_pat();
+    if apic::init_ap().is_err() {
+        // Nothing could have sent us the SIPI
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+    // The BSP frees `startup` once this is visible
+    percpu.online.store(true, Ordering::Release);
+
+    loop {
+        interrupts::enable_and_hlt();
+    }
+}
+
+fn log_cpu(cpu: &PerCpu) {
+    let (package, core, thread) = topology(cpu.apic_id);
+    log::info!(
+        "CPU {}: APIC ID {} (package {}, core {}, thread {})",
+        cpu.id,
+        cpu.apic_id,
+        package,
+        core,
+        thread
+    );
+}
+
+/// Split an APIC ID into package, core and SMT thread numbers using the
+/// CPUID extended topology leaf
+fn topology(apic_id: u8) -> (u32, u32, u32) {
+    let mut smt_shift = 0;
+    let mut core_shift = 0;
+    if let Some(levels) = raw_cpuid::CpuId::new().get_extended_topology_info() {
+        for level in levels {
+            match level.level_type() {
+                TopologyType::SMT => smt_shift = level.shift_right_for_next_apic_id(),
+                TopologyType::Core => core_shift = level.shift_right_for_next_apic_id(),
+                _ => {}
+            }
+        }
+    }
+    let core_shift = core_shift.max(smt_shift);
+    let id = apic_id as u32;
+    (
+        id.checked_shr(core_shift).unwrap_or(0),
+        (id & ((1u32 << core_shift) - 1)) >> smt_shift,
+        id & ((1u32 << smt_shift) - 1),
+    )
+}
+
+/// Number of processors running the kernel
+pub fn online_cpus() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    irq::set_entry(idt, TLB_SHOOTDOWN_VECTOR, tlb_shootdown_entry);
+    irq::set_entry(idt, HALT_VECTOR, halt_entry);
+}
+
+fn flush_local(start: VirtAddr, count: u64) {
+    if count > FLUSH_ALL_THRESHOLD {
+        tlb::flush_all();
+    } else {
+        for page in 0..count {
+            tlb::flush(start + page * memory::FRAME_SIZE);
+        }
+    }
+}
+
+/// Invalidate `count` pages starting at `start` on every processor.
+///
+/// Returns once all online processors have flushed, so the frames that
+/// backed the pages can be reused afterwards.
+pub fn tlb_shootdown(start: VirtAddr, count: u64) {
+    flush_local(start, count);
+    if online_cpus() <= 1 {
+        return;
+    }
+
+    without_interrupts(|| {
+        let _guard = loop {
+            if let Some(guard) = SHOOTDOWN_LOCK.try_lock() {
+                break guard;
+            }
+            // The CPU holding the lock may be waiting for us
+            handle_tlb_shootdown();
+            core::hint::spin_loop();
+        };
+
+        SHOOTDOWN_START.store(start.as_u64(), Ordering::Relaxed);
+        SHOOTDOWN_COUNT.store(count, Ordering::Relaxed);
+        let me = percpu::cpu_id();
+        // No heap here: callers may hold the allocator's locks. Each target
+        // is counted before it is signalled so the count can't drop to zero
+        // early.
+        for cpu in CPUS
+            .lock()
+            .iter()
+            .filter(|cpu| cpu.id != me && cpu.is_online())
+        {
+            SHOOTDOWN_PENDING.fetch_add(1, Ordering::AcqRel);
+            cpu.tlb_flush_pending.store(true, Ordering::Release);
+            apic::send_ipi(cpu.apic_id, TLB_SHOOTDOWN_VECTOR);
+        }
+        while SHOOTDOWN_PENDING.load(Ordering::Acquire) != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+fn handle_tlb_shootdown() {
+    let cpu = percpu::current();
+    if cpu.tlb_flush_pending.swap(false, Ordering::AcqRel) {
+        flush_local(
+            VirtAddr::new(SHOOTDOWN_START.load(Ordering::Relaxed)),
+            SHOOTDOWN_COUNT.load(Ordering::Relaxed),
+        );
+        SHOOTDOWN_PENDING.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+interrupt_entry!(tlb_shootdown_entry, tlb_shootdown_interrupt);
+interrupt_entry!(halt_entry, halt_interrupt);
+
+extern "C" fn tlb_shootdown_interrupt() {
+    handle_tlb_shootdown();
+    apic::eoi();
+}
+
+extern "C" fn halt_interrupt() {
+    percpu::current().online.store(false, Ordering::Release);
+    ONLINE.fetch_sub(1, Ordering::AcqRel);
+    loop {
+        interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Stop every other processor; used when the kernel panics.
+pub fn halt_others() {
+    if online_cpus() > 1 {
+        apic::broadcast_ipi(HALT_VECTOR);
+    }
+}
+
+/// SMP errors
+#[derive(Debug, Clone, Copy)]
+pub enum SmpError {
+    NoApic,
+    NoMadt,
+    NoLowMemory,
+    MapFailed,
+    PageTableAbove4GiB,
+    OutOfMemory,
+    Timeout,
+}
+
+impl fmt::Display for SmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmpError::NoApic => write!(f, "APIC not enabled"),
+            SmpError::NoMadt => write!(f, "MADT not found"),
+            SmpError::NoLowMemory => write!(f, "No free frame below 1 MiB for the trampoline"),
+            SmpError::MapFailed => write!(f, "Failed to identity map the trampoline"),
+            SmpError::PageTableAbove4GiB => write!(f, "Kernel page table above 4 GiB"),
+            SmpError::OutOfMemory => write!(f, "Out of memory for AP stacks"),
+            SmpError::Timeout => write!(f, "Processor did not come online"),
+        }
+    }
+}
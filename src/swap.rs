@@ -0,0 +1,370 @@
+//! Swapping to a block device
+//!
+//! When a page fault runs out of physical memory, [`reclaim`] moves private
+//! anonymous user pages out to a swap area and frees their frames. The page
+//! table entry of an evicted page is left non-present and records the swap
+//! slot holding the contents (see `address_space`); the next access faults
+//! the page back in.
+//!
+//! Victims are chosen with the clock algorithm: a hand sweeps over the
+//! resident pages of all address spaces, clearing the accessed bit of pages
+//! used since its last visit and evicting the ones still unused. Address
+//! spaces loaded on another CPU are skipped, as their TLBs may still hold
+//! the pages.
+//!
+//! The swap area is a run of blocks on a [`BlockDevice`], normally a swap
+//! partition found by [`init`]. Its slots are reference counted, since a
+//! swapped out page is shared between parent and child after `fork`.
+//!
+//! Pages are written and read under the swap lock with interrupts disabled.
+//! The block drivers poll with `time::wait_for`, whose timeout still expires
+//! then, so a hung disk fails the I/O instead of stalling the CPU.
+
+use crate::address_space::AddressSpace;
+use crate::block::{self, BlockDevice, BlockError};
+use crate::drivers::storage::ata::{AtaController, LEGACY_CHANNELS};
+use crate::memory::{self, FRAME_SIZE};
+use crate::usermode::USER_START;
+use alloc::boxed::Box;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+/// Pages a page fault tries to free when memory runs out
+pub const RECLAIM_BATCH: usize = 32;
+/// Resident pages one [`reclaim`] call looks at before giving up
+const SCAN_LIMIT: usize = 16 * 1024;
+
+struct SwapArea {
+    device: Box<dyn BlockDevice>,
+    /// First block of the area
+    start: u64,
+    blocks_per_slot: u64,
+    slots: Slots,
+}
+
+/// Reference counts of the slots of a swap area
+struct Slots {
+    /// References to each slot; zero means free
+    refs: Vec<u32>,
+    used: usize,
+    /// Where the search for a free slot starts
+    next_free: usize,
+}
+
+impl Slots {
+    fn new(count: usize) -> Self {
+        Slots {
+            refs: vec![0; count],
+            used: 0,
+            next_free: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.refs.len()
+    }
+
+    /// Reserve a free slot, `None` if all are taken
+    fn allocate(&mut self) -> Option<u64> {
+        let count = self.refs.len();
+        let slot = (0..count)
+            .map(|i| (self.next_free + i) % count)
+            .find(|&slot| self.refs[slot] == 0)?;
+        self.refs[slot] = 1;
+        self.used += 1;
+        self.next_free = (slot + 1) % count;
+        Some(slot as u64)
+    }
+
+    fn share(&mut self, slot: u64) {
+        self.refs[slot as usize] += 1;
+    }
+
+    /// Drop a reference, returning whether it was the last one
+    fn release(&mut self, slot: u64) -> bool {
+        let refs = &mut self.refs[slot as usize];
+        *refs -= 1;
+        if *refs == 0 {
+            self.used -= 1;
+        }
+        *refs == 0
+    }
+}
+
+static SWAP: Mutex<Option<SwapArea>> = Mutex::new(None);
+static SWAPPED_IN: AtomicU64 = AtomicU64::new(0);
+static SWAPPED_OUT: AtomicU64 = AtomicU64::new(0);
+
+/// Address spaces whose pages may be swapped out
+static SPACES: Mutex<Vec<Weak<AddressSpace>>> = Mutex::new(Vec::new());
+/// Clock hand: index into the address spaces and the next address there
+static HAND: Mutex<(usize, u64)> = Mutex::new((0, USER_START));
+
+/// Run `f` on the swap area with interrupts disabled, `None` if swapping
+/// is off.
+fn with_area<R>(f: impl FnOnce(&mut SwapArea) -> R) -> Option<R> {
+    without_interrupts(|| SWAP.lock().as_mut().map(f))
+}
+
+/// Look for a swap partition on the legacy ATA disks and swap to the first
+/// one found.
+pub fn init() {
+    for (io_base, control_base) in LEGACY_CHANNELS {
+        let mut disk = AtaController::new(io_base, control_base);
+        if !matches!(disk.detect(), Ok(true)) {
+            continue;
+        }
+        let partitions = match block::mbr_partitions(&mut disk) {
+            Ok(partitions) => partitions,
+            Err(e) => {
+                log::warn!("Swap: ATA disk at {:#x}: {}", io_base, e);
+                continue;
+            }
+        };
+        let Some(partition) = partitions
+            .into_iter()
+            .find(|partition| partition.kind == block::MBR_LINUX_SWAP)
+        else {
+            continue;
+        };
+        match enable(Box::new(disk), partition.start, partition.blocks) {
+            Ok(()) => {
+                log::info!(
+                    "Swap: {} KiB on ATA disk at {:#x}",
+                    partition.blocks * 512 / 1024,
+                    io_base
+                );
+                return;
+            }
+            Err(e) => {
+                log::warn!("Swap: {}", e);
+            }
+        }
+    }
+}
+
+/// Swap to `blocks` blocks of `device` starting at block `start`, e.g. a
+/// swap partition. Anything stored there is overwritten.
+pub fn enable(device: Box<dyn BlockDevice>, start: u64, blocks: u64) -> Result<(), SwapError> {
+    let block_size = device.block_size() as u64;
+    if block_size == 0 || FRAME_SIZE % block_size != 0 {
+        return Err(SwapError::UnsupportedBlockSize);
+    }
+    let blocks_per_slot = FRAME_SIZE / block_size;
+    let slots = (blocks / blocks_per_slot) as usize;
+    if slots == 0 {
+        return Err(SwapError::TooSmall);
+    }
+
+    let area = SwapArea {
+        device,
+        start,
+        blocks_per_slot,
+        slots: Slots::new(slots),
+    };
+    without_interrupts(|| {
+        let mut swap = SWAP.lock();
+        if swap.is_some() {
+            return Err(SwapError::AlreadyEnabled);
+        }
+        *swap = Some(area);
+        Ok(())
+    })
+}
+
+/// Make the pages of `space` candidates for swapping.
+pub fn register(space: &Arc<AddressSpace>) {
+    without_interrupts(|| {
+        let mut spaces = SPACES.lock();
+        spaces.retain(|space| space.strong_count() > 0);
+        spaces.push(Arc::downgrade(space));
+    });
+}
+
+/// Swap out up to `count` pages and return how many were freed.
+pub fn reclaim(count: usize) -> usize {
+    if with_area(|_| ()).is_none() {
+        return 0;
+    }
+    let spaces: Vec<Arc<AddressSpace>> =
+        without_interrupts(|| SPACES.lock().iter().filter_map(Weak::upgrade).collect());
+    if spaces.is_empty() {
+        return 0;
+    }
+
+    let mut evicted = 0;
+    without_interrupts(|| {
+        let mut hand = HAND.lock();
+        let mut budget = SCAN_LIMIT;
+        // Going around twice reaches pages whose accessed bit the first
+        // round cleared
+        for _ in 0..=2 * spaces.len() {
+            let space = &spaces[hand.0 % spaces.len()];
+            let (freed, next) = space.reclaim(VirtAddr::new(hand.1), count - evicted, &mut budget);
+            evicted += freed;
+            match next {
+                Some(addr) => hand.1 = addr.as_u64(),
+                None => *hand = ((hand.0 + 1) % spaces.len(), USER_START),
+            }
+            if evicted == count || budget == 0 {
+                break;
+            }
+        }
+    });
+    // Dropping the last reference frees the address space, which takes
+    // the swap lock, so only now
+    drop(spaces);
+    evicted
+}
+
+/// Reserve a free slot, `None` if swapping is off or the area is full.
+pub(crate) fn allocate_slot() -> Option<u64> {
+    with_area(|area| area.slots.allocate()).flatten()
+}
+
+/// Take another reference to `slot`.
+pub(crate) fn share_slot(slot: u64) {
+    with_area(|area| area.slots.share(slot));
+}
+
+/// Drop a reference to `slot`, freeing it with the last one.
+pub(crate) fn release_slot(slot: u64) {
+    with_area(|area| area.slots.release(slot));
+}
+
+/// Write the contents of `frame` to `slot`.
+pub(crate) fn write_page(slot: u64, frame: PhysFrame) -> Result<(), BlockError> {
+    let page = unsafe { frame_bytes(frame) };
+    with_area(|area| {
+        let first = area.start + slot * area.blocks_per_slot;
+        for (i, block) in page
+            .chunks(page.len() / area.blocks_per_slot as usize)
+            .enumerate()
+        {
+            area.device.write_block(first + i as u64, block)?;
+        }
+        Ok(())
+    })
+    .unwrap_or(Err(BlockError::Io))?;
+    SWAPPED_OUT.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Read the contents of `slot` into `frame`.
+pub(crate) fn read_page(slot: u64, frame: PhysFrame) -> Result<(), BlockError> {
+    let page = unsafe { frame_bytes(frame) };
+    with_area(|area| {
+        let first = area.start + slot * area.blocks_per_slot;
+        let block_size = page.len() / area.blocks_per_slot as usize;
+        for (i, block) in page.chunks_mut(block_size).enumerate() {
+            area.device.read_block(first + i as u64, block)?;
+        }
+        Ok(())
+    })
+    .unwrap_or(Err(BlockError::Io))?;
+    SWAPPED_IN.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Contents of `frame` through the physical memory mapping.
+///
+/// # Safety
+/// Nothing else may access the frame meanwhile.
+unsafe fn frame_bytes(frame: PhysFrame) -> &'static mut [u8] {
+    core::slice::from_raw_parts_mut(
+        memory::phys_to_virt(frame.start_address()).as_mut_ptr(),
+        FRAME_SIZE as usize,
+    )
+}
+
+/// Swap usage counters
+#[derive(Debug, Clone, Copy)]
+pub struct SwapStats {
+    /// Pages the swap area holds
+    pub total_slots: usize,
+    pub used_slots: usize,
+    /// Pages read back in since boot
+    pub swapped_in: u64,
+    /// Pages written out since boot
+    pub swapped_out: u64,
+}
+
+/// Swap statistics, if swapping is on
+pub fn stats() -> Option<SwapStats> {
+    with_area(|area| SwapStats {
+        total_slots: area.slots.len(),
+        used_slots: area.slots.used,
+        swapped_in: SWAPPED_IN.load(Ordering::Relaxed),
+        swapped_out: SWAPPED_OUT.load(Ordering::Relaxed),
+    })
+}
+
+/// Swap setup errors
+#[derive(Debug, Clone, Copy)]
+pub enum SwapError {
+    AlreadyEnabled,
+    /// Not even one page fits
+    TooSmall,
+    /// Blocks don't evenly divide a page
+    UnsupportedBlockSize,
+}
+
+impl fmt::Display for SwapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SwapError::AlreadyEnabled => write!(f, "Swap area already enabled"),
+            SwapError::TooSmall => write!(f, "Swap area too small"),
+            SwapError::UnsupportedBlockSize => write!(f, "Unsupported swap block size"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_slots_allocate() {
+        let mut slots = Slots::new(3);
+        assert_eq!(slots.allocate(), Some(0));
+        assert_eq!(slots.allocate(), Some(1));
+        assert_eq!(slots.allocate(), Some(2));
+        assert_eq!(slots.allocate(), None);
+        assert_eq!(slots.used, 3);
+
+        // The search goes on after the last slot handed out
+        assert!(slots.release(1));
+        assert_eq!(slots.used, 2);
+        assert_eq!(slots.allocate(), Some(1));
+        assert!(slots.release(0));
+        assert!(slots.release(2));
+        assert_eq!(slots.allocate(), Some(2));
+        assert_eq!(slots.allocate(), Some(0));
+    }
+
+    #[test_case]
+    fn test_slots_shared() {
+        let mut slots = Slots::new(2);
+        let slot = slots.allocate().unwrap();
+        slots.share(slot);
+        slots.share(slot);
+        assert!(!slots.release(slot));
+        assert!(!slots.release(slot));
+        assert_eq!(slots.used, 1);
+        assert_eq!(slots.refs[slot as usize], 1);
+
+        // Still taken: the next allocation gets the other slot
+        assert_eq!(slots.allocate(), Some(1 - slot));
+        assert_eq!(slots.allocate(), None);
+        assert!(slots.release(slot));
+        assert_eq!(slots.used, 1);
+        assert_eq!(slots.allocate(), Some(slot));
+    }
+}
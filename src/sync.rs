@@ -0,0 +1,481 @@
+//! Synchronization primitives
+//!
+//! [`Mutex`], [`Semaphore`], [`Condvar`] and [`RwLock`] put waiting threads
+//! to sleep through the scheduler instead of spinning; they must not be used
+//! from interrupt handlers. Before the scheduler runs they fall back to
+//! spinning. [`IrqSpinLock`] is for data shared with interrupt handlers: it
+//! keeps interrupts disabled while held, so a handler can never spin on a
+//! lock owned by the code it interrupted.
+
+use crate::scheduler;
+use crate::thread::ThreadId;
+use alloc::collections::VecDeque;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+/// Put the current thread on the wait queue selected by `queue` and switch
+/// away. `guard` protects the queue and is released only once the thread is
+/// marked blocked, so a wakeup can't slip in between.
+///
+/// Must be called with interrupts disabled.
+fn block_on<S>(
+    mut guard: spin::MutexGuard<'_, S>,
+    queue: impl FnOnce(&mut S) -> &mut VecDeque<ThreadId>,
+) {
+    if !scheduler::is_running() {
+        drop(guard);
+        core::hint::spin_loop();
+        return;
+    }
+    scheduler::block_current(move |id| {
+        queue(&mut guard).push_back(id);
+        drop(guard);
+    });
+}
+
+/// Spinlock that disables interrupts while held
+pub struct IrqSpinLock<T: ?Sized> {
+    inner: spin::Mutex<T>,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSpinLock {
+            inner: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqSpinLock<T> {
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqSpinLockGuard {
+            guard: Some(self.inner.lock()),
+            restore_interrupts: enabled,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSpinLockGuard {
+                guard: Some(guard),
+                restore_interrupts: enabled,
+            }),
+            None => {
+                if enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Forcibly release the lock, e.g. to print from a fault handler.
+    ///
+    /// # Safety
+    /// The current owner must never touch the data again.
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock();
+    }
+}
+
+pub struct IrqSpinLockGuard<'a, T: ?Sized> {
+    guard: Option<spin::MutexGuard<'a, T>>,
+    restore_interrupts: bool,
+}
+
+impl<T: ?Sized> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // Release the lock before interrupts can come in again
+        self.guard.take();
+        if self.restore_interrupts {
+            interrupts::enable();
+        }
+    }
+}
+
+struct MutexState {
+    locked: bool,
+    waiters: VecDeque<ThreadId>,
+}
+
+/// Mutual exclusion lock that blocks waiting threads
+pub struct Mutex<T: ?Sized> {
+    state: spin::Mutex<MutexState>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            state: spin::Mutex::new(MutexState {
+                locked: false,
+                waiters: VecDeque::new(),
+            }),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        loop {
+            let acquired = without_interrupts(|| {
+                let mut state = self.state.lock();
+                if !state.locked {
+                    state.locked = true;
+                    return true;
+                }
+                block_on(state, |s| &mut s.waiters);
+                false
+            });
+            if acquired {
+                return MutexGuard { mutex: self };
+            }
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            if state.locked {
+                return None;
+            }
+            state.locked = true;
+            Some(MutexGuard { mutex: self })
+        })
+    }
+
+    fn unlock(&self) {
+        let next = without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.locked = false;
+            state.waiters.pop_front()
+        });
+        if let Some(id) = next {
+            scheduler::unblock(id);
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => write!(f, "Mutex {{ data: {:?} }}", &*guard),
+            None => write!(f, "Mutex {{ <locked> }}"),
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+struct SemaphoreState {
+    count: usize,
+    waiters: VecDeque<ThreadId>,
+}
+
+/// Counting semaphore
+pub struct Semaphore {
+    state: spin::Mutex<SemaphoreState>,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Semaphore {
+            state: spin::Mutex::new(SemaphoreState {
+                count,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Take a permit, sleeping until one is available.
+    pub fn acquire(&self) {
+        loop {
+            let acquired = without_interrupts(|| {
+                let mut state = self.state.lock();
+                if state.count > 0 {
+                    state.count -= 1;
+                    return true;
+                }
+                block_on(state, |s| &mut s.waiters);
+                false
+            });
+            if acquired {
+                return;
+            }
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            if state.count == 0 {
+                return false;
+            }
+            state.count -= 1;
+            true
+        })
+    }
+
+    /// Return a permit and wake one waiter.
+    pub fn release(&self) {
+        let next = without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.count += 1;
+            state.waiters.pop_front()
+        });
+        if let Some(id) = next {
+            scheduler::unblock(id);
+        }
+    }
+
+    pub fn available(&self) -> usize {
+        without_interrupts(|| self.state.lock().count)
+    }
+}
+
+/// Condition variable used together with [`Mutex`]
+pub struct Condvar {
+    waiters: spin::Mutex<VecDeque<ThreadId>>,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            waiters: spin::Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Release `guard`, sleep until notified and lock the mutex again.
+    ///
+    /// Wakeups may be spurious; check the condition in a loop or use
+    /// [`Condvar::wait_while`].
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        without_interrupts(|| {
+            // Queue up before the mutex is released so a notify issued right
+            // after can't be missed
+            let waiters = self.waiters.lock();
+            drop(guard);
+            if scheduler::is_running() {
+                block_on(waiters, |w| w);
+            }
+        });
+        mutex.lock()
+    }
+
+    /// Wait until `condition` returns false.
+    pub fn wait_while<'a, T: ?Sized, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        let next = without_interrupts(|| self.waiters.lock().pop_front());
+        if let Some(id) = next {
+            scheduler::unblock(id);
+        }
+    }
+
+    pub fn notify_all(&self) {
+        let waiters = without_interrupts(|| core::mem::take(&mut *self.waiters.lock()));
+        for id in waiters {
+            scheduler::unblock(id);
+        }
+    }
+}
+
+struct RwLockState {
+    readers: usize,
+    writer: bool,
+    waiting_readers: VecDeque<ThreadId>,
+    waiting_writers: VecDeque<ThreadId>,
+}
+
+/// Reader-writer lock preferring writers
+pub struct RwLock<T: ?Sized> {
+    state: spin::Mutex<RwLockState>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: spin::Mutex::new(RwLockState {
+                readers: 0,
+                writer: false,
+                waiting_readers: VecDeque::new(),
+                waiting_writers: VecDeque::new(),
+            }),
+            data: UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        loop {
+            let acquired = without_interrupts(|| {
+                let mut state = self.state.lock();
+                if !state.writer && state.waiting_writers.is_empty() {
+                    state.readers += 1;
+                    return true;
+                }
+                block_on(state, |s| &mut s.waiting_readers);
+                false
+            });
+            if acquired {
+                return RwLockReadGuard { lock: self };
+            }
+        }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        loop {
+            let acquired = without_interrupts(|| {
+                let mut state = self.state.lock();
+                if !state.writer && state.readers == 0 {
+                    state.writer = true;
+                    return true;
+                }
+                block_on(state, |s| &mut s.waiting_writers);
+                false
+            });
+            if acquired {
+                return RwLockWriteGuard { lock: self };
+            }
+        }
+    }
+
+    fn read_unlock(&self) {
+        let next = without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.readers -= 1;
+            if state.readers == 0 {
+                state.waiting_writers.pop_front()
+            } else {
+                None
+            }
+        });
+        if let Some(id) = next {
+            scheduler::unblock(id);
+        }
+    }
+
+    fn write_unlock(&self) {
+        let (writer, readers) = without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.writer = false;
+            match state.waiting_writers.pop_front() {
+                Some(id) => (Some(id), VecDeque::new()),
+                None => (None, core::mem::take(&mut state.waiting_readers)),
+            }
+        });
+        for id in writer.into_iter().chain(readers) {
+            scheduler::unblock(id);
+        }
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.read_unlock();
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.write_unlock();
+    }
+}
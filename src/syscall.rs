@@ -0,0 +1,655 @@
+//! System call entry and dispatch
+//!
+//! User programs enter with `syscall`: number in rax, arguments in rdi, rsi,
+//! rdx, r10, r8 and r9, result in rax. Failures come back as negative error
+//! numbers. All registers except rax, rcx and r11 are preserved.
+//!
+//! User code controls its own GS base, so the entry stub `swapgs`es to the
+//! per-CPU block kept in IA32_KERNEL_GS_BASE before touching `gs:` and
+//! swaps back right before `sysretq` (see `percpu`).
+
+use crate::address_space::{AddressSpaceError, Placement};
+use crate::elf::ElfError;
+use crate::fd::{FileError, FileRef, OpenFile};
+use crate::gdt;
+use crate::initrd;
+use crate::memory::FRAME_SIZE;
+use crate::percpu::{KERNEL_RSP_OFFSET, USER_RSP_OFFSET};
+use crate::process::{self, Pid, ProcessError};
+use crate::shm::{self, ShmError};
+use crate::thread;
+use crate::usermode::{self, UserExit};
+use crate::vma::{Backing, Protection};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::fmt;
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_YIELD: u64 = 2;
+pub const SYS_SLEEP: u64 = 3;
+pub const SYS_CLOCK: u64 = 4;
+pub const SYS_GETTID: u64 = 5;
+pub const SYS_READ: u64 = 6;
+pub const SYS_OPEN: u64 = 7;
+pub const SYS_CLOSE: u64 = 8;
+pub const SYS_FORK: u64 = 9;
+pub const SYS_EXEC: u64 = 10;
+pub const SYS_WAIT: u64 = 11;
+pub const SYS_GETPID: u64 = 12;
+pub const SYS_GETPPID: u64 = 13;
+pub const SYS_SHM_GET: u64 = 14;
+pub const SYS_SHM_ATTACH: u64 = 15;
+pub const SYS_SHM_DETACH: u64 = 16;
+pub const SYS_SHM_REMOVE: u64 = 17;
+pub const SYS_MMAP: u64 = 18;
+pub const SYS_MUNMAP: u64 = 19;
+pub const SYS_MPROTECT: u64 = 20;
+
+/// Largest buffer a single `read` or `write` accepts
+const MAX_IO: u64 = 64 * 1024;
+/// Longest path or argument string, terminator included
+const MAX_STRING: usize = 4096;
+/// Most entries an `argv` or `envp` array may have
+const MAX_ARGS: usize = 1024;
+
+/// `wait` option: return 0 instead of blocking
+const WNOHANG: u64 = 1;
+/// `open` access mode bits; only `O_RDONLY` (0) is supported
+const O_ACCMODE: u64 = 3;
+/// `shm_get` flag: create the region if the key is unknown
+const IPC_CREAT: u64 = 0o1000;
+
+/// `mmap` flags, numbered like Linux
+const MAP_SHARED: u64 = 0x01;
+const MAP_PRIVATE: u64 = 0x02;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+const MAP_POPULATE: u64 = 0x8000;
+const MAP_FIXED_NOREPLACE: u64 = 0x10_0000;
+/// Orbita extension: reserve a guard area that faults on any access
+const MAP_GUARD: u64 = 0x20_0000;
+/// Where `mmap` places mappings without an address
+const MMAP_BASE: u64 = 0x_1000_0000_0000;
+
+// Switch to the kernel GS base and the thread's trap stack, save the user
+// context as a `SyscallFrame`, call the dispatcher and sysret with the
+// frame's contents, which the dispatcher may have replaced (`exec`). The pad
+// keeps the stack 16-byte aligned at the call, the trap stack itself being
+// 8 bytes off.
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "swapgs",
+    "mov gs:[{user_rsp}], rsp",
+    "mov rsp, gs:[{kernel_rsp}]",
+    "sub rsp, 8",
+    "push qword ptr gs:[{user_rsp}]",
+    "push rcx",
+    "push r11",
+    "push r15",
+    "push r14",
+    "push r13",
+    "push r12",
+    "push rbp",
+    "push rbx",
+    "push r9",
+    "push r8",
+    "push r10",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rax",
+    "mov rdi, rsp",
+    "cld",
+    "call {dispatch}",
+    "cli",
+    "pop rax",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop r10",
+    "pop r8",
+    "pop r9",
+    "pop rbx",
+    "pop rbp",
+    "pop r12",
+    "pop r13",
+    "pop r14",
+    "pop r15",
+    "pop r11",
+    "pop rcx",
+    "pop rsp",
+    "swapgs",
+    "sysretq",
+    user_rsp = const USER_RSP_OFFSET,
+    kernel_rsp = const KERNEL_RSP_OFFSET,
+    dispatch = sym syscall_dispatch,
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// User registers saved on system call entry, the complete user context
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SyscallFrame {
+    /// System call number on entry, result on return
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    /// User RFLAGS
+    pub r11: u64,
+    /// User return address
+    pub rcx: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    /// Fresh context starting at `entry` on `stack` with `arg` in rdi
+    pub fn new(entry: u64, stack: u64, arg: u64) -> Self {
+        SyscallFrame {
+            rdi: arg,
+            r11: usermode::INITIAL_RFLAGS,
+            rcx: entry,
+            rsp: stack,
+            ..Default::default()
+        }
+    }
+
+    /// Argument `n` (0-based) in syscall calling convention order
+    pub fn arg(&self, n: usize) -> u64 {
+        match n {
+            0 => self.rdi,
+            1 => self.rsi,
+            2 => self.rdx,
+            3 => self.r10,
+            4 => self.r8,
+            _ => self.r9,
+        }
+    }
+}
+
+/// Enable `syscall`/`sysret` on the executing processor.
+///
+/// Must run after the GDT is loaded, on every CPU.
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("GDT layout doesn't match STAR");
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    // Entered with interrupts off until the stub is on the kernel stack
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+type SyscallHandler = fn(&mut SyscallFrame) -> Result<u64, SyscallError>;
+
+/// Handlers indexed by system call number
+static SYSCALL_TABLE: [SyscallHandler; 21] = [
+    sys_exit,
+    sys_write,
+    sys_yield,
+    sys_sleep,
+    sys_clock,
+    sys_gettid,
+    sys_read,
+    sys_open,
+    sys_close,
+    sys_fork,
+    sys_exec,
+    sys_wait,
+    sys_getpid,
+    sys_getppid,
+    sys_shm_get,
+    sys_shm_attach,
+    sys_shm_detach,
+    sys_shm_remove,
+    sys_mmap,
+    sys_munmap,
+    sys_mprotect,
+];
+
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    interrupts::enable();
+    let result = match SYSCALL_TABLE.get(frame.rax as usize) {
+        Some(handler) => handler(frame),
+        None => Err(SyscallError::NoSys),
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(e) => (-(e as i64)) as u64,
+    };
+}
+
+/// Check a user buffer and borrow it.
+fn user_slice<'a>(addr: u64, len: u64) -> Result<&'a [u8], SyscallError> {
+    if !usermode::check_user_access(addr, len, false) {
+        return Err(SyscallError::Fault);
+    }
+    if len == 0 {
+        return Ok(&[]);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) })
+}
+
+/// Check a writable user buffer and borrow it.
+fn user_slice_mut<'a>(addr: u64, len: u64) -> Result<&'a mut [u8], SyscallError> {
+    if !usermode::check_user_access(addr, len, true) {
+        return Err(SyscallError::Fault);
+    }
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    Ok(unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len as usize) })
+}
+
+/// Copy a NUL-terminated user string.
+fn user_string(addr: u64) -> Result<String, SyscallError> {
+    let mut bytes = Vec::new();
+    let mut addr = addr;
+    loop {
+        // Check one page at a time, the string may end before the next one
+        let chunk = FRAME_SIZE - addr % FRAME_SIZE;
+        let page = user_slice(addr, chunk)?;
+        match page.iter().position(|&b| b == 0) {
+            Some(len) => {
+                bytes.extend_from_slice(&page[..len]);
+                break;
+            }
+            None => bytes.extend_from_slice(page),
+        }
+        if bytes.len() >= MAX_STRING {
+            return Err(SyscallError::NameTooLong);
+        }
+        addr += chunk;
+    }
+    if bytes.len() >= MAX_STRING {
+        return Err(SyscallError::NameTooLong);
+    }
+    String::from_utf8(bytes).map_err(|_| SyscallError::Invalid)
+}
+
+/// Copy a NULL-terminated user array of string pointers; a null array is
+/// empty.
+fn user_string_array(addr: u64) -> Result<Vec<String>, SyscallError> {
+    let mut strings = Vec::new();
+    if addr == 0 {
+        return Ok(strings);
+    }
+    for i in 0..=MAX_ARGS as u64 {
+        let slot = user_slice(addr + i * 8, 8)?;
+        let ptr = u64::from_le_bytes(slot.try_into().unwrap());
+        if ptr == 0 {
+            return Ok(strings);
+        }
+        strings.push(user_string(ptr)?);
+    }
+    Err(SyscallError::TooBig)
+}
+
+/// exit(status)
+fn sys_exit(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    usermode::leave(UserExit::Exited(frame.arg(0) as i64))
+}
+
+/// write(fd, buf, len): returns the number of bytes written.
+fn sys_write(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let (fd, buf, len) = (frame.arg(0), frame.arg(1), frame.arg(2));
+    let file = file(fd)?;
+    let bytes = user_slice(buf, len.min(MAX_IO))?;
+    let written = file.lock().write(bytes)?;
+    Ok(written as u64)
+}
+
+/// yield()
+fn sys_yield(_frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    crate::thread::yield_now();
+    Ok(0)
+}
+
+/// sleep(milliseconds)
+fn sys_sleep(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    crate::thread::sleep(Duration::from_millis(frame.arg(0)));
+    Ok(0)
+}
+
+/// clock(): monotonic nanoseconds since boot
+fn sys_clock(_frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    Ok(crate::time::now_ns())
+}
+
+/// gettid(): ID of the calling thread
+fn sys_gettid(_frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    Ok(crate::thread::current_id().as_u64())
+}
+
+/// read(fd, buf, len): returns the number of bytes read, 0 at end of file.
+fn sys_read(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let (fd, buf, len) = (frame.arg(0), frame.arg(1), frame.arg(2));
+    let file = file(fd)?;
+    let bytes = user_slice_mut(buf, len.min(MAX_IO))?;
+    let read = file.lock().read(bytes)?;
+    Ok(read as u64)
+}
+
+/// open(path, flags): opens a file of the initrd read-only.
+fn sys_open(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let path = user_string(frame.arg(0))?;
+    if frame.arg(1) & O_ACCMODE != 0 {
+        return Err(SyscallError::ReadOnlyFs);
+    }
+    let data = initrd::read(&path).ok_or(SyscallError::NoEnt)?;
+    let fd = process::with_fds(|fds| fds.insert(OpenFile::Initrd { data, offset: 0 }))??;
+    Ok(fd as u64)
+}
+
+/// close(fd)
+fn sys_close(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let fd = frame.arg(0);
+    process::with_fds(|fds| fds.close(fd as usize))??;
+    Ok(0)
+}
+
+/// fork(): the child's PID in the parent, 0 in the child.
+fn sys_fork(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    Ok(process::fork(frame)?.as_u64())
+}
+
+/// exec(path, argv, envp): does not return on success.
+fn sys_exec(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let path = user_string(frame.arg(0))?;
+    let argv = user_string_array(frame.arg(1))?;
+    let envp = user_string_array(frame.arg(2))?;
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    let envp: Vec<&str> = envp.iter().map(String::as_str).collect();
+    process::exec(frame, &path, &argv, &envp)?;
+    // The new program starts with the registers exec put in the frame
+    Ok(frame.rax)
+}
+
+/// wait(pid, status, options): waits for child `pid`, or any child if
+/// `pid` is -1. Stores the status word if `status` isn't null and returns
+/// the PID of the reaped child, or 0 with `WNOHANG` if none has exited.
+fn sys_wait(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let (pid, status, options) = (frame.arg(0) as i64, frame.arg(1), frame.arg(2));
+    let pid = match pid {
+        -1 => None,
+        pid if pid > 0 => Some(Pid::from_u64(pid as u64)),
+        _ => return Err(SyscallError::Invalid),
+    };
+    if status != 0 && !usermode::check_user_access(status, 4, true) {
+        return Err(SyscallError::Fault);
+    }
+    match process::wait(pid, options & WNOHANG == 0)? {
+        Some((child, exit)) => {
+            if status != 0 {
+                user_slice_mut(status, 4)?.copy_from_slice(&exit.wait_status().to_le_bytes());
+            }
+            Ok(child.as_u64())
+        }
+        None => Ok(0),
+    }
+}
+
+/// getpid()
+fn sys_getpid(_frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    Ok(process::current()?.as_u64())
+}
+
+/// getppid(): 0 for init and orphans without an heir
+fn sys_getppid(_frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let pid = process::current()?;
+    Ok(process::parent(pid).map_or(0, |parent| parent.as_u64()))
+}
+
+/// shm_get(key, size, flags): id of the shared memory region with `key`,
+/// created with `size` bytes if missing and `IPC_CREAT` is set. Key 0
+/// always creates a new region.
+fn sys_shm_get(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let (key, size, flags) = (frame.arg(0), frame.arg(1), frame.arg(2));
+    Ok(shm::get(key, size, flags & IPC_CREAT != 0)?)
+}
+
+/// shm_attach(id, addr): maps region `id` at `addr`, or anywhere if `addr`
+/// is null, and returns the address.
+fn sys_shm_attach(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let addr = match frame.arg(1) {
+        0 => None,
+        addr => Some(VirtAddr::try_new(addr).map_err(|_| SyscallError::Invalid)?),
+    };
+    Ok(shm::attach(frame.arg(0), addr)?.as_u64())
+}
+
+/// shm_detach(addr)
+fn sys_shm_detach(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let addr = VirtAddr::try_new(frame.arg(0)).map_err(|_| SyscallError::Invalid)?;
+    shm::detach(addr)?;
+    Ok(0)
+}
+
+/// shm_remove(id): the region goes away once no process has it attached.
+fn sys_shm_remove(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    shm::remove(frame.arg(0))?;
+    Ok(0)
+}
+
+/// mmap(addr, len, prot, flags, fd, offset): returns the start of the new
+/// mapping. `addr` is only a hint unless `MAP_FIXED` or
+/// `MAP_FIXED_NOREPLACE` is set. `MAP_GUARD` ignores `prot`, `fd` and
+/// `offset`.
+fn sys_mmap(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let (addr, len, prot, flags) = (frame.arg(0), frame.arg(1), frame.arg(2), frame.arg(3));
+    let (fd, offset) = (frame.arg(4), frame.arg(5));
+    let mut protection = Protection::from_bits(prot).ok_or(SyscallError::Invalid)?;
+    let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED => true,
+        MAP_PRIVATE => false,
+        _ if flags & MAP_GUARD != 0 => false,
+        _ => return Err(SyscallError::Invalid),
+    };
+    if len == 0 || offset % FRAME_SIZE != 0 {
+        return Err(SyscallError::Invalid);
+    }
+
+    let backing = if flags & MAP_GUARD != 0 {
+        protection = Protection::NONE;
+        Backing::Guard
+    } else if flags & MAP_ANONYMOUS != 0 {
+        if shared {
+            let memory = shm::anonymous(len)?;
+            Backing::Shared { memory, first: 0 }
+        } else {
+            Backing::Anonymous
+        }
+    } else {
+        let data = file(fd)?.lock().mapping().ok_or(SyscallError::NoDev)?;
+        // No writing back to the read-only initrd
+        if shared && protection.contains(Protection::WRITE) {
+            return Err(SyscallError::Access);
+        }
+        Backing::File {
+            data,
+            offset,
+            shared,
+        }
+    };
+
+    let addr = VirtAddr::try_new(addr).map_err(|_| SyscallError::Invalid)?;
+    let placement = if flags & MAP_FIXED != 0 {
+        Placement::Fixed(addr)
+    } else if flags & MAP_FIXED_NOREPLACE != 0 {
+        Placement::FixedNoReplace(addr)
+    } else if addr.is_null() {
+        Placement::Anywhere(VirtAddr::new(MMAP_BASE))
+    } else {
+        Placement::Anywhere(addr)
+    };
+    let space = thread::current_address_space().ok_or(SyscallError::Invalid)?;
+    let start = space.map(placement, len, protection, backing)?;
+    if flags & MAP_POPULATE != 0 {
+        space.populate(start, len)?;
+    }
+    Ok(start.as_u64())
+}
+
+/// munmap(addr, len): parts of the range that aren't mapped are fine.
+fn sys_munmap(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let addr = VirtAddr::try_new(frame.arg(0)).map_err(|_| SyscallError::Invalid)?;
+    let space = thread::current_address_space().ok_or(SyscallError::Invalid)?;
+    space.unmap(addr, frame.arg(1))?;
+    Ok(0)
+}
+
+/// mprotect(addr, len, prot)
+fn sys_mprotect(frame: &mut SyscallFrame) -> Result<u64, SyscallError> {
+    let addr = VirtAddr::try_new(frame.arg(0)).map_err(|_| SyscallError::Invalid)?;
+    let protection = Protection::from_bits(frame.arg(2)).ok_or(SyscallError::Invalid)?;
+    let space = thread::current_address_space().ok_or(SyscallError::Invalid)?;
+    space.protect(addr, frame.arg(1), protection)?;
+    Ok(0)
+}
+
+/// Open file behind descriptor `fd` of the calling process
+fn file(fd: u64) -> Result<FileRef, SyscallError> {
+    Ok(process::with_fds(|fds| fds.get(fd as usize))??)
+}
+
+/// Errors returned to user mode, numbered like their Linux counterparts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    /// No such file
+    NoEnt = 2,
+    /// Device I/O failed
+    Io = 5,
+    /// Argument list too long
+    TooBig = 7,
+    /// Not a loadable program
+    NoExec = 8,
+    /// Bad file descriptor
+    BadFd = 9,
+    /// No child process to wait for
+    Child = 10,
+    /// Out of memory or address range not mapped
+    NoMem = 12,
+    /// Access not allowed
+    Access = 13,
+    /// Bad user address
+    Fault = 14,
+    /// Something is already there
+    Exist = 17,
+    /// File can't be mapped
+    NoDev = 19,
+    /// Invalid argument
+    Invalid = 22,
+    /// Too many open files
+    TooManyFiles = 24,
+    /// Write access to the read-only initrd
+    ReadOnlyFs = 30,
+    /// Path or string too long
+    NameTooLong = 36,
+    /// Unknown system call number
+    NoSys = 38,
+}
+
+impl From<ProcessError> for SyscallError {
+    fn from(e: ProcessError) -> Self {
+        match e {
+            ProcessError::NotFound => SyscallError::NoEnt,
+            ProcessError::Exec(ElfError::ArgumentsTooLarge) => SyscallError::TooBig,
+            ProcessError::Exec(ElfError::AddressSpace(_)) | ProcessError::OutOfMemory => {
+                SyscallError::NoMem
+            }
+            ProcessError::Exec(_) => SyscallError::NoExec,
+            ProcessError::NoChild => SyscallError::Child,
+            ProcessError::NotAProcess => SyscallError::Invalid,
+            ProcessError::Thread(_) => SyscallError::NoMem,
+        }
+    }
+}
+
+impl From<AddressSpaceError> for SyscallError {
+    fn from(e: AddressSpaceError) -> Self {
+        match e {
+            AddressSpaceError::InvalidAddress => SyscallError::Invalid,
+            AddressSpaceError::AlreadyMapped => SyscallError::Exist,
+            AddressSpaceError::PermissionDenied => SyscallError::Access,
+            // Linux reports unmapped ranges as ENOMEM too
+            AddressSpaceError::NotMapped
+            | AddressSpaceError::OutOfMemory
+            | AddressSpaceError::NoSpace => SyscallError::NoMem,
+            AddressSpaceError::Swap => SyscallError::Io,
+        }
+    }
+}
+
+impl From<ShmError> for SyscallError {
+    fn from(e: ShmError) -> Self {
+        match e {
+            ShmError::NotFound => SyscallError::NoEnt,
+            ShmError::OutOfMemory => SyscallError::NoMem,
+            ShmError::AddressSpace(e) => e.into(),
+            _ => SyscallError::Invalid,
+        }
+    }
+}
+
+impl From<FileError> for SyscallError {
+    fn from(e: FileError) -> Self {
+        match e {
+            FileError::BadFd | FileError::NotWritable => SyscallError::BadFd,
+            FileError::TooManyOpen => SyscallError::TooManyFiles,
+        }
+    }
+}
+
+impl fmt::Display for SyscallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyscallError::NoEnt => write!(f, "No such file or directory"),
+            SyscallError::Io => write!(f, "Input/output error"),
+            SyscallError::TooBig => write!(f, "Argument list too long"),
+            SyscallError::NoExec => write!(f, "Exec format error"),
+            SyscallError::BadFd => write!(f, "Bad file descriptor"),
+            SyscallError::Child => write!(f, "No child processes"),
+            SyscallError::NoMem => write!(f, "Out of memory"),
+            SyscallError::Access => write!(f, "Permission denied"),
+            SyscallError::Fault => write!(f, "Bad address"),
+            SyscallError::Exist => write!(f, "File exists"),
+            SyscallError::NoDev => write!(f, "No such device"),
+            SyscallError::Invalid => write!(f, "Invalid argument"),
+            SyscallError::TooManyFiles => write!(f, "Too many open files"),
+            SyscallError::ReadOnlyFs => write!(f, "Read-only file system"),
+            SyscallError::NameTooLong => write!(f, "File name too long"),
+            SyscallError::NoSys => write!(f, "Function not implemented"),
+        }
+    }
+}
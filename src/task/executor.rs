@@ -0,0 +1,117 @@
+use super::{Task, TaskId};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use heapless::mpmc::MpMcQueue;
+use x86_64::instructions::interrupts;
+
+/// Maximum number of tasks that can be queued for polling at once
+const TASK_QUEUE_SIZE: usize = 128;
+
+type TaskQueue = MpMcQueue<TaskId, TASK_QUEUE_SIZE>;
+
+/// Executor polling tasks whenever they are woken
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    task_queue: Arc<TaskQueue>,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(TaskQueue::new()),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        let task_id = task.id;
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        self.task_queue.enqueue(task_id).expect("task queue full");
+    }
+
+    /// Run tasks forever, halting the CPU while none of them is ready.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        while let Some(task_id) = self.task_queue.dequeue() {
+            let task = match self.tasks.get_mut(&task_id) {
+                Some(task) => task,
+                // Task already finished
+                None => continue,
+            };
+            let task_waker = self
+                .waker_cache
+                .entry(task_id)
+                .or_insert_with(|| TaskWaker::new(task_id, self.task_queue.clone()));
+            // Wakeups from here on have to queue the task again
+            task_waker.queued.store(false, Ordering::Release);
+            let waker = Waker::from(task_waker.clone());
+            let mut context = Context::from_waker(&waker);
+            if let Poll::Ready(()) = task.poll(&mut context) {
+                self.tasks.remove(&task_id);
+                self.waker_cache.remove(&task_id);
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // Check the queue with interrupts off so a wakeup between the check
+        // and `hlt` isn't lost
+        interrupts::disable();
+        match self.task_queue.dequeue() {
+            Some(task_id) => {
+                let _ = self.task_queue.enqueue(task_id);
+                interrupts::enable();
+            }
+            None => interrupts::enable_and_hlt(),
+        }
+    }
+}
+
+struct TaskWaker {
+    task_id: TaskId,
+    task_queue: Arc<TaskQueue>,
+    /// Task is already in the queue, further wakeups are no-ops
+    queued: AtomicBool,
+}
+
+impl TaskWaker {
+    fn new(task_id: TaskId, task_queue: Arc<TaskQueue>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
+            task_id,
+            task_queue,
+            queued: AtomicBool::new(false),
+        })
+    }
+
+    fn wake_task(&self) {
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        if self.task_queue.enqueue(self.task_id).is_err() {
+            log::warn!("task queue full, dropping wakeup");
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
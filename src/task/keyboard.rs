@@ -0,0 +1,74 @@
+//! Keyboard scancode stream
+
+use super::{Stream, WakerList};
+use crate::serial_println;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use heapless::mpmc::MpMcQueue;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+
+static SCANCODE_QUEUE: MpMcQueue<u8, 128> = MpMcQueue::new();
+static WAKERS: WakerList = WakerList::new();
+
+/// Queue a scancode read by the keyboard interrupt handler.
+///
+/// Must not block or allocate, it runs in interrupt context.
+pub(crate) fn add_scancode(scancode: u8) {
+    if SCANCODE_QUEUE.enqueue(scancode).is_err() {
+        log::warn!("scancode queue full; dropping keyboard input");
+        return;
+    }
+    WAKERS.wake_all();
+}
+
+/// Stream of raw scancodes from the PS/2 keyboard
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    pub fn new() -> Self {
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        if let Some(scancode) = SCANCODE_QUEUE.dequeue() {
+            return Poll::Ready(Some(scancode));
+        }
+        WAKERS.register(cx.waker());
+        // A scancode may have arrived before the waker was registered
+        match SCANCODE_QUEUE.dequeue() {
+            Some(scancode) => Poll::Ready(Some(scancode)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Decode keypresses and echo them to the text console
+pub async fn print_keypresses() {
+    let mut scancodes = ScancodeStream::new();
+    let mut keyboard = Keyboard::new(
+        ScancodeSet1::new(),
+        layouts::Us104Key,
+        HandleControl::Ignore,
+    );
+
+    while let Some(scancode) = scancodes.next().await {
+        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+            if let Some(key) = keyboard.process_keyevent(key_event) {
+                match key {
+                    DecodedKey::Unicode(character) => {
+                        crate::vga_buffer::WRITER.lock().write_byte(character as u8);
+                    }
+                    DecodedKey::RawKey(key) => {
+                        serial_println!("Special key pressed: {:?}", key);
+                    }
+                }
+            }
+        }
+    }
+}
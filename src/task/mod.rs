@@ -0,0 +1,107 @@
+//! Cooperative async tasks
+//!
+//! Kernel code that waits for devices can run as an `async` task on the
+//! [`executor::Executor`] instead of in interrupt context. Interrupt handlers
+//! only queue data and wake the task that consumes it.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub mod executor;
+pub mod keyboard;
+pub mod timer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// A pinned, heap allocated future driven by the executor
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+/// Asynchronous sequence of values
+pub trait Stream {
+    type Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>>;
+
+    /// Future resolving to the next item of the stream
+    fn next(&mut self) -> Next<'_, Self>
+    where
+        Self: Unpin,
+    {
+        Next { stream: self }
+    }
+}
+
+/// Future returned by [`Stream::next`]
+pub struct Next<'a, S: ?Sized> {
+    stream: &'a mut S,
+}
+
+impl<S: Stream + Unpin + ?Sized> Future for Next<'_, S> {
+    type Output = Option<S::Item>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut *self.stream).poll_next(cx)
+    }
+}
+
+/// Wakers waiting for an event raised from interrupt context.
+///
+/// Registration disables interrupts so an interrupt handler calling
+/// [`WakerList::wake_all`] can never spin on a lock held by the same CPU.
+pub struct WakerList {
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl WakerList {
+    pub const fn new() -> Self {
+        WakerList {
+            wakers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn register(&self, waker: &Waker) {
+        without_interrupts(|| {
+            let mut wakers = self.wakers.lock();
+            if !wakers.iter().any(|w| w.will_wake(waker)) {
+                wakers.push(waker.clone());
+            }
+        });
+    }
+
+    pub fn wake_all(&self) {
+        let wakers = without_interrupts(|| core::mem::take(&mut *self.wakers.lock()));
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
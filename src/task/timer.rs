@@ -0,0 +1,70 @@
+//! Timer tick stream and async sleep
+
+use super::{Stream, WakerList};
+use crate::time::{self, Instant};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+static WAKERS: WakerList = WakerList::new();
+
+/// Wake tasks waiting on the timer; called on every timer interrupt.
+pub(crate) fn tick() {
+    WAKERS.wake_all();
+}
+
+/// Stream yielding the tick count each time the timer fires
+pub struct TickStream {
+    last: u64,
+}
+
+impl TickStream {
+    pub fn new() -> Self {
+        TickStream {
+            last: time::ticks(),
+        }
+    }
+}
+
+impl Stream for TickStream {
+    type Item = u64;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u64>> {
+        WAKERS.register(cx.waker());
+        let ticks = time::ticks();
+        if ticks != self.last {
+            self.last = ticks;
+            return Poll::Ready(Some(ticks));
+        }
+        Poll::Pending
+    }
+}
+
+/// Future completing once its deadline has passed
+pub struct Sleep {
+    deadline: Instant,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        WAKERS.register(cx.waker());
+        if Instant::now() >= self.deadline {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Suspend the current task for at least `duration`
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        deadline: Instant::now() + duration,
+    }
+}
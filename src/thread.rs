@@ -0,0 +1,376 @@
+//! Kernel threads
+//!
+//! Each thread runs on its own kernel stack with an unmapped guard page
+//! below it, so an overflow faults instead of corrupting a neighbour. Only
+//! the top of a stack is mapped up front; the stack area is a demand region
+//! and the rest is mapped as the stack grows into it.
+
+use crate::address_space::{AddressSpace, AddressSpaceError};
+use crate::memory;
+use crate::process::Pid;
+use crate::scheduler;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+/// Virtual area kernel stacks are carved from
+pub(crate) const KERNEL_STACK_AREA: u64 = 0x_6666_0000_0000;
+/// Size of the stack area; it stays within a single level 4 entry
+pub(crate) const KERNEL_STACK_AREA_SIZE: u64 = 64 << 30;
+/// Usable size of a kernel stack
+pub const KERNEL_STACK_SIZE: u64 = 64 * 1024;
+/// Part of a kernel stack mapped when it is allocated. Exception frames are
+/// pushed there, and code holding the mapper or frame allocator lock must
+/// not run deeper, since a fault below would wait for that lock.
+const KERNEL_STACK_RESIDENT: u64 = 16 * 1024;
+/// Stack plus the guard page below it
+const STACK_SLOT_SIZE: u64 = KERNEL_STACK_SIZE + memory::FRAME_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    /// The thread that booted the kernel
+    pub const BOOT: ThreadId = ThreadId(0);
+
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Sleeping,
+    Blocked,
+    Exited,
+}
+
+/// Scheduling priority; higher levels always run first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Only runs when nothing else can
+    Idle = 0,
+    Low = 1,
+    Normal = 2,
+    High = 3,
+}
+
+pub const PRIORITY_LEVELS: usize = 4;
+
+lazy_static! {
+    /// Stack slots released by exited threads
+    static ref FREE_STACK_SLOTS: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+}
+static NEXT_STACK_SLOT: AtomicU64 = AtomicU64::new(0);
+
+/// A kernel stack; whatever part of it got mapped is unmapped again on drop
+pub(crate) struct KernelStack {
+    slot: u64,
+}
+
+impl KernelStack {
+    /// Allocate a stack whose lower part is mapped on first touch.
+    pub(crate) fn new() -> Result<Self, ThreadError> {
+        Self::with_resident(KERNEL_STACK_RESIDENT)
+    }
+
+    /// Allocate a fully mapped stack. Interrupt stacks need one: the page
+    /// fault handler can't resolve a fault on the stack it runs on.
+    pub(crate) fn new_resident() -> Result<Self, ThreadError> {
+        Self::with_resident(KERNEL_STACK_SIZE)
+    }
+
+    fn with_resident(resident: u64) -> Result<Self, ThreadError> {
+        let slot =
+            x86_64::instructions::interrupts::without_interrupts(|| FREE_STACK_SLOTS.lock().pop())
+                .unwrap_or_else(|| NEXT_STACK_SLOT.fetch_add(1, Ordering::Relaxed));
+        if slot >= KERNEL_STACK_AREA_SIZE / STACK_SLOT_SIZE {
+            return Err(ThreadError::OutOfMemory);
+        }
+        let stack = KernelStack { slot };
+
+        let first = Page::containing_address(stack.top() - resident);
+        memory::map_pages(
+            first,
+            resident / memory::FRAME_SIZE,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )
+        .map_err(|_| ThreadError::OutOfMemory)?;
+        Ok(stack)
+    }
+
+    /// Lowest mapped address, directly above the guard page
+    fn bottom(&self) -> VirtAddr {
+        VirtAddr::new(KERNEL_STACK_AREA + self.slot * STACK_SLOT_SIZE + memory::FRAME_SIZE)
+    }
+
+    pub(crate) fn top(&self) -> VirtAddr {
+        self.bottom() + KERNEL_STACK_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        memory::unmap_pages(
+            Page::containing_address(self.bottom()),
+            KERNEL_STACK_SIZE / memory::FRAME_SIZE,
+        );
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            FREE_STACK_SLOTS.lock().push(self.slot)
+        });
+    }
+}
+
+/// Register the kernel stack area as a demand region. The guard pages lie
+/// in it as well; the page fault handler leaves them unmapped.
+pub fn init() -> Result<(), AddressSpaceError> {
+    memory::register_demand_region(
+        VirtAddr::new(KERNEL_STACK_AREA),
+        KERNEL_STACK_AREA_SIZE,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )
+}
+
+/// Whether `addr` lies in the area kernel stacks are carved from
+pub fn is_stack_area(addr: VirtAddr) -> bool {
+    (KERNEL_STACK_AREA..KERNEL_STACK_AREA + KERNEL_STACK_AREA_SIZE).contains(&addr.as_u64())
+}
+
+/// Whether `addr` lies in the part of a kernel stack mapped when the stack
+/// is allocated
+pub fn is_resident_stack_page(addr: VirtAddr) -> bool {
+    is_stack_area(addr)
+        && STACK_SLOT_SIZE - (addr.as_u64() - KERNEL_STACK_AREA) % STACK_SLOT_SIZE
+            <= KERNEL_STACK_RESIDENT
+}
+
+/// Whether `addr` lies in the guard page of a kernel stack
+pub fn is_stack_guard_page(addr: VirtAddr) -> bool {
+    let addr = addr.as_u64();
+    addr >= KERNEL_STACK_AREA
+        && (addr - KERNEL_STACK_AREA) % STACK_SLOT_SIZE < memory::FRAME_SIZE
+        && (addr - KERNEL_STACK_AREA) / STACK_SLOT_SIZE < NEXT_STACK_SLOT.load(Ordering::Relaxed)
+}
+
+pub(crate) struct Thread {
+    pub(crate) id: ThreadId,
+    pub(crate) name: String,
+    pub(crate) state: ThreadState,
+    pub(crate) priority: Priority,
+    /// Saved stack pointer while the thread is switched out
+    pub(crate) rsp: u64,
+    /// Stack pointer for traps from ring 3 while the thread runs user code
+    pub(crate) kernel_rsp: u64,
+    /// Monotonic time in ns to wake a sleeping thread at
+    pub(crate) wake_at: u64,
+    /// Threads blocked in `join` on this one
+    pub(crate) joiners: Vec<ThreadId>,
+    pub(crate) entry: Option<Box<dyn FnOnce() + Send>>,
+    /// `None` for the boot thread, which runs on the bootloader's stack
+    pub(crate) stack: Option<KernelStack>,
+    /// Page table of the user program the thread runs, `None` for kernel
+    /// threads, which run on the kernel page table
+    pub(crate) address_space: Option<Arc<AddressSpace>>,
+    /// Process the thread belongs to, `None` for kernel threads
+    pub(crate) process: Option<Pid>,
+}
+
+impl Thread {
+    /// Thread object for the code that is already running
+    pub(crate) fn boot() -> Self {
+        Thread {
+            id: ThreadId::BOOT,
+            name: String::from("boot"),
+            state: ThreadState::Running,
+            priority: Priority::Normal,
+            rsp: 0,
+            kernel_rsp: 0,
+            wake_at: 0,
+            joiners: Vec::new(),
+            entry: None,
+            stack: None,
+            address_space: None,
+            process: None,
+        }
+    }
+
+    pub(crate) fn new(
+        name: &str,
+        priority: Priority,
+        entry: Box<dyn FnOnce() + Send>,
+    ) -> Result<Self, ThreadError> {
+        let stack = KernelStack::new()?;
+        let rsp = scheduler::prepare_stack(stack.top());
+        Ok(Thread {
+            id: ThreadId::new(),
+            name: String::from(name),
+            state: ThreadState::Ready,
+            priority,
+            rsp,
+            kernel_rsp: 0,
+            wake_at: 0,
+            joiners: Vec::new(),
+            entry: Some(entry),
+            stack: Some(stack),
+            address_space: None,
+            process: None,
+        })
+    }
+}
+
+/// Owned permission to wait for a thread and collect its result
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Block until the thread exits and return the value its closure produced.
+    pub fn join(self) -> T {
+        scheduler::wait_for_exit(self.id);
+        self.result
+            .lock()
+            .take()
+            .expect("thread exited without producing a result")
+    }
+}
+
+/// Start a thread running `f` at normal priority.
+pub fn spawn<F, T>(name: &str, f: F) -> Result<JoinHandle<T>, ThreadError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_with_priority(name, Priority::Normal, f)
+}
+
+/// Start a thread running `f` at `priority`.
+pub fn spawn_with_priority<F, T>(
+    name: &str,
+    priority: Priority,
+    f: F,
+) -> Result<JoinHandle<T>, ThreadError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_thread(name, priority, None, f)
+}
+
+/// Start the thread of process `pid`, running `f` with `address_space`
+/// loaded.
+pub(crate) fn spawn_user<F, T>(
+    name: &str,
+    pid: Pid,
+    address_space: Arc<AddressSpace>,
+    f: F,
+) -> Result<JoinHandle<T>, ThreadError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_thread(name, Priority::Normal, Some((pid, address_space)), f)
+}
+
+fn spawn_thread<F, T>(
+    name: &str,
+    priority: Priority,
+    user: Option<(Pid, Arc<AddressSpace>)>,
+    f: F,
+) -> Result<JoinHandle<T>, ThreadError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    if !scheduler::is_running() {
+        return Err(ThreadError::SchedulerNotRunning);
+    }
+    let result = Arc::new(Mutex::new(None));
+    let slot = result.clone();
+    let mut thread = Thread::new(
+        name,
+        priority,
+        Box::new(move || {
+            let value = f();
+            *slot.lock() = Some(value);
+        }),
+    )?;
+    if let Some((pid, address_space)) = user {
+        thread.process = Some(pid);
+        thread.address_space = Some(address_space);
+    }
+    let id = thread.id;
+    scheduler::add_thread(thread);
+    Ok(JoinHandle { id, result })
+}
+
+/// Give up the CPU to another ready thread of the same or higher priority.
+pub fn yield_now() {
+    scheduler::schedule();
+}
+
+/// Suspend the current thread for at least `duration`.
+pub fn sleep(duration: Duration) {
+    scheduler::sleep_until(crate::time::now_ns() + duration.as_nanos() as u64);
+}
+
+/// Terminate the current thread.
+pub fn exit() -> ! {
+    scheduler::exit_current()
+}
+
+/// ID of the running thread
+pub fn current_id() -> ThreadId {
+    scheduler::current_id()
+}
+
+/// Address space of the running thread, `None` for kernel threads
+pub fn current_address_space() -> Option<Arc<AddressSpace>> {
+    scheduler::current_address_space()
+}
+
+/// Process of the running thread, `None` for kernel threads
+pub fn current_process() -> Option<Pid> {
+    scheduler::current_process()
+}
+
+/// Thread errors
+#[derive(Debug, Clone, Copy)]
+pub enum ThreadError {
+    OutOfMemory,
+    SchedulerNotRunning,
+}
+
+impl fmt::Display for ThreadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThreadError::OutOfMemory => write!(f, "Out of memory for thread stack"),
+            ThreadError::SchedulerNotRunning => write!(f, "Scheduler not running"),
+        }
+    }
+}
@@ -0,0 +1,456 @@
+//! Timekeeping
+//!
+//! The PIT drives the periodic 1 kHz tick. The monotonic clock reads the
+//! invariant TSC when the CPU has one (calibrated against the HPET or PIT),
+//! otherwise the HPET main counter. Before [`init`], or with neither of
+//! them, it counts ticks; timeouts then read the PIT back instead, since
+//! ticks stop while interrupts are disabled.
+
+use crate::drivers::rtc::{DateTime, Rtc};
+use crate::interrupts::InterruptIndex;
+use crate::irq::{self, IrqReturn};
+use crate::{acpi, memory};
+use core::arch::x86_64::_rdtsc;
+use core::fmt;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+/// Frequency of the periodic timer interrupt
+pub const TICK_HZ: u64 = 1000;
+const NS_PER_TICK: u64 = 1_000_000_000 / TICK_HZ;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 2 gate and output bits
+const PIT_GATE: u16 = 0x61;
+
+// HPET registers
+const HPET_CAPABILITIES: u64 = 0x00;
+const HPET_CONFIG: u64 = 0x10;
+const HPET_COUNTER: u64 = 0xF0;
+/// COUNT_SIZE_CAP: the main counter is 64 bits wide rather than 32
+const HPET_COUNTER_64BIT: u64 = 1 << 13;
+
+/// Length of the TSC calibration interval
+const CALIBRATION_MS: u64 = 10;
+
+/// Clock backing [`now_ns`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockSource {
+    Pit = 0,
+    Hpet = 1,
+    Tsc = 2,
+}
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Reload value of PIT channel 0, see [`program_pit`]
+static PIT_DIVISOR: AtomicU64 = AtomicU64::new(0);
+/// Serializes latching and reading back PIT channel 0
+static PIT_LOCK: Mutex<()> = Mutex::new(());
+static SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Pit as u8);
+
+static HPET_BASE: AtomicU64 = AtomicU64::new(0);
+static HPET_PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+static HPET_START: AtomicU64 = AtomicU64::new(0);
+static HPET_64BIT: AtomicBool = AtomicBool::new(false);
+/// Last value of a 32-bit main counter, extended to 64 bits
+static HPET_LAST: AtomicU64 = AtomicU64::new(0);
+
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+/// Nanoseconds per TSC cycle as a 32.32 fixed point number
+static TSC_MULT: AtomicU64 = AtomicU64::new(0);
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+
+/// Unix time in nanoseconds at monotonic time 0
+static WALL_CLOCK_OFFSET_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Start the periodic tick and pick the best clock source.
+///
+/// Must run after the interrupt controllers are set up and before
+/// interrupts are enabled.
+pub fn init() {
+    program_pit(TICK_HZ);
+    if let Err(e) = irq::register_irq(InterruptIndex::Timer.irq(), "timer", timer_interrupt) {
+        log::error!("Failed to register timer interrupt: {}", e);
+    }
+
+    let hpet = init_hpet();
+    let invariant_tsc = raw_cpuid::CpuId::new()
+        .get_advanced_power_mgmt_info()
+        .map_or(false, |info| info.has_invariant_tsc());
+
+    // A TSC that may drift with frequency changes is still better than
+    // the tick count, which stops advancing while interrupts are disabled
+    let khz = match (invariant_tsc, hpet) {
+        (_, false) => calibrate_tsc_pit(),
+        (true, true) => calibrate_tsc_hpet(),
+        (false, true) => 0,
+    };
+    // A TSC that didn't move during calibration is of no use
+    if khz > 0 {
+        TSC_KHZ.store(khz, Ordering::Relaxed);
+        TSC_MULT.store((1_000_000u64 << 32) / khz, Ordering::Relaxed);
+        TSC_START.store(rdtsc(), Ordering::Relaxed);
+        SOURCE.store(ClockSource::Tsc as u8, Ordering::Relaxed);
+    } else if hpet {
+        SOURCE.store(ClockSource::Hpet as u8, Ordering::Relaxed);
+    }
+
+    log::info!(
+        "Clock source: {:?} (TSC {} kHz)",
+        clock_source(),
+        TSC_KHZ.load(Ordering::Relaxed)
+    );
+}
+
+fn timer_interrupt() -> IrqReturn {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    if clock_source() == ClockSource::Hpet {
+        // Reads keep a 32-bit counter's extension current across wraps
+        hpet_counter();
+    }
+    crate::task::timer::tick();
+    crate::scheduler::tick();
+    IrqReturn::Handled
+}
+
+/// Program PIT channel 0 as a rate generator firing at `hz`.
+fn program_pit(hz: u64) {
+    let divisor = (PIT_FREQUENCY / hz) as u16;
+    PIT_DIVISOR.store(divisor as u64, Ordering::Relaxed);
+    unsafe {
+        // Channel 0, lobyte/hibyte, mode 2
+        Port::<u8>::new(PIT_COMMAND).write(0b0011_0100);
+        let mut data = Port::<u8>::new(PIT_CHANNEL0);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    }
+}
+
+/// Current count of PIT channel 0, which runs from the divisor down to 1.
+fn read_pit_count() -> u64 {
+    without_interrupts(|| {
+        let _guard = PIT_LOCK.lock();
+        unsafe {
+            // Latch channel 0 so both bytes come from the same count
+            Port::<u8>::new(PIT_COMMAND).write(0b0000_0000);
+            let mut data = Port::<u8>::new(PIT_CHANNEL0);
+            let low = data.read() as u64;
+            let high = data.read() as u64;
+            (high << 8) | low
+        }
+    })
+}
+
+/// Map and start the HPET main counter if ACPI describes one.
+fn init_hpet() -> bool {
+    let table = match acpi::find_table(b"HPET") {
+        Some(table) => acpi::table_body(table),
+        None => return false,
+    };
+    // Generic address structure at offset 4, address space 0 is memory
+    if table.len() < 20 || table[4] != 0 {
+        return false;
+    }
+    let address = u64::from_le_bytes(table[8..16].try_into().unwrap());
+    let base = match memory::map_mmio(PhysAddr::new(address), 0x400) {
+        Ok(base) => base.as_u64(),
+        Err(_) => return false,
+    };
+
+    unsafe {
+        let capabilities = read_volatile((base + HPET_CAPABILITIES) as *const u64);
+        let period = capabilities >> 32;
+        if period == 0 || period > 100_000_000 {
+            return false;
+        }
+        HPET_PERIOD_FS.store(period, Ordering::Relaxed);
+        HPET_64BIT.store(capabilities & HPET_COUNTER_64BIT != 0, Ordering::Relaxed);
+
+        let config = (base + HPET_CONFIG) as *mut u64;
+        write_volatile(config, read_volatile(config) | 1);
+    }
+    HPET_BASE.store(base, Ordering::Relaxed);
+    HPET_LAST.store(read_hpet_counter() as u32 as u64, Ordering::Relaxed);
+    HPET_START.store(hpet_counter(), Ordering::Relaxed);
+    true
+}
+
+fn read_hpet_counter() -> u64 {
+    unsafe { read_volatile((HPET_BASE.load(Ordering::Relaxed) + HPET_COUNTER) as *const u64) }
+}
+
+/// HPET main counter as a 64-bit count.
+///
+/// A 32-bit counter wraps every five minutes at the usual 14.3 MHz, so its
+/// wraps are counted in [`HPET_LAST`]. That takes a read at least every
+/// half wrap, which the timer interrupt provides.
+fn hpet_counter() -> u64 {
+    let raw = read_hpet_counter();
+    if HPET_64BIT.load(Ordering::Relaxed) {
+        return raw;
+    }
+    let mut last = HPET_LAST.load(Ordering::Acquire);
+    loop {
+        let delta = (raw as u32).wrapping_sub(last as u32);
+        // Read before a racing CPU stored a newer value
+        if delta > u32::MAX / 2 {
+            return last;
+        }
+        match HPET_LAST.compare_exchange_weak(
+            last,
+            last + delta as u64,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => return last + delta as u64,
+            Err(current) => last = current,
+        }
+    }
+}
+
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// TSC frequency in kHz measured against the HPET
+fn calibrate_tsc_hpet() -> u64 {
+    let period = HPET_PERIOD_FS.load(Ordering::Relaxed);
+    let counts = CALIBRATION_MS * 1_000_000_000_000 / period;
+
+    let start = hpet_counter();
+    let tsc_start = rdtsc();
+    while hpet_counter().wrapping_sub(start) < counts {
+        core::hint::spin_loop();
+    }
+    (rdtsc() - tsc_start) / CALIBRATION_MS
+}
+
+/// TSC frequency in kHz measured with a one-shot countdown on PIT channel 2
+fn calibrate_tsc_pit() -> u64 {
+    let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+    unsafe {
+        let mut gate = Port::<u8>::new(PIT_GATE);
+        // Speaker off, gate low while programming
+        let value = gate.read() & !0x03;
+        gate.write(value);
+
+        // Channel 2, lobyte/hibyte, mode 0
+        Port::<u8>::new(PIT_COMMAND).write(0b1011_0000);
+        let mut data = Port::<u8>::new(PIT_CHANNEL2);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        gate.write(value | 0x01);
+        let tsc_start = rdtsc();
+        // OUT2 goes high when the count reaches zero
+        while gate.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        let khz = (rdtsc() - tsc_start) / CALIBRATION_MS;
+        gate.write(value);
+        khz
+    }
+}
+
+/// Clock currently backing [`now_ns`]
+pub fn clock_source() -> ClockSource {
+    match SOURCE.load(Ordering::Relaxed) {
+        2 => ClockSource::Tsc,
+        1 => ClockSource::Hpet,
+        _ => ClockSource::Pit,
+    }
+}
+
+/// Timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Monotonic nanoseconds since the clock was initialized
+pub fn now_ns() -> u64 {
+    match clock_source() {
+        ClockSource::Tsc => {
+            let delta = rdtsc().wrapping_sub(TSC_START.load(Ordering::Relaxed));
+            ((delta as u128 * TSC_MULT.load(Ordering::Relaxed) as u128) >> 32) as u64
+        }
+        ClockSource::Hpet => {
+            let delta = hpet_counter().wrapping_sub(HPET_START.load(Ordering::Relaxed));
+            (delta as u128 * HPET_PERIOD_FS.load(Ordering::Relaxed) as u128 / 1_000_000) as u64
+        }
+        ClockSource::Pit => ticks() * NS_PER_TICK,
+    }
+}
+
+/// Time since boot
+pub fn uptime() -> Duration {
+    Duration::from_nanos(now_ns())
+}
+
+/// Seed the wall clock from the CMOS RTC.
+pub fn init_wall_clock() {
+    let rtc = Rtc::new(acpi::century_register());
+    match rtc.read() {
+        Ok(datetime) => {
+            let unix_ns = datetime.to_unix_timestamp() * 1_000_000_000;
+            WALL_CLOCK_OFFSET_NS.store(unix_ns.saturating_sub(now_ns()), Ordering::Relaxed);
+            log::info!("RTC: {} UTC", datetime);
+        }
+        Err(e) => {
+            log::warn!("RTC: {}", e);
+        }
+    }
+}
+
+/// Nanoseconds since the Unix epoch
+pub fn unix_time_ns() -> u64 {
+    WALL_CLOCK_OFFSET_NS.load(Ordering::Relaxed) + now_ns()
+}
+
+/// Current calendar date and time (UTC)
+pub fn wall_clock() -> DateTime {
+    DateTime::from_unix_timestamp(unix_time_ns() / 1_000_000_000)
+}
+
+/// A point on the monotonic clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(now_ns())
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(now_ns().saturating_sub(self.0))
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+}
+
+impl core::ops::Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0.saturating_add(duration.as_nanos() as u64))
+    }
+}
+
+/// Time measured by polling, which keeps going with interrupts disabled.
+///
+/// Reads the monotonic clock, except with the PIT as clock source: ticks
+/// don't advance without interrupts, so PIT channel 0 is read back instead.
+/// That must happen at least once per tick to not miss a reload.
+enum Stopwatch {
+    /// Monotonic time at the start
+    Clock(u64),
+    /// Last PIT count read and PIT counts seen since the start
+    Pit { last: u64, counts: u64 },
+}
+
+impl Stopwatch {
+    fn start() -> Self {
+        // Before `init` programs the PIT its count can't be followed
+        let pit_programmed = PIT_DIVISOR.load(Ordering::Relaxed) != 0;
+        match clock_source() {
+            ClockSource::Pit if pit_programmed => Stopwatch::Pit {
+                last: read_pit_count(),
+                counts: 0,
+            },
+            _ => Stopwatch::Clock(now_ns()),
+        }
+    }
+
+    /// Nanoseconds since the start
+    fn elapsed_ns(&mut self) -> u64 {
+        match self {
+            Stopwatch::Clock(start) => now_ns().saturating_sub(*start),
+            Stopwatch::Pit { last, counts } => {
+                let count = read_pit_count();
+                *counts += if count <= *last {
+                    *last - count
+                } else {
+                    // Reloaded since the last read
+                    *last + PIT_DIVISOR.load(Ordering::Relaxed) - count
+                };
+                *last = count;
+                *counts * 1_000_000_000 / PIT_FREQUENCY
+            }
+        }
+    }
+}
+
+/// Spin for `us` microseconds without yielding the CPU.
+pub fn busy_wait_us(us: u64) {
+    let mut stopwatch = Stopwatch::start();
+    while stopwatch.elapsed_ns() < us * 1000 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Wait at least `duration`.
+///
+/// Blocks the current thread once the scheduler runs, before that halts
+/// between timer interrupts, and spins when interrupts are disabled or on an
+/// application processor, which has neither threads nor a timer.
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    let bsp = crate::percpu::is_bsp();
+    let enabled = x86_64::instructions::interrupts::are_enabled();
+    if bsp && enabled && crate::scheduler::is_running() {
+        crate::scheduler::sleep_until(deadline.as_nanos());
+        return;
+    }
+    if !bsp || !enabled {
+        busy_wait_us(duration.as_micros() as u64);
+        return;
+    }
+    while Instant::now() < deadline {
+        x86_64::instructions::hlt();
+    }
+}
+
+pub fn sleep_ms(ms: u64) {
+    sleep(Duration::from_millis(ms));
+}
+
+/// Poll `condition` until it returns true or `timeout` passes.
+///
+/// The timeout also expires with interrupts disabled, once [`init`] has
+/// run.
+pub fn wait_for<F: FnMut() -> bool>(
+    timeout: Duration,
+    mut condition: F,
+) -> Result<(), TimeoutError> {
+    let timeout = timeout.as_nanos() as u64;
+    let mut stopwatch = Stopwatch::start();
+    loop {
+        if condition() {
+            return Ok(());
+        }
+        if stopwatch.elapsed_ns() >= timeout {
+            return Err(TimeoutError);
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// The condition passed to [`wait_for`] did not become true in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutError;
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Operation timed out")
+    }
+}
@@ -0,0 +1,276 @@
+//! Ring 3 execution
+//!
+//! [`run`] drops the current thread to ring 3 at a given entry point and
+//! returns once the program exits through the `exit` system call or takes a
+//! fault. Meanwhile the kernel side of the thread stays parked on its stack;
+//! the parked stack pointer doubles as the syscall stack and TSS RSP0, so
+//! traps from user mode land right below the parked frames and [`leave`] can
+//! unwind straight back to them.
+
+use crate::address_space::AddressSpaceError;
+use crate::memory::{self, GlobalFrameAllocator};
+use crate::percpu::{KERNEL_RSP_OFFSET, TSS_RSP0_OFFSET};
+use crate::syscall::SyscallFrame;
+use core::arch::global_asm;
+use core::fmt;
+use core::mem::offset_of;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::mapper::{MapToError, TranslateResult};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+/// Lowest address user programs may use, the start of level 4 slot 1. The
+/// bootloader is configured to map nothing in the user slots (see
+/// `[package.metadata.bootloader]` in Cargo.toml and
+/// `address_space::check_boot_layout`); `/bin/init` is linked here too.
+pub const USER_START: u64 = 0x0000_0080_0000_0000;
+/// End of the user address range, below the kernel heap
+pub const USER_END: u64 = 0x0000_4000_0000_0000;
+
+// user_enter(context): save the callee-saved registers, park the stack
+// pointer as the trap stack and sysret into `context`. Programs start with
+// a zero GS base; `swapgs` moves it in and the per-CPU block out to
+// IA32_KERNEL_GS_BASE.
+// user_leave(kind, value): unwind to the parked frame and return (kind, value)
+// from user_enter.
+global_asm!(
+    ".global user_enter",
+    "user_enter:",
+    "cli",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov gs:[{kernel_rsp}], rsp",
+    "mov rax, gs:[{tss_rsp0}]",
+    "mov [rax], rsp",
+    "mov ecx, {kernel_gs_base}",
+    "xor eax, eax",
+    "xor edx, edx",
+    "wrmsr",
+    "mov rax, [rdi + {rax}]",
+    "mov rsi, [rdi + {rsi}]",
+    "mov rdx, [rdi + {rdx}]",
+    "mov r10, [rdi + {r10}]",
+    "mov r8, [rdi + {r8}]",
+    "mov r9, [rdi + {r9}]",
+    "mov rbx, [rdi + {rbx}]",
+    "mov rbp, [rdi + {rbp}]",
+    "mov r12, [rdi + {r12}]",
+    "mov r13, [rdi + {r13}]",
+    "mov r14, [rdi + {r14}]",
+    "mov r15, [rdi + {r15}]",
+    "mov r11, [rdi + {r11}]",
+    "mov rcx, [rdi + {rcx}]",
+    "mov rsp, [rdi + {rsp}]",
+    "mov rdi, [rdi + {rdi}]",
+    "swapgs",
+    "sysretq",
+    ".global user_leave",
+    "user_leave:",
+    "mov rsp, gs:[{kernel_rsp}]",
+    "mov rax, rdi",
+    "mov rdx, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+    kernel_rsp = const KERNEL_RSP_OFFSET,
+    tss_rsp0 = const TSS_RSP0_OFFSET,
+    kernel_gs_base = const IA32_KERNEL_GS_BASE,
+    rax = const offset_of!(SyscallFrame, rax),
+    rdi = const offset_of!(SyscallFrame, rdi),
+    rsi = const offset_of!(SyscallFrame, rsi),
+    rdx = const offset_of!(SyscallFrame, rdx),
+    r10 = const offset_of!(SyscallFrame, r10),
+    r8 = const offset_of!(SyscallFrame, r8),
+    r9 = const offset_of!(SyscallFrame, r9),
+    rbx = const offset_of!(SyscallFrame, rbx),
+    rbp = const offset_of!(SyscallFrame, rbp),
+    r12 = const offset_of!(SyscallFrame, r12),
+    r13 = const offset_of!(SyscallFrame, r13),
+    r14 = const offset_of!(SyscallFrame, r14),
+    r15 = const offset_of!(SyscallFrame, r15),
+    r11 = const offset_of!(SyscallFrame, r11),
+    rcx = const offset_of!(SyscallFrame, rcx),
+    rsp = const offset_of!(SyscallFrame, rsp),
+);
+
+/// MSR whose value `swapgs` exchanges with the GS base
+const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+#[repr(C)]
+struct RawExit {
+    kind: u64,
+    value: u64,
+}
+
+extern "C" {
+    fn user_enter(context: *const SyscallFrame) -> RawExit;
+    fn user_leave(kind: u64, value: u64) -> !;
+}
+
+const EXIT_KIND_EXITED: u64 = 0;
+const EXIT_KIND_FAULT: u64 = 1;
+
+/// RFLAGS a program starts with: IF set, everything else clear
+pub const INITIAL_RFLAGS: u64 = 0x202;
+
+/// Flags a context may carry into user mode: CF, PF, AF, ZF, SF, DF and OF
+const USER_RFLAGS: u64 = 0xCD5;
+
+/// Why a program returned control to [`run`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserExit {
+    /// The program called `exit` with this status
+    Exited(i64),
+    /// The program raised the exception with this vector
+    Fault(u8),
+}
+
+/// Whether `[start, start + len)` lies within the user address range
+pub fn is_user_range(start: u64, len: u64) -> bool {
+    match start.checked_add(len) {
+        Some(end) => start >= USER_START && end <= USER_END,
+        None => false,
+    }
+}
+
+/// Run user code at `entry` on `stack_top` until it exits or faults.
+///
+/// `arg` is passed to the program in rdi. Both addresses must lie in the
+/// user range and be mapped with [`map_user_pages`].
+pub fn run(entry: VirtAddr, stack_top: VirtAddr, arg: u64) -> Result<UserExit, UserModeError> {
+    if !is_user_range(stack_top.as_u64() - 1, 1) {
+        return Err(UserModeError::InvalidAddress);
+    }
+    run_context(&SyscallFrame::new(entry.as_u64(), stack_top.as_u64(), arg))
+}
+
+/// Resume user code with the registers in `context` until it exits or
+/// faults, e.g. the child of a `fork`.
+pub fn run_context(context: &SyscallFrame) -> Result<UserExit, UserModeError> {
+    // sysret to a non-canonical address would fault in ring 0
+    if !is_user_range(context.rcx, 1) {
+        return Err(UserModeError::InvalidAddress);
+    }
+    let mut context = *context;
+    context.r11 = (context.r11 & USER_RFLAGS) | INITIAL_RFLAGS;
+
+    let interrupts_enabled = interrupts::are_enabled();
+    let raw = unsafe { user_enter(&context) };
+    if interrupts_enabled {
+        interrupts::enable();
+    }
+
+    Ok(match raw.kind {
+        EXIT_KIND_EXITED => UserExit::Exited(raw.value as i64),
+        _ => UserExit::Fault(raw.value as u8),
+    })
+}
+
+/// Abandon the running user program and resume the kernel in [`run`].
+///
+/// Must be called on the trap stack of the current thread, i.e. from a
+/// system call or from an exception taken in ring 3.
+pub fn leave(exit: UserExit) -> ! {
+    let (kind, value) = match exit {
+        UserExit::Exited(status) => (EXIT_KIND_EXITED, status as u64),
+        UserExit::Fault(vector) => (EXIT_KIND_FAULT, vector as u64),
+    };
+    unsafe { user_leave(kind, value) }
+}
+
+/// Map `count` zeroed pages at `start` for user mode, in the address space
+/// of the current thread or the kernel page table for kernel threads.
+///
+/// `flags` are combined with `PRESENT` and `USER_ACCESSIBLE`; pass
+/// `NO_EXECUTE` for data.
+pub fn map_user_pages(start: Page, count: u64, flags: PageTableFlags) -> Result<(), UserModeError> {
+    if let Some(space) = crate::thread::current_address_space() {
+        return space.map_zeroed(start, count, flags).map_err(|e| match e {
+            AddressSpaceError::InvalidAddress => UserModeError::InvalidAddress,
+            _ => UserModeError::OutOfMemory,
+        });
+    }
+    if !is_user_range(start.start_address().as_u64(), count * memory::FRAME_SIZE) {
+        return Err(UserModeError::InvalidAddress);
+    }
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    memory::with_mapper(|mapper| {
+        for page in Page::range(start, start + count) {
+            let frame = GlobalFrameAllocator
+                .allocate_frame()
+                .ok_or(MapToError::<Size4KiB>::FrameAllocationFailed)?;
+            // Fresh frames may still hold kernel data
+            unsafe {
+                core::ptr::write_bytes(
+                    memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                    0,
+                    memory::FRAME_SIZE as usize,
+                );
+                mapper
+                    .map_to(page, frame, flags, &mut GlobalFrameAllocator)?
+                    .flush();
+            }
+        }
+        Ok(())
+    })
+    .map_err(|_: MapToError<Size4KiB>| UserModeError::OutOfMemory)
+}
+
+/// Check that user code may access `len` bytes at `addr`, for writing too if
+/// `write` is set.
+///
+/// In a process the areas of its address space decide. Otherwise pages not
+/// mapped yet pass if they belong to a user demand region.
+pub fn check_user_access(addr: u64, len: u64, write: bool) -> bool {
+    if len == 0 {
+        return true;
+    }
+    if !is_user_range(addr, len) {
+        return false;
+    }
+    if let Some(space) = crate::thread::current_address_space() {
+        return space.check_access(VirtAddr::new(addr), len, write);
+    }
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(addr + len - 1));
+    Page::range_inclusive(first, last).all(|page| {
+        let addr = page.start_address();
+        memory::with_mapper(|mapper| match mapper.translate(addr) {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None,
+        })
+        .or_else(|| memory::demand_region(addr).map(|region| region.flags))
+        .map_or(false, |flags| flags.contains(required))
+    })
+}
+
+/// User mode errors
+#[derive(Debug, Clone, Copy)]
+pub enum UserModeError {
+    InvalidAddress,
+    OutOfMemory,
+}
+
+impl fmt::Display for UserModeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserModeError::InvalidAddress => write!(f, "Address outside the user range"),
+            UserModeError::OutOfMemory => write!(f, "Out of memory for user pages"),
+        }
+    }
+}
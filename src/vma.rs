@@ -0,0 +1,531 @@
+//! Virtual memory areas
+//!
+//! A [`VmaTree`] records which ranges of an address space are in use, with
+//! what protection and what backs them. Page tables follow lazily: user
+//! areas are filled in on the first fault (see `address_space`), the kernel
+//! device window eagerly (see `memory::map_physical`).
+
+use crate::address_space::AddressSpaceError;
+use crate::memory::FRAME_SIZE;
+use crate::shm::SharedMemory;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::{BitOr, Range};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Access rights of an area, numbered like `PROT_*`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protection(u8);
+
+impl Protection {
+    pub const NONE: Protection = Protection(0);
+    pub const READ: Protection = Protection(1);
+    pub const WRITE: Protection = Protection(2);
+    pub const EXEC: Protection = Protection(4);
+
+    /// Protection from `PROT_*` bits, `None` if unknown bits are set
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        (bits & !7 == 0).then_some(Protection(bits as u8))
+    }
+
+    pub fn contains(self, other: Protection) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether a read, write or instruction fetch is allowed. The MMU can't
+    /// express write-only or execute-only pages, so any access implies read.
+    pub fn allows(self, write: bool, exec: bool) -> bool {
+        self != Protection::NONE
+            && (!write || self.contains(Protection::WRITE))
+            && (!exec || self.contains(Protection::EXEC))
+    }
+
+    /// Flags for a user page with this protection. Pages without any access
+    /// stay mapped for the kernel only, so their contents survive.
+    pub fn user_page_flags(self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self == Protection::NONE {
+            return flags | PageTableFlags::NO_EXECUTE;
+        }
+        flags |= PageTableFlags::USER_ACCESSIBLE;
+        if self.contains(Protection::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.contains(Protection::EXEC) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+impl BitOr for Protection {
+    type Output = Protection;
+
+    fn bitor(self, other: Protection) -> Protection {
+        Protection(self.0 | other.0)
+    }
+}
+
+/// What provides the contents of an area
+#[derive(Clone)]
+pub enum Backing {
+    /// Zeroed private memory
+    Anonymous,
+    /// Private copy of file data; `offset` is the file offset of the area
+    /// start. Bytes past the end of the file read as zero. `shared`
+    /// mappings may never become writable, files are read-only.
+    File {
+        data: &'static [u8],
+        offset: u64,
+        shared: bool,
+    },
+    /// Frames of a shared memory region starting at frame `first`
+    Shared {
+        memory: Arc<SharedMemory>,
+        first: usize,
+    },
+    /// Reserved and never mapped; every access faults
+    Guard,
+    /// Fixed physical range mapped with the extra `flags`, for device
+    /// memory and DMA buffers in the kernel
+    Physical {
+        start: PhysAddr,
+        flags: PageTableFlags,
+    },
+}
+
+/// Virtual memory area, page aligned
+#[derive(Clone)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub protection: Protection,
+    pub backing: Backing,
+}
+
+impl Vma {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    /// Cut the area at `addr`, keeping the lower part and returning the
+    /// upper one.
+    fn split_off(&mut self, addr: VirtAddr) -> Vma {
+        let delta = addr - self.start;
+        let backing = match &self.backing {
+            Backing::File {
+                data,
+                offset,
+                shared,
+            } => Backing::File {
+                data: *data,
+                offset: offset + delta,
+                shared: *shared,
+            },
+            Backing::Shared { memory, first } => Backing::Shared {
+                memory: memory.clone(),
+                first: first + (delta / FRAME_SIZE) as usize,
+            },
+            Backing::Physical { start, flags } => Backing::Physical {
+                start: *start + delta,
+                flags: *flags,
+            },
+            backing => backing.clone(),
+        };
+        let upper = Vma {
+            start: addr,
+            end: self.end,
+            protection: self.protection,
+            backing,
+        };
+        self.end = addr;
+        upper
+    }
+
+    /// Whether `next` starts where this area ends and carries on with the
+    /// same protection and the backing right after this area's.
+    fn continues_into(&self, next: &Vma) -> bool {
+        let len = self.end - self.start;
+        let backing = match (&self.backing, &next.backing) {
+            (Backing::Anonymous, Backing::Anonymous) | (Backing::Guard, Backing::Guard) => true,
+            (
+                Backing::File {
+                    data,
+                    offset,
+                    shared,
+                },
+                Backing::File {
+                    data: next_data,
+                    offset: next_offset,
+                    shared: next_shared,
+                },
+            ) => {
+                core::ptr::eq(*data, *next_data)
+                    && offset + len == *next_offset
+                    && shared == next_shared
+            }
+            (
+                Backing::Shared { memory, first },
+                Backing::Shared {
+                    memory: next_memory,
+                    first: next_first,
+                },
+            ) => {
+                Arc::ptr_eq(memory, next_memory)
+                    && first + (len / FRAME_SIZE) as usize == *next_first
+            }
+            (
+                Backing::Physical { start, flags },
+                Backing::Physical {
+                    start: next_start,
+                    flags: next_flags,
+                },
+            ) => *start + len == *next_start && flags == next_flags,
+            _ => false,
+        };
+        self.end == next.start && self.protection == next.protection && backing
+    }
+}
+
+/// Non-overlapping areas of one address range, by start address
+#[derive(Clone)]
+pub struct VmaTree {
+    areas: BTreeMap<u64, Vma>,
+    range: Range<u64>,
+}
+
+impl VmaTree {
+    /// Empty tree managing `range`
+    pub const fn new(range: Range<u64>) -> Self {
+        VmaTree {
+            areas: BTreeMap::new(),
+            range,
+        }
+    }
+
+    /// Area containing `addr`
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.areas
+            .range(..=addr.as_u64())
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    /// Areas overlapping `start..end`, in address order
+    pub fn overlapping(&self, start: VirtAddr, end: VirtAddr) -> impl Iterator<Item = &Vma> {
+        let first = self
+            .find(start)
+            .map_or(start.as_u64(), |vma| vma.start.as_u64());
+        self.areas.range(first..end.as_u64()).map(|(_, vma)| vma)
+    }
+
+    /// Whether `start..end` lies inside the managed range
+    pub fn in_range(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        start < end && self.range.start <= start.as_u64() && end.as_u64() <= self.range.end
+    }
+
+    /// Whether no area overlaps `start..end`
+    pub fn is_free(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.overlapping(start, end).next().is_none()
+    }
+
+    /// Whether areas cover all of `start..end` without holes
+    pub fn covers(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        let mut covered = start;
+        for vma in self.overlapping(start, end) {
+            if vma.start > covered {
+                return false;
+            }
+            covered = vma.end;
+        }
+        covered >= end
+    }
+
+    /// Lowest free page-aligned range of `size` bytes at or above `hint`,
+    /// or anywhere if there is no room above it
+    pub fn find_free(&self, size: u64, hint: VirtAddr) -> Option<VirtAddr> {
+        let hint = hint.align_up(FRAME_SIZE).as_u64().max(self.range.start);
+        self.find_free_from(size, hint)
+            .or_else(|| self.find_free_from(size, self.range.start))
+            .map(VirtAddr::new)
+    }
+
+    fn find_free_from(&self, size: u64, from: u64) -> Option<u64> {
+        let mut candidate = from;
+        for vma in self.areas.values() {
+            let (start, end) = (vma.start.as_u64(), vma.end.as_u64());
+            if end <= candidate {
+                continue;
+            }
+            if start >= candidate.checked_add(size)? {
+                break;
+            }
+            candidate = end;
+        }
+        (candidate.checked_add(size)? <= self.range.end).then_some(candidate)
+    }
+
+    /// Add `vma`, which must lie in a free part of the managed range.
+    pub fn insert(&mut self, vma: Vma) -> Result<(), AddressSpaceError> {
+        if !self.in_range(vma.start, vma.end) {
+            return Err(AddressSpaceError::InvalidAddress);
+        }
+        if !self.is_free(vma.start, vma.end) {
+            return Err(AddressSpaceError::AlreadyMapped);
+        }
+        self.areas.insert(vma.start.as_u64(), vma);
+        Ok(())
+    }
+
+    /// Cut the area containing `addr` in two there, if any.
+    pub fn split(&mut self, addr: VirtAddr) {
+        let Some((_, vma)) = self.areas.range_mut(..addr.as_u64()).next_back() else {
+            return;
+        };
+        if vma.contains(addr) {
+            let upper = vma.split_off(addr);
+            self.areas.insert(addr.as_u64(), upper);
+        }
+    }
+
+    /// Areas inside `start..end` after splitting the ones crossing its ends
+    pub fn isolate(&mut self, start: VirtAddr, end: VirtAddr) -> impl Iterator<Item = &mut Vma> {
+        self.split(start);
+        self.split(end);
+        self.areas
+            .range_mut(start.as_u64()..end.as_u64())
+            .map(|(_, vma)| vma)
+    }
+
+    /// Join the areas in `start..end` and their neighbours on either side
+    /// wherever one continues the other, undoing earlier splits.
+    pub fn merge(&mut self, start: VirtAddr, end: VirtAddr) {
+        let first = self
+            .areas
+            .range(..start.as_u64())
+            .next_back()
+            .map_or(start.as_u64(), |(&key, _)| key);
+        let keys: Vec<u64> = self
+            .areas
+            .range(first..=end.as_u64())
+            .map(|(&key, _)| key)
+            .collect();
+        let Some((&first, rest)) = keys.split_first() else {
+            return;
+        };
+        let mut current = first;
+        for &key in rest {
+            if self.areas[&current].continues_into(&self.areas[&key]) {
+                let next = self.areas.remove(&key).unwrap();
+                self.areas.get_mut(&current).unwrap().end = next.end;
+            } else {
+                current = key;
+            }
+        }
+    }
+
+    /// Take out everything in `start..end`, splitting areas crossing its
+    /// ends, and return the removed parts.
+    pub fn remove(&mut self, start: VirtAddr, end: VirtAddr) -> Vec<Vma> {
+        let keys: Vec<u64> = self
+            .isolate(start, end)
+            .map(|vma| vma.start.as_u64())
+            .collect();
+        keys.into_iter()
+            .filter_map(|key| self.areas.remove(&key))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RW: Protection = Protection(Protection::READ.0 | Protection::WRITE.0);
+
+    fn tree() -> VmaTree {
+        VmaTree::new(0x10_0000..0x20_0000)
+    }
+
+    fn vma(start: u64, end: u64, protection: Protection, backing: Backing) -> Vma {
+        Vma {
+            start: VirtAddr::new(start),
+            end: VirtAddr::new(end),
+            protection,
+            backing,
+        }
+    }
+
+    fn anonymous(start: u64, end: u64, protection: Protection) -> Vma {
+        vma(start, end, protection, Backing::Anonymous)
+    }
+
+    fn bounds(tree: &VmaTree) -> Vec<(u64, u64)> {
+        tree.areas
+            .values()
+            .map(|vma| (vma.start.as_u64(), vma.end.as_u64()))
+            .collect()
+    }
+
+    fn range(start: u64, end: u64) -> (VirtAddr, VirtAddr) {
+        (VirtAddr::new(start), VirtAddr::new(end))
+    }
+
+    #[test_case]
+    fn test_vma_insert() {
+        let mut tree = tree();
+        tree.insert(anonymous(0x10_1000, 0x10_3000, RW)).unwrap();
+        assert!(matches!(
+            tree.insert(anonymous(0x10_2000, 0x10_4000, RW)),
+            Err(AddressSpaceError::AlreadyMapped)
+        ));
+        assert!(matches!(
+            tree.insert(anonymous(0xF_F000, 0x10_1000, RW)),
+            Err(AddressSpaceError::InvalidAddress)
+        ));
+        assert!(matches!(
+            tree.insert(anonymous(0x1F_F000, 0x20_1000, RW)),
+            Err(AddressSpaceError::InvalidAddress)
+        ));
+        // Touching areas are fine, on both sides
+        tree.insert(anonymous(0x10_3000, 0x10_4000, Protection::READ))
+            .unwrap();
+        tree.insert(anonymous(0x10_0000, 0x10_1000, Protection::READ))
+            .unwrap();
+        assert_eq!(
+            bounds(&tree),
+            [
+                (0x10_0000, 0x10_1000),
+                (0x10_1000, 0x10_3000),
+                (0x10_3000, 0x10_4000)
+            ]
+        );
+        assert_eq!(
+            tree.find_free(0x2000, VirtAddr::new(0x10_0000)),
+            Some(VirtAddr::new(0x10_4000))
+        );
+    }
+
+    #[test_case]
+    fn test_vma_find_boundaries() {
+        let mut tree = tree();
+        tree.insert(anonymous(0x10_1000, 0x10_3000, RW)).unwrap();
+        tree.insert(anonymous(0x10_4000, 0x10_5000, RW)).unwrap();
+
+        assert!(tree.find(VirtAddr::new(0x10_0FFF)).is_none());
+        assert_eq!(
+            tree.find(VirtAddr::new(0x10_1000)).unwrap().start.as_u64(),
+            0x10_1000
+        );
+        assert_eq!(
+            tree.find(VirtAddr::new(0x10_2FFF)).unwrap().start.as_u64(),
+            0x10_1000
+        );
+        assert!(tree.find(VirtAddr::new(0x10_3000)).is_none());
+
+        let (start, end) = range(0x10_3000, 0x10_4000);
+        assert!(tree.is_free(start, end));
+        assert!(!tree.covers(start, end));
+        let (start, end) = range(0x10_2000, 0x10_4000);
+        assert!(!tree.is_free(start, end));
+        assert!(!tree.covers(start, end));
+        let (start, end) = range(0x10_1000, 0x10_3000);
+        assert!(tree.covers(start, end));
+        assert_eq!(tree.overlapping(start, end).count(), 1);
+    }
+
+    #[test_case]
+    fn test_vma_split_on_unmap() {
+        static DATA: [u8; 0x5000] = [0; 0x5000];
+
+        let mut tree = tree();
+        let file = Backing::File {
+            data: &DATA,
+            offset: 0x1000,
+            shared: false,
+        };
+        tree.insert(vma(0x10_0000, 0x10_4000, Protection::READ, file))
+            .unwrap();
+
+        let (start, end) = range(0x10_1000, 0x10_2000);
+        let removed = tree.remove(start, end);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].start, start);
+        assert_eq!(removed[0].end, end);
+        assert_eq!(
+            bounds(&tree),
+            [(0x10_0000, 0x10_1000), (0x10_2000, 0x10_4000)]
+        );
+        // The upper part still maps the same file bytes
+        match &tree.find(VirtAddr::new(0x10_2000)).unwrap().backing {
+            Backing::File { offset, .. } => assert_eq!(*offset, 0x3000),
+            _ => panic!("backing changed"),
+        }
+    }
+
+    #[test_case]
+    fn test_vma_split_on_protect() {
+        let mut tree = tree();
+        tree.insert(anonymous(0x10_0000, 0x10_4000, RW)).unwrap();
+
+        let (start, end) = range(0x10_1000, 0x10_3000);
+        for vma in tree.isolate(start, end) {
+            vma.protection = Protection::READ;
+        }
+        assert_eq!(
+            bounds(&tree),
+            [
+                (0x10_0000, 0x10_1000),
+                (0x10_1000, 0x10_3000),
+                (0x10_3000, 0x10_4000)
+            ]
+        );
+        let protections: Vec<Protection> = tree.areas.values().map(|vma| vma.protection).collect();
+        assert_eq!(protections, [RW, Protection::READ, RW]);
+    }
+
+    #[test_case]
+    fn test_vma_merge() {
+        let mut tree = tree();
+        tree.insert(anonymous(0x10_0000, 0x10_4000, RW)).unwrap();
+        let (start, end) = range(0x10_1000, 0x10_3000);
+        for vma in tree.isolate(start, end) {
+            vma.protection = Protection::READ;
+        }
+        tree.merge(start, end);
+        assert_eq!(bounds(&tree).len(), 3);
+
+        // Changing it back joins all three parts again
+        for vma in tree.isolate(start, end) {
+            vma.protection = RW;
+        }
+        tree.merge(start, end);
+        assert_eq!(bounds(&tree), [(0x10_0000, 0x10_4000)]);
+
+        // Only backings that continue each other are joined
+        static DATA: [u8; 0x3000] = [0; 0x3000];
+        let file = |offset| Backing::File {
+            data: &DATA,
+            offset,
+            shared: false,
+        };
+        tree.insert(vma(0x10_4000, 0x10_5000, RW, file(0))).unwrap();
+        tree.insert(vma(0x10_5000, 0x10_6000, RW, file(0x1000)))
+            .unwrap();
+        tree.insert(vma(0x10_6000, 0x10_7000, RW, file(0x1000)))
+            .unwrap();
+        let (start, end) = range(0x10_4000, 0x10_7000);
+        tree.merge(start, end);
+        assert_eq!(
+            bounds(&tree),
+            [
+                (0x10_0000, 0x10_4000),
+                (0x10_4000, 0x10_6000),
+                (0x10_6000, 0x10_7000)
+            ]
+        );
+    }
+}